@@ -0,0 +1,210 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+use anyhow::{Context as AnyhowContext, Result};
+use snocat::server::admin::{send_request, AdminRequest, AdminResponse, AdminService};
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::SystemTime,
+};
+
+/// Parameters for sending a request to a running server's admin socket
+#[derive(PartialEq, Clone, Debug)]
+pub struct AdminArgs {
+  pub socket: PathBuf,
+  pub request: AdminRequest,
+}
+
+/// Answers admin requests on a Unix socket at `path`, until the returned task is aborted
+///
+/// The socket is readable and writable only by its owner; a stale socket at `path` is replaced,
+/// but one which another process is still serving is left in place.
+#[cfg(unix)]
+pub fn serve_admin_socket<TTunnel: Send + Sync + 'static>(
+  path: &Path,
+  service: AdminService<TTunnel>,
+) -> Result<tokio::task::JoinHandle<()>> {
+  remove_stale_socket(path)?;
+  let listener = bind_owner_only(path)?;
+  tracing::info!(socket = ?path, "Serving admin requests");
+  let connections = tokio_stream::wrappers::UnixListenerStream::new(listener);
+  Ok(tokio::task::spawn(
+    Arc::new(service).serve_connections(connections),
+  ))
+}
+
+/// Removes a socket left at `path` by a process which is no longer listening on it
+///
+/// Fails if `path` is not a socket, or if anything still answers connections to it.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+  use std::os::unix::fs::FileTypeExt;
+  let metadata = match std::fs::symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e).context("Failed inspecting admin socket path"),
+  };
+  if !metadata.file_type().is_socket() {
+    anyhow::bail!("Admin socket path {:?} exists and is not a socket", path);
+  }
+  match std::os::unix::net::UnixStream::connect(path) {
+    Ok(_) => anyhow::bail!(
+      "Admin socket {:?} is already being served by another process",
+      path
+    ),
+    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+      std::fs::remove_file(path).context("Failed removing stale admin socket")
+    }
+    Err(e) => Err(e).with_context(|| format!("Failed probing existing admin socket {:?}", path)),
+  }
+}
+
+/// Binds a socket at `path` which only its owner may reach, even while it is being set up
+///
+/// The socket is bound inside a private staging directory and restricted before it is
+/// moved into place, so it is never reachable with the permissions given by the umask.
+#[cfg(unix)]
+fn bind_owner_only(path: &Path) -> Result<tokio::net::UnixListener> {
+  use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+  let file_name = path
+    .file_name()
+    .context("Admin socket path must name a file")?;
+  let mut staging_name = std::ffi::OsString::from(".");
+  staging_name.push(file_name);
+  staging_name.push(format!(
+    ".{}.{:08x}",
+    std::process::id(),
+    rand::random::<u32>()
+  ));
+  let staging = path.with_file_name(staging_name);
+  std::fs::DirBuilder::new()
+    .mode(0o700)
+    .create(&staging)
+    .context("Failed creating admin socket staging directory")?;
+  let staged = staging.join("admin.sock");
+  let result = (|| {
+    let listener =
+      tokio::net::UnixListener::bind(&staged).context("Failed binding admin socket")?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))
+      .context("Failed restricting admin socket permissions")?;
+    std::fs::rename(&staged, path).context("Failed moving admin socket into place")?;
+    Ok(listener)
+  })();
+  if result.is_err() {
+    let _ = std::fs::remove_file(&staged);
+  }
+  let _ = std::fs::remove_dir(&staging);
+  result
+}
+
+#[cfg(not(unix))]
+pub fn serve_admin_socket<TTunnel: Send + Sync + 'static>(
+  _path: &Path,
+  _service: AdminService<TTunnel>,
+) -> Result<tokio::task::JoinHandle<()>> {
+  anyhow::bail!("Admin sockets are only supported on Unix")
+}
+
+/// Sends a request to a running server's admin socket, printing its response
+pub async fn admin_main(args: AdminArgs) -> Result<()> {
+  let response = request(&args.socket, &args.request).await?;
+  match response {
+    AdminResponse::Tunnels { tunnels } => {
+      println!("ID\tNAME\tREMOTE\tCONNECTED\tSTREAMS\tPORTS");
+      for tunnel in tunnels {
+        let connected = tunnel
+          .connected_since
+          .duration_since(SystemTime::UNIX_EPOCH)
+          .map_or(0, |since| since.as_secs());
+        let ports: Vec<String> = tunnel.ports.iter().map(u16::to_string).collect();
+        println!(
+          "{}\t{}\t{}\t{}\t{}\t{}",
+          tunnel.id.inner(),
+          tunnel.name.as_ref().map_or("-", |name| name.raw()),
+          tunnel.remote_addr.as_deref().unwrap_or("-"),
+          connected,
+          tunnel
+            .active_streams
+            .map_or_else(|| String::from("-"), |streams| streams.to_string()),
+          ports.join(","),
+        );
+      }
+    }
+    AdminResponse::Ports { allocations } => {
+      println!("PORT\tTUNNEL");
+      for allocation in allocations {
+        println!(
+          "{}\t{}",
+          allocation.port,
+          allocation
+            .tunnel
+            .map_or_else(|| String::from("-"), |id| id.inner().to_string()),
+        );
+      }
+    }
+    AdminResponse::Closed { id } => println!("Closed tunnel {}", id.inner()),
+    AdminResponse::Draining => println!("Draining"),
+    AdminResponse::NoSuchTunnel => anyhow::bail!("No such tunnel is established"),
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+async fn request(socket: &Path, request: &AdminRequest) -> Result<AdminResponse> {
+  let mut connection = tokio::net::UnixStream::connect(socket)
+    .await
+    .context("Failed connecting to admin socket")?;
+  send_request(&mut connection, request).await
+}
+
+#[cfg(not(unix))]
+async fn request(_socket: &Path, _request: &AdminRequest) -> Result<AdminResponse> {
+  anyhow::bail!("Admin sockets are only supported on Unix")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::{bind_owner_only, remove_stale_socket};
+  use std::os::unix::fs::PermissionsExt;
+
+  /// Verifies that the socket is owner-only once bound, and that no staging directory is left behind
+  #[tokio::test]
+  async fn admin_socket_is_owner_only() {
+    let directory = std::env::temp_dir().join(format!("snocat-admin-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("admin.sock");
+    let listener = bind_owner_only(&path);
+    let mode = std::fs::metadata(&path).map(|metadata| metadata.permissions().mode());
+    let entries: Vec<_> = std::fs::read_dir(&directory)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .collect();
+    drop(listener);
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(mode.unwrap() & 0o777, 0o600);
+    assert_eq!(entries, vec![std::ffi::OsString::from("admin.sock")]);
+  }
+
+  /// Verifies that live sockets are left to their owners, while stale ones are replaced
+  #[test]
+  fn only_stale_sockets_are_removed() {
+    let directory = std::env::temp_dir().join(format!("snocat-admin-stale-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("admin.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let live = remove_stale_socket(&path);
+    let live_kept = path.exists();
+    // Closing the listener leaves its socket file behind
+    drop(listener);
+    let stale = remove_stale_socket(&path);
+    let stale_kept = path.exists();
+    std::fs::write(&path, b"not a socket").unwrap();
+    let regular = remove_stale_socket(&path);
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert!(live.is_err());
+    assert!(live_kept);
+    stale.unwrap();
+    assert!(!stale_kept);
+    assert!(regular.is_err());
+  }
+}
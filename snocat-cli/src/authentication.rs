@@ -0,0 +1,220 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+use anyhow::{Context as AnyhowContext, Result};
+use snocat::common::authentication::{
+  ArcAuthenticationHandler, AuthenticationHandler, CertificateAuthenticationHandler,
+  HmacAuthenticationHandler, MethodPolicy, NegotiatingAuthenticationHandler, Revocations,
+  SimpleAckAuthenticationHandler, ThrottleMode, ThrottlePolicy, ThrottlingAuthenticationHandler,
+  TokenAuthenticationHandler, TokenClaimsRegistry, TokenVerifier,
+  CERTIFICATE_AUTHENTICATION_METHOD, HMAC_AUTHENTICATION_METHOD, TOKEN_AUTHENTICATION_METHOD,
+};
+use snocat::common::authorization::{ArcAuthorizer, FilePolicy};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+/// Authentication options shared by both modes
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct AuthenticationArgs {
+  /// Pre-shared keys for mutual HMAC authentication
+  pub key_file: Option<PathBuf>,
+  /// Keys against which presented tokens are verified
+  pub token_keys: Option<PathBuf>,
+  pub token_audience: String,
+  pub token_clock_skew: Duration,
+  /// A token to present when connecting
+  pub token_file: Option<PathBuf>,
+  /// Whether tunnels may be named by the certificate the transport verified, such as
+  /// when the server verifies client certificates or the client presents one
+  pub certificate: bool,
+  /// Permitted authentication methods in order of preference; all configured methods if unset
+  pub methods: Option<Vec<String>>,
+  /// Deadline for each tunnel's authentication
+  pub timeout: Duration,
+  /// Failures from a single address after which it is locked out; zero disables throttling
+  pub max_failures: usize,
+  pub lockout: Duration,
+  /// Rules deciding what authenticated tunnels may do; everything is permitted if unset
+  pub authorization_policy: Option<PathBuf>,
+  /// How often tunnels are re-authenticated, if at all
+  pub reauthentication_interval: Option<Duration>,
+  /// Revoked tunnel names, key IDs, and certificate serials, reloaded on SIGHUP
+  pub revocation_list: Option<PathBuf>,
+}
+
+/// Loads the configured revocation list, if any
+///
+/// The server reloads the list alongside its other settings; see [build_revocations] otherwise.
+pub fn load_revocations(args: &AuthenticationArgs) -> Result<Option<Arc<Revocations>>> {
+  args
+    .revocation_list
+    .as_ref()
+    .map(|path| Revocations::from_file(path).map(Arc::new))
+    .transpose()
+}
+
+/// Loads the configured revocation list, reloading it whenever SIGHUP is received
+pub fn build_revocations(args: &AuthenticationArgs) -> Result<Option<Arc<Revocations>>> {
+  let revocations = match load_revocations(args)? {
+    Some(revocations) => revocations,
+    None => return Ok(None),
+  };
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup()).context("Listening for SIGHUP")?;
+    let weak = Arc::downgrade(&revocations);
+    tokio::task::spawn(async move {
+      while hangups.recv().await.is_some() {
+        let revocations = match weak.upgrade() {
+          Some(revocations) => revocations,
+          None => break,
+        };
+        if let Err(e) = revocations.reload() {
+          tracing::error!(error = ?e, "Failed to reload revocation list; keeping the previous list");
+        }
+      }
+    });
+  }
+  Ok(Some(revocations))
+}
+
+/// Loads the configured authorization policy, if any
+pub fn build_authorizer(args: &AuthenticationArgs) -> Result<Option<ArcAuthorizer>> {
+  args
+    .authorization_policy
+    .as_ref()
+    .map(|path| FilePolicy::load(path).map(|policy| Arc::new(policy) as ArcAuthorizer))
+    .transpose()
+}
+
+/// Selects the authentication handler for either mode from its CLI arguments
+///
+/// Configured methods are negotiated with the peer by name, and addresses which fail
+/// repeatedly are locked out. Without any configured methods, tunnels are accepted
+/// unauthenticated.
+pub fn build_authentication_handler(
+  args: &AuthenticationArgs,
+  revocations: Option<&Arc<Revocations>>,
+) -> Result<Arc<dyn AuthenticationHandler + Send + Sync + 'static>> {
+  let handler = build_method_handler(args, revocations, None)?;
+  Ok(with_throttling(args, handler))
+}
+
+/// Wraps `handler` such that addresses which fail repeatedly are locked out, if configured
+pub fn with_throttling(
+  args: &AuthenticationArgs,
+  handler: ArcAuthenticationHandler,
+) -> ArcAuthenticationHandler {
+  if args.max_failures == 0 {
+    return handler;
+  }
+  Arc::new(
+    ThrottlingAuthenticationHandler::new(
+      handler,
+      ThrottlePolicy::new(
+        args.max_failures,
+        args.lockout,
+        args.lockout,
+        ThrottleMode::Refuse,
+      ),
+    )
+    .with_authentication_timeout(Some(args.timeout)),
+  )
+}
+
+/// The deadline daemons should apply to authentication themselves
+///
+/// When throttling, the throttler applies the deadline, so that timed-out attempts count
+/// against their remote; a daemon's own deadline would abandon them before they are counted.
+pub fn daemon_authentication_timeout(args: &AuthenticationArgs) -> Option<Duration> {
+  if args.max_failures == 0 {
+    Some(args.timeout)
+  } else {
+    None
+  }
+}
+
+/// Negotiates between the configured authentication methods, without throttling
+///
+/// Verified token claims are recorded in `token_claims`, if given.
+pub fn build_method_handler(
+  args: &AuthenticationArgs,
+  revocations: Option<&Arc<Revocations>>,
+  token_claims: Option<&Arc<TokenClaimsRegistry>>,
+) -> Result<ArcAuthenticationHandler> {
+  let mut methods: Vec<(String, ArcAuthenticationHandler)> = Vec::new();
+  if args.token_keys.is_some() || args.token_file.is_some() {
+    let verifier = args
+      .token_keys
+      .as_ref()
+      .map(|path| {
+        TokenVerifier::from_key_file(path, args.token_audience.clone(), args.token_clock_skew)
+      })
+      .transpose()?
+      .map(|verifier| match revocations {
+        Some(revocations) => verifier.with_revocations(Arc::clone(revocations)),
+        None => verifier,
+      });
+    let token = args
+      .token_file
+      .as_ref()
+      .map(|path| {
+        std::fs::read_to_string(path)
+          .with_context(|| format!("Failed reading token file {}", path.display()))
+          .map(|token| token.trim().to_string())
+      })
+      .transpose()?;
+    let handler = TokenAuthenticationHandler::new(verifier, token);
+    methods.push((
+      TOKEN_AUTHENTICATION_METHOD.into(),
+      Arc::new(match token_claims {
+        Some(token_claims) => handler.with_claims_registry(Arc::clone(token_claims)),
+        None => handler,
+      }),
+    ));
+  }
+  if let Some(path) = &args.key_file {
+    methods.push((
+      HMAC_AUTHENTICATION_METHOD.into(),
+      Arc::new({
+        let handler = HmacAuthenticationHandler::from_key_file(path)?;
+        match revocations {
+          Some(revocations) => handler.with_revocations(Arc::clone(revocations)),
+          None => handler,
+        }
+      }),
+    ));
+  }
+
+  if args.certificate {
+    methods.push((
+      CERTIFICATE_AUTHENTICATION_METHOD.into(),
+      Arc::new({
+        let handler = CertificateAuthenticationHandler::new();
+        match revocations {
+          Some(revocations) => handler.with_revocations(Arc::clone(revocations)),
+          None => handler,
+        }
+      }),
+    ));
+  }
+
+  if methods.is_empty() {
+    tracing::warn!("No authentication options provided; tunnels will not be authenticated");
+    return Ok(Arc::new(SimpleAckAuthenticationHandler::new()));
+  }
+  let policy = match &args.methods {
+    Some(permitted) => {
+      if let Some(unknown) = permitted
+        .iter()
+        .find(|name| !methods.iter().any(|(method, _)| method == *name))
+      {
+        anyhow::bail!("Authentication method {:?} is not configured", unknown);
+      }
+      MethodPolicy::permitting(permitted.clone())?
+    }
+    None => MethodPolicy::new(),
+  };
+  Ok(Arc::new(NegotiatingAuthenticationHandler::new(
+    methods, policy,
+  )))
+}
@@ -1,29 +1,345 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 use anyhow::{Context as AnyhowContext, Result};
+use rcgen::{
+  BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+  ExtendedKeyUsagePurpose, IsCa, KeyPair, SanType, SignatureAlgorithm,
+};
+use snocat::common::{
+  authentication::{parse_certificate, AttributeValue},
+  protocol::tunnel::TunnelName,
+};
+use std::{
+  net::IpAddr,
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::Duration,
+};
 use tracing::instrument;
 
+/// Key algorithms available to generated certificates
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum KeyType {
+  EcdsaP256,
+  EcdsaP384,
+  Ed25519,
+}
+
+impl KeyType {
+  pub const NAMES: &'static [&'static str] = &["ecdsa-p256", "ecdsa-p384", "ed25519"];
+
+  fn algorithm(self) -> &'static SignatureAlgorithm {
+    match self {
+      KeyType::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+      KeyType::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+      KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+    }
+  }
+}
+
+impl FromStr for KeyType {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "ecdsa-p256" => Ok(KeyType::EcdsaP256),
+      "ecdsa-p384" => Ok(KeyType::EcdsaP384),
+      "ed25519" => Ok(KeyType::Ed25519),
+      other => anyhow::bail!(
+        "Unknown key type {:?}; expected one of {}",
+        other,
+        KeyType::NAMES.join(", ")
+      ),
+    }
+  }
+}
+
+/// Parameters for creating a certificate authority
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct AuthorityArgs {
+  pub output_base_path: PathBuf,
+  pub name: String,
+  pub key_type: KeyType,
+  pub validity: Duration,
+}
+
+/// What an issued certificate identifies
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Subject {
+  /// A server, reachable by any of these DNS names or IP addresses
+  Server { sans: Vec<String> },
+  /// A client, named as the tunnel it authenticates
+  Client { tunnel_name: TunnelName },
+}
+
+/// Parameters for issuing a certificate signed by an authority
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct IssueArgs {
+  pub output_base_path: PathBuf,
+  pub authority_cert: PathBuf,
+  pub authority_key: PathBuf,
+  pub subject: Subject,
+  pub key_type: KeyType,
+  pub validity: Duration,
+}
+
+/// Writes a self-signed certificate for `host_sans` to `<base>.pub.pem`, and its key to `<base>.priv.pem`
 #[instrument]
-pub async fn certgen_main(output_base_path: String, host_san: String) -> Result<()> {
-  use std::fs;
-  use std::path::PathBuf;
-  let path = PathBuf::from(output_base_path);
-  if let Some(parent) = path.parent() {
-    fs::create_dir_all(parent).context("Directory creation must succeed for certs")?;
-  }
-  let cert =
-    rcgen::generate_simple_self_signed(vec![host_san]).context("Certificate generation failed")?;
-  let public_pem = cert.serialize_pem()?;
-  let private_pem = cert.serialize_private_key_pem();
-  fs::write(
-    path.with_file_name(path.file_name().unwrap().to_str().unwrap().to_string() + ".pub.pem"),
-    &public_pem,
+pub async fn certgen_main(output_base_path: String, host_sans: Vec<String>) -> Result<()> {
+  let mut params = CertificateParams::default();
+  params.subject_alt_names = host_sans.iter().map(|san| parse_san(san)).collect();
+  let cert = Certificate::from_params(params).context("Certificate generation failed")?;
+  write_pem_pair(
+    Path::new(&output_base_path),
+    &cert.serialize_pem()?,
+    &cert.serialize_private_key_pem(),
   )
-  .context("Failed writing public key")?;
-  fs::write(
-    path.with_file_name(path.file_name().unwrap().to_str().unwrap().to_string() + ".priv.pem"),
-    &private_pem,
+}
+
+/// Writes a certificate authority to `<base>.pub.pem`, and its key to `<base>.priv.pem`
+///
+/// The authority may only sign end-entity certificates, rather than further authorities.
+pub fn authority_main(args: AuthorityArgs) -> Result<()> {
+  let mut params = base_params(args.key_type, args.validity)?;
+  params.distinguished_name = common_name(&args.name);
+  params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+  let authority = Certificate::from_params(params).context("Certificate generation failed")?;
+  write_pem_pair(
+    &args.output_base_path,
+    &authority.serialize_pem()?,
+    &authority.serialize_private_key_pem(),
   )
-  .context("Failed writing private key")?;
+}
+
+/// Writes a certificate signed by an authority to `<base>.pub.pem`, and its key to `<base>.priv.pem`
+///
+/// The certificate is followed by the authority's in `<base>.pub.pem`, so that servers may present
+/// the full chain to clients which only trust the authority.
+pub fn issue_main(args: IssueArgs) -> Result<()> {
+  let authority_pem =
+    std::fs::read_to_string(&args.authority_cert).context("Failed reading authority cert file")?;
+  let authority_key =
+    std::fs::read_to_string(&args.authority_key).context("Failed reading authority key file")?;
+  let (chain, private_pem) = issue(
+    &authority_pem,
+    &authority_key,
+    &args.subject,
+    args.key_type,
+    args.validity,
+  )?;
+  write_pem_pair(&args.output_base_path, &chain, &private_pem)
+}
+
+/// Issues a certificate, returning its chain and private key as PEM
+fn issue(
+  authority_pem: &str,
+  authority_key: &str,
+  subject: &Subject,
+  key_type: KeyType,
+  validity: Duration,
+) -> Result<(String, String)> {
+  let authority = load_authority(authority_pem, authority_key)?;
+  let mut params = base_params(key_type, validity)?;
+  params.use_authority_key_identifier_extension = true;
+  match subject {
+    Subject::Server { sans } => {
+      let first = sans
+        .first()
+        .context("Server certificates need at least one SAN")?;
+      params.distinguished_name = common_name(first);
+      params.subject_alt_names = sans.iter().map(|san| parse_san(san)).collect();
+      params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    }
+    Subject::Client { tunnel_name } => {
+      params.distinguished_name = common_name(tunnel_name.raw());
+      params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    }
+  }
+  let cert = Certificate::from_params(params).context("Certificate generation failed")?;
+  let mut chain = cert.serialize_pem_with_signer(&authority)?;
+  chain.push_str(authority_pem);
+  Ok((chain, cert.serialize_private_key_pem()))
+}
+
+fn base_params(key_type: KeyType, validity: Duration) -> Result<CertificateParams> {
+  let mut params = CertificateParams::default();
+  params.alg = key_type.algorithm();
+  params.not_before = chrono::Utc::now();
+  // Certificates cannot express expiry beyond the year 9999
+  params.not_after = chrono::Duration::from_std(validity)
+    .ok()
+    .and_then(|validity| params.not_before.checked_add_signed(validity))
+    .filter(|not_after| chrono::Datelike::year(not_after) <= 9999)
+    .context("Validity period is too long")?;
+  params.serial_number = Some(rand::random());
+  Ok(params)
+}
+
+fn common_name(name: &str) -> DistinguishedName {
+  let mut distinguished_name = DistinguishedName::new();
+  distinguished_name.push(DnType::CommonName, name);
+  distinguished_name
+}
+
+/// IP addresses become IP SANs; anything else is taken as a DNS name
+fn parse_san(san: &str) -> SanType {
+  match san.parse::<IpAddr>() {
+    Ok(ip) => SanType::IpAddress(ip),
+    Err(_) => SanType::DnsName(san.to_string()),
+  }
+}
+
+/// Rebuilds an authority's signer from its first certificate and its private key
+fn load_authority(cert_pem: &str, key_pem: &str) -> Result<Certificate> {
+  let key_pair = KeyPair::from_pem(key_pem).context("Failed parsing authority key")?;
+  let chain = quinn::CertificateChain::from_pem(cert_pem.as_bytes())
+    .context("Failed parsing authority cert")?;
+  let cert = chain
+    .iter()
+    .next()
+    .context("Authority cert file holds no certificates")?;
+  let (subject, public_key) = parse_authority(&cert.0).context(
+    "Failed reading authority cert; its names must be UTF-8 strings, as in those made by `cert ca`",
+  )?;
+  if public_key != key_pair.public_key_der() {
+    anyhow::bail!("Authority key does not match the authority cert");
+  }
+  let mut params = CertificateParams::default();
+  params.alg = key_pair
+    .compatible_algs()
+    .next()
+    .context("Authority key has no signature algorithm")?;
+  params.distinguished_name = subject;
+  params.key_pair = Some(key_pair);
+  Certificate::from_params(params).context("Authority key is unusable")
+}
+
+/// Reads the subject and public key info of a DER certificate, which are all that signing needs
+///
+/// Subject values must be UTF-8 strings, as written by rcgen, so that issued certificates name
+/// their issuer exactly as the authority names itself.
+fn parse_authority(der: &[u8]) -> Result<(DistinguishedName, Vec<u8>)> {
+  let certificate = parse_certificate(der)?;
+  let mut subject = DistinguishedName::new();
+  for (kind, value) in certificate.subject {
+    match value {
+      AttributeValue::Utf8(value) => subject.push(DnType::from_oid(&kind), value),
+      _ => anyhow::bail!("Subject attribute {:?} is not a UTF-8 string", kind),
+    }
+  }
+  Ok((subject, certificate.public_key_info))
+}
+
+/// Writes a certificate chain to `<base>.pub.pem`, and its key to `<base>.priv.pem`
+fn write_pem_pair(output_base_path: &Path, public_pem: &str, private_pem: &str) -> Result<()> {
+  if let Some(parent) = output_base_path.parent() {
+    std::fs::create_dir_all(parent).context("Directory creation must succeed for certs")?;
+  }
+  let with_suffix = |suffix: &str| {
+    let mut name = output_base_path
+      .file_name()
+      .context("Certificate path must name a file")?
+      .to_os_string();
+    name.push(suffix);
+    Ok::<_, anyhow::Error>(output_base_path.with_file_name(name))
+  };
+  std::fs::write(with_suffix(".pub.pem")?, public_pem).context("Failed writing public key")?;
+  snocat::util::write_private_file(&with_suffix(".priv.pem")?, private_pem.as_bytes())
+    .context("Failed writing private key")?;
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{base_params, common_name, issue, load_authority, write_pem_pair, KeyType, Subject};
+  use rcgen::{BasicConstraints, Certificate, IsCa};
+  use snocat::common::protocol::tunnel::TunnelName;
+  use std::time::Duration;
+
+  fn authority(key_type: KeyType) -> Certificate {
+    let mut params = base_params(key_type, Duration::from_secs(3600)).unwrap();
+    params.distinguished_name = common_name("Test Authority");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    Certificate::from_params(params).unwrap()
+  }
+
+  #[test]
+  fn overlong_validity_is_rejected() {
+    assert!(base_params(KeyType::EcdsaP256, Duration::from_secs(u64::MAX)).is_err());
+    let millennia = Duration::from_secs(10_000 * 366 * 24 * 60 * 60);
+    assert!(base_params(KeyType::EcdsaP256, millennia).is_err());
+    let century = Duration::from_secs(100 * 366 * 24 * 60 * 60);
+    assert!(base_params(KeyType::EcdsaP256, century).is_ok());
+  }
+
+  #[test]
+  fn authorities_issue_from_pem() {
+    for key_type in [KeyType::EcdsaP256, KeyType::EcdsaP384, KeyType::Ed25519].iter() {
+      let original = authority(*key_type);
+      let authority_pem = original.serialize_pem().unwrap();
+      let authority_key = original.serialize_private_key_pem();
+      let loaded = load_authority(&authority_pem, &authority_key).unwrap();
+      // Issued certificates identify their issuer's key by this
+      assert_eq!(loaded.get_key_identifier(), original.get_key_identifier());
+
+      let (chain, _key) = issue(
+        &authority_pem,
+        &authority_key,
+        &Subject::Client {
+          tunnel_name: TunnelName::new("edge"),
+        },
+        KeyType::EcdsaP256,
+        Duration::from_secs(3600),
+      )
+      .unwrap();
+      let chain = quinn::CertificateChain::from_pem(chain.as_bytes()).unwrap();
+      let authority_chain = quinn::CertificateChain::from_pem(authority_pem.as_bytes()).unwrap();
+      assert_eq!(chain.iter().count(), 2);
+      assert_eq!(chain.iter().nth(1), authority_chain.iter().next());
+    }
+  }
+
+  #[test]
+  fn mismatched_authority_keys_are_rejected() {
+    let original = authority(KeyType::EcdsaP256);
+    let other = authority(KeyType::EcdsaP256);
+    assert!(load_authority(
+      &original.serialize_pem().unwrap(),
+      &other.serialize_private_key_pem(),
+    )
+    .is_err());
+  }
+
+  /// Verifies that re-issuing over a world-readable key leaves it readable only by its owner
+  #[cfg(unix)]
+  #[test]
+  fn reissued_private_keys_are_owner_only() {
+    use std::os::unix::fs::PermissionsExt;
+    let base = std::env::temp_dir().join(format!("snocat-cert-{}", std::process::id()));
+    let private_path = base.with_file_name(format!("snocat-cert-{}.priv.pem", std::process::id()));
+    std::fs::write(&private_path, "stale").unwrap();
+    std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let written = write_pem_pair(&base, "public", "private");
+    let mode = std::fs::metadata(&private_path)
+      .unwrap()
+      .permissions()
+      .mode();
+    let contents = std::fs::read_to_string(&private_path).unwrap();
+    std::fs::remove_file(&private_path).unwrap();
+    std::fs::remove_file(
+      base.with_file_name(format!("snocat-cert-{}.pub.pem", std::process::id())),
+    )
+    .unwrap();
+    written.unwrap();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(contents, "private");
+  }
+
+  #[test]
+  fn key_types_parse() {
+    for name in KeyType::NAMES {
+      assert!(name.parse::<KeyType>().is_ok());
+    }
+    assert!("rsa".parse::<KeyType>().is_err());
+  }
+}
@@ -1,37 +1,89 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 
-use crate::services::{demand_proxy::DemandProxyClient, PresetServiceRegistry};
+use crate::{
+  config::ClientServiceKind,
+  forwards::{Forward, RetryDelay},
+  transport::TransportArgs,
+};
 use anyhow::{Context as AnyhowContext, Error as AnyErr, Result};
-use futures::{future::*, *};
+use futures::future::*;
+use quinn::crypto::rustls::TlsSession;
 use snocat::{
   common::{
-    authentication::SimpleAckAuthenticationHandler,
+    authorization::ServiceAuthorization,
     protocol::{
+      discovery::{ServiceDiscoveryService, SERVICE_DISCOVERY_ADDRESS},
+      heartbeat::HeartbeatPolicy,
       proxy_tcp::TcpStreamService,
+      service_registry::{ServiceScope, TrieServiceRegistry},
       traits::{InMemoryTunnelRegistry, TunnelRegistry},
       tunnel::{
-        from_quinn_endpoint, id::MonotonicAtomicGenerator, QuinnTunnel, TunnelSide, TunnelUplink,
+        from_quinn_endpoint, id::MonotonicAtomicGenerator, QuinnTunnel, TunnelId, TunnelName,
+        TunnelSide, TunnelUplink,
       },
-      Request, RouteAddress, Router, RoutingError,
+      RouteAddress, RoutedLink, Router, RoutingError,
     },
-    tunnel_source::DynamicConnectionSet,
   },
-  server::modular::ModularDaemon,
+  server::modular::{DisconnectReason, ModularDaemon},
   util::{self, tunnel_stream::TunnelStream},
 };
 use std::{
-  path::PathBuf,
+  path::{Path, PathBuf},
   sync::{Arc, Weak},
 };
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio_util::sync::CancellationToken;
 
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct ClientArgs {
   pub authority_cert: Option<PathBuf>,
+  /// Certificate presented to the server, which names the tunnel if the server verifies it
+  pub client_certificate: Option<ClientCertificate>,
   pub driver_host: std::net::SocketAddr,
   pub driver_san: String,
-  pub proxy_target_host: std::net::SocketAddr,
+  /// Forwards requested of the server, each maintained independently
+  pub forwards: Vec<Forward>,
+  /// Heartbeats sent to the server, if enabled
+  pub heartbeat: Option<HeartbeatPolicy>,
+  /// Time without requests after which a tunnel is closed, if any
+  pub idle_tunnel_timeout: Option<std::time::Duration>,
+  /// Time without bytes moving after which a forwarded stream is closed, if any
+  pub idle_stream_timeout: Option<std::time::Duration>,
+  pub transport: TransportArgs,
+  /// Services offered to the server
+  pub services: Vec<ClientServiceKind>,
+  pub authentication: crate::authentication::AuthenticationArgs,
+}
+
+/// PEM files of a client certificate chain and its private key
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct ClientCertificate {
+  pub cert: PathBuf,
+  pub key: PathBuf,
+}
+
+impl ClientCertificate {
+  fn load(&self) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+    use rustls::internal::pemfile;
+    let cert_pem = std::fs::read(&self.cert).context("Failed reading client cert file")?;
+    let key_pem = std::fs::read(&self.key).context("Failed reading client key file")?;
+    let chain = pemfile::certs(&mut &cert_pem[..])
+      .map_err(|()| AnyErr::msg("Malformed client certificate chain"))?;
+    if chain.is_empty() {
+      return Err(AnyErr::msg("No client certificate found"));
+    }
+    let key = pemfile::pkcs8_private_keys(&mut &key_pem[..])
+      .ok()
+      .and_then(|keys| keys.into_iter().next())
+      .or_else(|| {
+        pemfile::rsa_private_keys(&mut &key_pem[..])
+          .ok()
+          .and_then(|keys| keys.into_iter().next())
+      })
+      .ok_or_else(|| AnyErr::msg("No private key found for the client certificate"))?;
+    Ok((chain, key))
+  }
 }
 
 pub struct SnocatClientRouter {
@@ -49,12 +101,12 @@ impl SnocatClientRouter {
 impl Router for SnocatClientRouter {
   fn route(
     &self,
-    request: &Request,
+    address: &RouteAddress,
     // We don't need this one because we keep our own reference around for an unboxed variant
     // this allows us to access methods specific to our tunnel type's implementation
     _tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
-  ) -> BoxFuture<'_, Result<(RouteAddress, Box<dyn TunnelStream + Send + Sync>), RoutingError>> {
-    let addr = request.address.clone();
+  ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+    let addr = address.clone();
     async move {
       let tunnel_registry = self
         .typed_tunnel_registry
@@ -84,32 +136,6 @@ impl Router for SnocatClientRouter {
 
 pub async fn client_main(config: ClientArgs) -> Result<()> {
   let config = Arc::new(config);
-  let authority = match &config.authority_cert {
-    Some(authority_cert_path) => {
-      let cert_pem =
-        std::fs::read(authority_cert_path).context("Failed reading authority cert file")?;
-      let authority = quinn::CertificateChain::from_pem(&cert_pem)?;
-      let authority = quinn::Certificate::from(
-        authority
-          .iter()
-          .nth(0)
-          .cloned()
-          .ok_or_else(|| AnyErr::msg("No root authority"))?,
-      );
-      Some(authority)
-    }
-    None => None,
-  };
-  let quinn_config = {
-    let mut qc = quinn::ClientConfigBuilder::default();
-    qc.enable_keylog();
-    if let Some(authority) = authority {
-      qc.add_certificate_authority(authority)?;
-    }
-    qc.protocols(util::ALPN_QUIC_HTTP);
-    qc.build()
-  };
-
   let (shutdown, sigint_handler_task) = {
     let shutdown = CancellationToken::new();
     let shutdown_trigger = shutdown.clone();
@@ -120,18 +146,45 @@ pub async fn client_main(config: ClientArgs) -> Result<()> {
     (shutdown, sigint_handler_task)
   };
 
-  let proxy_target = config.proxy_target_host.clone();
+  let service_registry = Arc::new(TrieServiceRegistry::new());
 
-  let service_registry = Arc::new(PresetServiceRegistry::new());
+  let tunnel_registry: Arc<InMemoryTunnelRegistry> = Arc::new(InMemoryTunnelRegistry::new());
 
-  let tcp_proxy_service = TcpStreamService::new(false);
-  service_registry.add_service_blocking(Arc::new(tcp_proxy_service));
+  let authorizer = crate::authentication::build_authorizer(&config.authentication)?;
 
-  let tunnel_registry: Arc<InMemoryTunnelRegistry> = Arc::new(InMemoryTunnelRegistry::new());
+  let tcp_proxy_service =
+    TcpStreamService::new(false).with_idle_timeout(config.idle_stream_timeout);
+  let tcp_proxy_service = match &authorizer {
+    Some(authorizer) => tcp_proxy_service.with_authorization(ServiceAuthorization::new(
+      Arc::clone(authorizer),
+      Arc::downgrade(&tunnel_registry) as Weak<_>,
+    )),
+    None => tcp_proxy_service,
+  };
+  if config.services.contains(&ClientServiceKind::TcpProxy) {
+    service_registry.register("/", 0, ServiceScope::Global, Arc::new(tcp_proxy_service));
+  }
+
+  if config.services.contains(&ClientServiceKind::Discovery) {
+    let discovery_service = ServiceDiscoveryService::new(
+      Arc::downgrade(&service_registry) as Weak<_>,
+      Arc::downgrade(&tunnel_registry) as Weak<_>,
+    );
+    service_registry.register(
+      SERVICE_DISCOVERY_ADDRESS,
+      0,
+      ServiceScope::Global,
+      Arc::new(discovery_service),
+    );
+  }
 
   let router = Arc::new(SnocatClientRouter::new(Arc::downgrade(&tunnel_registry)));
 
-  let authentication_handler = Arc::new(SimpleAckAuthenticationHandler::new());
+  let revocations = crate::authentication::build_revocations(&config.authentication)?;
+  let authentication_handler = crate::authentication::build_authentication_handler(
+    &config.authentication,
+    revocations.as_ref(),
+  )?;
 
   // Our tunnel IDs are just increments atop the unix timestamp millisecond we started the server
   // This would still likely lead to eventual collisions in a shared-ID cluster, so don't do that
@@ -143,109 +196,195 @@ pub async fn client_main(config: ClientArgs) -> Result<()> {
       .as_millis() as u64,
   ));
 
-  let modular = Arc::new(ModularDaemon::<QuinnTunnel<_>>::new(
-    service_registry,
-    tunnel_registry,
-    router,
-    authentication_handler,
-    tunnel_id_generator,
-  ));
-
-  let (endpoint, _incoming) = {
-    let mut response_endpoint = quinn::Endpoint::builder();
-    response_endpoint.default_client_config(quinn_config);
-    response_endpoint.bind(&"[::]:0".parse()?)? // Should this be IPv4 if the server is?
-  };
-
-  let (mut add_new_connection, connections_handle) = {
-    let mut current_connection_id = 0u32;
-    let connections = DynamicConnectionSet::<u32, _>::new();
-    let connections_handle = connections.handle();
-    let add_new_connection = move |tunnel: QuinnTunnel<_>| -> u32 {
-      let connection_id = current_connection_id;
-      current_connection_id += 1;
-      assert!(
-        connections
-          .attach_stream(connection_id, stream::once(future::ready(tunnel)).boxed())
-          .is_none(),
-        "Connection IDs must be unique"
-      );
-      connection_id
+  let modular = {
+    let modular = ModularDaemon::<QuinnTunnel<_>>::new(
+      service_registry,
+      tunnel_registry,
+      router,
+      authentication_handler,
+      tunnel_id_generator,
+    )
+    .with_authentication_timeout(crate::authentication::daemon_authentication_timeout(
+      &config.authentication,
+    ))
+    .with_reauthentication_interval(config.authentication.reauthentication_interval)
+    .with_heartbeat(config.heartbeat)
+    .with_idle_timeout(config.idle_tunnel_timeout);
+    let modular = match revocations {
+      Some(revocations) => modular.with_revocations(revocations),
+      None => modular,
     };
-    (add_new_connection, connections_handle)
+    match authorizer {
+      Some(authorizer) => Arc::new(modular.with_authorizer(authorizer)),
+      None => Arc::new(modular),
+    }
   };
 
-  {
-    let connecting: Result<_, _> = endpoint
-      .connect(&config.driver_host, &config.driver_san)
-      .context("Connecting to server")?
-      .await;
-    let connection = connecting.context("Finalizing connection to server...")?;
-    let tunnel = from_quinn_endpoint(connection, TunnelSide::Connect);
-    let addr = tunnel.addr();
-    let conn_id = add_new_connection(tunnel);
-    tracing::info!(remote = ?addr, connection_id = conn_id, "connected");
-  }
+  let endpoint = build_endpoint(
+    config.authority_cert.as_deref(),
+    config.client_certificate.as_ref(),
+    &config.transport,
+  )?;
+
+  // Whether an authenticated tunnel is available to carry forwards
+  let (tunnel_ready_sender, tunnel_ready) = watch::channel(false);
+  let authenticated = modular.tunnel_authenticated.subscribe();
+  let disconnected = modular.tunnel_disconnected.subscribe();
+
+  // Fail fast if the server can't be reached at all; connections lost later are retried
+  let (tunnels, tunnel_source) = mpsc::unbounded_channel();
+  let _ = tunnels.send(connect(&endpoint, &config.driver_host, &config.driver_san).await?);
 
   tracing::debug!("Setting up stream handling...");
   let request_handler = Arc::clone(modular.requests());
 
   let daemon = modular
-    .run(connections_handle.map(|(_k, v)| v), shutdown.clone())
+    .run(
+      tokio_stream::wrappers::UnboundedReceiverStream::new(tunnel_source),
+      shutdown.clone(),
+    )
     .map_err(|_| anyhow::Error::msg("Daemon panicked and lost context"))
     .boxed();
 
-  let tcp_watcher = {
-    let shutdown = shutdown;
-    tokio::task::spawn(async move {
-      // TODO: Wait until a connection is completed before requesting a proxy
-      // TODO: While shutdown is not requested, attempt to reconnect every second
-      tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-      let demand_proxy = DemandProxyClient {
-        proxied_subject: "".into(),
-      };
-      let (_remote_addr, wait_close) = request_handler
-        .handle(
-          format!(
-            "/proxyme/0.0.1/{}/{}",
-            proxy_target.ip().to_string(),
-            proxy_target.port()
-          ),
-          demand_proxy,
-        )
-        .await?;
-      let res = futures::future::select(wait_close, Box::pin(shutdown.cancelled())).await;
-      match res {
-        Either::Left((Err(res), _listener)) => Err(res)?,
-        Either::Left((Ok(()), _listener)) => (),
-        Either::Right(((), _finish_listener)) => (),
-      };
-      Result::<(), anyhow::Error>::Ok(())
-    })
-    .boxed()
-    // JoinHandle
-    .map_err(|join_error| {
-      if join_error.is_panic() {
-        anyhow::Error::msg("TCP Watcher panicked and lost context")
-      } else {
-        anyhow::Error::context(
-          anyhow::Error::msg(join_error.to_string()),
-          "TCP Watcher join handle failed",
-        )
-      }
-    })
-    // Flatten Result<Result<T, E>, E> to Result<T, E>; this is why we need HKT, Rust!
-    .map(|result| match result {
-      Ok(Ok(ok)) => Ok(ok),
-      Ok(Err(err)) => Err(err),
-      Err(err) => Err(err),
-    })
-    .boxed()
-  };
+  let connector = tokio::task::spawn(maintain_connection(
+    endpoint,
+    Arc::clone(&config),
+    tunnels,
+    authenticated,
+    disconnected,
+    tunnel_ready_sender,
+    shutdown.clone(),
+  ));
 
-  let ((), ()) = futures::future::try_join(daemon, tcp_watcher).await?;
+  let forwards = crate::forwards::run_forwards(
+    config.forwards.clone(),
+    request_handler,
+    tunnel_ready,
+    config.idle_stream_timeout,
+    shutdown,
+  )
+  .map(Result::<(), anyhow::Error>::Ok);
+
+  let (_report, ()) = futures::future::try_join(daemon, forwards).await?;
 
   sigint_handler_task.abort();
+  connector.abort();
   tracing::info!("Disconnecting...");
   Ok(())
 }
+
+/// Binds an endpoint for connecting to servers, trusting `authority_cert` if one is given,
+/// and presenting `client_certificate` to servers which request one
+pub fn build_endpoint(
+  authority_cert: Option<&Path>,
+  client_certificate: Option<&ClientCertificate>,
+  transport: &TransportArgs,
+) -> Result<quinn::Endpoint> {
+  let authority = match authority_cert {
+    Some(authority_cert_path) => {
+      let cert_pem =
+        std::fs::read(authority_cert_path).context("Failed reading authority cert file")?;
+      let authority = quinn::CertificateChain::from_pem(&cert_pem)?;
+      let authority = quinn::Certificate::from(
+        authority
+          .iter()
+          .nth(0)
+          .cloned()
+          .ok_or_else(|| AnyErr::msg("No root authority"))?,
+      );
+      Some(authority)
+    }
+    None => None,
+  };
+  let quinn_config = {
+    let mut qc = quinn::ClientConfigBuilder::default();
+    qc.enable_keylog();
+    if let Some(authority) = authority {
+      qc.add_certificate_authority(authority)?;
+    }
+    qc.protocols(util::ALPN_QUIC_HTTP);
+    let mut client_config = qc.build();
+    if let Some(client_certificate) = client_certificate {
+      let (chain, key) = client_certificate.load()?;
+      Arc::make_mut(&mut client_config.crypto)
+        .set_single_client_cert(chain, key)
+        .context("Invalid client certificate or key")?;
+    }
+    client_config.transport = Arc::new(crate::transport::build_transport_config(transport)?);
+    client_config
+  };
+  let (endpoint, _incoming) = {
+    let mut response_endpoint = quinn::Endpoint::builder();
+    response_endpoint.default_client_config(quinn_config);
+    response_endpoint.bind(&"[::]:0".parse()?)? // Should this be IPv4 if the server is?
+  };
+  Ok(endpoint)
+}
+
+pub async fn connect(
+  endpoint: &quinn::Endpoint,
+  driver_host: &std::net::SocketAddr,
+  driver_san: &str,
+) -> Result<QuinnTunnel<TlsSession>> {
+  let connecting: Result<_, _> = endpoint
+    .connect(driver_host, driver_san)
+    .context("Connecting to server")?
+    .await;
+  let connection = connecting.context("Finalizing connection to server...")?;
+  let tunnel = from_quinn_endpoint(connection, TunnelSide::Connect);
+  tracing::info!(remote = ?tunnel.addr(), "connected");
+  Ok(tunnel)
+}
+
+/// Reconnects to the server whenever the tunnel is lost, until shutdown is requested
+///
+/// Reports whether the current tunnel has authenticated through `tunnel_ready`.
+async fn maintain_connection(
+  endpoint: quinn::Endpoint,
+  config: Arc<ClientArgs>,
+  tunnels: mpsc::UnboundedSender<QuinnTunnel<TlsSession>>,
+  mut authenticated: broadcast::Receiver<(TunnelId, TunnelName, Arc<QuinnTunnel<TlsSession>>)>,
+  mut disconnected: broadcast::Receiver<(TunnelId, Option<TunnelName>, DisconnectReason)>,
+  tunnel_ready: watch::Sender<bool>,
+  shutdown: CancellationToken,
+) {
+  let mut retry_delay = RetryDelay::new();
+  loop {
+    // Await the current tunnel's loss, noting when it authenticates
+    let reason = loop {
+      tokio::select! {
+        event = authenticated.recv() => match event {
+          Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+            let _ = tunnel_ready.send(true);
+            retry_delay.reset();
+          }
+          Err(broadcast::error::RecvError::Closed) => return,
+        },
+        event = disconnected.recv() => match event {
+          Ok((_id, _name, reason)) => break Some(reason),
+          Err(broadcast::error::RecvError::Lagged(_)) => break None,
+          Err(broadcast::error::RecvError::Closed) => return,
+        },
+        _ = shutdown.cancelled() => return,
+      }
+    };
+    let _ = tunnel_ready.send(false);
+    loop {
+      let delay = retry_delay.advance();
+      tracing::warn!(?reason, ?delay, "Tunnel lost; reconnecting");
+      tokio::select! {
+        _ = tokio::time::sleep(delay) => (),
+        _ = shutdown.cancelled() => return,
+      }
+      match connect(&endpoint, &config.driver_host, &config.driver_san).await {
+        Ok(tunnel) => {
+          if tunnels.send(tunnel).is_err() {
+            return;
+          }
+          break;
+        }
+        Err(e) => tracing::warn!(error = %format!("{:#}", e), "Failed to reconnect"),
+      }
+    }
+  }
+}
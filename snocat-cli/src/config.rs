@@ -0,0 +1,452 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! TOML configuration files for the `server` and `client` modes
+//!
+//! Flags passed explicitly on the command line take precedence over a file's values,
+//! which in turn take precedence over the flags' defaults.
+#![warn(unused_imports)]
+
+use anyhow::{Context as AnyhowContext, Result};
+use std::{
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+  pub logging: LoggingSection,
+  pub transport: TransportSection,
+  pub authentication: AuthenticationSection,
+  pub heartbeat: HeartbeatSection,
+  pub idle: IdleSection,
+  pub server: ServerSection,
+  pub client: ClientSection,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+  /// Tracing filter directives, used unless `RUST_LOG` is set
+  pub filter: Option<String>,
+  pub format: LogFormat,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+  Full,
+  Compact,
+  Pretty,
+}
+
+impl Default for LogFormat {
+  fn default() -> Self {
+    LogFormat::Pretty
+  }
+}
+
+/// QUIC transport parameters; durations are in seconds, where 0 disables
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSection {
+  pub receive_window: Option<u64>,
+  pub send_window: Option<u64>,
+  pub stream_receive_window: Option<u64>,
+  pub keep_alive_interval: Option<u64>,
+  pub idle_timeout: Option<u64>,
+  /// Whether clients may migrate to new addresses; server only
+  pub migration: Option<bool>,
+  /// Whether clients must prove their address before connecting; server only
+  pub stateless_retry: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthenticationSection {
+  pub key_file: Option<PathBuf>,
+  pub token_keys: Option<PathBuf>,
+  pub token_audience: Option<String>,
+  pub token_clock_skew: Option<u64>,
+  pub token_file: Option<PathBuf>,
+  /// Permitted authentication methods, in order of preference
+  pub methods: Option<Vec<String>>,
+  pub timeout: Option<u64>,
+  pub max_failures: Option<u64>,
+  pub lockout: Option<u64>,
+  pub authorization_policy: Option<PathBuf>,
+  pub reauth_interval: Option<u64>,
+  pub revocation_list: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSection {
+  pub interval: Option<u64>,
+  pub timeout: Option<u64>,
+  pub failures: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleSection {
+  pub tunnel_timeout: Option<u64>,
+  pub stream_timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+  pub cert: Option<PathBuf>,
+  pub key: Option<PathBuf>,
+  /// Authority against which client certificates are verified
+  pub client_ca: Option<PathBuf>,
+  /// Address on which tunnels are accepted
+  pub quic: Option<String>,
+  /// Address on which forwarded ports are bound
+  pub bind_ip: Option<String>,
+  /// Range of forwarded ports, as `<start>:<end>`
+  pub ports: Option<String>,
+  pub drain_grace_period: Option<u64>,
+  pub admin_socket: Option<PathBuf>,
+  /// Services offered to clients; `demand_proxy` and `discovery` if unset
+  ///
+  /// `tcp_proxy` and `relay` are only offered when listed, and require an authorization policy.
+  pub services: Option<Vec<ServerServiceKind>>,
+  /// Seconds between checks for changed files to reload
+  pub watch_interval: Option<u64>,
+  pub limits: LimitsSection,
+  pub routing: RoutingSection,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerServiceKind {
+  DemandProxy,
+  Discovery,
+  /// Connections to TCP destinations reachable from the server, for clients' local forwards
+  TcpProxy,
+  /// Relays to services reachable through other tunnels, for `snocat-cli connect`
+  Relay,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+  pub max_tunnel_requests: Option<u64>,
+  pub max_tunnel_negotiations: Option<u64>,
+  pub max_requests: Option<u64>,
+  pub negotiation_timeout: Option<u64>,
+  pub backpressure: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingSection {
+  pub strategy: Option<RoutingStrategy>,
+  /// Candidate tunnels tried before a request fails, for balancing strategies
+  pub max_attempts: Option<usize>,
+}
+
+/// How the server chooses among tunnels when opening links
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingStrategy {
+  /// Always use the most recently connected tunnel
+  Newest,
+  RoundRobin,
+  Random,
+  LeastActiveStreams,
+  LowestRtt,
+}
+
+impl Default for RoutingStrategy {
+  fn default() -> Self {
+    RoutingStrategy::Newest
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSection {
+  pub authority: Option<PathBuf>,
+  /// Certificate chain presented to the server, and its private key
+  pub cert: Option<PathBuf>,
+  pub key: Option<PathBuf>,
+  /// Server to connect to, as `<host>:<port>`
+  pub driver: Option<String>,
+  pub driver_san: Option<String>,
+  /// Local addresses for the server to expose on ports it binds, as `<ip>:<port>`
+  pub remote_forwards: Option<Vec<String>>,
+  /// Local listeners sending connections to targets reachable from the server,
+  /// as `<listen address>=<host>:<port>`
+  pub local_forwards: Option<Vec<String>>,
+  /// Services offered to the server; `tcp_proxy` and `discovery` if unset
+  pub services: Option<Vec<ClientServiceKind>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientServiceKind {
+  TcpProxy,
+  Discovery,
+}
+
+impl ConfigFile {
+  /// Reads a config file, resolving relative paths within it against the file's directory
+  pub fn load(path: &Path) -> Result<Self> {
+    let content = std::fs::read_to_string(path)
+      .with_context(|| format!("Failed reading config file {}", path.display()))?;
+    let mut config =
+      Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))?;
+    if let Some(base) = path.parent() {
+      config.resolve_paths(base);
+    }
+    Ok(config)
+  }
+
+  pub fn parse(content: &str) -> Result<Self> {
+    // Deserialization errors name the offending key, such as "for key `server.quic`"
+    let config: Self = toml::from_str(content)?;
+    if let Some(filter) = &config.logging.filter {
+      tracing_subscriber::EnvFilter::try_new(filter).context("Invalid `logging.filter`")?;
+    }
+    if let Some(0) = config.server.routing.max_attempts {
+      anyhow::bail!("Invalid `server.routing.max_attempts`: at least one attempt is required");
+    }
+    Ok(config)
+  }
+
+  fn resolve_paths(&mut self, base: &Path) {
+    let authentication = &mut self.authentication;
+    let paths = [
+      &mut self.server.cert,
+      &mut self.server.key,
+      &mut self.server.client_ca,
+      &mut self.server.admin_socket,
+      &mut self.client.authority,
+      &mut self.client.cert,
+      &mut self.client.key,
+      &mut authentication.key_file,
+      &mut authentication.token_keys,
+      &mut authentication.token_file,
+      &mut authentication.authorization_policy,
+      &mut authentication.revocation_list,
+    ];
+    for path in std::array::IntoIter::new(paths).flatten() {
+      if path.is_relative() {
+        *path = base.join(&path);
+      }
+    }
+  }
+}
+
+/// Resolves each setting from an explicitly-passed flag, then a config file, then the flag's default
+pub struct Settings<'a, 'b> {
+  args: &'a clap::ArgMatches<'b>,
+}
+
+impl<'a, 'b> Settings<'a, 'b> {
+  pub fn new(args: &'a clap::ArgMatches<'b>) -> Self {
+    Self { args }
+  }
+
+  /// Whether `flag` was passed explicitly, rather than left to its default
+  fn passed(&self, flag: &str) -> bool {
+    self.args.occurrences_of(flag) > 0
+  }
+
+  pub fn value<T>(&self, flag: &str, file: Option<T>) -> Result<Option<T>>
+  where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+  {
+    match self.args.value_of(flag) {
+      Some(raw) if self.passed(flag) || file.is_none() => raw
+        .parse()
+        .map(Some)
+        .with_context(|| format!("Invalid --{}", flag)),
+      _ => Ok(file),
+    }
+  }
+
+  /// As [Self::value], parsing file values with `parse` and naming `key` if they are invalid
+  pub fn parsed<T, F>(
+    &self,
+    flag: &str,
+    key: &str,
+    file: Option<&str>,
+    parse: F,
+  ) -> Result<Option<T>>
+  where
+    F: Fn(&str) -> Result<T>,
+  {
+    match (self.args.value_of(flag), file) {
+      (Some(raw), None) => parse(raw)
+        .map(Some)
+        .with_context(|| format!("Invalid --{}", flag)),
+      (Some(raw), Some(_)) if self.passed(flag) => parse(raw)
+        .map(Some)
+        .with_context(|| format!("Invalid --{}", flag)),
+      (_, Some(raw)) => parse(raw)
+        .map(Some)
+        .with_context(|| format!("Invalid `{}`", key)),
+      (None, None) => Ok(None),
+    }
+  }
+
+  /// As [Self::parsed], for flags which may be repeated and keys listing their values
+  pub fn parsed_all<T, F>(
+    &self,
+    flag: &str,
+    key: &str,
+    file: Option<&[String]>,
+    parse: F,
+  ) -> Result<Vec<T>>
+  where
+    F: Fn(&str) -> Result<T>,
+  {
+    match (self.args.values_of(flag), file) {
+      (Some(raw), _) if self.passed(flag) || file.is_none() => raw
+        .map(|raw| parse(raw).with_context(|| format!("Invalid --{}", flag)))
+        .collect(),
+      (_, Some(raw)) => raw
+        .iter()
+        .map(|raw| parse(raw).with_context(|| format!("Invalid `{}`", key)))
+        .collect(),
+      (_, None) => Ok(Vec::new()),
+    }
+  }
+
+  /// As [Self::value], requiring that a file exists at paths given by the config file
+  pub fn existing_file(
+    &self,
+    flag: &str,
+    key: &str,
+    file: Option<&PathBuf>,
+  ) -> Result<Option<PathBuf>> {
+    match self.value(flag, file.cloned())? {
+      Some(path) if !self.passed(flag) && file.is_some() && !path.exists() => {
+        anyhow::bail!("Invalid `{}`: no file exists at {}", key, path.display())
+      }
+      path => Ok(path),
+    }
+  }
+
+  /// Whether a switch was passed, or else enabled by the config file
+  ///
+  /// A `--no-<flag>` counterpart, where defined, disables the switch over the config file.
+  pub fn switch(&self, flag: &str, file: Option<bool>) -> bool {
+    if self.args.is_present(flag) {
+      true
+    } else if self.args.is_present(&format!("no-{}", flag)) {
+      false
+    } else {
+      file.unwrap_or(false)
+    }
+  }
+}
+
+/// Fails, naming both `flag` and `key`, if neither provided a value
+pub fn required<T>(value: Option<T>, flag: &str, key: &str) -> Result<T> {
+  value.ok_or_else(|| {
+    anyhow::Error::msg(format!(
+      "Missing `{}`; set it in the config file or pass --{}",
+      key, flag
+    ))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ConfigFile, RoutingStrategy, ServerServiceKind};
+
+  #[test]
+  fn config_files_parse() {
+    let config = ConfigFile::parse(
+      r#"
+        [logging]
+        filter = "info"
+        format = "compact"
+
+        [transport]
+        receive_window = 1048576
+        keep_alive_interval = 0
+
+        [authentication]
+        methods = ["token", "hmac"]
+        timeout = 10
+
+        [server]
+        quic = "0.0.0.0:9090"
+        ports = "8080:8090"
+        services = ["demand_proxy"]
+
+        [server.routing]
+        strategy = "least_active_streams"
+
+        [client]
+        remote_forwards = ["127.0.0.1:22"]
+        local_forwards = ["127.0.0.1:8022=db.internal:5432"]
+      "#,
+    )
+    .unwrap();
+    assert_eq!(config.transport.receive_window, Some(1048576));
+    assert_eq!(config.transport.keep_alive_interval, Some(0));
+    assert_eq!(
+      config.authentication.methods,
+      Some(vec![String::from("token"), String::from("hmac")])
+    );
+    assert_eq!(config.server.ports.as_deref(), Some("8080:8090"));
+    assert_eq!(
+      config.server.services,
+      Some(vec![ServerServiceKind::DemandProxy])
+    );
+    assert_eq!(
+      config.server.routing.strategy,
+      Some(RoutingStrategy::LeastActiveStreams)
+    );
+    assert_eq!(
+      config.client.remote_forwards,
+      Some(vec![String::from("127.0.0.1:22")])
+    );
+    assert_eq!(
+      config.client.local_forwards,
+      Some(vec![String::from("127.0.0.1:8022=db.internal:5432")])
+    );
+  }
+
+  #[test]
+  fn config_errors_name_keys() {
+    let error = |content: &str| format!("{:#}", ConfigFile::parse(content).unwrap_err());
+    assert!(error("[server]\ndrain_grace_period = \"soon\"").contains("server.drain_grace_period"));
+    assert!(error("[server.limits]\nmax_reqs = 4").contains("max_reqs"));
+    assert!(error("[server.routing]\nstrategy = \"fastest\"").contains("server.routing.strategy"));
+    assert!(error("[logging]\nfilter = \"=[\"").contains("logging.filter"));
+  }
+
+  #[test]
+  fn switches_can_be_negated() {
+    use clap::{App, Arg};
+    let switch = |argv: &[&str], file: Option<bool>| {
+      let matches = App::new("test")
+        .arg(
+          Arg::with_name("backpressure")
+            .long("backpressure")
+            .overrides_with("no-backpressure"),
+        )
+        .arg(
+          Arg::with_name("no-backpressure")
+            .long("no-backpressure")
+            .overrides_with("backpressure"),
+        )
+        .get_matches_from(std::iter::once("test").chain(argv.iter().copied()));
+      super::Settings::new(&matches).switch("backpressure", file)
+    };
+    assert!(!switch(&[], None));
+    assert!(switch(&[], Some(true)));
+    assert!(switch(&["--backpressure"], Some(false)));
+    assert!(!switch(&["--no-backpressure"], Some(true)));
+    assert!(switch(&["--no-backpressure", "--backpressure"], Some(true)));
+  }
+}
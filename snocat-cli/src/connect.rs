@@ -0,0 +1,224 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Splices stdin and stdout to a single service reached through the server, as for a `ProxyCommand`
+
+use crate::{
+  client::{build_endpoint, connect, ClientCertificate, SnocatClientRouter},
+  server::NAMED_TUNNEL_PREFIX,
+  services::relay::{RelayClient, RelayStatus, RELAY_ADDRESS_BASE},
+  transport::TransportArgs,
+};
+use anyhow::Result;
+use quinn::crypto::rustls::TlsSession;
+use snocat::{
+  common::protocol::{
+    negotiation::NegotiationError,
+    request_handler::RequestHandlingError,
+    service_registry::TrieServiceRegistry,
+    traits::InMemoryTunnelRegistry,
+    tunnel::{id::MonotonicAtomicGenerator, QuinnTunnel, TunnelName},
+    ClientError, RouteAddress,
+  },
+  server::modular::{DisconnectReason, ModularDaemon},
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct ConnectArgs {
+  pub authority_cert: Option<PathBuf>,
+  pub client_certificate: Option<ClientCertificate>,
+  pub driver_host: SocketAddr,
+  pub driver_san: String,
+  /// Tunnel to reach the service through
+  ///
+  /// Relays always name their tunnel, as the server's routing strategy may choose our own.
+  pub tunnel: TunnelName,
+  /// Address of the service, as requested of the tunnel it is routed to
+  pub address: RouteAddress,
+  pub transport: TransportArgs,
+  pub authentication: crate::authentication::AuthenticationArgs,
+}
+
+/// Failures of `connect` mode which exit with their own status codes
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectError {
+  #[error("No tunnel serves {0}")]
+  RouteNotFound(RouteAddress),
+  #[error("The request for {0} was refused")]
+  Refused(RouteAddress),
+  #[error("Transport failure: {0}")]
+  Transport(String),
+}
+
+impl ConnectError {
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      ConnectError::RouteNotFound(_) => 2,
+      ConnectError::Refused(_) => 3,
+      ConnectError::Transport(_) => 4,
+    }
+  }
+}
+
+/// The status with which `connect` mode exits for the result of [connect_main]
+pub fn exit_code(result: &Result<()>) -> i32 {
+  match result {
+    Ok(()) => 0,
+    Err(e) => e
+      .downcast_ref::<ConnectError>()
+      .map_or(1, ConnectError::exit_code),
+  }
+}
+
+/// The address of the server's relay service for `address` on the tunnel named `tunnel`
+fn relay_address(tunnel: &TunnelName, address: &str) -> RouteAddress {
+  format!(
+    "{}{}/{}{}",
+    RELAY_ADDRESS_BASE.trim_end_matches('/'),
+    NAMED_TUNNEL_PREFIX,
+    tunnel.raw(),
+    address
+  )
+}
+
+/// The failure, if any, of a relay request for `address` which has run to completion
+fn relay_outcome(
+  address: &str,
+  result: Result<RelayStatus, RequestHandlingError>,
+) -> Result<(), ConnectError> {
+  match result {
+    Ok(RelayStatus::Connected) => Ok(()),
+    Ok(RelayStatus::RouteNotFound) => Err(ConnectError::RouteNotFound(address.into())),
+    Ok(RelayStatus::Refused) => Err(ConnectError::Refused(address.into())),
+    Ok(RelayStatus::Unavailable) => Err(ConnectError::Transport(String::from(
+      "The server could not reach the service's tunnel",
+    ))),
+    Err(RequestHandlingError::NegotiationError(
+      NegotiationError::Refused
+      | NegotiationError::Unauthorized
+      | NegotiationError::UnsupportedServiceVersion,
+      _,
+    )) => Err(ConnectError::Refused(address.into())),
+    Err(RequestHandlingError::ProtocolClientError(ClientError::UnexpectedEnd)) => Err(
+      ConnectError::Transport(String::from("The relayed stream ended unexpectedly")),
+    ),
+    Err(e) => Err(ConnectError::Transport(e.to_string())),
+  }
+}
+
+pub async fn connect_main(config: ConnectArgs) -> Result<()> {
+  let endpoint = build_endpoint(
+    config.authority_cert.as_deref(),
+    config.client_certificate.as_ref(),
+    &config.transport,
+  )?;
+  let tunnel = connect(&endpoint, &config.driver_host, &config.driver_san)
+    .await
+    .map_err(|e| ConnectError::Transport(format!("{:#}", e)))?;
+
+  let tunnel_registry: Arc<InMemoryTunnelRegistry> = Arc::new(InMemoryTunnelRegistry::new());
+  let router = Arc::new(SnocatClientRouter::new(Arc::downgrade(&tunnel_registry)));
+  let revocations = crate::authentication::build_revocations(&config.authentication)?;
+  let authentication_handler = crate::authentication::build_authentication_handler(
+    &config.authentication,
+    revocations.as_ref(),
+  )?;
+  // Only one tunnel is ever made, so its ID needn't be unique beyond this process
+  let tunnel_id_generator = Arc::new(MonotonicAtomicGenerator::new(0));
+  let modular = Arc::new(
+    ModularDaemon::<QuinnTunnel<TlsSession>>::new(
+      // Nothing is offered to the server; requests only travel outward
+      Arc::new(TrieServiceRegistry::new()),
+      tunnel_registry,
+      router,
+      authentication_handler,
+      tunnel_id_generator,
+    )
+    .with_authentication_timeout(crate::authentication::daemon_authentication_timeout(
+      &config.authentication,
+    )),
+  );
+
+  let mut authenticated = modular.tunnel_authenticated.subscribe();
+  let mut disconnected = modular.tunnel_disconnected.subscribe();
+  let requests = Arc::clone(modular.requests());
+  let shutdown = CancellationToken::new();
+  let (tunnels, tunnel_source) = mpsc::unbounded_channel();
+  let _ = tunnels.send(tunnel);
+  let daemon = modular.run(
+    tokio_stream::wrappers::UnboundedReceiverStream::new(tunnel_source),
+    shutdown.clone(),
+  );
+
+  let result = async {
+    tokio::select! {
+      event = authenticated.recv() => match event {
+        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => (),
+        Err(broadcast::error::RecvError::Closed) => {
+          let message = String::from("Daemon stopped before authenticating");
+          return Err(ConnectError::Transport(message));
+        }
+      },
+      event = disconnected.recv() => return Err(match event {
+        Ok((_id, _name, DisconnectReason::AuthenticationFailed))
+        | Ok((_id, _name, DisconnectReason::Revoked)) => {
+          ConnectError::Refused(config.address.clone())
+        }
+        Ok((_id, _name, reason)) => {
+          ConnectError::Transport(format!("Tunnel closed before authenticating: {:?}", reason))
+        }
+        Err(e) => ConnectError::Transport(e.to_string()),
+      }),
+    }
+    let address = relay_address(&config.tunnel, &config.address);
+    tracing::debug!(?address, "Requesting relay");
+    let client = RelayClient::new(tokio::io::stdin(), tokio::io::stdout());
+    relay_outcome(&config.address, requests.handle(address, client).await)
+  }
+  .await;
+
+  drop(tunnels);
+  shutdown.cancel();
+  let _report = daemon.await;
+  endpoint.wait_idle().await;
+  Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{relay_address, relay_outcome, ConnectError};
+  use crate::services::relay::RelayStatus;
+  use snocat::common::protocol::{
+    request_handler::RequestHandlingError, tunnel::TunnelName, ClientError,
+  };
+
+  #[test]
+  fn relay_addresses() {
+    assert_eq!(
+      relay_address(&TunnelName::new("bastion"), "/tcp/22"),
+      "/relay/tunnel/bastion/tcp/22"
+    );
+    assert_eq!(
+      relay_address(&TunnelName::new("bastion"), "/ip4/10.0.0.2/tcp/22"),
+      "/relay/tunnel/bastion/ip4/10.0.0.2/tcp/22"
+    );
+  }
+
+  #[test]
+  fn relay_outcomes_exit_with_their_status() {
+    let address = "/tcp/22";
+    let exit_code = |result| relay_outcome(address, result).map_err(|e| e.exit_code());
+    assert_eq!(exit_code(Ok(RelayStatus::Connected)), Ok(()));
+    assert_eq!(exit_code(Ok(RelayStatus::RouteNotFound)), Err(2));
+    assert_eq!(exit_code(Ok(RelayStatus::Refused)), Err(3));
+    assert_eq!(exit_code(Ok(RelayStatus::Unavailable)), Err(4));
+    // Streams which break, or end before the relay reports its status, are transport failures
+    let broken = RequestHandlingError::ProtocolClientError(ClientError::UnexpectedEnd);
+    assert!(matches!(
+      relay_outcome(address, Err(broken)),
+      Err(ConnectError::Transport(_))
+    ));
+  }
+}
@@ -0,0 +1,386 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Forwards requested by the client, each re-established independently while the client runs
+#![warn(unused_imports)]
+
+use crate::services::demand_proxy::{DemandProxyClient, DEMAND_PROXY_ADDRESS_BASE};
+use anyhow::{Context as AnyhowContext, Result};
+use futures::future::{join_all, BoxFuture, FutureExt};
+use snocat::common::protocol::{
+  proxy_tcp::{DnsTarget, TcpStreamClient, TcpStreamTarget},
+  request_handler::RequestClientHandler,
+};
+use std::{fmt::Display, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{net::TcpListener, sync::watch};
+use tokio_util::sync::CancellationToken;
+
+/// Delay before the first retry of a failed forward or connection
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound of the delay between retries, which doubles with each consecutive failure
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Forward {
+  /// Exposes a target reachable from the client on ports bound by the server
+  Remote { target: SocketAddr },
+  /// Listens locally, sending each connection through the tunnel to a target reachable from the server
+  Local {
+    listen: SocketAddr,
+    target: TcpStreamTarget,
+  },
+}
+
+impl Forward {
+  /// Parses a remote forward's target, as `<ip>:<port>`
+  pub fn parse_remote(s: &str) -> Result<Self> {
+    let target = s
+      .parse()
+      .with_context(|| format!("Remote forward target {:?} must be an <ip>:<port>", s))?;
+    Ok(Forward::Remote { target })
+  }
+
+  /// Parses a local forward, as `<listen address>=<target>`
+  ///
+  /// Targets are resolved by the server, and may be given as `<host>:<port>`, or as a route
+  /// address such as `/dns/<host>/tcp/<port>`.
+  pub fn parse_local(s: &str) -> Result<Self> {
+    let (listen, target) = s
+      .split_once('=')
+      .with_context(|| format!("Local forward {:?} must be <listen address>=<target>", s))?;
+    let listen = listen
+      .parse()
+      .with_context(|| format!("Local forward listen address {:?} is invalid", listen))?;
+    let target = Self::parse_target(target)
+      .with_context(|| format!("Local forward target {:?} is invalid", target))?;
+    Ok(Forward::Local { listen, target })
+  }
+
+  fn parse_target(target: &str) -> Result<TcpStreamTarget> {
+    if target.starts_with('/') {
+      return Ok(target.parse()?);
+    }
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+      return Ok(TcpStreamTarget::SocketAddr(addr));
+    }
+    let (host, port) = target
+      .rsplit_once(':')
+      .context("Targets must be a <host>:<port> or a route address")?;
+    if host.is_empty() || host.contains(':') || host.contains('/') {
+      anyhow::bail!("Invalid host {:?}", host);
+    }
+    Ok(
+      DnsTarget::PreferHigher {
+        host: host.to_string(),
+        port: port.parse()?,
+      }
+      .into(),
+    )
+  }
+}
+
+impl Display for Forward {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Forward::Remote { target } => write!(f, "remote {}", target),
+      Forward::Local { listen, target } => write!(f, "local {} to {}", listen, target),
+    }
+  }
+}
+
+/// The state of a single forward, logged whenever it changes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ForwardStatus {
+  /// No authenticated tunnel is available to carry the forward
+  WaitingForTunnel,
+  /// The server is listening on these addresses on the forward's behalf
+  Exposed(Vec<SocketAddr>),
+  /// Accepting local connections on this address
+  Listening(SocketAddr),
+  /// The forward failed, and will be retried after `delay`
+  Retrying {
+    error: String,
+    delay: Duration,
+  },
+  Stopped,
+}
+
+/// Doubles the delay between consecutive retries, up to [MAX_RETRY_DELAY]
+#[derive(Debug)]
+pub struct RetryDelay {
+  next: Duration,
+}
+
+impl RetryDelay {
+  pub fn new() -> Self {
+    Self {
+      next: MIN_RETRY_DELAY,
+    }
+  }
+
+  /// Returns the delay to wait before the next retry, and lengthens the one following it
+  pub fn advance(&mut self) -> Duration {
+    let delay = self.next;
+    self.next = std::cmp::min(self.next * 2, MAX_RETRY_DELAY);
+    delay
+  }
+
+  pub fn reset(&mut self) {
+    self.next = MIN_RETRY_DELAY;
+  }
+}
+
+/// Runs each forward in its own task until shutdown is requested
+///
+/// `tunnel_ready` reports whether an authenticated tunnel is available; forwards wait for one
+/// before making requests, and are re-requested after their tunnel is lost.
+pub async fn run_forwards(
+  forwards: Vec<Forward>,
+  requests: Arc<RequestClientHandler>,
+  tunnel_ready: watch::Receiver<bool>,
+  idle_timeout: Option<Duration>,
+  shutdown: CancellationToken,
+) {
+  if forwards.is_empty() {
+    tracing::info!("No forwards configured; serving requests from the server only");
+  }
+  let tasks = forwards.into_iter().map(|forward| {
+    tokio::task::spawn(
+      ForwardRunner {
+        forward,
+        requests: Arc::clone(&requests),
+        tunnel_ready: tunnel_ready.clone(),
+        idle_timeout,
+        shutdown: shutdown.clone(),
+        status: None,
+        retry_delay: RetryDelay::new(),
+      }
+      .run(),
+    )
+  });
+  for result in join_all(tasks).await {
+    if let Err(join_error) = result {
+      tracing::error!(error = ?join_error, "Forward task failed");
+    }
+  }
+}
+
+struct ForwardRunner {
+  forward: Forward,
+  requests: Arc<RequestClientHandler>,
+  tunnel_ready: watch::Receiver<bool>,
+  idle_timeout: Option<Duration>,
+  shutdown: CancellationToken,
+  status: Option<ForwardStatus>,
+  retry_delay: RetryDelay,
+}
+
+impl ForwardRunner {
+  async fn run(mut self) {
+    // Both only resolve once shutdown is requested
+    let _shutdown = match self.forward.clone() {
+      Forward::Remote { target } => self.run_remote(target).await,
+      Forward::Local { listen, target } => self.run_local(listen, target).await,
+    };
+    self.set_status(ForwardStatus::Stopped);
+  }
+
+  /// Requests that the server expose `target` each time a tunnel is available
+  ///
+  /// Resolves to `None` once shutdown is requested.
+  async fn run_remote(&mut self, target: SocketAddr) -> Option<()> {
+    let addr = format!(
+      "{}{}/{}",
+      DEMAND_PROXY_ADDRESS_BASE,
+      target.ip(),
+      target.port()
+    );
+    loop {
+      self.wait_for_tunnel().await?;
+      let demand_proxy = DemandProxyClient {
+        proxied_subject: "".into(),
+      };
+      let requested = Arc::clone(&self.requests).handle(addr.clone(), demand_proxy);
+      let error = match requested.await {
+        Ok((bound, closed)) => {
+          self.set_status(ForwardStatus::Exposed(bound));
+          self.retry_delay.reset();
+          tokio::select! {
+            res = closed => match res {
+              Ok(()) => String::from("Closed by the server"),
+              Err(e) => describe(e),
+            },
+            _ = self.shutdown.cancelled() => return None,
+          }
+        }
+        Err(e) => describe(e),
+      };
+      self.retry(error).await?;
+    }
+  }
+
+  /// Listens on `listen`, rebinding after failures, and forwards each connection to `target`
+  ///
+  /// Resolves to `None` once shutdown is requested.
+  async fn run_local(&mut self, listen: SocketAddr, target: TcpStreamTarget) -> Option<()> {
+    let addr = TcpStreamClient::<(), ()>::build_addr(target);
+    loop {
+      let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+          self.retry(e.to_string()).await?;
+          continue;
+        }
+      };
+      self.set_status(ForwardStatus::Listening(
+        listener.local_addr().unwrap_or(listen),
+      ));
+      self.retry_delay.reset();
+      let error = loop {
+        let (tcp_stream, peer) = tokio::select! {
+          accepted = listener.accept() => match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => break e.to_string(),
+          },
+          _ = self.shutdown.cancelled() => return None,
+        };
+        if !*self.tunnel_ready.borrow() {
+          tracing::warn!(forward = %self.forward, ?peer, "No tunnel available; dropping connection");
+          continue;
+        }
+        tokio::task::spawn(Self::forward_connection(
+          self.forward.clone(),
+          Arc::clone(&self.requests),
+          addr.clone(),
+          tcp_stream,
+          self.idle_timeout,
+        ));
+      };
+      self.retry(error).await?;
+    }
+  }
+
+  fn forward_connection(
+    forward: Forward,
+    requests: Arc<RequestClientHandler>,
+    addr: String,
+    tcp_stream: tokio::net::TcpStream,
+    idle_timeout: Option<Duration>,
+  ) -> BoxFuture<'static, ()> {
+    async move {
+      let peer = tcp_stream.peer_addr().ok();
+      let (tcp_recv, tcp_send) = tokio::io::split(tcp_stream);
+      let client = TcpStreamClient::new(tcp_recv, tcp_send).with_idle_timeout(idle_timeout);
+      if let Err(e) = requests.handle(addr, client).await {
+        let error = describe(e);
+        tracing::warn!(forward = %forward, ?peer, %error, "Failed to forward connection");
+      }
+    }
+    .boxed()
+  }
+
+  /// Waits until an authenticated tunnel is available, or resolves to `None` on shutdown
+  async fn wait_for_tunnel(&mut self) -> Option<()> {
+    while !*self.tunnel_ready.borrow() {
+      self.set_status(ForwardStatus::WaitingForTunnel);
+      tokio::select! {
+        changed = self.tunnel_ready.changed() => changed.ok()?,
+        _ = self.shutdown.cancelled() => return None,
+      }
+    }
+    Some(())
+  }
+
+  /// Waits out the retry delay after a failure, or resolves to `None` on shutdown
+  async fn retry(&mut self, error: String) -> Option<()> {
+    // Requests in progress fail as their tunnel shuts down, which isn't worth reporting
+    if self.shutdown.is_cancelled() {
+      return None;
+    }
+    let delay = self.retry_delay.advance();
+    self.set_status(ForwardStatus::Retrying { error, delay });
+    tokio::select! {
+      _ = tokio::time::sleep(delay) => Some(()),
+      _ = self.shutdown.cancelled() => None,
+    }
+  }
+
+  fn set_status(&mut self, status: ForwardStatus) {
+    if self.status.as_ref() == Some(&status) {
+      return;
+    }
+    match &status {
+      ForwardStatus::Retrying { error, delay } => {
+        tracing::warn!(forward = %self.forward, %error, ?delay, "Forward failed; retrying")
+      }
+      status => tracing::info!(forward = %self.forward, ?status, "Forward status changed"),
+    }
+    self.status = Some(status);
+  }
+}
+
+/// Describes an error along with its sources
+fn describe(error: impl std::error::Error + Send + Sync + 'static) -> String {
+  format!("{:#}", anyhow::Error::new(error))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Forward, RetryDelay, MAX_RETRY_DELAY};
+  use snocat::common::protocol::proxy_tcp::{DnsTarget, TcpStreamTarget};
+  use std::time::Duration;
+
+  #[test]
+  fn forwards_parse() {
+    assert_eq!(
+      Forward::parse_remote("127.0.0.1:22").unwrap(),
+      Forward::Remote {
+        target: "127.0.0.1:22".parse().unwrap()
+      }
+    );
+    assert_eq!(
+      Forward::parse_local("127.0.0.1:8022=db.internal:5432").unwrap(),
+      Forward::Local {
+        listen: "127.0.0.1:8022".parse().unwrap(),
+        target: TcpStreamTarget::Dns(DnsTarget::PreferHigher {
+          host: "db.internal".into(),
+          port: 5432
+        }),
+      }
+    );
+    assert_eq!(
+      Forward::parse_local("[::1]:8022=[::1]:22").unwrap(),
+      Forward::Local {
+        listen: "[::1]:8022".parse().unwrap(),
+        target: TcpStreamTarget::SocketAddr("[::1]:22".parse().unwrap()),
+      }
+    );
+    assert_eq!(
+      Forward::parse_local("127.0.0.1:8022=/dns4/db.internal/tcp/5432").unwrap(),
+      Forward::Local {
+        listen: "127.0.0.1:8022".parse().unwrap(),
+        target: TcpStreamTarget::Dns(DnsTarget::Dns4 {
+          host: "db.internal".into(),
+          port: 5432
+        }),
+      }
+    );
+    assert!(Forward::parse_remote("localhost").is_err());
+    assert!(Forward::parse_local("127.0.0.1:8022").is_err());
+    assert!(Forward::parse_local("127.0.0.1:8022=db.internal").is_err());
+    assert!(Forward::parse_local("8022=db.internal:5432").is_err());
+  }
+
+  #[test]
+  fn retry_delays_double_until_capped() {
+    let mut delay = RetryDelay::new();
+    assert_eq!(delay.advance(), Duration::from_secs(1));
+    assert_eq!(delay.advance(), Duration::from_secs(2));
+    assert_eq!(delay.advance(), Duration::from_secs(4));
+    for _ in 0..8 {
+      delay.advance();
+    }
+    assert_eq!(delay.advance(), MAX_RETRY_DELAY);
+    delay.reset();
+    assert_eq!(delay.advance(), Duration::from_secs(1));
+  }
+}
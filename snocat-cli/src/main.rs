@@ -9,12 +9,25 @@
 
 use anyhow::Result;
 use clap::{App, Arg, SubCommand};
-use snocat::util;
+use futures::future::FutureExt;
+use snocat::{
+  common::protocol::{
+    heartbeat::HeartbeatPolicy,
+    tunnel::{TunnelId, TunnelName},
+  },
+  server::{
+    admin::{AdminRequest, TunnelSelector},
+    limits::{OverloadBehavior, RequestLimits},
+  },
+  util,
+};
 use std::{
+  convert::TryFrom,
   path::{Path, PathBuf},
-  str::FromStr,
+  time::Duration,
 };
 
+use config::{required, ConfigFile, LogFormat, LoggingSection, Settings};
 use util::validators::{
   parse_ipaddr, parse_port_range, parse_socketaddr, validate_existing_file, validate_ipaddr,
   validate_port_range, validate_socketaddr,
@@ -22,31 +35,27 @@ use util::validators::{
 
 mod services;
 
+mod admin;
+mod authentication;
 mod certgen;
 mod client;
+mod config;
+mod connect;
+mod forwards;
+mod reload;
 mod server;
+mod token;
+mod transport;
 
 // Consider for tests : https://github.com/djc/quinn/blob/main/quinn/examples/insecure_connection.rs
 fn main() {
-  // let collector = tracing_subscriber::fmt()
-  //   .with_max_level(tracing::Level::TRACE)
-  //   .finish();
-  let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-    .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("quinn=warn,quinn_proto=warn,debug"));
-  let collector = tracing_subscriber::fmt()
-    .pretty()
-    .with_env_filter(env_filter)
-    // .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
-    .finish();
-  tracing::subscriber::set_global_default(collector).expect("Logger init must succeed");
   let app = App::new(env!("CARGO_BIN_NAME"))
     .version(env!("CARGO_PKG_VERSION"))
     .about(env!("CARGO_PKG_DESCRIPTION"))
-    .subcommand(
+    .subcommand(with_config_args(with_idle_args(with_heartbeat_args(with_authentication_args(with_client_certificate_args(
       SubCommand::with_name("client")
         .alias("-c")
-        .about("Bind a local port to a remote server")
-        // .arg(Arg::with_name("client-cert").long("client-cert").short("c").validator(validate_existing_file).takes_value(true))
+        .about("Forward ports through a tunnel to a remote server")
         .arg(
           Arg::with_name("authority")
             .long("authority")
@@ -60,27 +69,38 @@ fn main() {
             .long("driver")
             .short("d")
             .validator(validate_socketaddr)
-            .takes_value(true)
-            .required(true),
+            .takes_value(true),
         )
         .arg(
           Arg::with_name("driver-san")
             .long("driver-san")
             .visible_alias("san")
             .short("s")
-            .takes_value(true)
-            .required(true),
+            .takes_value(true),
         )
         .arg(
           Arg::with_name("target")
+            .help("Local address for the server to expose on a port it binds; may be repeated")
             .long("target")
+            .visible_alias("remote-forward")
             .short("t")
             .validator(validate_socketaddr)
             .takes_value(true)
-            .required(true),
+            .multiple(true)
+            .number_of_values(1),
+        )
+        .arg(
+          Arg::with_name("local-forward")
+            .help("Local listener whose connections are sent through the tunnel to a target reachable from the server, as <listen address>=<host>:<port>; may be repeated")
+            .long("local-forward")
+            .short("L")
+            .validator(|v| forwards::Forward::parse_local(&v).map(|_| ()).map_err(|e| format!("{:#}", e)))
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
         ),
-    )
-    .subcommand(
+    ))))))
+    .subcommand(with_config_args(with_idle_args(with_heartbeat_args(with_authentication_args(
       SubCommand::with_name("server")
         .alias("-s")
         .about("Run in server mode, supporting connections from multiple clients")
@@ -89,19 +109,24 @@ fn main() {
             .long("cert")
             .short("c")
             .validator(validate_existing_file)
-            .takes_value(true)
-            .required(true),
+            .takes_value(true),
         )
         .arg(
           Arg::with_name("key")
             .long("key")
             .short("k")
             .validator(validate_existing_file)
-            .takes_value(true)
-            .required(true),
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("client-ca")
+            .help("Certificate authority against which client certificates are verified, enabling the `certificate` authentication method")
+            .long("client-ca")
+            .validator(validate_existing_file)
+            .takes_value(true),
         )
         .arg(
-          Arg::with_name("tcp")
+          Arg::with_name("bindip")
             .long("bindip")
             .short("i")
             .validator(validate_ipaddr)
@@ -110,7 +135,7 @@ fn main() {
             .required(true),
         )
         .arg(
-          Arg::with_name("bind_range")
+          Arg::with_name("ports")
             .long("ports")
             .short("p")
             .validator(validate_port_range)
@@ -127,82 +152,1011 @@ fn main() {
             .default_value("127.0.0.1:9090")
             .takes_value(true)
             .required(true),
+        )
+        .arg(
+          Arg::with_name("drain-grace-period")
+            .help("Seconds that tunnels may finish requests in progress after shutdown is requested; 0 waits indefinitely")
+            .long("drain-grace-period")
+            .validator(validate_u64)
+            .takes_value(true)
+            .default_value("30"),
+        )
+        .arg(
+          Arg::with_name("max-tunnel-requests")
+            .help("Requests each tunnel may have in progress at once; 0 is unlimited")
+            .long("max-tunnel-requests")
+            .validator(validate_u64)
+            .takes_value(true)
+            .default_value("256"),
+        )
+        .arg(
+          Arg::with_name("max-tunnel-negotiations")
+            .help("Requests each tunnel may have negotiating at once; 0 is unlimited")
+            .long("max-tunnel-negotiations")
+            .validator(validate_u64)
+            .takes_value(true)
+            .default_value("32"),
+        )
+        .arg(
+          Arg::with_name("max-requests")
+            .help("Requests which may be in progress at once across all tunnels; 0 is unlimited")
+            .long("max-requests")
+            .validator(validate_u64)
+            .takes_value(true)
+            .default_value("0"),
+        )
+        .arg(
+          Arg::with_name("negotiation-timeout")
+            .help("Seconds after which a request which has not completed negotiation is abandoned; 0 waits indefinitely")
+            .long("negotiation-timeout")
+            .validator(validate_u64)
+            .takes_value(true)
+            .default_value("10"),
+        )
+        .arg(
+          Arg::with_name("backpressure")
+            .help("Stop reading requests from tunnels at their limits, rather than refusing them as overloaded")
+            .long("backpressure")
+            .overrides_with("no-backpressure"),
+        )
+        .arg(
+          Arg::with_name("no-backpressure")
+            .help("Refuse requests from tunnels at their limits as overloaded, even if the config file enables backpressure")
+            .long("no-backpressure")
+            .overrides_with("backpressure"),
+        )
+        .arg(
+          Arg::with_name("admin-socket")
+            .help("Path of a Unix socket on which to answer `admin` requests")
+            .long("admin-socket")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("watch-interval")
+            .help("Seconds between checks of the config, certificate, authentication, and revocation files for changes, which are reloaded as on SIGHUP; 0 disables checks")
+            .long("watch-interval")
+            .validator(validate_u64)
+            .takes_value(true)
+            .default_value("5"),
+        ),
+    )))))
+    .subcommand(with_config_args(with_authentication_args(with_client_certificate_args(
+      SubCommand::with_name("connect")
+        .about("Connect stdin and stdout to a service reached through the server, as for an SSH ProxyCommand")
+        .arg(
+          Arg::with_name("authority")
+            .long("authority")
+            .short("a")
+            .validator(validate_existing_file)
+            .takes_value(true)
+            .required(false),
+        )
+        .arg(
+          Arg::with_name("driver")
+            .long("via")
+            .visible_alias("driver")
+            .short("d")
+            .validator(validate_socketaddr)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("driver-san")
+            .long("driver-san")
+            .visible_alias("san")
+            .short("s")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("tunnel")
+            .help("Name of the tunnel to reach the service through")
+            .long("tunnel")
+            .short("n")
+            .takes_value(true)
+            .required(true),
+        )
+        .arg(
+          Arg::with_name("address")
+            .help("Route address of the service, such as /tcp/22")
+            .validator(validate_route_address)
+            .takes_value(true)
+            .required(true),
         ),
-    )
+    ))))
     .subcommand(
       SubCommand::with_name("cert")
-        .about("Generate self-signed certificates for local usage")
+        .about("Generate a self-signed certificate as <path>.pub.pem and <path>.priv.pem, or manage a certificate authority")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("path").takes_value(true).required(true))
         .arg(
           Arg::with_name("san")
+            .help("DNS name or IP address of the host; may be repeated")
             .long("san")
             .takes_value(true)
             .required(false)
+            .multiple(true)
+            .number_of_values(1)
             .default_value("localhost"),
+        )
+        .subcommand(with_certificate_args(
+          SubCommand::with_name("ca")
+            .about("Create a certificate authority as <path>.pub.pem and <path>.priv.pem")
+            .arg(Arg::with_name("path").takes_value(true).required(true))
+            .arg(
+              Arg::with_name("name")
+                .help("Common name of the authority")
+                .long("name")
+                .takes_value(true)
+                .default_value("snocat CA"),
+            ),
+          "3650",
+        ))
+        .subcommand(with_issuer_args(with_certificate_args(
+          SubCommand::with_name("server")
+            .about("Issue a server certificate chain as <path>.pub.pem, with its key as <path>.priv.pem")
+            .arg(Arg::with_name("path").takes_value(true).required(true))
+            .arg(
+              Arg::with_name("san")
+                .help("DNS name or IP address clients may reach the server by; may be repeated")
+                .long("san")
+                .takes_value(true)
+                .required(true)
+                .multiple(true)
+                .number_of_values(1),
+            ),
+          "365",
+        )))
+        .subcommand(with_issuer_args(with_certificate_args(
+          SubCommand::with_name("client")
+            .about("Issue a client certificate chain as <path>.pub.pem, with its key as <path>.priv.pem")
+            .arg(Arg::with_name("path").takes_value(true).required(true))
+            .arg(
+              Arg::with_name("name")
+                .help("Name of the tunnel the certificate authenticates, used as its subject")
+                .long("name")
+                .short("n")
+                .takes_value(true)
+                .required(true),
+            ),
+          "365",
+        ))),
+    )
+    .subcommand(
+      SubCommand::with_name("admin")
+        .about("Inspect and manage a running server through its admin socket")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+          Arg::with_name("socket")
+            .long("socket")
+            .short("s")
+            .takes_value(true)
+            .required(true),
+        )
+        .subcommand(SubCommand::with_name("list").about("List established tunnels"))
+        .subcommand(
+          SubCommand::with_name("close")
+            .about("Close a tunnel immediately, abandoning its requests in progress")
+            .arg(
+              Arg::with_name("id")
+                .long("id")
+                .validator(validate_u64)
+                .takes_value(true),
+            )
+            .arg(Arg::with_name("name").long("name").takes_value(true))
+            .group(
+              clap::ArgGroup::with_name("tunnel")
+                .args(&["id", "name"])
+                .required(true),
+            ),
+        )
+        .subcommand(SubCommand::with_name("drain").about("Drain all tunnels and shut down"))
+        .subcommand(SubCommand::with_name("ports").about("List allocated ports")),
+    )
+    .subcommand(
+      SubCommand::with_name("token")
+        .about("Generate signing keys and issue bearer tokens")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+          SubCommand::with_name("keygen")
+            .about("Generate an Ed25519 token signing key as <path>.priv.json and <path>.pub.json")
+            .arg(Arg::with_name("path").takes_value(true).required(true))
+            .arg(
+              Arg::with_name("id")
+                .long("id")
+                .takes_value(true)
+                .required(true),
+            ),
+        )
+        .subcommand(
+          SubCommand::with_name("issue")
+            .about("Print a token signed by a key from a token key file")
+            .arg(
+              Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .validator(validate_existing_file)
+                .takes_value(true)
+                .required(true),
+            )
+            .arg(Arg::with_name("key-id").long("key-id").takes_value(true))
+            .arg(
+              Arg::with_name("name")
+                .help("Name of the tunnel authenticated by the token")
+                .long("name")
+                .short("n")
+                .takes_value(true)
+                .required(true),
+            )
+            .arg(
+              Arg::with_name("audience")
+                .long("audience")
+                .takes_value(true)
+                .default_value(DEFAULT_TOKEN_AUDIENCE),
+            )
+            .arg(
+              Arg::with_name("ttl")
+                .help("Lifetime of the token in seconds")
+                .long("ttl")
+                .validator(validate_u64)
+                .takes_value(true)
+                .default_value("3600"),
+            )
+            .arg(
+              Arg::with_name("label")
+                .help("A key=value label to attach to the tunnel")
+                .long("label")
+                .short("l")
+                .validator(token::validate_label)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+            )
+            .arg(
+              Arg::with_name("service")
+                .help(
+                  "Route-address prefix of a service the bearer may request; all if none are given",
+                )
+                .long("service")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+            ),
         ),
     )
     .setting(clap::AppSettings::SubcommandRequiredElseHelp);
   let matches = app.get_matches();
   let mode = matches.subcommand_name().unwrap_or("<No subcommand?>");
-  let handler = main_args_handler(&matches);
+  let config = load_config(&matches);
+  init_logging(config.as_ref().map(|config| &config.logging).ok(), mode);
+  let handler = async { main_args_handler(&matches, config?).await };
   let rt = tokio::runtime::Builder::new_multi_thread()
     .thread_name("tokio-reactor-worker")
     .enable_all()
     .build()
     .expect("Tokio Runtime setup failure");
-  match rt.block_on(handler) {
+  let result = rt.block_on(handler);
+  match &result {
     Err(err) => {
       tracing::error!(mode = mode, err = ?err, "dispatch_command_failure");
     }
     Ok(_) => tracing::info!("{} exited successfully", mode),
   }
+  if mode == "connect" {
+    // Exit without awaiting the runtime, whose blocking read of stdin may never complete
+    std::process::exit(connect::exit_code(&result));
+  }
+}
+
+const DEFAULT_TOKEN_AUDIENCE: &str = "snocat";
+const DEFAULT_ROUTING_ATTEMPTS: usize = 3;
+
+fn validate_u64(v: String) -> std::result::Result<(), String> {
+  v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn validate_route_address(v: String) -> std::result::Result<(), String> {
+  match v.starts_with('/') {
+    true => Ok(()),
+    false => Err(String::from("Route addresses must begin with '/'")),
+  }
+}
+
+fn with_config_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+  subcommand.arg(
+    Arg::with_name("config")
+      .help("TOML file of settings, which flags passed explicitly override")
+      .long("config")
+      .validator(validate_existing_file)
+      .takes_value(true),
+  )
+}
+
+/// Loads the config file given to the `server`, `client`, or `connect` modes, if any
+fn load_config(matches: &'_ clap::ArgMatches<'_>) -> Result<ConfigFile> {
+  match matches.subcommand() {
+    ("server", Some(opts)) | ("client", Some(opts)) | ("connect", Some(opts)) => {
+      load_mode_config(opts)
+    }
+    (_, _) => Ok(ConfigFile::default()),
+  }
+}
+
+fn load_mode_config(opts: &'_ clap::ArgMatches<'_>) -> Result<ConfigFile> {
+  match opts.value_of("config") {
+    Some(path) => ConfigFile::load(Path::new(path)),
+    None => Ok(ConfigFile::default()),
+  }
+}
+
+/// Logs as the config file specifies, unless `RUST_LOG` is set
+///
+/// The `connect` mode logs only warnings by default, and to stderr, as its stdout carries data.
+fn init_logging(logging: Option<&LoggingSection>, mode: &str) {
+  use tracing_subscriber::fmt::writer::BoxMakeWriter;
+  let connect_mode = mode == "connect";
+  let default_filter = logging
+    .and_then(|logging| logging.filter.as_deref())
+    .unwrap_or(match connect_mode {
+      true => "warn",
+      false => "quinn=warn,quinn_proto=warn,debug",
+    });
+  let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+    .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default_filter));
+  let writer = match connect_mode {
+    true => BoxMakeWriter::new(std::io::stderr),
+    false => BoxMakeWriter::new(std::io::stdout),
+  };
+  let collector = tracing_subscriber::fmt()
+    .with_env_filter(env_filter)
+    .with_writer(writer);
+  let format = logging.map_or_else(LogFormat::default, |logging| logging.format);
+  match format {
+    LogFormat::Full => tracing::subscriber::set_global_default(collector.finish()),
+    LogFormat::Compact => tracing::subscriber::set_global_default(collector.compact().finish()),
+    LogFormat::Pretty => tracing::subscriber::set_global_default(collector.pretty().finish()),
+  }
+  .expect("Logger init must succeed");
+}
+
+/// Resolves a duration in seconds
+fn seconds(settings: &Settings, flag: &str, file: Option<u64>) -> Result<Duration> {
+  Ok(Duration::from_secs(
+    settings.value(flag, file)?.unwrap_or_default(),
+  ))
+}
+
+/// Resolves a duration in seconds, where 0 disables it
+fn optional_seconds(
+  settings: &Settings,
+  flag: &str,
+  file: Option<u64>,
+) -> Result<Option<Duration>> {
+  Ok(Some(seconds(settings, flag, file)?).filter(|duration| !duration.is_zero()))
+}
+
+fn with_certificate_args<'a, 'b>(subcommand: App<'a, 'b>, default_days: &'a str) -> App<'a, 'b> {
+  subcommand
+    .arg(
+      Arg::with_name("days")
+        .help("Days for which the certificate is valid")
+        .long("days")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value(default_days),
+    )
+    .arg(
+      Arg::with_name("key-type")
+        .long("key-type")
+        .possible_values(certgen::KeyType::NAMES)
+        .takes_value(true)
+        .default_value("ecdsa-p256"),
+    )
+}
+
+fn with_issuer_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+  subcommand
+    .arg(
+      Arg::with_name("ca-cert")
+        .help("Certificate of the issuing authority, as created by `cert ca`")
+        .long("ca-cert")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(true),
+    )
+    .arg(
+      Arg::with_name("ca-key")
+        .help("Private key of the issuing authority")
+        .long("ca-key")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(true),
+    )
+}
+
+fn with_heartbeat_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+  subcommand
+    .arg(
+      Arg::with_name("heartbeat-interval")
+        .help("Seconds between heartbeat pings to each tunnel's remote; 0 disables heartbeats")
+        .long("heartbeat-interval")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::with_name("heartbeat-timeout")
+        .help("Seconds after which an unanswered heartbeat ping fails")
+        .long("heartbeat-timeout")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("10"),
+    )
+    .arg(
+      Arg::with_name("heartbeat-failures")
+        .help("Consecutive failed heartbeat pings after which a tunnel is closed")
+        .long("heartbeat-failures")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("3"),
+    )
+}
+
+fn heartbeat_arg_handling(
+  settings: &Settings,
+  file: &config::HeartbeatSection,
+) -> Result<Option<HeartbeatPolicy>> {
+  let interval = match optional_seconds(settings, "heartbeat-interval", file.interval)? {
+    Some(interval) => interval,
+    None => return Ok(None),
+  };
+  let failures = settings
+    .value("heartbeat-failures", file.failures)?
+    .unwrap();
+  let failures = u32::try_from(failures).map_err(|_| {
+    anyhow::anyhow!(
+      "Invalid --heartbeat-failures / heartbeat.failures: at most {} are supported",
+      u32::MAX
+    )
+  })?;
+  Ok(Some(
+    HeartbeatPolicy::new(interval)
+      .with_timeout(seconds(settings, "heartbeat-timeout", file.timeout)?)
+      .with_failure_threshold(failures),
+  ))
+}
+
+fn with_client_certificate_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+  subcommand
+    .arg(
+      Arg::with_name("cert")
+        .help("Client certificate chain to present to the server, enabling the `certificate` authentication method")
+        .long("cert")
+        .validator(validate_existing_file)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("key")
+        .help("Private key of the client certificate")
+        .long("key")
+        .validator(validate_existing_file)
+        .takes_value(true),
+    )
+}
+
+/// Reads the client certificate and key, which are only meaningful together
+fn client_certificate_arg_handling(
+  settings: &Settings,
+  file: &config::ClientSection,
+) -> Result<Option<client::ClientCertificate>> {
+  let cert = settings.existing_file("cert", "client.cert", file.cert.as_ref())?;
+  let key = settings.existing_file("key", "client.key", file.key.as_ref())?;
+  match (cert, key) {
+    (Some(cert), Some(key)) => Ok(Some(client::ClientCertificate { cert, key })),
+    (None, None) => Ok(None),
+    (Some(_), None) => required(None, "key", "client.key"),
+    (None, Some(_)) => required(None, "cert", "client.cert"),
+  }
+}
+
+fn with_idle_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+  subcommand
+    .arg(
+      Arg::with_name("idle-tunnel-timeout")
+        .help("Seconds a tunnel may go without requests before it is closed; 0 disables")
+        .long("idle-tunnel-timeout")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::with_name("idle-stream-timeout")
+        .help(
+          "Seconds a forwarded stream may go without moving bytes before it is closed; 0 disables",
+        )
+        .long("idle-stream-timeout")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("0"),
+    )
+}
+
+fn with_authentication_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+  subcommand
+    .arg(
+      Arg::with_name("auth-key-file")
+        .help("JSON file of pre-shared keys used to mutually authenticate tunnels")
+        .long("auth-key-file")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(false),
+    )
+    .arg(
+      Arg::with_name("token-keys")
+        .help("JSON file of keys against which presented bearer tokens are verified")
+        .long("token-keys")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(false),
+    )
+    .arg(
+      Arg::with_name("token-audience")
+        .help("Audience that verified tokens must have been issued for")
+        .long("token-audience")
+        .takes_value(true)
+        .default_value(DEFAULT_TOKEN_AUDIENCE),
+    )
+    .arg(
+      Arg::with_name("token-clock-skew")
+        .help("Seconds of clock skew tolerated when checking token validity")
+        .long("token-clock-skew")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("60"),
+    )
+    .arg(
+      Arg::with_name("auth-methods")
+        .help("Comma-separated authentication methods to permit, in order of preference")
+        .long("auth-methods")
+        .takes_value(true)
+        .required(false),
+    )
+    .arg(
+      Arg::with_name("auth-timeout")
+        .help("Seconds after which an incomplete authentication is abandoned")
+        .long("auth-timeout")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("30"),
+    )
+    .arg(
+      Arg::with_name("auth-max-failures")
+        .help("Failed authentications from one address, within the lockout period, before it is locked out; 0 disables")
+        .long("auth-max-failures")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("10"),
+    )
+    .arg(
+      Arg::with_name("auth-lockout")
+        .help("Seconds for which addresses are locked out after repeated authentication failures")
+        .long("auth-lockout")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("60"),
+    )
+    .arg(
+      Arg::with_name("authorization-policy")
+        .help(
+          "JSON file of rules deciding which routes, connections, and ports tunnels may use; \
+           required to offer the tcp_proxy and relay services",
+        )
+        .long("authorization-policy")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(false),
+    )
+    .arg(
+      Arg::with_name("reauth-interval")
+        .help("Seconds between re-authentications of established tunnels; 0 disables")
+        .long("reauth-interval")
+        .validator(validate_u64)
+        .takes_value(true)
+        .default_value("0"),
+    )
+    .arg(
+      Arg::with_name("revocation-list")
+        .help("JSON file of revoked tunnel names, key IDs, and certificate serials, reloaded on SIGHUP")
+        .long("revocation-list")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(false),
+    )
+    .arg(
+      Arg::with_name("token-file")
+        .help("File containing a bearer token to present when connecting")
+        .long("token-file")
+        .validator(validate_existing_file)
+        .takes_value(true)
+        .required(false),
+    )
+}
+
+/// Reads the authentication options, where `certificate` is whether the transport
+/// verifies certificates which name the tunnel
+fn authentication_arg_handling(
+  settings: &Settings,
+  file: &config::AuthenticationSection,
+  certificate: bool,
+) -> Result<authentication::AuthenticationArgs> {
+  let methods = match settings.value::<String>("auth-methods", None)? {
+    Some(methods) => Some(methods.split(',').map(|m| m.trim().to_string()).collect()),
+    None => file.methods.clone(),
+  };
+  Ok(authentication::AuthenticationArgs {
+    key_file: settings.existing_file(
+      "auth-key-file",
+      "authentication.key_file",
+      file.key_file.as_ref(),
+    )?,
+    token_keys: settings.existing_file(
+      "token-keys",
+      "authentication.token_keys",
+      file.token_keys.as_ref(),
+    )?,
+    token_audience: settings
+      .value("token-audience", file.token_audience.clone())?
+      .unwrap(),
+    token_clock_skew: seconds(settings, "token-clock-skew", file.token_clock_skew)?,
+    token_file: settings.existing_file(
+      "token-file",
+      "authentication.token_file",
+      file.token_file.as_ref(),
+    )?,
+    certificate,
+    methods,
+    timeout: seconds(settings, "auth-timeout", file.timeout)?,
+    max_failures: settings
+      .value("auth-max-failures", file.max_failures)?
+      .unwrap() as usize,
+    lockout: seconds(settings, "auth-lockout", file.lockout)?,
+    authorization_policy: settings.existing_file(
+      "authorization-policy",
+      "authentication.authorization_policy",
+      file.authorization_policy.as_ref(),
+    )?,
+    reauthentication_interval: optional_seconds(settings, "reauth-interval", file.reauth_interval)?,
+    revocation_list: settings.existing_file(
+      "revocation-list",
+      "authentication.revocation_list",
+      file.revocation_list.as_ref(),
+    )?,
+  })
+}
+
+fn cert_validity(args: &'_ clap::ArgMatches<'_>) -> Result<Duration> {
+  let days: u64 = args.value_of("days").unwrap().parse()?;
+  days
+    .checked_mul(24 * 60 * 60)
+    .map(Duration::from_secs)
+    .ok_or_else(|| anyhow::Error::msg("Invalid --days: validity period is too long"))
+}
+
+fn cert_issue_arg_handling(
+  args: &'_ clap::ArgMatches<'_>,
+  subject: certgen::Subject,
+) -> Result<certgen::IssueArgs> {
+  Ok(certgen::IssueArgs {
+    output_base_path: PathBuf::from(args.value_of("path").unwrap()),
+    authority_cert: PathBuf::from(args.value_of("ca-cert").unwrap()),
+    authority_key: PathBuf::from(args.value_of("ca-key").unwrap()),
+    subject,
+    key_type: args.value_of("key-type").unwrap().parse()?,
+    validity: cert_validity(args)?,
+  })
+}
+
+pub fn token_issue_arg_handling(args: &'_ clap::ArgMatches<'_>) -> Result<token::IssueArgs> {
+  Ok(token::IssueArgs {
+    key_file: PathBuf::from(args.value_of("key").unwrap()),
+    key_id: args.value_of("key-id").map(String::from),
+    tunnel_name: args.value_of("name").unwrap().into(),
+    audience: args.value_of("audience").unwrap().into(),
+    lifetime: Duration::from_secs(args.value_of("ttl").unwrap().parse()?),
+    labels: args
+      .values_of("label")
+      .into_iter()
+      .flatten()
+      .map(token::parse_label)
+      .collect::<Result<_>>()?,
+    services: args
+      .values_of("service")
+      .into_iter()
+      .flatten()
+      .map(String::from)
+      .collect(),
+  })
 }
 
-pub async fn client_arg_handling(args: &'_ clap::ArgMatches<'_>) -> Result<client::ClientArgs> {
-  let authority_cert_path = args
-    .value_of("authority")
-    .map(|path| PathBuf::from_str(path))
-    // flip Option<Result<T, E>> to Result<Option<T>, E>
-    .map_or(Ok(None), |v| v.map(Some))?;
+pub async fn client_arg_handling(
+  args: &'_ clap::ArgMatches<'_>,
+  config: &ConfigFile,
+) -> Result<client::ClientArgs> {
+  let settings = Settings::new(args);
+  let file = &config.client;
+  let driver = settings.parsed(
+    "driver",
+    "client.driver",
+    file.driver.as_deref(),
+    parse_socketaddr,
+  )?;
+  let remote_forwards = settings.parsed_all(
+    "target",
+    "client.remote_forwards",
+    file.remote_forwards.as_deref(),
+    forwards::Forward::parse_remote,
+  )?;
+  let local_forwards = settings.parsed_all(
+    "local-forward",
+    "client.local_forwards",
+    file.local_forwards.as_deref(),
+    forwards::Forward::parse_local,
+  )?;
+  let client_certificate = client_certificate_arg_handling(&settings, file)?;
   Ok(client::ClientArgs {
-    authority_cert: authority_cert_path,
-    driver_host: parse_socketaddr(args.value_of("driver").unwrap())?,
-    driver_san: args.value_of("driver-san").unwrap().into(),
-    proxy_target_host: parse_socketaddr(args.value_of("target").unwrap())?,
+    authority_cert: settings.existing_file(
+      "authority",
+      "client.authority",
+      file.authority.as_ref(),
+    )?,
+    client_certificate: client_certificate.clone(),
+    driver_host: required(driver, "driver", "client.driver")?,
+    driver_san: required(
+      settings.value("driver-san", file.driver_san.clone())?,
+      "driver-san",
+      "client.driver_san",
+    )?,
+    forwards: remote_forwards.into_iter().chain(local_forwards).collect(),
+    heartbeat: heartbeat_arg_handling(&settings, &config.heartbeat)?,
+    idle_tunnel_timeout: optional_seconds(
+      &settings,
+      "idle-tunnel-timeout",
+      config.idle.tunnel_timeout,
+    )?,
+    idle_stream_timeout: optional_seconds(
+      &settings,
+      "idle-stream-timeout",
+      config.idle.stream_timeout,
+    )?,
+    transport: transport::TransportArgs::default().with_section(&config.transport),
+    services: file.services.clone().unwrap_or_else(|| {
+      vec![
+        config::ClientServiceKind::TcpProxy,
+        config::ClientServiceKind::Discovery,
+      ]
+    }),
+    authentication: authentication_arg_handling(
+      &settings,
+      &config.authentication,
+      client_certificate.is_some(),
+    )?,
+  })
+}
+
+pub async fn connect_arg_handling(
+  args: &'_ clap::ArgMatches<'_>,
+  config: &ConfigFile,
+) -> Result<connect::ConnectArgs> {
+  let settings = Settings::new(args);
+  let file = &config.client;
+  let driver = settings.parsed(
+    "driver",
+    "client.driver",
+    file.driver.as_deref(),
+    parse_socketaddr,
+  )?;
+  let client_certificate = client_certificate_arg_handling(&settings, file)?;
+  Ok(connect::ConnectArgs {
+    authority_cert: settings.existing_file(
+      "authority",
+      "client.authority",
+      file.authority.as_ref(),
+    )?,
+    client_certificate: client_certificate.clone(),
+    driver_host: required(driver, "driver", "client.driver")?,
+    driver_san: required(
+      settings.value("driver-san", file.driver_san.clone())?,
+      "driver-san",
+      "client.driver_san",
+    )?,
+    tunnel: TunnelName::new(args.value_of("tunnel").unwrap()),
+    address: args.value_of("address").unwrap().into(),
+    transport: transport::TransportArgs::default().with_section(&config.transport),
+    authentication: authentication_arg_handling(
+      &settings,
+      &config.authentication,
+      client_certificate.is_some(),
+    )?,
   })
 }
 
-pub async fn server_arg_handling(args: &'_ clap::ArgMatches<'_>) -> Result<server::ServerArgs> {
-  let cert_path = Path::new(args.value_of("cert").unwrap()).to_path_buf();
-  let key_path = Path::new(args.value_of("key").unwrap()).to_path_buf();
+pub async fn server_arg_handling(
+  args: &'_ clap::ArgMatches<'_>,
+  config: &ConfigFile,
+) -> Result<server::ServerArgs> {
+  let settings = Settings::new(args);
+  let file = &config.server;
+  let cert = settings.existing_file("cert", "server.cert", file.cert.as_ref())?;
+  let key = settings.existing_file("key", "server.key", file.key.as_ref())?;
+  let client_ca =
+    settings.existing_file("client-ca", "server.client_ca", file.client_ca.as_ref())?;
+  let quic = settings.parsed(
+    "quic",
+    "server.quic",
+    file.quic.as_deref(),
+    parse_socketaddr,
+  )?;
+  let bind_ip = settings.parsed(
+    "bindip",
+    "server.bind_ip",
+    file.bind_ip.as_deref(),
+    parse_ipaddr,
+  )?;
+  let ports = settings.parsed(
+    "ports",
+    "server.ports",
+    file.ports.as_deref(),
+    parse_port_range,
+  )?;
 
   Ok(server::ServerArgs {
-    cert: cert_path,
-    key: key_path,
-    quinn_bind_addr: parse_socketaddr(args.value_of("quic").unwrap())?,
-    tcp_bind_ip: parse_ipaddr(args.value_of("tcp").unwrap())?,
-    tcp_bind_port_range: parse_port_range(args.value_of("bind_range").unwrap())?,
+    cert: required(cert, "cert", "server.cert")?,
+    key: required(key, "key", "server.key")?,
+    client_ca: client_ca.clone(),
+    quinn_bind_addr: required(quic, "quic", "server.quic")?,
+    tcp_bind_ip: required(bind_ip, "bindip", "server.bind_ip")?,
+    tcp_bind_port_range: required(ports, "ports", "server.ports")?,
+    drain_grace_period: optional_seconds(&settings, "drain-grace-period", file.drain_grace_period)?,
+    request_limits: request_limits_arg_handling(&settings, &file.limits)?,
+    heartbeat: heartbeat_arg_handling(&settings, &config.heartbeat)?,
+    idle_tunnel_timeout: optional_seconds(
+      &settings,
+      "idle-tunnel-timeout",
+      config.idle.tunnel_timeout,
+    )?,
+    idle_stream_timeout: optional_seconds(
+      &settings,
+      "idle-stream-timeout",
+      config.idle.stream_timeout,
+    )?,
+    admin_socket: settings.value("admin-socket", file.admin_socket.clone())?,
+    transport: transport::TransportArgs::server_defaults().with_section(&config.transport),
+    services: file.services.clone().unwrap_or_else(|| {
+      vec![
+        config::ServerServiceKind::DemandProxy,
+        config::ServerServiceKind::Discovery,
+      ]
+    }),
+    routing: file.routing.strategy.unwrap_or_default(),
+    routing_attempts: file
+      .routing
+      .max_attempts
+      .unwrap_or(DEFAULT_ROUTING_ATTEMPTS),
+    config_file: args.value_of("config").map(PathBuf::from),
+    watch_interval: optional_seconds(&settings, "watch-interval", file.watch_interval)?,
+    authentication: authentication_arg_handling(
+      &settings,
+      &config.authentication,
+      client_ca.is_some(),
+    )?,
   })
 }
 
-async fn main_args_handler(matches: &'_ clap::ArgMatches<'_>) -> Result<()> {
+fn admin_arg_handling(args: &'_ clap::ArgMatches<'_>) -> Result<admin::AdminArgs> {
+  let request = match args.subcommand() {
+    ("list", Some(_)) => AdminRequest::ListTunnels,
+    ("close", Some(opts)) => AdminRequest::CloseTunnel {
+      tunnel: match opts.value_of("id") {
+        Some(id) => TunnelSelector::Id(TunnelId::new(id.parse()?)),
+        None => TunnelSelector::Name(TunnelName::new(opts.value_of("name").unwrap())),
+      },
+    },
+    ("drain", Some(_)) => AdminRequest::Drain,
+    ("ports", Some(_)) => AdminRequest::ListPorts,
+    (_, _) => unreachable!(),
+  };
+  Ok(admin::AdminArgs {
+    socket: PathBuf::from(args.value_of("socket").unwrap()),
+    request,
+  })
+}
+
+fn request_limits_arg_handling(
+  settings: &Settings,
+  file: &config::LimitsSection,
+) -> Result<RequestLimits> {
+  let limit = |name: &str, file: Option<u64>| -> Result<Option<usize>> {
+    let limit = settings.value(name, file)?.unwrap_or_default() as usize;
+    Ok(Some(limit).filter(|limit| *limit != 0))
+  };
+  Ok(
+    RequestLimits::new()
+      .with_tunnel_handlers(limit("max-tunnel-requests", file.max_tunnel_requests)?)
+      .with_tunnel_negotiations(limit(
+        "max-tunnel-negotiations",
+        file.max_tunnel_negotiations,
+      )?)
+      .with_handlers(limit("max-requests", file.max_requests)?)
+      .with_negotiation_timeout(optional_seconds(
+        settings,
+        "negotiation-timeout",
+        file.negotiation_timeout,
+      )?)
+      .with_overload_behavior(match settings.switch("backpressure", file.backpressure) {
+        true => OverloadBehavior::Backpressure,
+        false => OverloadBehavior::Refuse,
+      }),
+  )
+}
+
+async fn main_args_handler(
+  matches: &'_ clap::ArgMatches<'static>,
+  config: ConfigFile,
+) -> Result<()> {
   match matches.subcommand() {
     ("server", Some(opts)) => {
-      let config = server_arg_handling(opts).await?;
+      let config = server_arg_handling(opts, &config).await?;
       tracing::info!("Running as server with config {:#?}", config);
-      server::server_main(config).await
+      let opts = opts.clone();
+      let reload: server::ServerArgsLoader = Box::new(move || {
+        let opts = opts.clone();
+        async move { server_arg_handling(&opts, &load_mode_config(&opts)?).await }.boxed()
+      });
+      server::server_main(config, reload).await
     }
     ("client", Some(opts)) => {
-      let config = client_arg_handling(opts).await?;
+      let config = client_arg_handling(opts, &config).await?;
       tracing::info!("Running as client with config {:#?}", config);
       client::client_main(config).await
     }
-    ("cert", Some(opts)) => {
-      tracing::info!("Generating certs...");
-      let path_raw = opts.value_of("path").expect("Path argument is required");
-      let san = opts.value_of("san").expect("SAN argument must exist");
-      certgen::certgen_main(path_raw.into(), san.into()).await
+    ("connect", Some(opts)) => {
+      let config = connect_arg_handling(opts, &config).await?;
+      tracing::debug!("Connecting with config {:#?}", config);
+      connect::connect_main(config).await
     }
+    ("cert", Some(opts)) => match opts.subcommand() {
+      ("ca", Some(opts)) => certgen::authority_main(certgen::AuthorityArgs {
+        output_base_path: PathBuf::from(opts.value_of("path").unwrap()),
+        name: opts.value_of("name").unwrap().into(),
+        key_type: opts.value_of("key-type").unwrap().parse()?,
+        validity: cert_validity(opts)?,
+      }),
+      ("server", Some(opts)) => certgen::issue_main(cert_issue_arg_handling(
+        opts,
+        certgen::Subject::Server {
+          sans: opts.values_of("san").unwrap().map(String::from).collect(),
+        },
+      )?),
+      ("client", Some(opts)) => certgen::issue_main(cert_issue_arg_handling(
+        opts,
+        certgen::Subject::Client {
+          tunnel_name: TunnelName::new(opts.value_of("name").unwrap()),
+        },
+      )?),
+      (_, _) => {
+        tracing::info!("Generating certs...");
+        let path_raw = opts.value_of("path").expect("Path argument is required");
+        let sans = opts.values_of("san").expect("SAN argument must exist");
+        certgen::certgen_main(path_raw.into(), sans.map(String::from).collect()).await
+      }
+    },
+    ("admin", Some(opts)) => admin::admin_main(admin_arg_handling(opts)?).await,
+    ("token", Some(opts)) => match opts.subcommand() {
+      ("keygen", Some(opts)) => token::keygen_main(
+        Path::new(opts.value_of("path").unwrap()),
+        opts.value_of("id").unwrap().into(),
+      ),
+      ("issue", Some(opts)) => token::issue_main(token_issue_arg_handling(opts)?),
+      (_, _) => unreachable!(),
+    },
     (_, _) => unreachable!(),
   }
 }
@@ -0,0 +1,220 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Support for reloading settings while tunnels remain connected
+#![warn(unused_imports)]
+
+use anyhow::Result;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::{
+  collections::BTreeMap,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+  time::{Duration, SystemTime},
+};
+
+/// Serves the certificate of a replaceable TLS configuration to each new connection
+///
+/// Quinn cannot replace an endpoint's server config once it is listening, but its TLS
+/// configuration consults this resolver on every handshake, so replacing the resolver
+/// behind it changes the certificate presented to new connections only.
+pub struct ReloadableCertificate {
+  current: RwLock<Arc<dyn rustls::ResolvesServerCert>>,
+}
+
+impl ReloadableCertificate {
+  /// Takes over certificate resolution for `server_config`, initially with its own certificate
+  pub fn install(server_config: &mut quinn::ServerConfig) -> Arc<Self> {
+    let crypto = Arc::make_mut(&mut server_config.crypto);
+    let reloadable = Arc::new(Self {
+      current: RwLock::new(Arc::clone(&crypto.cert_resolver)),
+    });
+    crypto.cert_resolver = Arc::clone(&reloadable) as Arc<_>;
+    reloadable
+  }
+
+  /// The resolver presenting the current certificate
+  pub fn current(&self) -> Arc<dyn rustls::ResolvesServerCert> {
+    Arc::clone(
+      &self
+        .current
+        .read()
+        .expect("Certificate lock must not be poisoned"),
+    )
+  }
+
+  /// Presents the certificate of `server_config` to all future connections
+  pub fn replace(&self, server_config: &quinn::ServerConfig) {
+    *self
+      .current
+      .write()
+      .expect("Certificate lock must not be poisoned") =
+      Arc::clone(&server_config.crypto.cert_resolver);
+  }
+}
+
+impl rustls::ResolvesServerCert for ReloadableCertificate {
+  fn resolve(&self, client_hello: rustls::ClientHello) -> Option<rustls::sign::CertifiedKey> {
+    self.current().resolve(client_hello)
+  }
+}
+
+/// Why settings are being reloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadTrigger {
+  Hangup,
+  FilesChanged(Vec<PathBuf>),
+}
+
+/// Detects changes to files by polling their modification times
+#[derive(Debug, Default)]
+pub struct FileWatcher {
+  modified: BTreeMap<PathBuf, Option<SystemTime>>,
+}
+
+impl FileWatcher {
+  /// Watches `paths`, forgetting any others, and treating their current state as unchanged
+  pub fn watch(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+    self.modified = paths
+      .into_iter()
+      .map(|path| {
+        let modified = Self::modified(&path);
+        (path, modified)
+      })
+      .collect();
+  }
+
+  /// Lists the files modified, created, or removed since they were last checked
+  pub fn changed(&mut self) -> Vec<PathBuf> {
+    self
+      .modified
+      .iter_mut()
+      .filter_map(|(path, modified)| {
+        let current = Self::modified(path);
+        if current == *modified {
+          return None;
+        }
+        *modified = current;
+        Some(path.clone())
+      })
+      .collect()
+  }
+
+  fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+      .and_then(|metadata| metadata.modified())
+      .ok()
+  }
+}
+
+/// Yields each SIGHUP received, or nothing on platforms without them
+pub fn hangups() -> Result<BoxStream<'static, ReloadTrigger>> {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    let hangups = signal(SignalKind::hangup())?;
+    Ok(
+      stream::unfold(hangups, |mut hangups| async move {
+        hangups
+          .recv()
+          .await
+          .map(|()| (ReloadTrigger::Hangup, hangups))
+      })
+      .boxed(),
+    )
+  }
+  #[cfg(not(unix))]
+  {
+    Ok(stream::pending().boxed())
+  }
+}
+
+/// Yields the files changed since the previous poll, polling every `interval`
+pub fn file_changes(
+  watcher: Arc<std::sync::Mutex<FileWatcher>>,
+  interval: Duration,
+) -> BoxStream<'static, ReloadTrigger> {
+  stream::unfold(
+    (watcher, tokio::time::interval(interval)),
+    |(watcher, mut ticks)| async move {
+      ticks.tick().await;
+      let changed = watcher
+        .lock()
+        .expect("File watcher lock must not be poisoned")
+        .changed();
+      Some((changed, (watcher, ticks)))
+    },
+  )
+  .filter_map(|changed| async move {
+    match changed.is_empty() {
+      true => None,
+      false => Some(ReloadTrigger::FilesChanged(changed)),
+    }
+  })
+  .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::{FileWatcher, ReloadableCertificate};
+
+  /// A server config presenting a new self-signed certificate for `localhost`
+  fn self_signed_config() -> quinn::ServerConfig {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let chain = quinn::CertificateChain::from_certs(vec![quinn::Certificate::from_der(
+      &certificate.serialize_der().unwrap(),
+    )
+    .unwrap()]);
+    let key = quinn::PrivateKey::from_der(&certificate.serialize_private_key_der()).unwrap();
+    let mut builder = quinn::ServerConfigBuilder::default();
+    builder.certificate(chain, key).unwrap();
+    builder.build()
+  }
+
+  /// Whether two resolvers are the same object, regardless of their vtables
+  fn same_resolver<T: ?Sized>(a: &Arc<dyn rustls::ResolvesServerCert>, b: &Arc<T>) -> bool {
+    std::ptr::eq(Arc::as_ptr(a) as *const (), Arc::as_ptr(b) as *const ())
+  }
+
+  #[test]
+  fn replaced_certificates_are_presented() {
+    let mut installed = self_signed_config();
+    let original = Arc::clone(&installed.crypto.cert_resolver);
+    let certificate = ReloadableCertificate::install(&mut installed);
+    assert!(same_resolver(&installed.crypto.cert_resolver, &certificate));
+    assert!(same_resolver(&certificate.current(), &original));
+
+    let replacement = self_signed_config();
+    certificate.replace(&replacement);
+    assert!(same_resolver(
+      &certificate.current(),
+      &replacement.crypto.cert_resolver
+    ));
+    // Connections to the installed config resolve through the replaceable certificate
+    assert!(same_resolver(&installed.crypto.cert_resolver, &certificate));
+  }
+
+  #[test]
+  fn watcher_reports_each_change_once() {
+    let directory = std::env::temp_dir().join(format!("snocat-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let (existing, created) = (directory.join("existing"), directory.join("created"));
+    std::fs::write(&existing, "a").unwrap();
+
+    let mut watcher = FileWatcher::default();
+    watcher.watch(vec![existing.clone(), created.clone()]);
+    let unchanged = watcher.changed();
+    std::fs::write(&created, "b").unwrap();
+    let after_creation = watcher.changed();
+    let repeated = watcher.changed();
+    std::fs::remove_file(&existing).unwrap();
+    let after_removal = watcher.changed();
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert_eq!(unchanged, Vec::<std::path::PathBuf>::new());
+    assert_eq!(after_creation, vec![created]);
+    assert_eq!(repeated, Vec::<std::path::PathBuf>::new());
+    assert_eq!(after_removal, vec![existing]);
+  }
+}
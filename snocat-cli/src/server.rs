@@ -1,30 +1,59 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 use crate::{
-  services::{demand_proxy::DemandProxyService, PresetServiceRegistry},
+  config::{RoutingStrategy, ServerServiceKind},
+  reload::{FileWatcher, ReloadTrigger, ReloadableCertificate},
+  services::{
+    demand_proxy::{DemandProxyService, DEMAND_PROXY_ADDRESS_BASE},
+    relay::{RelayService, RELAY_ADDRESS_BASE},
+  },
+  transport::TransportArgs,
   util,
 };
 use anyhow::{Context as AnyhowContext, Result};
-use futures::future::{BoxFuture, FutureExt, TryFutureExt};
-use quinn::TransportConfig;
+use futures::{
+  future::{BoxFuture, FutureExt, TryFutureExt},
+  stream::{self, StreamExt},
+};
 use snocat::{
   common::{
-    authentication::SimpleAckAuthenticationHandler,
+    authentication::{
+      ReloadableAuthenticationHandler, RevocationList, Revocations, TokenClaimsRegistry,
+      TokenServiceAuthorizer,
+    },
+    authorization::{
+      AllOfAuthorizer, AllowAllAuthorizer, ArcAuthorizer, ReloadableAuthorizer,
+      ServiceAuthorization,
+    },
     protocol::{
+      discovery::{ServiceDiscoveryService, SERVICE_DISCOVERY_ADDRESS},
+      heartbeat::HeartbeatPolicy,
+      proxy_tcp::TcpStreamService,
+      routing::{
+        BalancingRouter, BalancingStrategy, NamedTunnelRouter, PrefixRewrite, PrefixRoute,
+        PrefixRouter,
+      },
+      service_registry::{ServiceScope, TrieServiceRegistry},
       traits::{InMemoryTunnelRegistry, TunnelRegistry},
-      tunnel::{id::MonotonicAtomicGenerator, QuinnTunnel},
-      Request, RouteAddress, Router, RoutingError,
+      tunnel::{id::MonotonicAtomicGenerator, QuinnTunnel, TunnelId, TunnelName},
+      RouteAddress, RoutedLink, Router, RoutingError,
     },
     tunnel_source::QuinnListenEndpoint,
   },
-  server::{modular::ModularDaemon, PortRangeAllocator},
+  server::{
+    admin::AdminService,
+    limits::RequestLimits,
+    modular::{DisconnectReason, ModularDaemon},
+    PortRangeAllocator,
+  },
   util::tunnel_stream::TunnelStream,
 };
 use std::{
   boxed::Box,
   net::{IpAddr, Ipv4Addr, Ipv6Addr},
   path::PathBuf,
-  sync::{Arc, Weak},
+  sync::{Arc, Mutex, Weak},
+  time::Duration,
 };
 use tokio_util::sync::CancellationToken;
 
@@ -33,11 +62,41 @@ use tokio_util::sync::CancellationToken;
 pub struct ServerArgs {
   pub cert: PathBuf,
   pub key: PathBuf,
+  /// Authority against which client certificates are verified, if they are requested
+  pub client_ca: Option<PathBuf>,
   pub quinn_bind_addr: std::net::SocketAddr,
   pub tcp_bind_ip: std::net::IpAddr,
   pub tcp_bind_port_range: std::ops::RangeInclusive<u16>,
+  /// Time after shutdown is requested before tunnels with requests in progress are closed
+  pub drain_grace_period: Option<std::time::Duration>,
+  pub request_limits: RequestLimits,
+  /// Heartbeats sent to each client, if enabled
+  pub heartbeat: Option<HeartbeatPolicy>,
+  /// Time without requests after which a tunnel is closed, if any
+  pub idle_tunnel_timeout: Option<std::time::Duration>,
+  /// Time without bytes moving after which a forwarded stream is closed, if any
+  pub idle_stream_timeout: Option<std::time::Duration>,
+  /// Unix socket on which admin requests are answered, if any
+  pub admin_socket: Option<PathBuf>,
+  pub transport: TransportArgs,
+  /// Services offered to clients
+  pub services: Vec<ServerServiceKind>,
+  pub routing: RoutingStrategy,
+  /// Tunnels tried for each request before it fails, when balancing between them
+  pub routing_attempts: usize,
+  /// Config file whose changes are reloaded, if any
+  pub config_file: Option<PathBuf>,
+  /// How often the config, certificate, and authentication files are checked for changes
+  pub watch_interval: Option<Duration>,
+  pub authentication: crate::authentication::AuthenticationArgs,
 }
 
+/// Addresses beneath this prefix are routed to the tunnel named by their next segment
+pub const NAMED_TUNNEL_PREFIX: &str = "/tunnel";
+
+/// Re-reads the server's settings, such as from its config file and the flags it was started with
+pub type ServerArgsLoader = Box<dyn Fn() -> BoxFuture<'static, Result<ServerArgs>> + Send + Sync>;
+
 pub struct SnocatServerRouter {
   typed_tunnel_registry: Weak<InMemoryTunnelRegistry>,
 }
@@ -53,12 +112,12 @@ impl SnocatServerRouter {
 impl Router for SnocatServerRouter {
   fn route(
     &self,
-    request: &Request,
+    address: &RouteAddress,
     // We don't need this one because we keep our own reference around for an unboxed variant
     // this allows us to access methods specific to our tunnel type's implementation
     _tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
-  ) -> BoxFuture<'_, Result<(RouteAddress, Box<dyn TunnelStream + Send + Sync>), RoutingError>> {
-    let addr = request.address.clone();
+  ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+    let addr = address.clone();
     async move {
       let tunnel_registry = self
         .typed_tunnel_registry
@@ -88,7 +147,7 @@ impl Router for SnocatServerRouter {
 
 /// Run a Snocat server that binds TCP sockets for each tunnel that connects
 #[tracing::instrument(
-skip(config),
+skip(config, reload),
 fields(
 addr=?config.tcp_bind_ip,
 ports=?config.tcp_bind_port_range,
@@ -96,8 +155,13 @@ quinn=?config.quinn_bind_addr,
 ),
 err
 )]
-pub async fn server_main(config: self::ServerArgs) -> Result<()> {
-  let quinn_config = build_quinn_config(&config)?;
+pub async fn server_main(config: self::ServerArgs, reload: ServerArgsLoader) -> Result<()> {
+  require_policy_for_services(
+    &config.services,
+    config.authentication.authorization_policy.is_some(),
+  )?;
+  let mut quinn_config = build_quinn_config(&config)?;
+  let certificate = ReloadableCertificate::install(&mut quinn_config);
   let endpoint = QuinnListenEndpoint::bind(config.quinn_bind_addr, quinn_config)?;
 
   let (shutdown, sigint_handler_task) = {
@@ -113,11 +177,36 @@ pub async fn server_main(config: self::ServerArgs) -> Result<()> {
 
   let tunnel_registry = Arc::new(InMemoryTunnelRegistry::new());
 
-  let service_registry = Arc::new(PresetServiceRegistry::new());
+  let service_registry = Arc::new(TrieServiceRegistry::new());
 
-  let router = { Arc::new(SnocatServerRouter::new(Arc::downgrade(&tunnel_registry))) };
+  let router = build_router(&config, &tunnel_registry);
 
-  let authentication_handler = Arc::new(SimpleAckAuthenticationHandler::new());
+  let revocations = crate::authentication::load_revocations(&config.authentication)?;
+  // Shared across reloads, such that tunnels keep the services their tokens list
+  let token_claims = Arc::new(TokenClaimsRegistry::new());
+  let authentication_methods = Arc::new(ReloadableAuthenticationHandler::new(
+    crate::authentication::build_method_handler(
+      &config.authentication,
+      revocations.as_ref(),
+      Some(&token_claims),
+    )?,
+  ));
+  let authentication_handler = crate::authentication::with_throttling(
+    &config.authentication,
+    Arc::clone(&authentication_methods) as Arc<_>,
+  );
+  let policy = crate::authentication::build_authorizer(&config.authentication)?
+    .map(|authorizer| Arc::new(ReloadableAuthorizer::new(authorizer)));
+  // Tunnels are held to both the authorization policy and the services their tokens list
+  let authorizer: ArcAuthorizer = Arc::new(AllOfAuthorizer::new(
+    policy
+      .iter()
+      .map(|policy| Arc::clone(policy) as ArcAuthorizer)
+      .chain(std::iter::once(
+        Arc::new(TokenServiceAuthorizer::new(Arc::clone(&token_claims))) as ArcAuthorizer,
+      ))
+      .collect(),
+  ));
 
   // Our tunnel IDs are just increments atop the unix timestamp millisecond we started the server
   // This would still likely lead to eventual collisions in a shared-ID cluster, so don't do that
@@ -129,28 +218,122 @@ pub async fn server_main(config: self::ServerArgs) -> Result<()> {
       .as_millis() as u64,
   ));
 
-  let modular = Arc::new(ModularDaemon::<QuinnTunnel<_>>::new(
-    service_registry.clone(),
-    tunnel_registry.clone(),
-    router,
-    authentication_handler,
-    tunnel_id_generator,
+  let modular = {
+    let modular = ModularDaemon::<QuinnTunnel<_>>::new(
+      service_registry.clone(),
+      tunnel_registry.clone(),
+      router,
+      authentication_handler,
+      tunnel_id_generator,
+    )
+    .with_authentication_timeout(crate::authentication::daemon_authentication_timeout(
+      &config.authentication,
+    ))
+    .with_reauthentication_interval(config.authentication.reauthentication_interval)
+    .with_drain_grace_period(config.drain_grace_period)
+    .with_request_limits(config.request_limits.clone())
+    .with_heartbeat(config.heartbeat)
+    .with_idle_timeout(config.idle_tunnel_timeout);
+    let modular = match &revocations {
+      Some(revocations) => modular.with_revocations(Arc::clone(revocations)),
+      None => modular,
+    };
+    Arc::new(modular.with_authorizer(Arc::clone(&authorizer)))
+  };
+  let claims_cleanup = tokio::task::spawn(forget_disconnected_claims(
+    modular.tunnel_disconnected.subscribe(),
+    Arc::clone(&token_claims),
   ));
 
+  let port_range_allocator = PortRangeAllocator::new(config.tcp_bind_port_range.clone());
+
   {
-    let demand_proxy_service = Arc::new(DemandProxyService::new(
+    let demand_proxy_service = DemandProxyService::new(
       Arc::downgrade(&tunnel_registry) as Weak<_>, // `as` clause triggers CoerceUnsize to make a dynamic Arc
       Arc::downgrade(modular.requests()),
-      PortRangeAllocator::new(config.tcp_bind_port_range),
+      port_range_allocator.clone(),
       vec![
         IpAddr::V6(Ipv6Addr::UNSPECIFIED),
         IpAddr::V4(Ipv4Addr::UNSPECIFIED),
       ],
+    )
+    .with_idle_timeout(config.idle_stream_timeout);
+    let demand_proxy_service = Arc::new(demand_proxy_service.with_authorization(
+      ServiceAuthorization::new(
+        Arc::clone(&authorizer),
+        Arc::downgrade(&tunnel_registry) as Weak<_>,
+      ),
     ));
-    service_registry.add_service_blocking(demand_proxy_service);
+    if config.services.contains(&ServerServiceKind::DemandProxy) {
+      service_registry.register(
+        DEMAND_PROXY_ADDRESS_BASE,
+        0,
+        ServiceScope::Global,
+        demand_proxy_service,
+      );
+    }
+
+    if config.services.contains(&ServerServiceKind::TcpProxy) {
+      let tcp_proxy_service =
+        TcpStreamService::new(false).with_idle_timeout(config.idle_stream_timeout);
+      let tcp_proxy_service = tcp_proxy_service.with_authorization(ServiceAuthorization::new(
+        Arc::clone(&authorizer),
+        Arc::downgrade(&tunnel_registry) as Weak<_>,
+      ));
+      service_registry.register("/", 0, ServiceScope::Global, Arc::new(tcp_proxy_service));
+    }
+
+    if config.services.contains(&ServerServiceKind::Relay) {
+      let relay_service = RelayService::new(Arc::downgrade(modular.requests()))
+        .with_idle_timeout(config.idle_stream_timeout);
+      service_registry.register(
+        RELAY_ADDRESS_BASE,
+        0,
+        ServiceScope::Global,
+        Arc::new(relay_service),
+      );
+    }
+
+    if config.services.contains(&ServerServiceKind::Discovery) {
+      let discovery_service = ServiceDiscoveryService::new(
+        Arc::downgrade(&service_registry) as Weak<_>,
+        Arc::downgrade(&tunnel_registry) as Weak<_>,
+      )
+      .with_authorizer(Arc::clone(&authorizer) as _);
+      service_registry.register(
+        SERVICE_DISCOVERY_ADDRESS,
+        0,
+        ServiceScope::Global,
+        Arc::new(discovery_service),
+      );
+    }
     drop(service_registry);
   }
 
+  let admin_task = match &config.admin_socket {
+    Some(admin_socket) => Some(crate::admin::serve_admin_socket(
+      admin_socket,
+      AdminService::new(Arc::clone(&modular), shutdown.clone())
+        .with_port_allocator(port_range_allocator.clone()),
+    )?),
+    None => None,
+  };
+
+  let reloader = tokio::task::spawn(
+    Reloader {
+      args: config.clone(),
+      load: reload,
+      certificate,
+      authentication: authentication_methods,
+      token_claims,
+      policy,
+      services: config.services.clone(),
+      revocations,
+      ports: port_range_allocator.clone(),
+    }
+    .run(),
+  );
+
   modular
     .run(endpoint, shutdown)
     .map_err(|_| anyhow::Error::msg("Modular runtime panicked and lost context"))
@@ -158,32 +341,501 @@ pub async fn server_main(config: self::ServerArgs) -> Result<()> {
 
   sigint_handler_task.abort();
   let _cancelled = sigint_handler_task.await;
+  claims_cleanup.abort();
+  let _cancelled = claims_cleanup.await;
+  reloader.abort();
+  let _cancelled = reloader.await;
+  if let Some(admin_task) = admin_task {
+    admin_task.abort();
+    let _cancelled = admin_task.await;
+    if let Some(admin_socket) = &config.admin_socket {
+      let _ = std::fs::remove_file(admin_socket);
+    }
+  }
 
   Ok(())
 }
 
+/// Fails if services reaching beyond the requesting tunnel are offered without an
+/// authorization policy
+///
+/// `tcp_proxy` connects to any host the server can reach, and `relay` to any service behind
+/// any other tunnel; without a policy, every authenticated tunnel could use either.
+fn require_policy_for_services(services: &[ServerServiceKind], has_policy: bool) -> Result<()> {
+  if has_policy {
+    return Ok(());
+  }
+  let unrestricted: Vec<&str> = services
+    .iter()
+    .filter_map(|service| match service {
+      ServerServiceKind::TcpProxy => Some("tcp_proxy"),
+      ServerServiceKind::Relay => Some("relay"),
+      ServerServiceKind::DemandProxy | ServerServiceKind::Discovery => None,
+    })
+    .collect();
+  if !unrestricted.is_empty() {
+    anyhow::bail!(
+      "Services {:?} let tunnels reach other hosts and tunnels, and require an authorization policy",
+      unrestricted
+    );
+  }
+  Ok(())
+}
+
+/// Forgets the token claims of each tunnel once it disconnects
+async fn forget_disconnected_claims(
+  mut disconnected: tokio::sync::broadcast::Receiver<(
+    TunnelId,
+    Option<TunnelName>,
+    DisconnectReason,
+  )>,
+  token_claims: Arc<TokenClaimsRegistry>,
+) {
+  use tokio::sync::broadcast::error::RecvError;
+  loop {
+    match disconnected.recv().await {
+      Ok((id, _name, _reason)) => {
+        token_claims.forget(&id);
+      }
+      Err(RecvError::Lagged(missed)) => {
+        tracing::warn!(
+          missed,
+          "Missed tunnel disconnections; their token claims are retained"
+        );
+      }
+      Err(RecvError::Closed) => break,
+    }
+  }
+}
+
+/// Applies reloaded settings to a running server, keeping its tunnels connected
+struct Reloader {
+  args: ServerArgs,
+  load: ServerArgsLoader,
+  certificate: Arc<ReloadableCertificate>,
+  authentication: Arc<ReloadableAuthenticationHandler>,
+  token_claims: Arc<TokenClaimsRegistry>,
+  /// Present only if the server was started with an authorization policy
+  policy: Option<Arc<ReloadableAuthorizer>>,
+  /// Services offered since startup, which reloads do not change
+  services: Vec<ServerServiceKind>,
+  revocations: Option<Arc<Revocations>>,
+  ports: PortRangeAllocator,
+}
+
+impl Reloader {
+  /// Reloads on each SIGHUP, and whenever a watched file changes
+  async fn run(mut self) {
+    let watcher = Arc::new(Mutex::new(FileWatcher::default()));
+    self.watch(&watcher);
+    let hangups = match crate::reload::hangups() {
+      Ok(hangups) => hangups,
+      Err(e) => {
+        tracing::error!(error = ?e, "Failed to listen for SIGHUP; reloading only on file changes");
+        stream::pending().boxed()
+      }
+    };
+    let mut triggers = match self.args.watch_interval {
+      Some(interval) => stream::select(
+        hangups,
+        crate::reload::file_changes(Arc::clone(&watcher), interval),
+      )
+      .boxed(),
+      None => hangups,
+    };
+    while let Some(trigger) = triggers.next().await {
+      match &trigger {
+        ReloadTrigger::Hangup => tracing::info!("SIGHUP received; reloading settings"),
+        ReloadTrigger::FilesChanged(files) => {
+          tracing::info!(?files, "Files changed; reloading settings")
+        }
+      }
+      match self.reload().await {
+        Ok(()) => self.watch(&watcher),
+        Err(e) => tracing::error!(error = ?e, "Reload rejected; keeping the previous settings"),
+      }
+    }
+  }
+
+  fn watch(&self, watcher: &Mutex<FileWatcher>) {
+    let authentication = &self.args.authentication;
+    let files = self
+      .args
+      .config_file
+      .iter()
+      .chain(vec![&self.args.cert, &self.args.key])
+      .chain(authentication.key_file.iter())
+      .chain(authentication.token_keys.iter())
+      .chain(authentication.token_file.iter())
+      .chain(authentication.authorization_policy.iter())
+      .chain(authentication.revocation_list.iter())
+      .cloned();
+    watcher
+      .lock()
+      .expect("File watcher lock must not be poisoned")
+      .watch(files);
+  }
+
+  async fn reload(&mut self) -> Result<()> {
+    let args = (self.load)().await?;
+    // Everything is built before anything is applied, such that a rejected reload changes nothing
+    let server_config = build_quinn_config(&args)?;
+    let authentication = crate::authentication::build_method_handler(
+      &args.authentication,
+      self.revocations.as_ref(),
+      Some(&self.token_claims),
+    )?;
+    let authorizer = crate::authentication::build_authorizer(&args.authentication)?;
+    if self.policy.is_none() && authorizer.is_some() {
+      anyhow::bail!("An authorization policy can only be added by restarting the server");
+    }
+    require_policy_for_services(&self.services, authorizer.is_some())?;
+    // Read from where it was loaded at startup, as moving the list requires a restart
+    let revocation_list = self
+      .revocations
+      .as_ref()
+      .and_then(|revocations| revocations.path())
+      .map(RevocationList::load)
+      .transpose()?;
+
+    let previous = std::mem::replace(&mut self.args, args);
+    let args = &self.args;
+    Self::warn_of_restarts(&previous, args);
+    self.certificate.replace(&server_config);
+    self.authentication.replace(authentication);
+    if let (Some(revocations), Some(list)) = (&self.revocations, revocation_list) {
+      revocations.replace(list);
+    }
+    if let Some(reloadable) = &self.policy {
+      // Removing the policy permits everything, as though the server had been started without one
+      reloadable.replace(authorizer.unwrap_or_else(|| Arc::new(AllowAllAuthorizer::new())));
+    }
+    if previous.tcp_bind_port_range != args.tcp_bind_port_range {
+      tracing::info!(
+        previous = ?previous.tcp_bind_port_range,
+        current = ?args.tcp_bind_port_range,
+        "Port range changed"
+      );
+      self.ports.set_range(args.tcp_bind_port_range.clone());
+    }
+    if previous.authentication != args.authentication {
+      tracing::info!(authentication = ?args.authentication, "Authentication settings changed");
+    }
+    tracing::info!(
+      cert = ?args.cert,
+      key = ?args.key,
+      "Reloaded certificate, port range, authentication, revocations, and authorization policy"
+    );
+    Ok(())
+  }
+
+  /// Warns of changed settings which the running server cannot apply
+  fn warn_of_restarts(previous: &ServerArgs, current: &ServerArgs) {
+    let (a, b) = (&previous.authentication, &current.authentication);
+    let restart_required = [
+      (
+        "server.quic",
+        previous.quinn_bind_addr != current.quinn_bind_addr,
+      ),
+      (
+        "server.bind_ip",
+        previous.tcp_bind_ip != current.tcp_bind_ip,
+      ),
+      (
+        "server.drain_grace_period",
+        previous.drain_grace_period != current.drain_grace_period,
+      ),
+      (
+        "server.limits",
+        previous.request_limits != current.request_limits,
+      ),
+      (
+        "server.admin_socket",
+        previous.admin_socket != current.admin_socket,
+      ),
+      ("server.services", previous.services != current.services),
+      (
+        "server.routing",
+        (previous.routing, previous.routing_attempts)
+          != (current.routing, current.routing_attempts),
+      ),
+      (
+        "server.watch_interval",
+        previous.watch_interval != current.watch_interval,
+      ),
+      ("heartbeat", previous.heartbeat != current.heartbeat),
+      (
+        "idle",
+        (previous.idle_tunnel_timeout, previous.idle_stream_timeout)
+          != (current.idle_tunnel_timeout, current.idle_stream_timeout),
+      ),
+      ("transport", previous.transport != current.transport),
+      ("server.client_ca", previous.client_ca != current.client_ca),
+      ("authentication.timeout", a.timeout != b.timeout),
+      (
+        "authentication.max_failures",
+        (a.max_failures, a.lockout) != (b.max_failures, b.lockout),
+      ),
+      (
+        "authentication.reauth_interval",
+        a.reauthentication_interval != b.reauthentication_interval,
+      ),
+      (
+        "authentication.revocation_list",
+        a.revocation_list != b.revocation_list,
+      ),
+    ];
+    for (setting, _) in restart_required.iter().filter(|(_, changed)| *changed) {
+      tracing::warn!(
+        setting,
+        "Setting changed, but takes effect only after a restart"
+      );
+    }
+  }
+}
+
+/// Routes `/tunnel/<name>/...` addresses to the tunnel of that name, and others to the newest
+/// tunnel, or balancing between all tunnels by the configured strategy
+fn build_router(
+  config: &ServerArgs,
+  tunnel_registry: &Arc<InMemoryTunnelRegistry>,
+) -> Arc<dyn Router + Send + Sync + 'static> {
+  Arc::new(PrefixRouter::new(
+    vec![PrefixRoute::new(
+      NAMED_TUNNEL_PREFIX,
+      PrefixRewrite::Strip,
+      Arc::new(NamedTunnelRouter::new()),
+    )],
+    Some(build_strategy_router(config, tunnel_registry)),
+  ))
+}
+
+fn build_strategy_router(
+  config: &ServerArgs,
+  tunnel_registry: &Arc<InMemoryTunnelRegistry>,
+) -> Arc<dyn Router + Send + Sync + 'static> {
+  let strategy = match config.routing {
+    RoutingStrategy::Newest => {
+      return Arc::new(SnocatServerRouter::new(Arc::downgrade(tunnel_registry)));
+    }
+    RoutingStrategy::RoundRobin => BalancingStrategy::RoundRobin,
+    RoutingStrategy::Random => BalancingStrategy::Random,
+    RoutingStrategy::LeastActiveStreams => BalancingStrategy::LeastActiveStreams,
+    RoutingStrategy::LowestRtt => BalancingStrategy::LowestRtt,
+  };
+  let typed_tunnel_registry = Arc::downgrade(tunnel_registry);
+  Arc::new(BalancingRouter::new(
+    move |_address, _tunnel_registry| {
+      let typed_tunnel_registry = typed_tunnel_registry.clone();
+      async move {
+        match typed_tunnel_registry.upgrade() {
+          // Records are listed in order of their IDs, as round-robin rotation requires
+          Some(tunnel_registry) => tunnel_registry.records().await,
+          None => Vec::new(),
+        }
+      }
+      .boxed()
+    },
+    strategy,
+    config.routing_attempts,
+  ))
+}
+
 fn build_quinn_config(config: &ServerArgs) -> Result<quinn::ServerConfig> {
   let cert_pem = std::fs::read(&config.cert).context("Failed reading cert file")?;
   let priv_pem = std::fs::read(&config.key).context("Failed reading private key file")?;
   let priv_key =
     quinn::PrivateKey::from_pem(&priv_pem).context("Quinn .pem parsing of private key failed")?;
-  let mut config = quinn::ServerConfigBuilder::default();
-  config.use_stateless_retry(true);
-  let mut transport_config = TransportConfig::default();
-  transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
-  transport_config.receive_window(512 * 1024 * 1024)?;
-  transport_config.send_window(512 * 1024 * 1024);
-  transport_config.stream_receive_window(512 * 1024 * 1024 / 8)?;
-  transport_config
-    .max_idle_timeout(Some(std::time::Duration::from_secs(30)))
-    .unwrap();
+  let transport_config = crate::transport::build_transport_config(&config.transport)?;
   let mut server_config = quinn::ServerConfig::default();
   server_config.transport = Arc::new(transport_config);
-  server_config.migration(true);
+  server_config.migration(config.transport.migration.unwrap_or(true));
   let mut cfg_builder = quinn::ServerConfigBuilder::new(server_config);
+  cfg_builder.use_stateless_retry(config.transport.stateless_retry.unwrap_or(true));
   cfg_builder.protocols(util::ALPN_QUIC_HTTP);
   cfg_builder.enable_keylog();
   let cert_chain = quinn::CertificateChain::from_pem(&cert_pem)?;
   cfg_builder.certificate(cert_chain, priv_key)?;
-  Ok(cfg_builder.build())
+  let mut server_config = cfg_builder.build();
+  if let Some(client_ca) = &config.client_ca {
+    let ca_pem = std::fs::read(client_ca).context("Failed reading client CA file")?;
+    let mut roots = rustls::RootCertStore::empty();
+    match roots.add_pem_file(&mut &ca_pem[..]) {
+      Ok((added, _)) if added > 0 => (),
+      _ => anyhow::bail!("No valid certificates in client CA file"),
+    }
+    // Clients without certificates may still authenticate by other methods
+    Arc::make_mut(&mut server_config.crypto)
+      .set_client_certificate_verifier(rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots));
+  }
+  Ok(server_config)
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::FutureExt;
+  use snocat::{
+    common::{
+      authentication::{ReloadableAuthenticationHandler, Revocations, TokenClaimsRegistry},
+      protocol::tunnel::TunnelName,
+    },
+    server::PortRangeAllocator,
+  };
+  use std::{path::Path, sync::Arc, time::Duration};
+
+  use super::{build_quinn_config, require_policy_for_services, Reloader, ServerArgs};
+  use crate::{
+    authentication::AuthenticationArgs,
+    config::{RoutingStrategy, ServerServiceKind},
+    reload::ReloadableCertificate,
+  };
+
+  /// Settings for a server presenting a new self-signed certificate, written into `directory`
+  fn server_args(directory: &Path, name: &str) -> ServerArgs {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let (cert, key) = (
+      directory.join(format!("{}.pem", name)),
+      directory.join(format!("{}.key", name)),
+    );
+    std::fs::write(&cert, certificate.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key, certificate.serialize_private_key_pem()).unwrap();
+    ServerArgs {
+      cert,
+      key,
+      client_ca: None,
+      quinn_bind_addr: "127.0.0.1:0".parse().unwrap(),
+      tcp_bind_ip: "127.0.0.1".parse().unwrap(),
+      tcp_bind_port_range: 8000..=8010,
+      drain_grace_period: None,
+      request_limits: Default::default(),
+      heartbeat: None,
+      idle_tunnel_timeout: None,
+      idle_stream_timeout: None,
+      admin_socket: None,
+      transport: Default::default(),
+      services: vec![ServerServiceKind::DemandProxy],
+      routing: RoutingStrategy::Newest,
+      routing_attempts: 1,
+      config_file: None,
+      watch_interval: None,
+      authentication: AuthenticationArgs {
+        key_file: None,
+        token_keys: None,
+        token_audience: String::from("snocat"),
+        token_clock_skew: Duration::from_secs(0),
+        token_file: None,
+        certificate: true,
+        methods: None,
+        timeout: Duration::from_secs(10),
+        max_failures: 0,
+        lockout: Duration::from_secs(0),
+        authorization_policy: None,
+        reauthentication_interval: None,
+        revocation_list: None,
+      },
+    }
+  }
+
+  #[tokio::test]
+  async fn rejected_reloads_change_nothing() {
+    let directory = std::env::temp_dir().join(format!("snocat-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let args = server_args(&directory, "original");
+    // Valid in every respect, but adds a policy, which requires a restart
+    let mut rejected = server_args(&directory, "replacement");
+    rejected.tcp_bind_port_range = 9000..=9010;
+    rejected.authentication.certificate = false;
+    let policy = directory.join("policy.json");
+    std::fs::write(&policy, r#"{ "rules": [] }"#).unwrap();
+    rejected.authentication.authorization_policy = Some(policy);
+
+    let mut quinn_config = build_quinn_config(&args).unwrap();
+    let certificate = ReloadableCertificate::install(&mut quinn_config);
+    let presented = certificate.current();
+    let token_claims = Arc::new(TokenClaimsRegistry::new());
+    let handler =
+      crate::authentication::build_method_handler(&args.authentication, None, Some(&token_claims))
+        .unwrap();
+    let authentication = Arc::new(ReloadableAuthenticationHandler::new(Arc::clone(&handler)));
+    let ports = PortRangeAllocator::new(args.tcp_bind_port_range.clone());
+    let mut reloader = Reloader {
+      args: args.clone(),
+      load: Box::new(move || futures::future::ready(Ok(rejected.clone())).boxed()),
+      certificate: Arc::clone(&certificate),
+      authentication: Arc::clone(&authentication),
+      token_claims,
+      policy: None,
+      services: args.services.clone(),
+      revocations: None,
+      ports: ports.clone(),
+    };
+
+    let result = reloader.reload().await;
+    std::fs::remove_dir_all(&directory).unwrap();
+    let rejection = result.unwrap_err().to_string();
+    assert!(rejection.contains("restarting"), "{}", rejection);
+    assert_eq!(reloader.args, args);
+    assert_eq!(ports.range(), args.tcp_bind_port_range);
+    assert!(std::ptr::eq(
+      Arc::as_ptr(&certificate.current()) as *const (),
+      Arc::as_ptr(&presented) as *const ()
+    ));
+    assert!(std::ptr::eq(
+      Arc::as_ptr(&authentication.current()) as *const (),
+      Arc::as_ptr(&handler) as *const ()
+    ));
+  }
+
+  #[tokio::test]
+  async fn reloads_replace_revocation_lists() {
+    let directory = std::env::temp_dir().join(format!("snocat-revocations-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let list = directory.join("revoked.json");
+    std::fs::write(&list, r#"{ "tunnel_names": [] }"#).unwrap();
+    let mut args = server_args(&directory, "server");
+    args.authentication.revocation_list = Some(list.clone());
+    let revocations = Arc::new(Revocations::from_file(&list).unwrap());
+
+    let mut quinn_config = build_quinn_config(&args).unwrap();
+    let token_claims = Arc::new(TokenClaimsRegistry::new());
+    let handler =
+      crate::authentication::build_method_handler(&args.authentication, None, Some(&token_claims))
+        .unwrap();
+    let reloaded = args.clone();
+    let mut reloader = Reloader {
+      args: args.clone(),
+      load: Box::new(move || futures::future::ready(Ok(reloaded.clone())).boxed()),
+      certificate: ReloadableCertificate::install(&mut quinn_config),
+      authentication: Arc::new(ReloadableAuthenticationHandler::new(handler)),
+      token_claims,
+      policy: None,
+      services: args.services.clone(),
+      revocations: Some(Arc::clone(&revocations)),
+      ports: PortRangeAllocator::new(args.tcp_bind_port_range.clone()),
+    };
+
+    std::fs::write(&list, r#"{ "tunnel_names": ["edge"] }"#).unwrap();
+    let applied = reloader.reload().await;
+    let revoked = revocations.is_name_revoked(&TunnelName::new("edge"));
+    // An unreadable list rejects the reload, keeping the previous list
+    std::fs::write(&list, "not json").unwrap();
+    let rejected = reloader.reload().await;
+    let still_revoked = revocations.is_name_revoked(&TunnelName::new("edge"));
+    std::fs::remove_dir_all(&directory).unwrap();
+    applied.unwrap();
+    assert!(revoked);
+    assert!(rejected.is_err());
+    assert!(still_revoked);
+  }
+
+  #[test]
+  fn proxying_services_require_a_policy() {
+    let local = [ServerServiceKind::DemandProxy, ServerServiceKind::Discovery];
+    assert!(require_policy_for_services(&local, false).is_ok());
+    for service in [ServerServiceKind::TcpProxy, ServerServiceKind::Relay].iter() {
+      let services = [ServerServiceKind::DemandProxy, *service];
+      assert!(require_policy_for_services(&services, false).is_err());
+      assert!(require_policy_for_services(&services, true).is_ok());
+    }
+  }
 }
@@ -2,11 +2,12 @@
 // Licensed under the MIT license OR Apache 2.0
 
 use snocat::{
+  common::authorization::{Permission, ServiceAuthorization},
   common::protocol::{
     request_handler::RequestClientHandler,
     traits::TunnelRegistry,
     tunnel::{Tunnel, TunnelId},
-    Client, ClientError, RouteAddress, Service, ServiceError,
+    Client, ClientError, ProtocolVersion, RouteAddress, Service, ServiceError,
   },
   server::PortRangeAllocator,
 };
@@ -14,6 +15,7 @@ use std::{
   backtrace::Backtrace,
   net::IpAddr,
   sync::{Arc, Weak},
+  time::Duration,
 };
 use tokio_util::sync::CancellationToken;
 
@@ -35,9 +37,14 @@ pub struct DemandProxyClient {
 impl Client for DemandProxyClient {
   type Response = (Vec<SocketAddr>, BoxFuture<'static, Result<(), ClientError>>);
 
+  fn protocol_id(&self) -> Option<&str> {
+    Some(DEMAND_PROXY_PROTOCOL_ID)
+  }
+
   fn handle(
     self,
     addr: RouteAddress,
+    _version: ProtocolVersion,
     mut tunnel: Box<dyn TunnelStream + Send + 'static>,
   ) -> BoxFuture<Result<Self::Response, ClientError>> {
     let span = tracing::span!(tracing::Level::DEBUG, "demand_proxy_client", target=?addr);
@@ -82,6 +89,8 @@ pub struct DemandProxyService {
   request_client_handler: Weak<RequestClientHandler>,
   port_range_allocator: PortRangeAllocator,
   bind_addrs: Arc<Vec<IpAddr>>,
+  authorization: Option<Arc<ServiceAuthorization>>,
+  idle_timeout: Option<Duration>,
 }
 
 impl std::fmt::Debug for DemandProxyService {
@@ -90,7 +99,13 @@ impl std::fmt::Debug for DemandProxyService {
   }
 }
 
-const DEMAND_PROXY_ADDRESS_BASE: &'static str = "/proxyme/0.0.1/";
+/// Addresses take the form `/proxyme/<port>` or `/proxyme/<host>/<port>`
+pub const DEMAND_PROXY_ADDRESS_BASE: &str = "/proxyme/";
+
+/// Address base used before the protocol version was negotiated rather than addressed
+const LEGACY_DEMAND_PROXY_ADDRESS_BASE: &str = "/proxyme/0.0.1/";
+
+pub const DEMAND_PROXY_PROTOCOL_ID: &str = "snocat.demand-proxy";
 
 impl DemandProxyService {
   pub fn new(
@@ -105,9 +120,23 @@ impl DemandProxyService {
       request_client_handler,
       port_range_allocator,
       bind_addrs: Arc::new(bind_addrs),
+      authorization: None,
+      idle_timeout: None,
     }
   }
 
+  /// Only binds ports for which the requesting tunnel holds a `Listen` permission
+  pub fn with_authorization(mut self, authorization: ServiceAuthorization) -> Self {
+    self.authorization = Some(Arc::new(authorization));
+    self
+  }
+
+  /// Closes forwarded streams once no bytes move in either direction for `timeout`, if one is given
+  pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.idle_timeout = timeout;
+    self
+  }
+
   /// Removes incidents where one "unspecified" / dual-stack-mode IP will steal from others on the host
   fn handle_dual_stack_addrs(bind_addrs: &mut Vec<IpAddr>) {
     match bind_addrs.iter().find(|addr| addr.is_unspecified()) {
@@ -126,6 +155,7 @@ impl DemandProxyService {
     weak_tunnel: Weak<dyn Tunnel + Send + Sync + Unpin>,
     request_client_handler: Weak<RequestClientHandler>,
     stop_accepting: CancellationToken,
+    idle_timeout: Option<Duration>,
   ) -> Result<(), ServiceError> {
     use futures::stream::{StreamExt, TryStreamExt};
     let tcp_listener = tokio_stream::wrappers::TcpListenerStream::new(tcp_listener);
@@ -161,7 +191,7 @@ impl DemandProxyService {
             request_handler::RequestHandlingError,
           };
           let (tcp_recv, tcp_send) = tokio::io::split(tcp_stream);
-          let client = TcpStreamClient::new(tcp_recv, tcp_send);
+          let client = TcpStreamClient::new(tcp_recv, tcp_send).with_idle_timeout(idle_timeout);
           let target: TcpStreamTarget = DnsTarget::PreferHigher {
             host: target_addr
               .0
@@ -196,7 +226,8 @@ impl DemandProxyService {
 
   fn parse_address(addr: &str) -> Result<(Option<&str>, u16), ()> {
     addr
-      .strip_prefix(DEMAND_PROXY_ADDRESS_BASE)
+      .strip_prefix(LEGACY_DEMAND_PROXY_ADDRESS_BASE)
+      .or_else(|| addr.strip_prefix(DEMAND_PROXY_ADDRESS_BASE))
       .ok_or(())
       .and_then(|suffix: &str| {
         let (host, port) = match suffix.split_once("/") {
@@ -215,6 +246,7 @@ impl DemandProxyService {
     weak_tunnel: Weak<dyn Tunnel + Send + Sync + Unpin>,
     request_client_handler: Weak<RequestClientHandler>,
     no_new_requests_listener: CancellationToken,
+    idle_timeout: Option<Duration>,
   ) -> Result<(), ServiceError> {
     let span =
       tracing::span!(tracing::Level::DEBUG, "demand_proxy_forwarding", target = ?target_addr);
@@ -236,13 +268,20 @@ impl DemandProxyService {
     )
     .try_for_each_concurrent(
       None,
-      |(listener, weak_tunnel, request_client_handler, no_new_requests_listener, parsed_addr)| {
+      move |(
+        listener,
+        weak_tunnel,
+        request_client_handler,
+        no_new_requests_listener,
+        parsed_addr,
+      )| {
         Self::run_tcp_listener(
           parsed_addr,
           listener,
           weak_tunnel,
           request_client_handler,
           no_new_requests_listener,
+          idle_timeout,
         )
       },
     )
@@ -263,9 +302,20 @@ impl Service for DemandProxyService {
     Self::parse_address(addr).is_ok()
   }
 
+  fn protocol_id(&self) -> Option<&str> {
+    Some(DEMAND_PROXY_PROTOCOL_ID)
+  }
+
+  fn description(&self) -> Option<String> {
+    Some(String::from(
+      "Binds TCP ports which forward connections back through the requesting tunnel",
+    ))
+  }
+
   fn handle(
     &'_ self,
     addr: RouteAddress,
+    _version: ProtocolVersion,
     mut stream: Box<dyn TunnelStream + Send + 'static>,
     tunnel_id: TunnelId,
   ) -> BoxFuture<'_, Result<(), ServiceError>> {
@@ -276,6 +326,7 @@ impl Service for DemandProxyService {
     let tunnel_registry = Weak::clone(&self.tunnel_registry);
     let port_range_allocator = self.port_range_allocator.clone();
     let bind_addrs = Arc::clone(&self.bind_addrs);
+    let authorization = self.authorization.clone();
     let parsed_addr = {
       let (host, port) = match Self::parse_address(&addr).or(Err(ServiceError::AddressError)) {
         Ok(x) => x,
@@ -301,8 +352,23 @@ impl Service for DemandProxyService {
           .ok_or(ServiceError::DependencyFailure)?;
         Arc::downgrade(&tunnel.tunnel)
       };
-      let port = match port_range_allocator.allocate().await {
+      let port = match port_range_allocator.allocate_to(tunnel_id).await {
         Ok(port) => {
+          let authorized = match &authorization {
+            Some(authorization) => {
+              authorization
+                .authorize(tunnel_id, Permission::Listen { port: port.port() })
+                .await
+            }
+            None => Ok(()),
+          };
+          if let Err(denial) = authorized {
+            // Refuse the client as though no port were available, releasing the allocation
+            write_framed_json(&mut stream, PortGrantedNotificationType::None)
+              .await
+              .map_err(|_| ServiceError::IllegalResponse)?;
+            return Err(ServiceError::Unauthorized(denial));
+          }
           // Notify the client of their allocated port
           write_framed_json(
             &mut stream,
@@ -384,6 +450,7 @@ impl Service for DemandProxyService {
         weak_tunnel,
         self.request_client_handler.clone(),
         no_new_requests,
+        self.idle_timeout,
       );
 
       let (mut stream, listener_result) =
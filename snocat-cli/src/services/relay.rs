@@ -0,0 +1,524 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Relays streams from a requesting tunnel to TCP services reached through the daemon's router
+
+use futures::future::{BoxFuture, FutureExt};
+use snocat::{
+  common::protocol::{
+    negotiation::NegotiationError,
+    proxy_tcp::TCP_PROXY_PROTOCOL_ID,
+    request_handler::{RequestClientHandler, RequestHandlingError},
+    tunnel::TunnelId,
+    Client, ClientError, ProtocolVersion, RouteAddress, Service, ServiceError,
+  },
+  util::{proxy_generic_tokio_streams_with_idle_timeout, proxy_tokio_stream},
+};
+use std::{
+  sync::{Arc, Mutex, Weak},
+  time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing_futures::Instrument;
+
+use crate::util::tunnel_stream::TunnelStream;
+
+/// Addresses take the form `/relay/<address>`, relaying to `/<address>` through the daemon's router
+pub const RELAY_ADDRESS_BASE: &str = "/relay/";
+
+pub const RELAY_PROTOCOL_ID: &str = "snocat.relay";
+
+/// Written by the relay once it has tried to reach the requested address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayStatus {
+  /// The stream now continues to the requested service
+  Connected,
+  /// No tunnel matches the requested address
+  RouteNotFound,
+  /// The tunnel's remote refused the request
+  Refused,
+  /// A link to the tunnel could not be opened, or failed during negotiation
+  Unavailable,
+}
+
+impl RelayStatus {
+  fn code(self) -> u8 {
+    match self {
+      RelayStatus::Connected => 0,
+      RelayStatus::RouteNotFound => 1,
+      RelayStatus::Refused => 2,
+      RelayStatus::Unavailable => 3,
+    }
+  }
+
+  fn from_code(code: u8) -> Option<Self> {
+    match code {
+      0 => Some(RelayStatus::Connected),
+      1 => Some(RelayStatus::RouteNotFound),
+      2 => Some(RelayStatus::Refused),
+      3 => Some(RelayStatus::Unavailable),
+      _ => None,
+    }
+  }
+}
+
+/// Requests that a relay connect it onward, then splices the relayed stream to `recv` and `send`
+#[derive(Debug)]
+pub struct RelayClient<Reader, Writer> {
+  recv: Reader,
+  send: Writer,
+}
+
+impl<Reader, Writer> RelayClient<Reader, Writer> {
+  pub fn new(recv: Reader, send: Writer) -> Self {
+    Self { recv, send }
+  }
+}
+
+impl<Reader, Writer> Client for RelayClient<Reader, Writer>
+where
+  Reader: AsyncRead + Send + Unpin + 'static,
+  Writer: AsyncWrite + Send + Unpin + 'static,
+{
+  /// The relay's status; streams are only spliced once [RelayStatus::Connected]
+  type Response = RelayStatus;
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(RELAY_PROTOCOL_ID)
+  }
+
+  fn handle(
+    mut self,
+    _addr: RouteAddress,
+    _version: ProtocolVersion,
+    mut tunnel: Box<dyn TunnelStream + Send + 'static>,
+  ) -> BoxFuture<Result<Self::Response, ClientError>> {
+    async move {
+      let code = tunnel
+        .read_u8()
+        .await
+        .map_err(|_| ClientError::UnexpectedEnd)?;
+      let status = RelayStatus::from_code(code).ok_or(ClientError::IllegalResponse(None))?;
+      if status == RelayStatus::Connected {
+        let (mut tunr, mut tunw) = tokio::io::split(tunnel);
+        // Unlike a proxy, whose streams end however they may, a stream breaking partway
+        // is reported, as the service's output may have been cut short
+        let outward = Box::pin(proxy_tokio_stream(&mut self.recv, &mut tunw));
+        let inward = Box::pin(proxy_tokio_stream(&mut tunr, &mut self.send));
+        if let Err(failure) = futures::future::try_select(outward, inward).await {
+          let (error, _other) = failure.factor_first();
+          tracing::debug!(?error, "Relayed stream broke");
+          return Err(ClientError::UnexpectedEnd);
+        }
+        let _ = self.send.flush().await;
+      }
+      Ok(status)
+    }
+    .boxed()
+  }
+}
+
+/// The stream of the tunnel requesting a relay, taken by whichever of the relay's outcomes occurs first
+type Upstream = Arc<Mutex<Option<Box<dyn TunnelStream + Send + 'static>>>>;
+
+/// Forwards the upstream stream to a TCP service once its downstream request is accepted
+struct RelayedTcpClient {
+  upstream: Upstream,
+  idle_timeout: Option<Duration>,
+}
+
+impl Client for RelayedTcpClient {
+  type Response = ();
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(TCP_PROXY_PROTOCOL_ID)
+  }
+
+  fn handle(
+    self,
+    _addr: RouteAddress,
+    _version: ProtocolVersion,
+    downstream: Box<dyn TunnelStream + Send + 'static>,
+  ) -> BoxFuture<Result<Self::Response, ClientError>> {
+    async move {
+      let mut upstream = self
+        .upstream
+        .lock()
+        .expect("Relay stream lock must not be poisoned")
+        .take()
+        .ok_or(ClientError::UnexpectedEnd)?;
+      upstream
+        .write_u8(RelayStatus::Connected.code())
+        .await
+        .map_err(|_| ClientError::UnexpectedEnd)?;
+      let (mut upr, mut upw) = tokio::io::split(upstream);
+      let (mut downr, mut downw) = tokio::io::split(downstream);
+      let res = proxy_generic_tokio_streams_with_idle_timeout(
+        (&mut upw, &mut upr),
+        (&mut downw, &mut downr),
+        self.idle_timeout,
+      )
+      .await;
+      match res {
+        Ok(_) => tracing::info!(target = "relay_close", "Closing relayed stream"),
+        Err(idle) => tracing::info!(target = "relay_close", %idle, "Closing idle relayed stream"),
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+}
+
+pub struct RelayService {
+  request_client_handler: Weak<RequestClientHandler>,
+  idle_timeout: Option<Duration>,
+}
+
+impl std::fmt::Debug for RelayService {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(RelayService))
+      .field("idle_timeout", &self.idle_timeout)
+      .finish_non_exhaustive()
+  }
+}
+
+impl RelayService {
+  pub fn new(request_client_handler: Weak<RequestClientHandler>) -> Self {
+    Self {
+      request_client_handler,
+      idle_timeout: None,
+    }
+  }
+
+  /// Closes relayed streams once no bytes move in either direction for `timeout`, if one is given
+  pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.idle_timeout = timeout;
+    self
+  }
+
+  /// The address relayed to, keeping its leading slash
+  fn relayed_address(addr: &str) -> Option<&str> {
+    let base = RELAY_ADDRESS_BASE.trim_end_matches('/');
+    addr
+      .strip_prefix(base)
+      .filter(|relayed| relayed.len() > 1 && relayed.starts_with('/'))
+  }
+}
+
+impl Service for RelayService {
+  fn accepts(&self, addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+    Self::relayed_address(addr).is_some()
+  }
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(RELAY_PROTOCOL_ID)
+  }
+
+  fn description(&self) -> Option<String> {
+    Some(String::from(
+      "Relays streams to TCP services reachable through other tunnels",
+    ))
+  }
+
+  fn handle(
+    &'_ self,
+    addr: RouteAddress,
+    _version: ProtocolVersion,
+    stream: Box<dyn TunnelStream + Send + 'static>,
+    tunnel_id: TunnelId,
+  ) -> BoxFuture<'_, Result<(), ServiceError>> {
+    let relayed = match Self::relayed_address(&addr) {
+      Some(relayed) => relayed.to_string(),
+      None => return futures::future::ready(Err(ServiceError::AddressError)).boxed(),
+    };
+    let span = tracing::span!(tracing::Level::DEBUG, "relay", ?tunnel_id, target = ?relayed);
+    let upstream: Upstream = Arc::new(Mutex::new(Some(stream)));
+    let client = RelayedTcpClient {
+      upstream: Arc::clone(&upstream),
+      idle_timeout: self.idle_timeout,
+    };
+    let request_client_handler = self.request_client_handler.clone();
+    async move {
+      let request_client_handler = request_client_handler
+        .upgrade()
+        .ok_or(ServiceError::DependencyFailure)?;
+      let (status, error) = match request_client_handler.handle(relayed, client).await {
+        Ok(()) => return Ok(()),
+        Err(RequestHandlingError::RouteNotFound(_)) => {
+          (RelayStatus::RouteNotFound, ServiceError::Refused)
+        }
+        Err(RequestHandlingError::RouteUnavailable(_)) => {
+          (RelayStatus::Unavailable, ServiceError::DependencyFailure)
+        }
+        Err(RequestHandlingError::NegotiationError(
+          NegotiationError::Refused
+          | NegotiationError::Unauthorized
+          | NegotiationError::UnsupportedServiceVersion,
+          _,
+        )) => (RelayStatus::Refused, ServiceError::Refused),
+        Err(RequestHandlingError::NegotiationError(e, _)) => {
+          tracing::debug!(error = ?e, "Relayed request negotiation failed");
+          (RelayStatus::Unavailable, ServiceError::DependencyFailure)
+        }
+        Err(RequestHandlingError::ProtocolClientError(_)) => {
+          return Err(ServiceError::UnexpectedEnd)
+        }
+      };
+      tracing::info!(?status, "Relay failed to connect");
+      let upstream = upstream
+        .lock()
+        .expect("Relay stream lock must not be poisoned")
+        .take();
+      if let Some(mut upstream) = upstream {
+        let _ = upstream.write_u8(status.code()).await;
+        let _ = upstream.shutdown().await;
+      }
+      Err(error)
+    }
+    .instrument(span)
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::{
+    future::{BoxFuture, FutureExt},
+    StreamExt,
+  };
+  use snocat::{
+    common::{
+      authorization::AllowAllAuthorizer,
+      protocol::{
+        negotiation::NegotiationService,
+        proxy_tcp::TCP_PROXY_PROTOCOL_ID,
+        request_handler::RequestClientHandler,
+        service_registry::{ServiceScope, TrieServiceRegistry},
+        traits::{InMemoryTunnelRegistry, TunnelRegistry},
+        tunnel::{
+          duplex::{channel as duplex, DuplexTunnel, EntangledTunnels},
+          Tunnel, TunnelId, TunnelIncomingType, TunnelName, TunnelUplink,
+        },
+        Client, ClientError, ProtocolVersion, RouteAddress, RoutedLink, Router, RoutingError,
+        Service, ServiceError,
+      },
+    },
+    util::tunnel_stream::{TunnelStream, WrappedStream},
+  };
+  use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+  };
+  use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf};
+
+  use super::{RelayClient, RelayService, RelayStatus};
+
+  /// Echoes each stream, as a TCP service might
+  struct EchoService;
+
+  impl Service for EchoService {
+    fn accepts(&self, _addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+      true
+    }
+
+    fn protocol_id(&self) -> Option<&str> {
+      Some(TCP_PROXY_PROTOCOL_ID)
+    }
+
+    fn handle<'a>(
+      &'a self,
+      _addr: RouteAddress,
+      _version: ProtocolVersion,
+      stream: Box<dyn TunnelStream + Send + 'static>,
+      _tunnel_id: TunnelId,
+    ) -> BoxFuture<'a, Result<(), ServiceError>> {
+      async move {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        tokio::io::copy(&mut reader, &mut writer)
+          .await
+          .map_err(|_| ServiceError::UnexpectedEnd)?;
+        Ok(())
+      }
+      .boxed()
+    }
+  }
+
+  /// Routes `/tunnel/edge/...` through the edge tunnel, without the tunnel's prefix
+  struct EdgeRouter {
+    edge: DuplexTunnel,
+  }
+
+  impl Router for EdgeRouter {
+    fn route(
+      &self,
+      address: &RouteAddress,
+      _tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
+    ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+      let address = address.strip_prefix("/tunnel/edge").map(String::from);
+      async move {
+        let address = address.ok_or(RoutingError::NoMatchingTunnel)?;
+        let link = self
+          .edge
+          .open_link()
+          .await
+          .map_err(RoutingError::LinkOpenFailure)?;
+        Ok((address, Box::new(link) as Box<_>))
+      }
+      .boxed()
+    }
+  }
+
+  /// Answers requests arriving over `edge` with an echo service beneath `/tcp`
+  async fn serve_edge(edge: DuplexTunnel) {
+    let services = Arc::new(TrieServiceRegistry::new());
+    services.register("/tcp", 0, ServiceScope::Global, Arc::new(EchoService));
+    let negotiator = NegotiationService::new(services, Arc::new(AllowAllAuthorizer::new()));
+    let mut downlink = edge.downlink().await.unwrap();
+    let mut incoming = downlink.as_stream();
+    while let Some(Ok(TunnelIncomingType::BiStream(link))) = incoming.next().await {
+      let negotiated = negotiator
+        .negotiate(link, TunnelId::new(2), TunnelName::new("edge"))
+        .await;
+      if let Ok((link, addr, version, service)) = negotiated {
+        tokio::task::spawn(async move {
+          let _ = service
+            .handle(addr, version, Box::new(link), TunnelId::new(2))
+            .await;
+        });
+      }
+    }
+  }
+
+  /// A relay reaching the edge tunnel, served unless `reachable` is false
+  fn relay_to_edge(reachable: bool) -> (RelayService, Arc<RequestClientHandler>) {
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    if reachable {
+      tokio::task::spawn(serve_edge(connector));
+    }
+    let requests = Arc::new(RequestClientHandler::new(
+      Arc::new(InMemoryTunnelRegistry::new()),
+      Arc::new(TrieServiceRegistry::new()),
+      Arc::new(EdgeRouter { edge: listener }),
+    ));
+    (RelayService::new(Arc::downgrade(&requests)), requests)
+  }
+
+  /// Requests `address` of `relay` as `connect` does, reading `input` and writing `output`
+  async fn request(
+    relay: &RelayService,
+    address: &str,
+    input: DuplexStream,
+    output: DuplexStream,
+  ) -> Result<RelayStatus, ClientError> {
+    let (upstream, downstream) = WrappedStream::duplex(4096);
+    let (result, _relayed) = futures::future::join(
+      RelayClient::new(input, output).handle(address.into(), 0, Box::new(downstream)),
+      relay.handle(address.into(), 0, Box::new(upstream), TunnelId::new(1)),
+    )
+    .await;
+    result
+  }
+
+  #[tokio::test]
+  async fn relays_bytes_once_connected() {
+    let (relay, _requests) = relay_to_edge(true);
+    let (input, mut input_writer) = tokio::io::duplex(64);
+    let (output, mut output_reader) = tokio::io::duplex(64);
+    let exchange = async move {
+      input_writer.write_all(b"hello").await.unwrap();
+      let mut echoed = [0u8; 5];
+      output_reader.read_exact(&mut echoed).await.unwrap();
+      assert_eq!(&echoed, b"hello");
+    };
+    let (result, ()) = futures::future::join(
+      request(&relay, "/relay/tunnel/edge/tcp/22", input, output),
+      exchange,
+    )
+    .await;
+    assert_eq!(result.unwrap(), RelayStatus::Connected);
+  }
+
+  #[tokio::test]
+  async fn reports_failures_to_connect() {
+    let (relay, _requests) = relay_to_edge(true);
+    let status = |address: &'static str| {
+      let relay = &relay;
+      async move {
+        let (input, _input_writer) = tokio::io::duplex(64);
+        let (output, _output_reader) = tokio::io::duplex(64);
+        request(relay, address, input, output).await.unwrap()
+      }
+    };
+    assert_eq!(
+      status("/relay/tunnel/core/tcp/22").await,
+      RelayStatus::RouteNotFound
+    );
+    // The edge offers nothing beneath `/udp`
+    assert_eq!(
+      status("/relay/tunnel/edge/udp/53").await,
+      RelayStatus::Refused
+    );
+
+    let (relay, _requests) = relay_to_edge(false);
+    let (input, _input_writer) = tokio::io::duplex(64);
+    let (output, _output_reader) = tokio::io::duplex(64);
+    assert_eq!(
+      request(&relay, "/relay/tunnel/edge/tcp/22", input, output)
+        .await
+        .unwrap(),
+      RelayStatus::Unavailable
+    );
+  }
+
+  #[tokio::test]
+  async fn streams_ending_before_a_status_are_unexpected() {
+    let (upstream, downstream) = WrappedStream::duplex(64);
+    drop(upstream);
+    let (input, _input_writer) = tokio::io::duplex(64);
+    let (output, _output_reader) = tokio::io::duplex(64);
+    let result = RelayClient::new(input, output)
+      .handle("/relay/tunnel/edge/tcp/22".into(), 0, Box::new(downstream))
+      .await;
+    assert!(matches!(result, Err(ClientError::UnexpectedEnd)));
+  }
+
+  /// Reads its bytes, then fails as a reset stream would
+  struct BreaksAfter(Vec<u8>);
+
+  impl AsyncRead for BreaksAfter {
+    fn poll_read(
+      mut self: Pin<&mut Self>,
+      _cx: &mut Context<'_>,
+      buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+      if self.0.is_empty() {
+        return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+      }
+      let length = self.0.len().min(buf.remaining());
+      buf.put_slice(&self.0[..length]);
+      self.0.drain(..length);
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  #[tokio::test]
+  async fn streams_breaking_once_connected_are_unexpected() {
+    let connected_then_partial = vec![RelayStatus::Connected.code(), b'h', b'i'];
+    let downstream = WrappedStream::Boxed(
+      Box::new(BreaksAfter(connected_then_partial)),
+      Box::new(tokio::io::sink()),
+    );
+    let (input, _input_writer) = tokio::io::duplex(64);
+    let (output, mut output_reader) = tokio::io::duplex(64);
+    let result = RelayClient::new(input, output)
+      .handle("/relay/tunnel/edge/tcp/22".into(), 0, Box::new(downstream))
+      .await;
+    assert!(matches!(result, Err(ClientError::UnexpectedEnd)));
+    let mut partial = [0u8; 2];
+    output_reader.read_exact(&mut partial).await.unwrap();
+    assert_eq!(&partial, b"hi");
+  }
+}
@@ -0,0 +1,66 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+use anyhow::{Context as AnyhowContext, Result};
+use snocat::common::authentication::{TokenClaims, TokenKeyEntry, TokenKeyFile, TokenSigner};
+use std::{
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+/// Parameters for minting a bearer token
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct IssueArgs {
+  pub key_file: PathBuf,
+  pub key_id: Option<String>,
+  pub tunnel_name: String,
+  pub audience: String,
+  pub lifetime: Duration,
+  pub labels: Vec<(String, String)>,
+  pub services: Vec<String>,
+}
+
+/// Prints a token signed by the chosen key
+pub fn issue_main(args: IssueArgs) -> Result<()> {
+  let signer = TokenSigner::from_key_file(&args.key_file, args.key_id.as_deref())?;
+  let mut claims = TokenClaims::new(
+    args.tunnel_name,
+    args.audience,
+    SystemTime::now(),
+    args.lifetime,
+  );
+  claims.labels.extend(args.labels);
+  claims.services = args.services;
+  println!("{}", signer.issue(&claims)?);
+  Ok(())
+}
+
+/// Writes an Ed25519 key pair to `<base>.priv.json`, and its public half to `<base>.pub.json`
+pub fn keygen_main(output_base_path: &Path, key_id: String) -> Result<()> {
+  let entry = TokenKeyEntry::generate_ed25519(key_id)?;
+  let with_suffix = |suffix: &str| {
+    let mut name = output_base_path
+      .file_name()
+      .context("Key path must name a file")?
+      .to_os_string();
+    name.push(suffix);
+    Ok::<_, anyhow::Error>(output_base_path.with_file_name(name))
+  };
+  TokenKeyFile {
+    keys: entry.to_public().into_iter().collect(),
+  }
+  .save(&with_suffix(".pub.json")?)?;
+  TokenKeyFile { keys: vec![entry] }.save_private(&with_suffix(".priv.json")?)?;
+  Ok(())
+}
+
+pub fn parse_label(v: &str) -> Result<(String, String)> {
+  let mut parts = v.splitn(2, '=');
+  match (parts.next(), parts.next()) {
+    (Some(key), Some(value)) if !key.is_empty() => Ok((key.into(), value.into())),
+    _ => anyhow::bail!("Labels must be of the form key=value"),
+  }
+}
+
+pub fn validate_label(v: String) -> Result<(), String> {
+  parse_label(&v).map(|_| ()).map_err(|e| e.to_string())
+}
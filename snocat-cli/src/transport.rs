@@ -0,0 +1,83 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+use anyhow::{Context as AnyhowContext, Result};
+use quinn::TransportConfig;
+use std::time::Duration;
+
+use crate::config::TransportSection;
+
+/// QUIC transport parameters; those unset are left at Quinn's defaults
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct TransportArgs {
+  pub receive_window: Option<u64>,
+  pub send_window: Option<u64>,
+  pub stream_receive_window: Option<u64>,
+  /// Interval between keepalive packets; zero disables keepalives
+  pub keep_alive_interval: Option<Duration>,
+  /// Time without packets after which a connection is lost; zero disables the timeout
+  pub idle_timeout: Option<Duration>,
+  pub migration: Option<bool>,
+  pub stateless_retry: Option<bool>,
+}
+
+impl TransportArgs {
+  /// The parameters which the server has always used
+  pub fn server_defaults() -> Self {
+    Self {
+      receive_window: Some(512 * 1024 * 1024),
+      send_window: Some(512 * 1024 * 1024),
+      stream_receive_window: Some(512 * 1024 * 1024 / 8),
+      keep_alive_interval: Some(Duration::from_secs(5)),
+      idle_timeout: Some(Duration::from_secs(30)),
+      migration: Some(true),
+      stateless_retry: Some(true),
+    }
+  }
+
+  /// Overrides these parameters with those set by a config file
+  pub fn with_section(self, section: &TransportSection) -> Self {
+    Self {
+      receive_window: section.receive_window.or(self.receive_window),
+      send_window: section.send_window.or(self.send_window),
+      stream_receive_window: section.stream_receive_window.or(self.stream_receive_window),
+      keep_alive_interval: section
+        .keep_alive_interval
+        .map(Duration::from_secs)
+        .or(self.keep_alive_interval),
+      idle_timeout: section
+        .idle_timeout
+        .map(Duration::from_secs)
+        .or(self.idle_timeout),
+      migration: section.migration.or(self.migration),
+      stateless_retry: section.stateless_retry.or(self.stateless_retry),
+    }
+  }
+}
+
+/// Builds a Quinn transport config, naming the config key of any out-of-bounds parameter
+pub fn build_transport_config(args: &TransportArgs) -> Result<TransportConfig> {
+  let nonzero = |duration: Duration| Some(duration).filter(|duration| !duration.is_zero());
+  let mut transport_config = TransportConfig::default();
+  if let Some(receive_window) = args.receive_window {
+    transport_config
+      .receive_window(receive_window)
+      .context("Invalid `transport.receive_window`")?;
+  }
+  if let Some(send_window) = args.send_window {
+    transport_config.send_window(send_window);
+  }
+  if let Some(stream_receive_window) = args.stream_receive_window {
+    transport_config
+      .stream_receive_window(stream_receive_window)
+      .context("Invalid `transport.stream_receive_window`")?;
+  }
+  if let Some(keep_alive_interval) = args.keep_alive_interval {
+    transport_config.keep_alive_interval(nonzero(keep_alive_interval));
+  }
+  if let Some(idle_timeout) = args.idle_timeout {
+    transport_config
+      .max_idle_timeout(nonzero(idle_timeout))
+      .context("Invalid `transport.idle_timeout`")?;
+  }
+  Ok(transport_config)
+}
@@ -0,0 +1,171 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+
+#ifndef SNOCAT_H
+#define SNOCAT_H
+
+// Generated by cbindgen from src/ffi; do not edit by hand
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef struct ExternError { int32_t code; char *message; } ExternError;
+typedef struct ByteBuffer { int64_t len; uint8_t *data; } ByteBuffer;
+typedef const char *FfiStr;
+
+/**
+ * An argument was null, malformed, or otherwise unusable
+ */
+#define SNOCAT_ERROR_INVALID_ARGUMENT 1
+
+/**
+ * The call is not valid in the current state of its target
+ */
+#define SNOCAT_ERROR_INVALID_STATE 2
+
+/**
+ * Files could not be loaded, or sockets bound
+ */
+#define SNOCAT_ERROR_IO 3
+
+typedef enum SnocatTunnelSide {
+  SnocatTunnelSide_Connect,
+  SnocatTunnelSide_Listen,
+} SnocatTunnelSide;
+
+/**
+ * Receives an encoded `ffi.proto` message, to be released with [snocat_bytebuffer_free]
+ *
+ * Calls given a null callback fail with [SNOCAT_ERROR_INVALID_ARGUMENT].
+ */
+typedef void (*SnocatMessageCallback)(void *context, ByteBuffer message);
+
+/**
+ * Begins authenticating a tunnel; `remote_address` is null if unknown, and valid only for the call
+ */
+typedef void (*SnocatBeginSessionCallback)(void *context, uint64_t session, enum SnocatTunnelSide side, const char *remote_address);
+
+/**
+ * Host callbacks authenticating the tunnels of a daemon
+ */
+typedef struct SnocatAuthenticator {
+  /**
+   * Passed to each callback
+   */
+  void *context;
+  /**
+   * Must not be null
+   */
+  SnocatBeginSessionCallback begin_session;
+  /**
+   * Called once the daemon no longer needs `context`; may be null
+   */
+  void (*release)(void *context);
+} SnocatAuthenticator;
+
+/**
+ * Releases an error message returned by this library
+ *
+ * # Safety
+ * `message` must have been returned by this library and not yet released, or be null.
+ */
+void snocat_string_free(char *message);
+
+/**
+ * Releases a message passed to a callback by this library
+ */
+void snocat_bytebuffer_free(ByteBuffer message);
+
+/**
+ * Reads up to `max_length` bytes from an authenticator session
+ *
+ * `callback` receives an `AuthenticatorSessionReadResult`, whose buffer is empty once the
+ * remote has finished writing.
+ */
+void snocat_authenticator_session_read(uint64_t session,
+                                       uint32_t max_length,
+                                       SnocatMessageCallback callback,
+                                       void *context,
+                                       ExternError *error);
+
+/**
+ * Writes `length` bytes from `data` to an authenticator session
+ *
+ * The bytes are copied before returning. `callback` receives an
+ * `AuthenticatorSessionWriteResult` once they have been flushed.
+ *
+ * # Safety
+ * `data` must point to `length` readable bytes, or may be null if `length` is zero.
+ */
+void snocat_authenticator_session_write(uint64_t session,
+                                        const uint8_t *data,
+                                        uintptr_t length,
+                                        SnocatMessageCallback callback,
+                                        void *context,
+                                        ExternError *error);
+
+/**
+ * Completes an authenticator session with an encoded `AuthenticatorSessionResult`
+ *
+ * An acceptance names the tunnel by its connection ID. The session handle is invalidated,
+ * but reads and writes already in progress still report their results.
+ *
+ * # Safety
+ * `result` must point to `length` readable bytes, or may be null if `length` is zero.
+ */
+void snocat_authenticator_session_complete(uint64_t session,
+                                           const uint8_t *result,
+                                           uintptr_t length,
+                                           ExternError *error);
+
+/**
+ * Creates a daemon whose tunnels are authenticated by `authenticator`
+ *
+ * The authenticator is released when the daemon is destroyed, or immediately if creation fails,
+ * as it does if `begin_session` is null.
+ *
+ * # Safety
+ * The authenticator's callbacks must remain callable from any thread until it is released.
+ */
+uint64_t snocat_daemon_create(struct SnocatAuthenticator authenticator, ExternError *error);
+
+/**
+ * Starts accepting tunnels on `listen_address`, returning the port bound
+ *
+ * The certificate chain and private key are read from PEM files. A daemon may only be started once.
+ */
+uint16_t snocat_daemon_start(uint64_t daemon,
+                             FfiStr listen_address,
+                             FfiStr certificate_path,
+                             FfiStr private_key_path,
+                             ExternError *error);
+
+/**
+ * Delivers an encoded `TunnelEvent` to `callback` as each tunnel connects, authenticates,
+ * and disconnects, until the daemon is destroyed
+ */
+void snocat_daemon_subscribe(uint64_t daemon,
+                             SnocatMessageCallback callback,
+                             void *context,
+                             ExternError *error);
+
+/**
+ * Stops accepting tunnels, and blocks until those established have closed
+ *
+ * Tunnels with requests still in progress are closed after 30 seconds, and any tunnels
+ * remaining are closed after 45 seconds.
+ *
+ * Must not be called from a callback.
+ */
+void snocat_daemon_shutdown(uint64_t daemon, ExternError *error);
+
+/**
+ * Shuts down and releases a daemon, invalidating its handle
+ *
+ * Must not be called from a callback.
+ */
+void snocat_daemon_destroy(uint64_t daemon, ExternError *error);
+
+#endif /* SNOCAT_H */
@@ -0,0 +1,441 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Authentication by the client certificate presented during the transport's TLS handshake
+//!
+//! The transport has already verified the certificate against its trusted authorities, so
+//! the listening side names the tunnel after the subject common name of the remote's leaf
+//! certificate, and tells the connecting side the outcome:
+//!
+//! 1. Listener sends `MAGIC`, a protocol version, and a status.
+//! 2. If accepted, listener sends the tunnel name, prefixed by its length as a `u16`.
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{revocation::Revocations, traits::*};
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+const MAGIC: &[u8; 4] = b"SNCT";
+const PROTOCOL_VERSION: u8 = 1;
+/// Conventional name of this method when negotiated by name
+pub const CERTIFICATE_AUTHENTICATION_METHOD: &str = "certificate";
+/// Tunnel names are sent behind a `u16` length, so longer subjects are refused
+const MAXIMUM_NAME_LENGTH: usize = u16::MAX as usize;
+
+const STATUS_ACCEPTED: u8 = 0;
+const STATUS_REFUSED: u8 = 1;
+
+/// The ASN.1 object identifier of an X.520 common name attribute
+const COMMON_NAME_OID: &[u64] = &[2, 5, 4, 3];
+
+/// The value of a subject attribute, as read from a certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttributeValue {
+  Utf8(String),
+  Printable(String),
+  Ia5(String),
+  /// A value of any other type, as DER
+  Other(Vec<u8>),
+}
+
+impl AttributeValue {
+  /// The value as a string, if it is one of the string types read
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      AttributeValue::Utf8(value)
+      | AttributeValue::Printable(value)
+      | AttributeValue::Ia5(value) => Some(value),
+      AttributeValue::Other(_) => None,
+    }
+  }
+}
+
+/// The fields of a DER certificate which snocat reads
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedCertificate {
+  /// The serial number, in lowercase hexadecimal
+  pub serial: String,
+  /// Subject attributes by object identifier, in the order they appear
+  pub subject: Vec<(Vec<u64>, AttributeValue)>,
+  /// The DER-encoded subject public key info
+  pub public_key_info: Vec<u8>,
+}
+
+impl ParsedCertificate {
+  /// The subject common name, if there is exactly one
+  ///
+  /// Common names are accepted as UTF-8, printable, or IA5 strings.
+  pub fn common_name(&self) -> Option<&str> {
+    let mut names = self
+      .subject
+      .iter()
+      .filter(|(kind, _)| kind.as_slice() == COMMON_NAME_OID)
+      .filter_map(|(_, value)| value.as_str());
+    match (names.next(), names.next()) {
+      (Some(name), None) => Some(name),
+      _ => None,
+    }
+  }
+}
+
+/// The subject common name of a DER certificate, if it has exactly one
+///
+/// Common names are accepted as UTF-8, printable, or IA5 strings.
+pub fn certificate_common_name(der: &[u8]) -> Option<String> {
+  parse_certificate(der)
+    .ok()?
+    .common_name()
+    .map(str::to_string)
+}
+
+/// The serial number of a DER certificate, in lowercase hexadecimal
+pub fn certificate_serial(der: &[u8]) -> Option<String> {
+  parse_certificate(der)
+    .ok()
+    .map(|certificate| certificate.serial)
+}
+
+/// Reads the serial number, subject, and public key info of a DER certificate
+pub fn parse_certificate(der: &[u8]) -> yasna::ASN1Result<ParsedCertificate> {
+  use yasna::tags::{TAG_IA5STRING, TAG_PRINTABLESTRING, TAG_UTF8STRING};
+  yasna::parse_der(der, |reader| {
+    reader.read_sequence(|reader| {
+      let tbs_certificate = reader.next().read_sequence(|reader| {
+        let _version = reader.read_optional(|reader| {
+          reader.read_tagged(yasna::Tag::context(0), |reader| reader.read_der())
+        })?;
+        let serial = reader.next().read_tagged_der()?;
+        let serial = serial
+          .value()
+          .iter()
+          .map(|byte| format!("{:02x}", byte))
+          .collect::<String>();
+        // Signature algorithm, issuer, and validity
+        for _ in 0..3 {
+          reader.next().read_der()?;
+        }
+        let mut subject = Vec::new();
+        reader.next().read_sequence_of(|reader| {
+          reader.read_set_of(|reader| {
+            reader.read_sequence(|reader| {
+              let kind = reader.next().read_oid()?;
+              let value = reader.next();
+              let value = match value.lookahead_tag()? {
+                TAG_UTF8STRING => AttributeValue::Utf8(value.read_utf8string()?),
+                TAG_PRINTABLESTRING => AttributeValue::Printable(value.read_printable_string()?),
+                TAG_IA5STRING => AttributeValue::Ia5(value.read_ia5_string()?),
+                _ => AttributeValue::Other(value.read_der()?),
+              };
+              subject.push((kind.components().clone(), value));
+              Ok(())
+            })
+          })
+        })?;
+        let public_key_info = reader.next().read_der()?;
+        // Unique identifiers and extensions
+        while reader.read_optional(|reader| reader.read_der())?.is_some() {}
+        Ok(ParsedCertificate {
+          serial,
+          subject,
+          public_key_info,
+        })
+      })?;
+      let _signature_algorithm = reader.next().read_der()?;
+      let _signature = reader.next().read_der()?;
+      Ok(tbs_certificate)
+    })
+  })
+}
+
+/// Names tunnels after the subject of the certificate their remote presented to the transport
+///
+/// Transports must verify presented certificates against a trusted authority, as this
+/// handler reads but does not verify them.
+#[derive(Debug, Default)]
+pub struct CertificateAuthenticationHandler {
+  revocations: Option<Arc<Revocations>>,
+}
+
+impl CertificateAuthenticationHandler {
+  pub fn new() -> Self {
+    Self { revocations: None }
+  }
+
+  /// Refuses certificates whose serial numbers appear in `revocations`
+  pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+    self.revocations = Some(revocations);
+    self
+  }
+
+  /// The tunnel name of a verified leaf certificate, unless it is unnamed or revoked
+  fn name_of(&self, leaf: &[u8]) -> Result<String, RemoteAuthenticationError> {
+    let certificate = parse_certificate(leaf).map_err(|_| {
+      tracing::debug!("Refusing unreadable client certificate");
+      RemoteAuthenticationError::Refused
+    })?;
+    let serial = &certificate.serial;
+    let name = match certificate.common_name() {
+      Some(name) if !name.is_empty() && name.len() <= MAXIMUM_NAME_LENGTH => name.to_string(),
+      _ => {
+        tracing::debug!("Refusing client certificate without a single common name");
+        return Err(RemoteAuthenticationError::Refused);
+      }
+    };
+    if self.revocations.as_ref().map_or(false, |revocations| {
+      revocations.is_certificate_revoked(serial)
+    }) {
+      tracing::debug!(
+        serial = serial.as_str(),
+        "Refusing revoked client certificate"
+      );
+      return Err(RemoteAuthenticationError::Revoked);
+    }
+    Ok(name)
+  }
+
+  fn authenticate_listen_side<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin + 'a>,
+    peer_certificates: Option<Vec<Vec<u8>>>,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let name = match peer_certificates.as_ref().and_then(|chain| chain.first()) {
+        Some(leaf) => self.name_of(leaf),
+        None => {
+          tracing::debug!("Refusing remote without a client certificate");
+          Err(RemoteAuthenticationError::Refused)
+        }
+      };
+
+      let mut reply = Vec::with_capacity(MAGIC.len() + 4);
+      reply.extend_from_slice(MAGIC);
+      reply.push(PROTOCOL_VERSION);
+      match &name {
+        Ok(name) => {
+          reply.push(STATUS_ACCEPTED);
+          reply.extend_from_slice(&(name.len() as u16).to_be_bytes());
+          reply.extend_from_slice(name.as_bytes());
+        }
+        Err(_) => reply.push(STATUS_REFUSED),
+      }
+      channel
+        .write_all(&reply)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+
+      Ok(TunnelName::new(name?))
+    }
+    .boxed()
+  }
+
+  fn authenticate_connecting_side<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin + 'a>,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let mut hello = [0u8; 6];
+      channel
+        .read_exact(&mut hello)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      if &hello[..4] != MAGIC {
+        return Err(violation("Invalid hello").into());
+      }
+      if hello[4] != PROTOCOL_VERSION {
+        return Err(violation("Unsupported protocol version").into());
+      }
+      match hello[5] {
+        STATUS_ACCEPTED => (),
+        STATUS_REFUSED => return Err(RemoteAuthenticationError::Refused.into()),
+        _ => return Err(violation("Invalid status").into()),
+      }
+
+      let length = channel
+        .read_u16()
+        .await
+        .map_err(|_| violation("Read unavailable"))? as usize;
+      let mut name = vec![0u8; length];
+      channel
+        .read_exact(&mut name)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      let name = String::from_utf8(name).map_err(|_| violation("Invalid tunnel name"))?;
+      Ok(TunnelName::new(name))
+    }
+    .boxed()
+  }
+}
+
+fn violation(reason: &str) -> RemoteAuthenticationError {
+  RemoteAuthenticationError::ProtocolViolation(reason.into())
+}
+
+impl AuthenticationHandler for CertificateAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    _shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    match tunnel_info.side {
+      TunnelSide::Listen => self.authenticate_listen_side(channel, tunnel_info.peer_certificates),
+      TunnelSide::Connect => self.authenticate_connecting_side(channel),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::{
+    certificate_common_name, certificate_serial, parse_certificate, AttributeValue,
+    CertificateAuthenticationHandler,
+  };
+  use crate::{
+    common::{
+      authentication::{
+        perform_authentication, AuthenticationError, RemoteAuthenticationError, RevocationList,
+        Revocations,
+      },
+      protocol::tunnel::{
+        duplex::{channel as duplex, EntangledTunnels},
+        TunnelName,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  fn certificate(common_name: &str) -> Vec<u8> {
+    let mut params = rcgen::CertificateParams::new(vec!["edge.example".into()]);
+    params.serial_number = Some(0x0a1b2c);
+    params
+      .distinguished_name
+      .push(rcgen::DnType::CommonName, common_name);
+    rcgen::Certificate::from_params(params)
+      .unwrap()
+      .serialize_der()
+      .unwrap()
+  }
+
+  /// Authenticates both sides of a duplex tunnel, as if the connector presented `chain`
+  async fn authenticate(
+    handler: CertificateAuthenticationHandler,
+    chain: Option<Vec<Vec<u8>>>,
+  ) -> (
+    Result<TunnelName, AuthenticationError>,
+    Result<TunnelName, AuthenticationError>,
+  ) {
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    let listener = match chain {
+      Some(chain) => listener.with_peer_certificates(chain),
+      None => listener,
+    };
+    let never_shutdown = CancellationListener::default();
+    futures::future::join(
+      perform_authentication(&handler, &listener, &never_shutdown),
+      perform_authentication(&handler, &connector, &never_shutdown),
+    )
+    .await
+  }
+
+  #[test]
+  fn common_names_are_read_from_subjects() {
+    assert_eq!(
+      certificate_common_name(&certificate("edge-7")),
+      Some(String::from("edge-7"))
+    );
+    assert_eq!(certificate_common_name(b"not a certificate"), None);
+    assert_eq!(
+      certificate_serial(&certificate("edge-7")),
+      Some(String::from("0a1b2c"))
+    );
+  }
+
+  #[test]
+  fn subjects_and_public_keys_are_read() {
+    let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let public_key = key_pair.public_key_der();
+    let mut params = rcgen::CertificateParams::new(vec!["edge.example".into()]);
+    params.key_pair = Some(key_pair);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+      .distinguished_name
+      .push(rcgen::DnType::OrganizationName, "Example");
+    params
+      .distinguished_name
+      .push(rcgen::DnType::CommonName, "edge-7");
+    let cert = rcgen::Certificate::from_params(params).unwrap();
+    let parsed = parse_certificate(&cert.serialize_der().unwrap()).unwrap();
+    assert_eq!(
+      parsed.subject,
+      vec![
+        (vec![2, 5, 4, 10], AttributeValue::Utf8("Example".into())),
+        (vec![2, 5, 4, 3], AttributeValue::Utf8("edge-7".into())),
+      ]
+    );
+    assert_eq!(parsed.public_key_info, public_key);
+  }
+
+  #[tokio::test]
+  async fn tunnels_are_named_by_their_certificate() {
+    let (server_res, client_res) = authenticate(
+      CertificateAuthenticationHandler::new(),
+      Some(vec![certificate("edge-7")]),
+    )
+    .await;
+    assert_eq!(server_res.unwrap(), TunnelName::new("edge-7"));
+    assert_eq!(client_res.unwrap(), TunnelName::new("edge-7"));
+  }
+
+  #[tokio::test]
+  async fn remotes_without_certificates_are_refused() {
+    let (server_res, client_res) =
+      authenticate(CertificateAuthenticationHandler::new(), None).await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+    assert!(matches!(
+      client_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+
+  #[tokio::test]
+  async fn revoked_certificates_are_refused() {
+    let mut list = RevocationList::default();
+    list.certificate_serials.insert("0A:1B:2C".into());
+    let handler =
+      CertificateAuthenticationHandler::new().with_revocations(Arc::new(Revocations::new(list)));
+    let (server_res, client_res) = authenticate(handler, Some(vec![certificate("edge-7")])).await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Revoked
+      ))
+    ));
+    assert!(matches!(
+      client_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+}
@@ -0,0 +1,544 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Mutual challenge-response authentication over a pre-shared key
+//!
+//! The listening side, which opens the authentication channel, speaks first:
+//!
+//! 1. Listener sends `MAGIC`, a protocol version, and a fresh nonce.
+//! 2. Connector replies with a key ID, its own fresh nonce, and an HMAC over both nonces.
+//! 3. Listener replies with a status and, if accepted, its own HMAC over both nonces.
+//!
+//! Each proof covers a nonce freshly generated by the verifying side, so recorded
+//! proofs cannot be replayed, and proofs are labelled by direction so that they
+//! cannot be reflected back at their sender.
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use ring::{
+  hmac,
+  rand::{SecureRandom, SystemRandom},
+};
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{revocation::Revocations, traits::*};
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+const MAGIC: &[u8; 4] = b"SNHM";
+const PROTOCOL_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 32; // HMAC-SHA256
+const LISTENER_LABEL: &[u8] = b"snocat-hmac-v1/listener";
+const CONNECTOR_LABEL: &[u8] = b"snocat-hmac-v1/connector";
+/// Conventional name of this method when negotiated by name
+pub const HMAC_AUTHENTICATION_METHOD: &str = "hmac-sha256";
+/// Pre-shared secrets shorter than this are rejected
+pub const MINIMUM_SECRET_LENGTH: usize = 16;
+/// Key IDs are sent behind a single length byte, so longer IDs are rejected
+pub const MAXIMUM_KEY_ID_LENGTH: usize = u8::MAX as usize;
+
+const STATUS_ACCEPTED: u8 = 0;
+const STATUS_REFUSED: u8 = 1;
+
+type Nonce = [u8; NONCE_LENGTH];
+
+/// A pre-shared secret, its public identifier, and the tunnel name it authenticates as
+#[derive(Clone)]
+pub struct PresharedKey {
+  id: String,
+  key: hmac::Key,
+  tunnel_name: TunnelName,
+}
+
+impl PresharedKey {
+  /// Fails if `id` cannot be framed in the handshake, or if `secret` is too short
+  pub fn new(id: String, secret: &[u8], tunnel_name: TunnelName) -> anyhow::Result<Self> {
+    if id.len() > MAXIMUM_KEY_ID_LENGTH {
+      anyhow::bail!(
+        "Key ID {:?} must be at most {} bytes",
+        id,
+        MAXIMUM_KEY_ID_LENGTH
+      );
+    }
+    if secret.len() < MINIMUM_SECRET_LENGTH {
+      anyhow::bail!(
+        "Secret for key {:?} must be at least {} bytes",
+        id,
+        MINIMUM_SECRET_LENGTH
+      );
+    }
+    Ok(Self {
+      id,
+      key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+      tunnel_name,
+    })
+  }
+
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn tunnel_name(&self) -> &TunnelName {
+    &self.tunnel_name
+  }
+
+  /// Proof that the `label`led side holds this key, given both sides' nonces
+  fn sign(&self, label: &[u8], listener_nonce: &Nonce, connector_nonce: &Nonce) -> hmac::Tag {
+    let mut context = hmac::Context::with_key(&self.key);
+    context.update(label);
+    context.update(listener_nonce);
+    context.update(connector_nonce);
+    context.update(self.id.as_bytes());
+    context.sign()
+  }
+
+  /// Verifies a proof in constant time
+  fn verify(
+    &self,
+    label: &[u8],
+    listener_nonce: &Nonce,
+    connector_nonce: &Nonce,
+    tag: &[u8],
+  ) -> bool {
+    let mut message = Vec::with_capacity(label.len() + 2 * NONCE_LENGTH + self.id.len());
+    message.extend_from_slice(label);
+    message.extend_from_slice(listener_nonce);
+    message.extend_from_slice(connector_nonce);
+    message.extend_from_slice(self.id.as_bytes());
+    hmac::verify(&self.key, &message, tag).is_ok()
+  }
+}
+
+impl std::fmt::Debug for PresharedKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(PresharedKey))
+      .field("id", &self.id)
+      .field("tunnel_name", &self.tunnel_name)
+      .finish_non_exhaustive()
+  }
+}
+
+/// On-disk representation of a set of pre-shared keys, stored as JSON
+///
+/// ```json
+/// { "identity": "edge-1", "keys": [{ "id": "edge-1", "secret": "<base64>", "tunnel_name": "edge" }] }
+/// ```
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PresharedKeyFile {
+  /// The key ID presented when connecting; may be omitted if only one key is present
+  #[serde(default)]
+  pub identity: Option<String>,
+  pub keys: Vec<PresharedKeyEntry>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PresharedKeyEntry {
+  pub id: String,
+  /// Base64-encoded secret
+  pub secret: String,
+  /// The name given to tunnels authenticated by this key; defaults to the key ID
+  #[serde(default)]
+  pub tunnel_name: Option<String>,
+}
+
+impl PresharedKeyFile {
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    use anyhow::Context;
+    let contents =
+      std::fs::read(path).with_context(|| format!("Failed reading key file {}", path.display()))?;
+    serde_json::from_slice(&contents)
+      .with_context(|| format!("Failed parsing key file {}", path.display()))
+  }
+
+  pub fn into_keys(self) -> anyhow::Result<(Option<String>, Vec<PresharedKey>)> {
+    let keys = self
+      .keys
+      .into_iter()
+      .map(|entry| {
+        let secret = base64::decode(&entry.secret).map_err(|e| {
+          anyhow::anyhow!("Secret for key {:?} is not valid base64: {}", entry.id, e)
+        })?;
+        let PresharedKeyEntry {
+          id, tunnel_name, ..
+        } = entry;
+        let tunnel_name = TunnelName::new(tunnel_name.unwrap_or_else(|| id.clone()));
+        PresharedKey::new(id, &secret, tunnel_name)
+      })
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok((self.identity, keys))
+  }
+}
+
+/// Authenticates peers which prove knowledge of a shared secret, while proving the same to them
+///
+/// Authenticated tunnels are named by the [PresharedKey] they used.
+pub struct HmacAuthenticationHandler {
+  keys: HashMap<String, PresharedKey>,
+  identity: Option<String>,
+  random: SystemRandom,
+  revocations: Option<Arc<Revocations>>,
+}
+
+impl HmacAuthenticationHandler {
+  /// Creates a handler accepting any of `keys`, presenting `identity` when connecting
+  ///
+  /// If no identity is given, connecting requires that exactly one key is known.
+  pub fn new(keys: Vec<PresharedKey>, identity: Option<String>) -> Self {
+    Self {
+      keys: keys.into_iter().map(|key| (key.id.clone(), key)).collect(),
+      identity,
+      random: SystemRandom::new(),
+      revocations: None,
+    }
+  }
+
+  /// Refuses connecting peers which prove a key whose ID is revoked
+  pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+    self.revocations = Some(revocations);
+    self
+  }
+
+  pub fn from_key_file(path: &Path) -> anyhow::Result<Self> {
+    let (identity, keys) = PresharedKeyFile::load(path)?.into_keys()?;
+    if let Some(identity) = &identity {
+      if !keys.iter().any(|key| key.id() == identity) {
+        anyhow::bail!(
+          "Identity {:?} does not name a key in the key file",
+          identity
+        );
+      }
+    }
+    Ok(Self::new(keys, identity))
+  }
+
+  fn identity_key(&self) -> Result<&PresharedKey, AuthenticationHandlingError> {
+    match &self.identity {
+      Some(identity) => self.keys.get(identity),
+      None if self.keys.len() == 1 => self.keys.values().next(),
+      None => None,
+    }
+    .ok_or_else(|| {
+      AuthenticationHandlingError::DependencyFailure(
+        String::from("identity"),
+        anyhow::Error::msg("No single identity key is configured for connecting"),
+      )
+    })
+  }
+
+  fn generate_nonce(&self) -> Result<Nonce, AuthenticationHandlingError> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    self.random.fill(&mut nonce).map_err(|_| {
+      AuthenticationHandlingError::DependencyFailure(
+        String::from("random"),
+        anyhow::Error::msg("System random number generator failed"),
+      )
+    })?;
+    Ok(nonce)
+  }
+
+  fn authenticate_listen_side<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin + 'a>,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let listener_nonce = self.generate_nonce()?;
+      let mut challenge = Vec::with_capacity(MAGIC.len() + 1 + NONCE_LENGTH);
+      challenge.extend_from_slice(MAGIC);
+      challenge.push(PROTOCOL_VERSION);
+      challenge.extend_from_slice(&listener_nonce);
+      channel
+        .write_all(&challenge)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+
+      let key_id = read_key_id(&mut channel).await?;
+      let mut connector_nonce = [0u8; NONCE_LENGTH];
+      let mut connector_tag = [0u8; TAG_LENGTH];
+      channel
+        .read_exact(&mut connector_nonce)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      channel
+        .read_exact(&mut connector_tag)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+
+      let key = match self.keys.get(&key_id) {
+        Some(key)
+          if connector_nonce != listener_nonce
+            && key.verify(
+              CONNECTOR_LABEL,
+              &listener_nonce,
+              &connector_nonce,
+              &connector_tag,
+            ) =>
+        {
+          key
+        }
+        _ => {
+          tracing::debug!(key_id = key_id.as_str(), "Refusing unproven key");
+          // Best-effort notification; the remote is refused regardless
+          let _ = channel.write_u8(STATUS_REFUSED).await;
+          let _ = channel.flush().await;
+          return Err(RemoteAuthenticationError::Refused.into());
+        }
+      };
+      if self
+        .revocations
+        .as_ref()
+        .map_or(false, |revocations| revocations.is_key_revoked(&key_id))
+      {
+        tracing::debug!(key_id = key_id.as_str(), "Refusing revoked key");
+        let _ = channel.write_u8(STATUS_REFUSED).await;
+        let _ = channel.flush().await;
+        return Err(RemoteAuthenticationError::Revoked.into());
+      }
+
+      let listener_tag = key.sign(LISTENER_LABEL, &listener_nonce, &connector_nonce);
+      let mut verdict = Vec::with_capacity(1 + TAG_LENGTH);
+      verdict.push(STATUS_ACCEPTED);
+      verdict.extend_from_slice(listener_tag.as_ref());
+      channel
+        .write_all(&verdict)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      Ok(key.tunnel_name().clone())
+    }
+    .boxed()
+  }
+
+  fn authenticate_connecting_side<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin + 'a>,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let key = self.identity_key()?;
+
+      let mut header = [0u8; 5];
+      channel
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      if &header[..4] != MAGIC {
+        return Err(violation("Invalid challenge header").into());
+      }
+      if header[4] != PROTOCOL_VERSION {
+        return Err(violation("Unsupported protocol version").into());
+      }
+      let mut listener_nonce = [0u8; NONCE_LENGTH];
+      channel
+        .read_exact(&mut listener_nonce)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+
+      let connector_nonce = self.generate_nonce()?;
+      let connector_tag = key.sign(CONNECTOR_LABEL, &listener_nonce, &connector_nonce);
+      let mut response = Vec::with_capacity(1 + key.id().len() + NONCE_LENGTH + TAG_LENGTH);
+      // Key IDs are bounded by MAXIMUM_KEY_ID_LENGTH when keys are constructed
+      response.push(key.id().len() as u8);
+      response.extend_from_slice(key.id().as_bytes());
+      response.extend_from_slice(&connector_nonce);
+      response.extend_from_slice(connector_tag.as_ref());
+      channel
+        .write_all(&response)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+
+      match channel
+        .read_u8()
+        .await
+        .map_err(|_| violation("Read unavailable"))?
+      {
+        STATUS_ACCEPTED => (),
+        STATUS_REFUSED => return Err(RemoteAuthenticationError::Refused.into()),
+        _ => return Err(violation("Invalid status").into()),
+      }
+      let mut listener_tag = [0u8; TAG_LENGTH];
+      channel
+        .read_exact(&mut listener_tag)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      if !key.verify(
+        LISTENER_LABEL,
+        &listener_nonce,
+        &connector_nonce,
+        &listener_tag,
+      ) {
+        tracing::debug!("Remote failed to prove knowledge of the shared key");
+        return Err(RemoteAuthenticationError::Refused.into());
+      }
+      Ok(key.tunnel_name().clone())
+    }
+    .boxed()
+  }
+}
+
+fn violation(reason: &str) -> RemoteAuthenticationError {
+  RemoteAuthenticationError::ProtocolViolation(reason.into())
+}
+
+/// Reads a key ID, prefixed by its length as a single byte
+async fn read_key_id<S: tokio::io::AsyncRead + Unpin + ?Sized>(
+  channel: &mut S,
+) -> Result<String, RemoteAuthenticationError> {
+  let length = channel
+    .read_u8()
+    .await
+    .map_err(|_| violation("Read unavailable"))?;
+  let mut key_id = vec![0u8; length as usize];
+  channel
+    .read_exact(&mut key_id)
+    .await
+    .map_err(|_| violation("Read unavailable"))?;
+  String::from_utf8(key_id).map_err(|_| violation("Key ID was not valid UTF8"))
+}
+
+impl std::fmt::Debug for HmacAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(HmacAuthenticationHandler))
+      .field("keys", &self.keys.keys().collect::<Vec<_>>())
+      .field("identity", &self.identity)
+      .finish_non_exhaustive()
+  }
+}
+
+impl AuthenticationHandler for HmacAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    _shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    match tunnel_info.side {
+      TunnelSide::Listen => self.authenticate_listen_side(channel),
+      TunnelSide::Connect => self.authenticate_connecting_side(channel),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{HmacAuthenticationHandler, PresharedKey};
+  use crate::{
+    common::{
+      authentication::{perform_authentication, AuthenticationError, RemoteAuthenticationError},
+      protocol::tunnel::{
+        duplex::{channel as duplex, EntangledTunnels},
+        TunnelName,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  fn key(id: &str, secret: &[u8]) -> PresharedKey {
+    PresharedKey::new(id.into(), secret, TunnelName::new(format!("{}-tunnel", id))).unwrap()
+  }
+
+  async fn authenticate(
+    server: HmacAuthenticationHandler,
+    client: HmacAuthenticationHandler,
+  ) -> (
+    Result<TunnelName, AuthenticationError>,
+    Result<TunnelName, AuthenticationError>,
+  ) {
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    let never_shutdown = CancellationListener::default();
+    futures::future::join(
+      perform_authentication(&server, &listener, &never_shutdown),
+      perform_authentication(&client, &connector, &never_shutdown),
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn mutual_authentication() {
+    let server = HmacAuthenticationHandler::new(
+      vec![key("a", b"0123456789abcdef"), key("b", b"fedcba9876543210")],
+      None,
+    );
+    let client =
+      HmacAuthenticationHandler::new(vec![key("b", b"fedcba9876543210")], Some("b".into()));
+    let (server_res, client_res) = authenticate(server, client).await;
+    assert_eq!(server_res.unwrap(), TunnelName::new("b-tunnel"));
+    assert_eq!(client_res.unwrap(), TunnelName::new("b-tunnel"));
+  }
+
+  #[tokio::test]
+  async fn wrong_secret_is_refused() {
+    let server = HmacAuthenticationHandler::new(vec![key("a", b"0123456789abcdef")], None);
+    let client = HmacAuthenticationHandler::new(vec![key("a", b"not-the-secret!!")], None);
+    let (server_res, client_res) = authenticate(server, client).await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+    assert!(matches!(
+      client_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+
+  #[tokio::test]
+  async fn unknown_key_is_refused() {
+    let server = HmacAuthenticationHandler::new(vec![key("a", b"0123456789abcdef")], None);
+    let client = HmacAuthenticationHandler::new(vec![key("z", b"0123456789abcdef")], None);
+    let (server_res, _client_res) = authenticate(server, client).await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+
+  #[test]
+  fn overlong_key_ids_are_rejected() {
+    let file = |id: String| super::PresharedKeyFile {
+      identity: None,
+      keys: vec![super::PresharedKeyEntry {
+        id,
+        secret: base64::encode(b"0123456789abcdef"),
+        tunnel_name: None,
+      }],
+    };
+    assert!(file("k".repeat(super::MAXIMUM_KEY_ID_LENGTH))
+      .into_keys()
+      .is_ok());
+    assert!(file("k".repeat(super::MAXIMUM_KEY_ID_LENGTH + 1))
+      .into_keys()
+      .is_err());
+  }
+
+  #[test]
+  fn constructed_keys_are_validated() {
+    let id = |len: usize| "k".repeat(len);
+    let secret = b"0123456789abcdef";
+    let name = || TunnelName::new("edge");
+    assert!(PresharedKey::new(id(super::MAXIMUM_KEY_ID_LENGTH), secret, name()).is_ok());
+    assert!(PresharedKey::new(id(super::MAXIMUM_KEY_ID_LENGTH + 1), secret, name()).is_err());
+    let short_secret = &secret[..super::MINIMUM_SECRET_LENGTH - 1];
+    assert!(PresharedKey::new(id(1), short_secret, name()).is_err());
+  }
+}
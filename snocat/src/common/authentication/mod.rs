@@ -10,3 +10,44 @@ pub use no_op_authentication::NoOpAuthenticationHandler;
 
 mod simple_ack_authentication;
 pub use simple_ack_authentication::SimpleAckAuthenticationHandler;
+
+mod hmac_authentication;
+pub use hmac_authentication::{
+  HmacAuthenticationHandler, PresharedKey, PresharedKeyEntry, PresharedKeyFile,
+  HMAC_AUTHENTICATION_METHOD,
+};
+
+mod token_authentication;
+pub use token_authentication::{
+  inspect_token, TokenAlgorithm, TokenAuthenticationHandler, TokenClaims, TokenClaimsRegistry,
+  TokenKeyEntry, TokenKeyFile, TokenRejection, TokenServiceAuthorizer, TokenSigner, TokenVerifier,
+  TOKEN_AUTHENTICATION_METHOD,
+};
+
+mod certificate_authentication;
+pub use certificate_authentication::{
+  certificate_common_name, parse_certificate, AttributeValue, CertificateAuthenticationHandler,
+  ParsedCertificate, CERTIFICATE_AUTHENTICATION_METHOD,
+};
+
+mod negotiating_authentication;
+pub use negotiating_authentication::{
+  ArcAuthenticationHandler, MethodPolicy, NegotiatingAuthenticationHandler,
+};
+
+mod throttling_authentication;
+pub use throttling_authentication::{
+  ThrottleMode, ThrottlePolicy, ThrottlingAuthenticationHandler,
+};
+
+mod reloadable_authentication;
+pub use reloadable_authentication::ReloadableAuthenticationHandler;
+
+mod revocation;
+pub use revocation::{RevocationList, Revocations};
+
+mod reauthentication;
+pub use reauthentication::{
+  reauthenticate, ReauthenticationError, ReauthenticationService, REAUTHENTICATION_ADDRESS,
+  REAUTHENTICATION_PROTOCOL_ID,
+};
@@ -0,0 +1,408 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Selection of an authentication method by name, from among several registered handlers
+//!
+//! The listening side, which opens the authentication channel, advertises its permitted
+//! methods in order of preference. The connecting side chooses the first it also permits,
+//! or an empty name if there is none, and both sides then run the chosen method's handler
+//! over the same channel.
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::traits::*;
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+const MAGIC: &[u8; 4] = b"SNAN";
+const PROTOCOL_VERSION: u8 = 1;
+/// At most this many methods may be registered, as the count is advertised in a single byte
+pub const MAXIMUM_METHODS: usize = u8::MAX as usize;
+/// Method names are advertised behind a single length byte, so longer names are rejected
+pub const MAXIMUM_METHOD_NAME_LENGTH: usize = u8::MAX as usize;
+
+pub type ArcAuthenticationHandler = Arc<dyn AuthenticationHandler + Send + Sync + 'static>;
+
+/// Restricts which registered authentication methods may be used
+///
+/// Swapping the policy allows a deployment to move between methods gradually; for example,
+/// by requiring certificates of some tunnels while others may still use shared secrets.
+#[derive(Clone, Debug, Default)]
+pub struct MethodPolicy {
+  /// Methods which may be offered or chosen, in order of preference
+  ///
+  /// If unset, all registered methods are permitted, in order of registration.
+  permitted: Option<Vec<String>>,
+  /// Tunnels which are refused unless they authenticated via one of the given methods
+  pub required_by_tunnel: HashMap<TunnelName, HashSet<String>>,
+}
+
+impl MethodPolicy {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Permits only the given methods, in the given order of preference
+  ///
+  /// Repeated methods keep their first position. Fails if a name is empty or longer than
+  /// [MAXIMUM_METHOD_NAME_LENGTH], or if more than [MAXIMUM_METHODS] are given.
+  pub fn permitting<I: IntoIterator<Item = S>, S: Into<String>>(
+    methods: I,
+  ) -> anyhow::Result<Self> {
+    let mut seen = HashSet::new();
+    let mut permitted = Vec::new();
+    for name in methods.into_iter().map(Into::into) {
+      if name.is_empty() || name.len() > MAXIMUM_METHOD_NAME_LENGTH {
+        anyhow::bail!(
+          "Method name {:?} must be 1 to {} bytes long",
+          name,
+          MAXIMUM_METHOD_NAME_LENGTH
+        );
+      }
+      if seen.insert(name.clone()) {
+        permitted.push(name);
+      }
+    }
+    if permitted.len() > MAXIMUM_METHODS {
+      anyhow::bail!("At most {} methods may be permitted", MAXIMUM_METHODS);
+    }
+    Ok(Self {
+      permitted: Some(permitted),
+      ..Default::default()
+    })
+  }
+
+  /// Requires that the named tunnel authenticate by one of the given methods
+  pub fn require_for<I: IntoIterator<Item = S>, S: Into<String>>(
+    mut self,
+    tunnel_name: TunnelName,
+    methods: I,
+  ) -> Self {
+    self
+      .required_by_tunnel
+      .insert(tunnel_name, methods.into_iter().map(Into::into).collect());
+    self
+  }
+
+  fn allows(&self, tunnel_name: &TunnelName, method: &str) -> bool {
+    self
+      .required_by_tunnel
+      .get(tunnel_name)
+      .map_or(true, |required| required.contains(method))
+  }
+}
+
+/// Delegates to one of several named [AuthenticationHandler]s, chosen with the remote
+pub struct NegotiatingAuthenticationHandler {
+  methods: Vec<(String, ArcAuthenticationHandler)>,
+  policy: MethodPolicy,
+}
+
+impl NegotiatingAuthenticationHandler {
+  /// Registers `methods` in order of preference
+  ///
+  /// Panics if method names are duplicated, empty, or longer than 255 bytes.
+  pub fn new(methods: Vec<(String, ArcAuthenticationHandler)>, policy: MethodPolicy) -> Self {
+    assert!(methods.len() <= MAXIMUM_METHODS, "Too many methods");
+    let mut seen = HashSet::new();
+    for (name, _) in methods.iter() {
+      assert!(
+        !name.is_empty() && name.len() <= MAXIMUM_METHOD_NAME_LENGTH,
+        "Method names must be 1 to 255 bytes long"
+      );
+      assert!(seen.insert(name.as_str()), "Duplicate method {:?}", name);
+    }
+    Self { methods, policy }
+  }
+
+  /// Permitted methods, in order of preference
+  fn permitted(&self) -> Vec<&str> {
+    match &self.policy.permitted {
+      Some(permitted) => permitted
+        .iter()
+        .map(String::as_str)
+        .filter(|name| self.handler(name).is_some())
+        .collect(),
+      None => self.methods.iter().map(|(name, _)| name.as_str()).collect(),
+    }
+  }
+
+  fn handler(&self, method: &str) -> Option<&ArcAuthenticationHandler> {
+    self
+      .methods
+      .iter()
+      .find(|(name, _)| name == method)
+      .map(|(_, handler)| handler)
+  }
+
+  fn advertise_and_await_choice<'a>(
+    &'a self,
+    channel: &'a mut (dyn TunnelStream + Send + Unpin),
+  ) -> BoxFuture<'a, Result<String, AuthenticationError>> {
+    async move {
+      // Bounded by MAXIMUM_METHODS and MAXIMUM_METHOD_NAME_LENGTH, as permitted methods are
+      // unique, registered, and validated when registered
+      let permitted = self.permitted();
+      let mut advertisement = Vec::new();
+      advertisement.extend_from_slice(MAGIC);
+      advertisement.push(PROTOCOL_VERSION);
+      advertisement.push(permitted.len() as u8);
+      for name in permitted.iter() {
+        advertisement.push(name.len() as u8);
+        advertisement.extend_from_slice(name.as_bytes());
+      }
+      channel
+        .write_all(&advertisement)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+
+      let choice = read_name(channel).await?;
+      if choice.is_empty() {
+        tracing::debug!("Remote permits none of the advertised authentication methods");
+        return Err(RemoteAuthenticationError::Refused.into());
+      }
+      if !permitted.contains(&choice.as_str()) {
+        return Err(violation("Chose an authentication method which was not offered").into());
+      }
+      Ok(choice)
+    }
+    .boxed()
+  }
+
+  fn receive_offer_and_choose<'a>(
+    &'a self,
+    channel: &'a mut (dyn TunnelStream + Send + Unpin),
+  ) -> BoxFuture<'a, Result<String, AuthenticationError>> {
+    async move {
+      let mut header = [0u8; 6];
+      channel
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      if &header[..4] != MAGIC {
+        return Err(violation("Invalid method advertisement").into());
+      }
+      if header[4] != PROTOCOL_VERSION {
+        return Err(violation("Unsupported protocol version").into());
+      }
+      let mut offered = Vec::with_capacity(header[5] as usize);
+      for _ in 0..header[5] {
+        offered.push(read_name(channel).await?);
+      }
+
+      let permitted = self.permitted();
+      let choice = offered
+        .into_iter()
+        .find(|name| permitted.contains(&name.as_str()));
+      let choice_bytes = choice.as_deref().unwrap_or("").as_bytes();
+      let mut response = Vec::with_capacity(1 + choice_bytes.len());
+      response.push(choice_bytes.len() as u8);
+      response.extend_from_slice(choice_bytes);
+      channel
+        .write_all(&response)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      choice.ok_or_else(|| {
+        tracing::debug!("Remote offered no permitted authentication method");
+        RemoteAuthenticationError::Refused.into()
+      })
+    }
+    .boxed()
+  }
+}
+
+fn violation(reason: &str) -> RemoteAuthenticationError {
+  RemoteAuthenticationError::ProtocolViolation(reason.into())
+}
+
+/// Reads a method name, prefixed by its length as a single byte
+async fn read_name<S: tokio::io::AsyncRead + Unpin + ?Sized>(
+  channel: &mut S,
+) -> Result<String, RemoteAuthenticationError> {
+  let length = channel
+    .read_u8()
+    .await
+    .map_err(|_| violation("Read unavailable"))?;
+  let mut name = vec![0u8; length as usize];
+  channel
+    .read_exact(&mut name)
+    .await
+    .map_err(|_| violation("Read unavailable"))?;
+  String::from_utf8(name).map_err(|_| violation("Method name was not valid UTF8"))
+}
+
+impl std::fmt::Debug for NegotiatingAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(NegotiatingAuthenticationHandler))
+      .field("methods", &self.methods)
+      .field("policy", &self.policy)
+      .finish()
+  }
+}
+
+impl AuthenticationHandler for NegotiatingAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let method = match tunnel_info.side {
+        TunnelSide::Listen => self.advertise_and_await_choice(channel.as_mut()).await?,
+        TunnelSide::Connect => self.receive_offer_and_choose(channel.as_mut()).await?,
+      };
+      tracing::debug!(method = method.as_str(), "Authentication method chosen");
+      let handler = self
+        .handler(&method)
+        .expect("Chosen methods must be registered");
+      let tunnel_name = handler
+        .authenticate(channel, tunnel_info, shutdown_notifier)
+        .await?;
+      if !self.policy.allows(&tunnel_name, &method) {
+        tracing::debug!(
+          method = method.as_str(),
+          tunnel = ?tunnel_name,
+          "Tunnel authenticated by a method its policy does not permit"
+        );
+        return Err(RemoteAuthenticationError::Refused.into());
+      }
+      Ok(tunnel_name)
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::{ArcAuthenticationHandler, MethodPolicy, NegotiatingAuthenticationHandler};
+  use crate::{
+    common::{
+      authentication::{
+        perform_authentication, AuthenticationError, HmacAuthenticationHandler,
+        NoOpAuthenticationHandler, PresharedKey, RemoteAuthenticationError,
+      },
+      protocol::tunnel::{
+        duplex::{channel as duplex, EntangledTunnels},
+        TunnelName,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  fn hmac() -> (String, ArcAuthenticationHandler) {
+    let key =
+      PresharedKey::new("edge".into(), b"0123456789abcdef", TunnelName::new("edge")).unwrap();
+    (
+      "hmac".into(),
+      Arc::new(HmacAuthenticationHandler::new(vec![key], None)),
+    )
+  }
+
+  fn anonymous() -> (String, ArcAuthenticationHandler) {
+    (
+      "anonymous".into(),
+      Arc::new(NoOpAuthenticationHandler::new()),
+    )
+  }
+
+  async fn authenticate(
+    server: NegotiatingAuthenticationHandler,
+    client: NegotiatingAuthenticationHandler,
+  ) -> (
+    Result<TunnelName, AuthenticationError>,
+    Result<TunnelName, AuthenticationError>,
+  ) {
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    let never_shutdown = CancellationListener::default();
+    futures::future::join(
+      perform_authentication(&server, &listener, &never_shutdown),
+      perform_authentication(&client, &connector, &never_shutdown),
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn negotiates_first_common_method() {
+    let server = NegotiatingAuthenticationHandler::new(
+      vec![anonymous(), hmac()],
+      MethodPolicy::permitting(vec!["hmac", "anonymous"]).unwrap(),
+    );
+    let client =
+      NegotiatingAuthenticationHandler::new(vec![anonymous(), hmac()], MethodPolicy::new());
+    let (server_res, client_res) = authenticate(server, client).await;
+    assert_eq!(server_res.unwrap(), TunnelName::new("edge"));
+    assert_eq!(client_res.unwrap(), TunnelName::new("edge"));
+  }
+
+  #[tokio::test]
+  async fn refuses_without_common_method() {
+    let server = NegotiatingAuthenticationHandler::new(vec![hmac()], MethodPolicy::new());
+    let client = NegotiatingAuthenticationHandler::new(vec![anonymous()], MethodPolicy::new());
+    let (server_res, client_res) = authenticate(server, client).await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+    assert!(matches!(
+      client_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+
+  #[tokio::test]
+  async fn enforces_required_method_for_tunnel() {
+    let server = NegotiatingAuthenticationHandler::new(
+      vec![hmac()],
+      MethodPolicy::new().require_for(TunnelName::new("edge"), vec!["certificate"]),
+    );
+    let client = NegotiatingAuthenticationHandler::new(vec![hmac()], MethodPolicy::new());
+    let (server_res, _client_res) = authenticate(server, client).await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+
+  #[test]
+  fn permitted_methods_are_validated() {
+    let policy = MethodPolicy::permitting(vec!["hmac", "anonymous", "hmac"]).unwrap();
+    assert_eq!(
+      policy.permitted,
+      Some(vec!["hmac".to_string(), "anonymous".to_string()])
+    );
+    assert!(MethodPolicy::permitting(vec![""]).is_err());
+    assert!(MethodPolicy::permitting(vec!["m".repeat(super::MAXIMUM_METHOD_NAME_LENGTH)]).is_ok());
+    assert!(
+      MethodPolicy::permitting(vec!["m".repeat(super::MAXIMUM_METHOD_NAME_LENGTH + 1)]).is_err()
+    );
+    let many = (0..=super::MAXIMUM_METHODS).map(|i| format!("method-{}", i));
+    assert!(MethodPolicy::permitting(many).is_err());
+  }
+}
@@ -0,0 +1,316 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Re-authentication of established tunnels over a fresh link
+//!
+//! The listening side opens a link, negotiates it to [REAUTHENTICATION_ADDRESS], and runs
+//! its authentication handler over it exactly as it did when the tunnel was established.
+//! The connecting side answers through [ReauthenticationService]. Credentials may change
+//! between attempts, but must prove the same tunnel name.
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+
+use super::{traits::*, ArcAuthenticationHandler};
+use crate::{
+  common::protocol::{
+    negotiation::{NegotiationClient, NegotiationError},
+    tunnel::{Tunnel, TunnelError, TunnelId, TunnelName},
+    ProtocolVersion, RouteAddress, Service, ServiceError, VersionRange,
+  },
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+pub const REAUTHENTICATION_ADDRESS: &str = "/snocat/reauthenticate";
+
+pub const REAUTHENTICATION_PROTOCOL_ID: &str = "snocat.reauthenticate";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReauthenticationError {
+  #[error("Failed to open a link for re-authentication")]
+  LinkFailed(#[from] TunnelError),
+  #[error("Remote refused re-authentication: {0}")]
+  NegotiationFailed(#[from] NegotiationError),
+  #[error(transparent)]
+  Authentication(#[from] AuthenticationError),
+  #[error("Tunnel {expected:?} re-authenticated as {actual:?}")]
+  IdentityChanged {
+    expected: TunnelName,
+    actual: TunnelName,
+  },
+}
+
+/// Re-authenticates the tunnel with ID `id` from its listening side, over a newly opened link
+pub fn reauthenticate<'a>(
+  handler: &'a (impl AuthenticationHandler + ?Sized),
+  tunnel: &'a (dyn Tunnel + Send + Sync + 'a),
+  id: TunnelId,
+  expected: &'a TunnelName,
+  shutdown_notifier: &'a CancellationListener,
+) -> BoxFuture<'a, Result<(), ReauthenticationError>> {
+  async move {
+    let link = tunnel.open_link().await?;
+    let (link, _version) = NegotiationClient::new(
+      Some(REAUTHENTICATION_PROTOCOL_ID.into()),
+      VersionRange::default(),
+    )
+    .handle(REAUTHENTICATION_ADDRESS.into(), link)
+    .await?;
+    let tunnel_info = TunnelInfo::of(tunnel).with_id(id);
+    let actual = handler
+      .authenticate(Box::new(link), tunnel_info, shutdown_notifier)
+      .await?;
+    if &actual != expected {
+      return Err(ReauthenticationError::IdentityChanged {
+        expected: expected.clone(),
+        actual,
+      });
+    }
+    Ok(())
+  }
+  .boxed()
+}
+
+/// Answers re-authentication requests on the connecting side of a single tunnel
+pub struct ReauthenticationService {
+  handler: ArcAuthenticationHandler,
+  tunnel_info: TunnelInfo,
+  tunnel_name: TunnelName,
+  shutdown_notifier: CancellationListener,
+}
+
+impl ReauthenticationService {
+  /// `tunnel_name` is the name under which the tunnel was first authenticated
+  pub fn new(
+    handler: ArcAuthenticationHandler,
+    tunnel_info: TunnelInfo,
+    tunnel_name: TunnelName,
+    shutdown_notifier: CancellationListener,
+  ) -> Self {
+    Self {
+      handler,
+      tunnel_info,
+      tunnel_name,
+      shutdown_notifier,
+    }
+  }
+}
+
+impl std::fmt::Debug for ReauthenticationService {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(ReauthenticationService))
+      .field("handler", &self.handler)
+      .field("tunnel_name", &self.tunnel_name)
+      .finish_non_exhaustive()
+  }
+}
+
+impl Service for ReauthenticationService {
+  fn accepts(&self, addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+    addr == REAUTHENTICATION_ADDRESS
+  }
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(REAUTHENTICATION_PROTOCOL_ID)
+  }
+
+  fn handle<'a>(
+    &'a self,
+    _addr: RouteAddress,
+    _version: ProtocolVersion,
+    stream: Box<dyn TunnelStream + Send + 'static>,
+    _tunnel_id: TunnelId,
+  ) -> BoxFuture<'a, Result<(), ServiceError>> {
+    async move {
+      let name = self
+        .handler
+        .authenticate(
+          Box::new(stream),
+          self.tunnel_info.clone(),
+          &self.shutdown_notifier,
+        )
+        .await
+        .map_err(|e| {
+          tracing::info!(error = %e, "Re-authentication with remote failed");
+          ServiceError::InternalFailure(e.into())
+        })?;
+      if name != self.tunnel_name {
+        tracing::warn!(?name, expected = ?self.tunnel_name, "Remote re-authenticated under a new name");
+        return Err(ServiceError::IllegalResponse);
+      }
+      tracing::debug!("Re-authenticated with remote");
+      Ok(())
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::StreamExt;
+  use std::sync::Arc;
+
+  use super::{reauthenticate, ReauthenticationError, ReauthenticationService};
+  use crate::{
+    common::{
+      authentication::{
+        ArcAuthenticationHandler, AuthenticationError, AuthenticationHandler,
+        CertificateAuthenticationHandler, HmacAuthenticationHandler, PresharedKey,
+        RemoteAuthenticationError, RevocationList, Revocations, TunnelInfo,
+      },
+      authorization::AllowAllAuthorizer,
+      protocol::{
+        negotiation::{ArcService, NegotiationService},
+        traits::ServiceRegistry,
+        tunnel::{
+          duplex::{channel, EntangledTunnels},
+          Tunnel, TunnelId, TunnelIncomingType, TunnelName,
+        },
+        RouteAddress,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  struct OnlyService(ArcService);
+
+  impl ServiceRegistry for OnlyService {
+    fn find_service(
+      self: Arc<Self>,
+      _addr: &RouteAddress,
+      _tunnel_id: &TunnelId,
+      _tunnel_name: &TunnelName,
+    ) -> Option<ArcService> {
+      Some(Arc::clone(&self.0))
+    }
+  }
+
+  fn hmac(key_id: &str) -> HmacAuthenticationHandler {
+    let key =
+      PresharedKey::new(key_id.into(), b"0123456789abcdef", TunnelName::new("edge")).unwrap();
+    HmacAuthenticationHandler::new(vec![key], None)
+  }
+
+  /// Re-authenticates the listening side of a duplex tunnel against a connector using `client`
+  async fn attempt(
+    server: impl AuthenticationHandler,
+    client: ArcAuthenticationHandler,
+  ) -> Result<(), ReauthenticationError> {
+    attempt_with_certificates(server, client, None).await
+  }
+
+  /// As [attempt], where the connector presented `chain` when the tunnel was established
+  async fn attempt_with_certificates(
+    server: impl AuthenticationHandler,
+    client: ArcAuthenticationHandler,
+    chain: Option<Vec<Vec<u8>>>,
+  ) -> Result<(), ReauthenticationError> {
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = channel();
+    let listener = match chain {
+      Some(chain) => listener.with_peer_certificates(chain),
+      None => listener,
+    };
+    let never_shutdown = CancellationListener::default();
+    let service = ReauthenticationService::new(
+      client,
+      TunnelInfo::of(&connector).with_id(TunnelId::new(1)),
+      TunnelName::new("edge"),
+      never_shutdown.clone(),
+    );
+    let negotiator = NegotiationService::new(
+      Arc::new(OnlyService(Arc::new(service))),
+      Arc::new(AllowAllAuthorizer::new()),
+    );
+    let connector_side = async move {
+      let mut downlink = connector.downlink().await.unwrap();
+      let link = match downlink.as_stream().next().await {
+        Some(Ok(TunnelIncomingType::BiStream(link))) => link,
+        _ => panic!("Expected a re-authentication link"),
+      };
+      let (link, addr, version, service) = negotiator
+        .negotiate(link, TunnelId::new(1), TunnelName::new("edge"))
+        .await
+        .unwrap();
+      // Failures are reported by the listening side
+      let _ = service
+        .handle(addr, version, Box::new(link), TunnelId::new(1))
+        .await;
+    };
+    let expected = TunnelName::new("edge");
+    let (result, ()) = futures::future::join(
+      reauthenticate(
+        &server,
+        &listener,
+        TunnelId::new(1),
+        &expected,
+        &never_shutdown,
+      ),
+      connector_side,
+    )
+    .await;
+    result
+  }
+
+  #[tokio::test]
+  async fn reauthenticates_with_rotated_credentials() {
+    let server = HmacAuthenticationHandler::new(
+      vec![
+        PresharedKey::new("old".into(), b"0123456789abcdef", TunnelName::new("edge")).unwrap(),
+        PresharedKey::new("new".into(), b"0123456789abcdef", TunnelName::new("edge")).unwrap(),
+      ],
+      None,
+    );
+    attempt(server, Arc::new(hmac("new"))).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn refuses_revoked_keys() {
+    let revocations = Revocations::new(RevocationList::default());
+    let revocations = Arc::new(revocations);
+    let server = hmac("edge-key").with_revocations(Arc::clone(&revocations));
+    attempt(server, Arc::new(hmac("edge-key"))).await.unwrap();
+
+    let mut list = RevocationList::default();
+    list.key_ids.insert("edge-key".into());
+    revocations.replace(list);
+    let server = hmac("edge-key").with_revocations(revocations);
+    assert!(matches!(
+      attempt(server, Arc::new(hmac("edge-key"))).await,
+      Err(ReauthenticationError::Authentication(
+        AuthenticationError::Remote(RemoteAuthenticationError::Revoked)
+      ))
+    ));
+  }
+
+  #[tokio::test]
+  async fn refuses_revoked_certificates() {
+    let mut params = rcgen::CertificateParams::new(vec!["edge.example".into()]);
+    params.serial_number = Some(0x0a1b2c);
+    params
+      .distinguished_name
+      .push(rcgen::DnType::CommonName, "edge");
+    let leaf = rcgen::Certificate::from_params(params)
+      .unwrap()
+      .serialize_der()
+      .unwrap();
+    let revocations = Arc::new(Revocations::new(RevocationList::default()));
+    let server =
+      || CertificateAuthenticationHandler::new().with_revocations(Arc::clone(&revocations));
+    let client = Arc::new(CertificateAuthenticationHandler::new());
+    attempt_with_certificates(server(), client.clone(), Some(vec![leaf.clone()]))
+      .await
+      .unwrap();
+
+    let mut list = RevocationList::default();
+    list.certificate_serials.insert("0a:1b:2c".into());
+    revocations.replace(list);
+    assert!(matches!(
+      attempt_with_certificates(server(), client, Some(vec![leaf])).await,
+      Err(ReauthenticationError::Authentication(
+        AuthenticationError::Remote(RemoteAuthenticationError::Revoked)
+      ))
+    ));
+  }
+}
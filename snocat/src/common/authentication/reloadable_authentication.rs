@@ -0,0 +1,119 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! An authentication handler which may be replaced while its daemon runs
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::{Arc, RwLock};
+
+use super::{traits::*, ArcAuthenticationHandler};
+use crate::{
+  common::protocol::tunnel::TunnelName,
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+/// Delegates to an inner [AuthenticationHandler], which may be replaced while in use
+///
+/// Authentications already in progress complete with the handler they started with.
+#[derive(Debug)]
+pub struct ReloadableAuthenticationHandler {
+  current: RwLock<ArcAuthenticationHandler>,
+}
+
+impl ReloadableAuthenticationHandler {
+  pub fn new(handler: ArcAuthenticationHandler) -> Self {
+    Self {
+      current: RwLock::new(handler),
+    }
+  }
+
+  pub fn current(&self) -> ArcAuthenticationHandler {
+    Arc::clone(
+      &self
+        .current
+        .read()
+        .expect("Authentication handler lock must not be poisoned"),
+    )
+  }
+
+  /// Uses `handler` for all future authentications, returning the handler it replaced
+  pub fn replace(&self, handler: ArcAuthenticationHandler) -> ArcAuthenticationHandler {
+    std::mem::replace(
+      &mut *self
+        .current
+        .write()
+        .expect("Authentication handler lock must not be poisoned"),
+      handler,
+    )
+  }
+}
+
+impl AuthenticationHandler for ReloadableAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    let handler = self.current();
+    async move {
+      handler
+        .authenticate(channel, tunnel_info, shutdown_notifier)
+        .await
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::ReloadableAuthenticationHandler;
+  use crate::{
+    common::{
+      authentication::{
+        perform_authentication, AuthenticationError, HmacAuthenticationHandler, PresharedKey,
+      },
+      protocol::tunnel::{
+        duplex::{channel, EntangledTunnels},
+        TunnelName,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  fn hmac(secret: &[u8]) -> Arc<HmacAuthenticationHandler> {
+    let key = PresharedKey::new("edge".into(), secret, TunnelName::new("edge")).unwrap();
+    Arc::new(HmacAuthenticationHandler::new(vec![key], None))
+  }
+
+  async fn attempt(
+    server: &ReloadableAuthenticationHandler,
+    client: &HmacAuthenticationHandler,
+  ) -> Result<TunnelName, AuthenticationError> {
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = channel();
+    let never_shutdown = CancellationListener::default();
+    let (server_res, _client_res) = futures::future::join(
+      perform_authentication(server, &listener, &never_shutdown),
+      perform_authentication(client, &connector, &never_shutdown),
+    )
+    .await;
+    server_res
+  }
+
+  #[tokio::test]
+  async fn replaced_handlers_authenticate_new_tunnels() {
+    let server = ReloadableAuthenticationHandler::new(hmac(b"0123456789abcdef"));
+    let rotated = hmac(b"fedcba9876543210");
+    assert!(attempt(&server, &rotated).await.is_err());
+    server.replace(rotated.clone());
+    assert_eq!(
+      attempt(&server, &rotated).await.unwrap(),
+      TunnelName::new("edge")
+    );
+  }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Deny lists of credentials which must no longer be accepted
+//!
+//! ```json
+//! {
+//!   "tunnel_names": ["edge-7"],
+//!   "key_ids": ["2021-04-signing"],
+//!   "certificate_serials": ["0a:1b:2c"]
+//! }
+//! ```
+#![warn(unused_imports)]
+
+use std::{
+  collections::BTreeSet,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use tokio::sync::watch;
+
+use crate::common::protocol::tunnel::TunnelName;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RevocationList {
+  #[serde(default)]
+  pub tunnel_names: BTreeSet<String>,
+  /// IDs of pre-shared keys and token signing keys
+  #[serde(default)]
+  pub key_ids: BTreeSet<String>,
+  /// Hexadecimal serial numbers, compared without separators, case, or leading zeros
+  #[serde(default)]
+  pub certificate_serials: BTreeSet<String>,
+}
+
+fn normalize_serial(serial: &str) -> String {
+  serial
+    .chars()
+    .filter(|c| *c != ':')
+    .flat_map(char::to_lowercase)
+    .skip_while(|c| *c == '0')
+    .collect()
+}
+
+impl RevocationList {
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    use anyhow::Context;
+    let contents = std::fs::read(path)
+      .with_context(|| format!("Failed reading revocation list {}", path.display()))?;
+    serde_json::from_slice(&contents)
+      .with_context(|| format!("Failed parsing revocation list {}", path.display()))
+  }
+
+  pub fn is_name_revoked(&self, tunnel_name: &TunnelName) -> bool {
+    self.tunnel_names.contains(tunnel_name.raw())
+  }
+
+  pub fn is_key_revoked(&self, key_id: &str) -> bool {
+    self.key_ids.contains(key_id)
+  }
+
+  pub fn is_certificate_revoked(&self, serial: &str) -> bool {
+    let serial = normalize_serial(serial);
+    self
+      .certificate_serials
+      .iter()
+      .any(|revoked| normalize_serial(revoked) == serial)
+  }
+}
+
+/// A [RevocationList] shared between authentication handlers and daemons
+///
+/// The list may be replaced or reloaded at runtime; subscribers are notified so that
+/// tunnels established under newly revoked credentials can be closed.
+pub struct Revocations {
+  path: Option<PathBuf>,
+  sender: watch::Sender<Arc<RevocationList>>,
+  current: watch::Receiver<Arc<RevocationList>>,
+}
+
+impl Revocations {
+  pub fn new(list: RevocationList) -> Self {
+    let (sender, current) = watch::channel(Arc::new(list));
+    Self {
+      path: None,
+      sender,
+      current,
+    }
+  }
+
+  /// Loads the list from `path`, which [Revocations::reload] reads again
+  pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+    Ok(Self {
+      path: Some(path.to_path_buf()),
+      ..Self::new(RevocationList::load(path)?)
+    })
+  }
+
+  /// The file the list was loaded from, if any
+  pub fn path(&self) -> Option<&Path> {
+    self.path.as_deref()
+  }
+
+  pub fn current(&self) -> Arc<RevocationList> {
+    Arc::clone(&self.current.borrow())
+  }
+
+  pub fn replace(&self, list: RevocationList) {
+    // The receiver held by `self` keeps the channel open
+    let _ = self.sender.send(Arc::new(list));
+  }
+
+  /// Re-reads the file the list was loaded from, keeping the current list on failure
+  pub fn reload(&self) -> anyhow::Result<()> {
+    let path = self
+      .path
+      .as_ref()
+      .ok_or_else(|| anyhow::Error::msg("Revocation list was not loaded from a file"))?;
+    self.replace(RevocationList::load(path)?);
+    tracing::info!(path = %path.display(), "Reloaded revocation list");
+    Ok(())
+  }
+
+  /// Yields each list as it replaces the last
+  pub fn subscribe(&self) -> watch::Receiver<Arc<RevocationList>> {
+    self.current.clone()
+  }
+
+  pub fn is_name_revoked(&self, tunnel_name: &TunnelName) -> bool {
+    self.current.borrow().is_name_revoked(tunnel_name)
+  }
+
+  pub fn is_key_revoked(&self, key_id: &str) -> bool {
+    self.current.borrow().is_key_revoked(key_id)
+  }
+
+  pub fn is_certificate_revoked(&self, serial: &str) -> bool {
+    self.current.borrow().is_certificate_revoked(serial)
+  }
+}
+
+impl std::fmt::Debug for Revocations {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(Revocations))
+      .field("path", &self.path)
+      .field("current", &*self.current.borrow())
+      .finish_non_exhaustive()
+  }
+}
@@ -0,0 +1,404 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Limits on repeated failed authentication attempts from a single remote address
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+  collections::{HashMap, VecDeque},
+  net::IpAddr,
+  sync::Mutex,
+  time::Duration,
+};
+use tokio::time::Instant;
+
+use super::{traits::*, ArcAuthenticationHandler};
+use crate::{
+  common::protocol::tunnel::{TunnelAddressInfo, TunnelName},
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+/// How a remote address is treated once it has exceeded its failure threshold
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThrottleMode {
+  /// Attempts are refused without being passed to the inner handler
+  Refuse,
+  /// Attempts wait until the lockout has passed before being passed to the inner handler,
+  /// or are closed locally if shutdown begins first
+  Delay,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ThrottlePolicy {
+  /// Failures tolerated within `window` before the address is locked out
+  pub max_failures: usize,
+  pub window: Duration,
+  /// How long an address is locked out once it exceeds `max_failures`
+  pub lockout: Duration,
+  pub mode: ThrottleMode,
+}
+
+impl ThrottlePolicy {
+  pub fn new(max_failures: usize, window: Duration, lockout: Duration, mode: ThrottleMode) -> Self {
+    assert!(max_failures > 0, "At least one failure must be tolerated");
+    Self {
+      max_failures,
+      window,
+      lockout,
+      mode,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct FailureRecord {
+  failures: VecDeque<Instant>,
+  locked_until: Option<Instant>,
+}
+
+impl FailureRecord {
+  fn is_stale(&self, now: Instant, window: Duration) -> bool {
+    self.locked_until.map_or(true, |until| until <= now)
+      && self
+        .failures
+        .back()
+        .map_or(true, |last| *last + window <= now)
+  }
+}
+
+/// Wraps an [AuthenticationHandler], tracking failed attempts per remote IP address
+///
+/// Addresses which fail `max_failures` times within the policy's window are locked out.
+/// Remotes without an IP address, as reported by [TunnelInfo::addr], are never throttled.
+pub struct ThrottlingAuthenticationHandler {
+  inner: ArcAuthenticationHandler,
+  policy: ThrottlePolicy,
+  authentication_timeout: Option<Duration>,
+  records: Mutex<HashMap<IpAddr, FailureRecord>>,
+}
+
+impl ThrottlingAuthenticationHandler {
+  pub fn new(inner: ArcAuthenticationHandler, policy: ThrottlePolicy) -> Self {
+    Self {
+      inner,
+      policy,
+      authentication_timeout: None,
+      records: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Fails attempts which take longer than `timeout` with [RemoteAuthenticationError::TimedOut],
+  /// counting them against the remote
+  ///
+  /// Deadlines applied around this handler, such as by a daemon, abandon the attempt before its
+  /// outcome can be recorded, so remotes which never answer are only throttled by this deadline.
+  /// The deadline starts once any [ThrottleMode::Delay] has passed.
+  pub fn with_authentication_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.authentication_timeout = timeout;
+    self
+  }
+
+  /// Whether a failure was caused by the remote, rather than by the local side or the transport
+  fn counts_against_remote(error: &RemoteAuthenticationError) -> bool {
+    use RemoteAuthenticationError::*;
+    match error {
+      Refused | ProtocolViolation(_) | TimedOut | LinkClosedRemotely | CredentialsExpired
+      | InvalidSignature | WrongAudience | Revoked => true,
+      LinkClosedLocally | IncomingStreamsClosed | TransportError => false,
+    }
+  }
+
+  fn remote_ip(addr: &TunnelAddressInfo) -> Option<IpAddr> {
+    match addr {
+      TunnelAddressInfo::Socket(socket_addr) => Some(socket_addr.ip()),
+      TunnelAddressInfo::Unidentified | TunnelAddressInfo::Port(_) => None,
+    }
+  }
+
+  /// The instant until which the address is locked out, if it currently is
+  fn locked_until(&self, ip: &IpAddr, now: Instant) -> Option<Instant> {
+    let records = self
+      .records
+      .lock()
+      .expect("Throttle lock must not be poisoned");
+    records
+      .get(ip)
+      .and_then(|record| record.locked_until)
+      .filter(|until| *until > now)
+  }
+
+  fn record_failure(&self, ip: IpAddr, now: Instant) {
+    let mut records = self
+      .records
+      .lock()
+      .expect("Throttle lock must not be poisoned");
+    // Forget addresses with nothing left to remember, so the table stays bounded
+    let window = self.policy.window;
+    records.retain(|_, record| !record.is_stale(now, window));
+
+    let record = records.entry(ip).or_default();
+    while matches!(record.failures.front(), Some(failure) if *failure + window <= now) {
+      record.failures.pop_front();
+    }
+    record.failures.push_back(now);
+    if record.failures.len() >= self.policy.max_failures {
+      tracing::info!(%ip, lockout = ?self.policy.lockout, "Throttling authentication attempts");
+      record.failures.clear();
+      record.locked_until = Some(now + self.policy.lockout);
+    }
+  }
+
+  fn record_success(&self, ip: &IpAddr) {
+    self
+      .records
+      .lock()
+      .expect("Throttle lock must not be poisoned")
+      .remove(ip);
+  }
+}
+
+impl std::fmt::Debug for ThrottlingAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(ThrottlingAuthenticationHandler))
+      .field("inner", &self.inner)
+      .field("policy", &self.policy)
+      .finish_non_exhaustive()
+  }
+}
+
+impl AuthenticationHandler for ThrottlingAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let ip = Self::remote_ip(&tunnel_info.addr);
+      let lockout = ip.and_then(|ip| Some((ip, self.locked_until(&ip, Instant::now())?)));
+      if let Some((ip, until)) = lockout {
+        match self.policy.mode {
+          ThrottleMode::Refuse => {
+            tracing::debug!(%ip, "Refusing throttled remote");
+            return Err(RemoteAuthenticationError::Refused.into());
+          }
+          ThrottleMode::Delay => {
+            tracing::debug!(%ip, "Delaying throttled remote");
+            tokio::select! {
+              () = tokio::time::sleep_until(until) => (),
+              () = shutdown_notifier.cancelled() => {
+                tracing::debug!(%ip, "Abandoning throttled remote on shutdown");
+                return Err(RemoteAuthenticationError::LinkClosedLocally.into());
+              }
+            }
+          }
+        }
+      }
+
+      let authentication = self
+        .inner
+        .authenticate(channel, tunnel_info, shutdown_notifier);
+      let result = match self.authentication_timeout {
+        Some(timeout) => with_authentication_timeout(authentication, timeout).await,
+        None => authentication.await,
+      };
+      let ip = match ip {
+        Some(ip) => ip,
+        None => return result,
+      };
+      match &result {
+        Ok(_) => self.record_success(&ip),
+        Err(AuthenticationError::Remote(e)) if Self::counts_against_remote(e) => {
+          self.record_failure(ip, Instant::now())
+        }
+        Err(_) => (),
+      }
+      result
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+  };
+
+  use tokio_util::sync::CancellationToken;
+
+  use super::{ThrottleMode, ThrottlePolicy, ThrottlingAuthenticationHandler};
+  use crate::{
+    common::{
+      authentication::{
+        perform_authentication, AuthenticationError, HmacAuthenticationHandler, PresharedKey,
+        RemoteAuthenticationError,
+      },
+      protocol::tunnel::{
+        duplex::{channel_with_addresses, EntangledTunnels},
+        TunnelAddressInfo, TunnelName,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  fn hmac(secret: &[u8]) -> HmacAuthenticationHandler {
+    let key = PresharedKey::new("edge".into(), secret, TunnelName::new("edge")).unwrap();
+    HmacAuthenticationHandler::new(vec![key], None)
+  }
+
+  async fn attempt(
+    server: &ThrottlingAuthenticationHandler,
+    client: &HmacAuthenticationHandler,
+  ) -> Result<TunnelName, AuthenticationError> {
+    let remote = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 4000));
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = channel_with_addresses(
+      TunnelAddressInfo::Socket(remote),
+      TunnelAddressInfo::Unidentified,
+    );
+    let never_shutdown = CancellationListener::default();
+    let (server_res, _client_res) = futures::future::join(
+      perform_authentication(server, &listener, &never_shutdown),
+      perform_authentication(client, &connector, &never_shutdown),
+    )
+    .await;
+    server_res
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn locks_out_repeated_failures() {
+    let server = ThrottlingAuthenticationHandler::new(
+      Arc::new(hmac(b"0123456789abcdef")),
+      ThrottlePolicy::new(
+        3,
+        Duration::from_secs(60),
+        Duration::from_secs(300),
+        ThrottleMode::Refuse,
+      ),
+    );
+    let impostor = hmac(b"not-the-secret!!");
+    let legitimate = hmac(b"0123456789abcdef");
+
+    for _ in 0..3 {
+      assert!(matches!(
+        attempt(&server, &impostor).await,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused
+        ))
+      ));
+    }
+    // Locked out, even with the correct secret
+    assert!(attempt(&server, &legitimate).await.is_err());
+
+    tokio::time::advance(Duration::from_secs(301)).await;
+    assert_eq!(
+      attempt(&server, &legitimate).await.unwrap(),
+      TunnelName::new("edge")
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn locks_out_repeated_timeouts() {
+    let server = ThrottlingAuthenticationHandler::new(
+      Arc::new(hmac(b"0123456789abcdef")),
+      ThrottlePolicy::new(
+        2,
+        Duration::from_secs(60),
+        Duration::from_secs(300),
+        ThrottleMode::Refuse,
+      ),
+    )
+    .with_authentication_timeout(Some(Duration::from_secs(5)));
+    let remote = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 4000));
+    let never_shutdown = CancellationListener::default();
+    // A remote which connects, but never answers the handshake
+    let mut silent_remotes = Vec::new();
+    for _ in 0..2 {
+      let EntangledTunnels {
+        listener,
+        connector,
+      } = channel_with_addresses(
+        TunnelAddressInfo::Socket(remote),
+        TunnelAddressInfo::Unidentified,
+      );
+      assert!(matches!(
+        perform_authentication(&server, &listener, &never_shutdown).await,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::TimedOut
+        ))
+      ));
+      silent_remotes.push(connector);
+    }
+    // Locked out without waiting for the handshake, even with the correct secret
+    let started = tokio::time::Instant::now();
+    assert!(matches!(
+      attempt(&server, &hmac(b"0123456789abcdef")).await,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+    assert_eq!(started.elapsed(), Duration::ZERO);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn delays_throttled_remotes() {
+    let server = ThrottlingAuthenticationHandler::new(
+      Arc::new(hmac(b"0123456789abcdef")),
+      ThrottlePolicy::new(
+        1,
+        Duration::from_secs(60),
+        Duration::from_secs(30),
+        ThrottleMode::Delay,
+      ),
+    );
+    assert!(attempt(&server, &hmac(b"not-the-secret!!")).await.is_err());
+    let started = tokio::time::Instant::now();
+    assert!(attempt(&server, &hmac(b"0123456789abcdef")).await.is_ok());
+    assert!(started.elapsed() >= Duration::from_secs(30));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn delays_end_on_shutdown() {
+    let server = ThrottlingAuthenticationHandler::new(
+      Arc::new(hmac(b"0123456789abcdef")),
+      ThrottlePolicy::new(
+        1,
+        Duration::from_secs(60),
+        Duration::from_secs(30),
+        ThrottleMode::Delay,
+      ),
+    );
+    assert!(attempt(&server, &hmac(b"not-the-secret!!")).await.is_err());
+    let remote = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 4000));
+    let EntangledTunnels {
+      listener,
+      connector: _connector,
+    } = channel_with_addresses(
+      TunnelAddressInfo::Socket(remote),
+      TunnelAddressInfo::Unidentified,
+    );
+    let shutdown = CancellationToken::new();
+    let shutdown_listener = CancellationListener::from(shutdown.clone());
+    let started = tokio::time::Instant::now();
+    let (result, ()) = futures::future::join(
+      perform_authentication(&server, &listener, &shutdown_listener),
+      async move {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        shutdown.cancel();
+      },
+    )
+    .await;
+    assert!(matches!(
+      result,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::LinkClosedLocally
+      ))
+    ));
+    assert!(started.elapsed() < Duration::from_secs(30));
+  }
+}
@@ -0,0 +1,1003 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Authentication by signed, expiring bearer tokens, verified offline against known keys
+//!
+//! Tokens use the compact JWT layout, `base64url(header).base64url(claims).base64url(signature)`,
+//! signed by either Ed25519 (`EdDSA`) or HMAC-SHA256 (`HS256`). The connecting side presents
+//! its token; the listening side verifies it and names the tunnel by its subject.
+//!
+//! Verified claims are recorded by tunnel ID, such that a [TokenServiceAuthorizer] can
+//! restrict each tunnel to the services its token lists.
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use ring::{
+  hmac,
+  rand::SystemRandom,
+  signature::{self, Ed25519KeyPair, KeyPair},
+};
+use std::{
+  collections::{BTreeMap, HashMap},
+  path::Path,
+  sync::{Arc, Mutex},
+  time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{revocation::Revocations, traits::*};
+use crate::{
+  common::{
+    authorization::{AuthorizationDenial, Authorizer, Permission},
+    protocol::{
+      is_route_beneath,
+      tunnel::{TunnelId, TunnelName, TunnelSide},
+    },
+  },
+  util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
+};
+
+const MAGIC: &[u8; 4] = b"SNTK";
+const PROTOCOL_VERSION: u8 = 1;
+/// Conventional name of this method when negotiated by name
+pub const TOKEN_AUTHENTICATION_METHOD: &str = "bearer-token";
+/// Tokens longer than this are refused without being read
+pub const MAXIMUM_TOKEN_LENGTH: usize = 8 * 1024;
+const TOKEN_TYPE: &str = "JWT";
+
+const STATUS_ACCEPTED: u8 = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TokenAlgorithm {
+  #[serde(rename = "EdDSA")]
+  Ed25519,
+  #[serde(rename = "HS256")]
+  HmacSha256,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct TokenHeader {
+  alg: TokenAlgorithm,
+  typ: String,
+  kid: String,
+}
+
+/// Claims carried by a token, using registered JWT claim names where one exists
+///
+/// Timestamps are in seconds since the unix epoch.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TokenClaims {
+  /// The name given to the authenticated tunnel
+  #[serde(rename = "sub")]
+  pub tunnel_name: String,
+  /// The deployment the token is valid for
+  #[serde(rename = "aud")]
+  pub audience: String,
+  #[serde(rename = "iat")]
+  pub issued_at: u64,
+  #[serde(rename = "nbf", default, skip_serializing_if = "Option::is_none")]
+  pub not_before: Option<u64>,
+  #[serde(rename = "exp")]
+  pub expires_at: u64,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub labels: BTreeMap<String, String>,
+  /// Route-address prefixes of the services the bearer may request
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub services: Vec<String>,
+}
+
+impl TokenClaims {
+  /// Claims valid from `now` until `lifetime` has passed
+  pub fn new(tunnel_name: String, audience: String, now: SystemTime, lifetime: Duration) -> Self {
+    let issued_at = unix_seconds(now);
+    Self {
+      tunnel_name,
+      audience,
+      issued_at,
+      not_before: None,
+      expires_at: issued_at.saturating_add(lifetime.as_secs()),
+      labels: BTreeMap::new(),
+      services: Vec::new(),
+    }
+  }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+  time
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+/// Reasons a token may fail verification
+///
+/// Each has a distinct status code on the wire, so both peers report the same failure.
+#[derive(thiserror::Error, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TokenRejection {
+  #[error("Token is malformed")]
+  Malformed,
+  #[error("Token was signed by an unknown key")]
+  UnknownKey,
+  #[error("Token signature is invalid")]
+  BadSignature,
+  #[error("Token has expired")]
+  Expired,
+  #[error("Token is not yet valid")]
+  NotYetValid,
+  #[error("Token was issued for a different audience")]
+  WrongAudience,
+  #[error("Token was signed by a revoked key")]
+  Revoked,
+}
+
+impl TokenRejection {
+  fn status(self) -> u8 {
+    match self {
+      TokenRejection::Malformed => 1,
+      TokenRejection::UnknownKey => 2,
+      TokenRejection::BadSignature => 3,
+      TokenRejection::Expired => 4,
+      TokenRejection::NotYetValid => 5,
+      TokenRejection::WrongAudience => 6,
+      TokenRejection::Revoked => 7,
+    }
+  }
+
+  fn from_status(status: u8) -> Option<Self> {
+    Some(match status {
+      1 => TokenRejection::Malformed,
+      2 => TokenRejection::UnknownKey,
+      3 => TokenRejection::BadSignature,
+      4 => TokenRejection::Expired,
+      5 => TokenRejection::NotYetValid,
+      6 => TokenRejection::WrongAudience,
+      7 => TokenRejection::Revoked,
+      _ => return None,
+    })
+  }
+}
+
+impl From<TokenRejection> for RemoteAuthenticationError {
+  fn from(rejection: TokenRejection) -> Self {
+    match rejection {
+      TokenRejection::Malformed => {
+        RemoteAuthenticationError::ProtocolViolation(rejection.to_string())
+      }
+      TokenRejection::UnknownKey => RemoteAuthenticationError::Refused,
+      TokenRejection::BadSignature => RemoteAuthenticationError::InvalidSignature,
+      TokenRejection::Expired | TokenRejection::NotYetValid => {
+        RemoteAuthenticationError::CredentialsExpired
+      }
+      TokenRejection::WrongAudience => RemoteAuthenticationError::WrongAudience,
+      TokenRejection::Revoked => RemoteAuthenticationError::Revoked,
+    }
+  }
+}
+
+fn encode(bytes: &[u8]) -> String {
+  base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(text: &str) -> Result<Vec<u8>, TokenRejection> {
+  base64::decode_config(text, base64::URL_SAFE_NO_PAD).map_err(|_| TokenRejection::Malformed)
+}
+
+/// Splits a token into its header, claims, and signature, along with the signed portion
+fn split_token(token: &str) -> Result<(TokenHeader, &str, Vec<u8>, &str), TokenRejection> {
+  let mut parts = token.split('.');
+  let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+    (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+    _ => return Err(TokenRejection::Malformed),
+  };
+  let header: TokenHeader =
+    serde_json::from_slice(&decode(header)?).map_err(|_| TokenRejection::Malformed)?;
+  if header.typ != TOKEN_TYPE {
+    return Err(TokenRejection::Malformed);
+  }
+  let signed = &token[..token.len() - signature.len() - 1];
+  Ok((header, claims, decode(signature)?, signed))
+}
+
+fn decode_claims(claims: &str) -> Result<TokenClaims, TokenRejection> {
+  serde_json::from_slice(&decode(claims)?).map_err(|_| TokenRejection::Malformed)
+}
+
+/// Reads the claims of a token without verifying them
+///
+/// Only suitable for tokens from a trusted source, such as one's own.
+pub fn inspect_token(token: &str) -> Result<TokenClaims, TokenRejection> {
+  let (_header, claims, _signature, _signed) = split_token(token)?;
+  decode_claims(claims)
+}
+
+/// Key material for signing or verifying tokens, as stored in a [TokenKeyFile]
+///
+/// Ed25519 keys hold a public key, and a PKCS#8 private key when used for signing.
+/// HMAC keys hold a secret, which is needed for both signing and verification.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TokenKeyEntry {
+  pub id: String,
+  pub algorithm: TokenAlgorithm,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub public_key: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub private_key: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub secret: Option<String>,
+}
+
+impl TokenKeyEntry {
+  /// Generates a fresh Ed25519 key pair
+  pub fn generate_ed25519(id: String) -> anyhow::Result<Self> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+      .map_err(|_| anyhow::Error::msg("Ed25519 key generation failed"))?;
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+      .map_err(|_| anyhow::Error::msg("Generated Ed25519 key was rejected"))?;
+    Ok(Self {
+      id,
+      algorithm: TokenAlgorithm::Ed25519,
+      public_key: Some(base64::encode(pair.public_key().as_ref())),
+      private_key: Some(base64::encode(pkcs8.as_ref())),
+      secret: None,
+    })
+  }
+
+  /// This entry without any material which would allow signing
+  ///
+  /// HMAC keys have no public part, so have nothing to share.
+  pub fn to_public(&self) -> Option<Self> {
+    match self.algorithm {
+      TokenAlgorithm::Ed25519 => Some(Self {
+        private_key: None,
+        ..self.clone()
+      }),
+      TokenAlgorithm::HmacSha256 => None,
+    }
+  }
+
+  fn field(&self, name: &str, value: &Option<String>) -> anyhow::Result<Vec<u8>> {
+    let value = value
+      .as_ref()
+      .ok_or_else(|| anyhow::anyhow!("Key {:?} is missing its {}", self.id, name))?;
+    base64::decode(value)
+      .map_err(|e| anyhow::anyhow!("Key {:?} has an invalid {}: {}", self.id, name, e))
+  }
+
+  fn verification_key(&self) -> anyhow::Result<VerificationKey> {
+    Ok(match self.algorithm {
+      TokenAlgorithm::Ed25519 => match &self.public_key {
+        Some(_) => VerificationKey::Ed25519(self.field("public_key", &self.public_key)?),
+        None => VerificationKey::Ed25519(
+          self
+            .signing_key()?
+            .ed25519_public_key()
+            .expect("Ed25519 entries produce Ed25519 signing keys"),
+        ),
+      },
+      TokenAlgorithm::HmacSha256 => VerificationKey::Hmac(hmac::Key::new(
+        hmac::HMAC_SHA256,
+        &self.field("secret", &self.secret)?,
+      )),
+    })
+  }
+
+  fn signing_key(&self) -> anyhow::Result<SigningKey> {
+    Ok(match self.algorithm {
+      TokenAlgorithm::Ed25519 => SigningKey::Ed25519(
+        Ed25519KeyPair::from_pkcs8(&self.field("private_key", &self.private_key)?)
+          .map_err(|e| anyhow::anyhow!("Key {:?} is not a valid PKCS#8 key: {}", self.id, e))?,
+      ),
+      TokenAlgorithm::HmacSha256 => SigningKey::Hmac(hmac::Key::new(
+        hmac::HMAC_SHA256,
+        &self.field("secret", &self.secret)?,
+      )),
+    })
+  }
+}
+
+/// A JSON file of [TokenKeyEntry]s
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TokenKeyFile {
+  pub keys: Vec<TokenKeyEntry>,
+}
+
+impl TokenKeyFile {
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    use anyhow::Context;
+    let contents = std::fs::read(path)
+      .with_context(|| format!("Failed reading token key file {}", path.display()))?;
+    serde_json::from_slice(&contents)
+      .with_context(|| format!("Failed parsing token key file {}", path.display()))
+  }
+
+  pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    std::fs::write(path, serde_json::to_vec_pretty(self)?)
+      .with_context(|| format!("Failed writing token key file {}", path.display()))
+  }
+
+  /// Saves a file holding signing keys, readable only by its owner where supported
+  pub fn save_private(&self, path: &Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    crate::util::write_private_file(path, &serde_json::to_vec_pretty(self)?)
+      .with_context(|| format!("Failed writing token key file {}", path.display()))
+  }
+}
+
+enum SigningKey {
+  Ed25519(Ed25519KeyPair),
+  Hmac(hmac::Key),
+}
+
+impl SigningKey {
+  fn ed25519_public_key(&self) -> Option<Vec<u8>> {
+    match self {
+      SigningKey::Ed25519(pair) => Some(pair.public_key().as_ref().to_vec()),
+      SigningKey::Hmac(_) => None,
+    }
+  }
+}
+
+enum VerificationKey {
+  Ed25519(Vec<u8>),
+  Hmac(hmac::Key),
+}
+
+impl VerificationKey {
+  fn algorithm(&self) -> TokenAlgorithm {
+    match self {
+      VerificationKey::Ed25519(_) => TokenAlgorithm::Ed25519,
+      VerificationKey::Hmac(_) => TokenAlgorithm::HmacSha256,
+    }
+  }
+
+  fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+    match self {
+      VerificationKey::Ed25519(public_key) => {
+        signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+          .verify(message, signature)
+          .is_ok()
+      }
+      VerificationKey::Hmac(key) => hmac::verify(key, message, signature).is_ok(),
+    }
+  }
+}
+
+/// Mints tokens signed by a single key
+pub struct TokenSigner {
+  key_id: String,
+  algorithm: TokenAlgorithm,
+  key: SigningKey,
+}
+
+impl TokenSigner {
+  pub fn new(entry: &TokenKeyEntry) -> anyhow::Result<Self> {
+    Ok(Self {
+      key_id: entry.id.clone(),
+      algorithm: entry.algorithm,
+      key: entry.signing_key()?,
+    })
+  }
+
+  /// Loads the key named `key_id`, or the only key if none is named
+  pub fn from_key_file(path: &Path, key_id: Option<&str>) -> anyhow::Result<Self> {
+    let file = TokenKeyFile::load(path)?;
+    let entry = match key_id {
+      Some(key_id) => file.keys.iter().find(|entry| entry.id == key_id),
+      None if file.keys.len() == 1 => file.keys.first(),
+      None => anyhow::bail!("A key ID must be chosen from the {} keys", file.keys.len()),
+    }
+    .ok_or_else(|| anyhow::anyhow!("No key {:?} in {}", key_id, path.display()))?;
+    Self::new(entry)
+  }
+
+  pub fn issue(&self, claims: &TokenClaims) -> anyhow::Result<String> {
+    let header = TokenHeader {
+      alg: self.algorithm,
+      typ: TOKEN_TYPE.into(),
+      kid: self.key_id.clone(),
+    };
+    let signed = format!(
+      "{}.{}",
+      encode(&serde_json::to_vec(&header)?),
+      encode(&serde_json::to_vec(claims)?)
+    );
+    let signature = match &self.key {
+      SigningKey::Ed25519(pair) => encode(pair.sign(signed.as_bytes()).as_ref()),
+      SigningKey::Hmac(key) => encode(hmac::sign(key, signed.as_bytes()).as_ref()),
+    };
+    Ok(format!("{}.{}", signed, signature))
+  }
+}
+
+impl std::fmt::Debug for TokenSigner {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(TokenSigner))
+      .field("key_id", &self.key_id)
+      .field("algorithm", &self.algorithm)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Verifies tokens against a set of keys, for a single audience
+pub struct TokenVerifier {
+  keys: HashMap<String, VerificationKey>,
+  audience: String,
+  clock_skew: Duration,
+  revocations: Option<Arc<Revocations>>,
+}
+
+impl TokenVerifier {
+  /// Tokens are accepted up to `clock_skew` before they become valid or after they expire
+  pub fn new(
+    entries: &[TokenKeyEntry],
+    audience: String,
+    clock_skew: Duration,
+  ) -> anyhow::Result<Self> {
+    let keys = entries
+      .iter()
+      .map(|entry| Ok((entry.id.clone(), entry.verification_key()?)))
+      .collect::<anyhow::Result<_>>()?;
+    Ok(Self {
+      keys,
+      audience,
+      clock_skew,
+      revocations: None,
+    })
+  }
+
+  /// Refuses tokens signed by keys whose IDs are revoked
+  pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+    self.revocations = Some(revocations);
+    self
+  }
+
+  pub fn from_key_file(
+    path: &Path,
+    audience: String,
+    clock_skew: Duration,
+  ) -> anyhow::Result<Self> {
+    Self::new(&TokenKeyFile::load(path)?.keys, audience, clock_skew)
+  }
+
+  pub fn verify(&self, token: &str, now: SystemTime) -> Result<TokenClaims, TokenRejection> {
+    let (header, claims, signature, signed) = split_token(token)?;
+    let key = self
+      .keys
+      .get(&header.kid)
+      .ok_or(TokenRejection::UnknownKey)?;
+    if let Some(revocations) = &self.revocations {
+      if revocations.is_key_revoked(&header.kid) {
+        return Err(TokenRejection::Revoked);
+      }
+    }
+    // The algorithm is fixed by the key, so a header may not downgrade it
+    if header.alg != key.algorithm() || !key.verify(signed.as_bytes(), &signature) {
+      return Err(TokenRejection::BadSignature);
+    }
+    let claims = decode_claims(claims)?;
+    if claims.audience != self.audience {
+      return Err(TokenRejection::WrongAudience);
+    }
+    let now = unix_seconds(now);
+    let skew = self.clock_skew.as_secs();
+    if now > claims.expires_at.saturating_add(skew) {
+      return Err(TokenRejection::Expired);
+    }
+    if claims.not_before.unwrap_or(claims.issued_at) > now.saturating_add(skew) {
+      return Err(TokenRejection::NotYetValid);
+    }
+    Ok(claims)
+  }
+}
+
+impl std::fmt::Debug for TokenVerifier {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(TokenVerifier))
+      .field("keys", &self.keys.keys().collect::<Vec<_>>())
+      .field("audience", &self.audience)
+      .field("clock_skew", &self.clock_skew)
+      .finish()
+  }
+}
+
+/// The claims most recently verified for each tunnel, by tunnel ID
+///
+/// Entries outlive their tunnels until removed through [TokenClaimsRegistry::forget].
+#[derive(Debug, Default)]
+pub struct TokenClaimsRegistry {
+  claims: Mutex<BTreeMap<TunnelId, TokenClaims>>,
+}
+
+impl TokenClaimsRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn get(&self, tunnel_id: &TunnelId) -> Option<TokenClaims> {
+    self
+      .claims
+      .lock()
+      .expect("Claims lock must not be poisoned")
+      .get(tunnel_id)
+      .cloned()
+  }
+
+  fn record(&self, tunnel_id: TunnelId, claims: TokenClaims) {
+    self
+      .claims
+      .lock()
+      .expect("Claims lock must not be poisoned")
+      .insert(tunnel_id, claims);
+  }
+
+  /// Removes the claims of a tunnel, such as once it has disconnected
+  pub fn forget(&self, tunnel_id: &TunnelId) -> Option<TokenClaims> {
+    self
+      .claims
+      .lock()
+      .expect("Claims lock must not be poisoned")
+      .remove(tunnel_id)
+  }
+}
+
+/// Authenticates connecting peers by the bearer token they present
+///
+/// The listening side verifies tokens and names tunnels by their subject, recording the
+/// claims of tunnels with an assigned ID for later lookup through
+/// [TokenAuthenticationHandler::claims]. The connecting side presents its token, and names
+/// the tunnel by the token's audience.
+pub struct TokenAuthenticationHandler {
+  verifier: Option<TokenVerifier>,
+  token: Option<String>,
+  claims: Arc<TokenClaimsRegistry>,
+}
+
+impl TokenAuthenticationHandler {
+  /// `verifier` is required to accept tunnels, and `token` to establish them
+  pub fn new(verifier: Option<TokenVerifier>, token: Option<String>) -> Self {
+    Self {
+      verifier,
+      token,
+      claims: Arc::new(TokenClaimsRegistry::new()),
+    }
+  }
+
+  /// Records verified claims in `claims`, which may be shared with other handlers and authorizers
+  pub fn with_claims_registry(mut self, claims: Arc<TokenClaimsRegistry>) -> Self {
+    self.claims = claims;
+    self
+  }
+
+  /// The claims most recently verified for the tunnel with the given ID
+  pub fn claims(&self, tunnel_id: &TunnelId) -> Option<TokenClaims> {
+    self.claims.get(tunnel_id)
+  }
+
+  fn authenticate_listen_side<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin + 'a>,
+    tunnel_id: Option<TunnelId>,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let verifier = self.verifier.as_ref().ok_or_else(|| {
+        AuthenticationHandlingError::DependencyFailure(
+          String::from("verifier"),
+          anyhow::Error::msg("No token verification keys are configured"),
+        )
+      })?;
+      let mut hello = Vec::with_capacity(MAGIC.len() + 1);
+      hello.extend_from_slice(MAGIC);
+      hello.push(PROTOCOL_VERSION);
+      channel
+        .write_all(&hello)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+
+      let length = channel
+        .read_u16()
+        .await
+        .map_err(|_| violation("Read unavailable"))? as usize;
+      if length > MAXIMUM_TOKEN_LENGTH {
+        let _ = channel.write_u8(TokenRejection::Malformed.status()).await;
+        return Err(violation("Token too long").into());
+      }
+      let mut token = vec![0u8; length];
+      channel
+        .read_exact(&mut token)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+
+      let verified = std::str::from_utf8(&token)
+        .map_err(|_| TokenRejection::Malformed)
+        .and_then(|token| verifier.verify(token, SystemTime::now()));
+      let status = match &verified {
+        Ok(_) => STATUS_ACCEPTED,
+        Err(rejection) => rejection.status(),
+      };
+      channel
+        .write_u8(status)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      let claims = verified.map_err(|rejection| {
+        tracing::debug!(?rejection, "Refusing token");
+        RemoteAuthenticationError::from(rejection)
+      })?;
+
+      let tunnel_name = TunnelName::new(claims.tunnel_name.clone());
+      if let Some(tunnel_id) = tunnel_id {
+        self.claims.record(tunnel_id, claims);
+      }
+      Ok(tunnel_name)
+    }
+    .boxed()
+  }
+
+  fn authenticate_connecting_side<'a>(
+    &'a self,
+    mut channel: Box<dyn TunnelStream + Send + Unpin + 'a>,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let token = self.token.as_ref().ok_or_else(|| {
+        AuthenticationHandlingError::DependencyFailure(
+          String::from("token"),
+          anyhow::Error::msg("No token is configured for connecting"),
+        )
+      })?;
+      let audience = inspect_token(token)
+        .map_err(|e| {
+          AuthenticationHandlingError::DependencyFailure(String::from("token"), e.into())
+        })?
+        .audience;
+      if token.len() > MAXIMUM_TOKEN_LENGTH {
+        return Err(
+          AuthenticationHandlingError::DependencyFailure(
+            String::from("token"),
+            anyhow::Error::msg("Configured token is too long"),
+          )
+          .into(),
+        );
+      }
+
+      let mut hello = [0u8; 5];
+      channel
+        .read_exact(&mut hello)
+        .await
+        .map_err(|_| violation("Read unavailable"))?;
+      if &hello[..4] != MAGIC {
+        return Err(violation("Invalid hello").into());
+      }
+      if hello[4] != PROTOCOL_VERSION {
+        return Err(violation("Unsupported protocol version").into());
+      }
+
+      let mut presentation = Vec::with_capacity(2 + token.len());
+      presentation.extend_from_slice(&(token.len() as u16).to_be_bytes());
+      presentation.extend_from_slice(token.as_bytes());
+      channel
+        .write_all(&presentation)
+        .await
+        .map_err(|_| violation("Write refused"))?;
+      channel
+        .flush()
+        .await
+        .map_err(|_| violation("Write refused"))?;
+
+      match channel
+        .read_u8()
+        .await
+        .map_err(|_| violation("Read unavailable"))?
+      {
+        STATUS_ACCEPTED => Ok(TunnelName::new(audience)),
+        status => match TokenRejection::from_status(status) {
+          Some(rejection) => Err(RemoteAuthenticationError::from(rejection).into()),
+          None => Err(violation("Invalid status").into()),
+        },
+      }
+    }
+    .boxed()
+  }
+}
+
+fn violation(reason: &str) -> RemoteAuthenticationError {
+  RemoteAuthenticationError::ProtocolViolation(reason.into())
+}
+
+impl std::fmt::Debug for TokenAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(TokenAuthenticationHandler))
+      .field("verifier", &self.verifier)
+      .field("has_token", &self.token.is_some())
+      .finish_non_exhaustive()
+  }
+}
+
+impl AuthenticationHandler for TokenAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    _shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    match tunnel_info.side {
+      TunnelSide::Listen => self.authenticate_listen_side(channel, tunnel_info.id),
+      TunnelSide::Connect => self.authenticate_connecting_side(channel),
+    }
+  }
+}
+
+/// Denies tunnels routes to services their token does not list
+///
+/// Tokens listing no services, and tunnels without recorded claims, such as those
+/// authenticated by other methods, are left to other authorizers.
+#[derive(Debug)]
+pub struct TokenServiceAuthorizer {
+  claims: Arc<TokenClaimsRegistry>,
+}
+
+impl TokenServiceAuthorizer {
+  pub fn new(claims: Arc<TokenClaimsRegistry>) -> Self {
+    Self { claims }
+  }
+}
+
+impl Authorizer for TokenServiceAuthorizer {
+  fn authorize(
+    &self,
+    tunnel_id: &TunnelId,
+    _tunnel_name: &TunnelName,
+    permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    let addr = match permission {
+      Permission::Route(addr) => addr,
+      _ => return Ok(()),
+    };
+    match self.claims.get(tunnel_id) {
+      Some(claims)
+        if !claims.services.is_empty()
+          && !claims
+            .services
+            .iter()
+            .any(|service| is_route_beneath(addr, service)) =>
+      {
+        Err(AuthorizationDenial::NotGranted)
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, SystemTime};
+
+  use std::sync::Arc;
+
+  use super::{
+    TokenAuthenticationHandler, TokenClaims, TokenClaimsRegistry, TokenKeyEntry, TokenRejection,
+    TokenServiceAuthorizer, TokenSigner, TokenVerifier,
+  };
+  use crate::{
+    common::{
+      authentication::{
+        perform_authentication, perform_identified_authentication, AuthenticationError,
+        RemoteAuthenticationError,
+      },
+      authorization::{AuthorizationDenial, Authorizer, Permission},
+      protocol::tunnel::{
+        duplex::{channel as duplex, EntangledTunnels},
+        TunnelId, TunnelName,
+      },
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  const SKEW: Duration = Duration::from_secs(30);
+
+  fn claims(now: SystemTime) -> TokenClaims {
+    let mut claims = TokenClaims::new(
+      "edge".into(),
+      "snocat".into(),
+      now,
+      Duration::from_secs(600),
+    );
+    claims.labels.insert("region".into(), "west".into());
+    claims.services.push("/proxyme/".into());
+    claims
+  }
+
+  #[test]
+  fn verification() {
+    let key = TokenKeyEntry::generate_ed25519("k1".into()).unwrap();
+    let signer = TokenSigner::new(&key).unwrap();
+    let verifier = TokenVerifier::new(&[key.to_public().unwrap()], "snocat".into(), SKEW).unwrap();
+    let now = SystemTime::now();
+    let token = signer.issue(&claims(now)).unwrap();
+
+    assert_eq!(verifier.verify(&token, now), Ok(claims(now)));
+    // Within tolerated skew on either end of the validity window
+    assert!(verifier.verify(&token, now - SKEW).is_ok());
+    assert!(verifier
+      .verify(&token, now + Duration::from_secs(600) + SKEW)
+      .is_ok());
+    assert_eq!(
+      verifier.verify(&token, now + Duration::from_secs(700)),
+      Err(TokenRejection::Expired)
+    );
+    assert_eq!(
+      verifier.verify(&token, now - Duration::from_secs(100)),
+      Err(TokenRejection::NotYetValid)
+    );
+
+    let other_audience =
+      TokenVerifier::new(&[key.to_public().unwrap()], "elsewhere".into(), SKEW).unwrap();
+    assert_eq!(
+      other_audience.verify(&token, now),
+      Err(TokenRejection::WrongAudience)
+    );
+
+    let impostor = TokenKeyEntry::generate_ed25519("k1".into()).unwrap();
+    let forged = TokenSigner::new(&impostor)
+      .unwrap()
+      .issue(&claims(now))
+      .unwrap();
+    assert_eq!(
+      verifier.verify(&forged, now),
+      Err(TokenRejection::BadSignature)
+    );
+
+    let unknown = TokenSigner::new(&TokenKeyEntry::generate_ed25519("k2".into()).unwrap())
+      .unwrap()
+      .issue(&claims(now))
+      .unwrap();
+    assert_eq!(
+      verifier.verify(&unknown, now),
+      Err(TokenRejection::UnknownKey)
+    );
+    assert_eq!(
+      verifier.verify("not.a-token", now),
+      Err(TokenRejection::Malformed)
+    );
+  }
+
+  #[tokio::test]
+  async fn authenticate_with_token() {
+    let key = TokenKeyEntry {
+      id: "shared".into(),
+      algorithm: super::TokenAlgorithm::HmacSha256,
+      public_key: None,
+      private_key: None,
+      secret: Some(base64::encode(b"0123456789abcdef0123456789abcdef")),
+    };
+    let now = SystemTime::now();
+    let token = TokenSigner::new(&key).unwrap().issue(&claims(now)).unwrap();
+    let server = TokenAuthenticationHandler::new(
+      Some(TokenVerifier::new(&[key.clone()], "snocat".into(), SKEW).unwrap()),
+      None,
+    );
+    let client = TokenAuthenticationHandler::new(None, Some(token));
+
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    let never_shutdown = CancellationListener::default();
+    let (server_res, client_res) = futures::future::join(
+      perform_identified_authentication(&server, TunnelId::new(1), &listener, &never_shutdown),
+      perform_authentication(&client, &connector, &never_shutdown),
+    )
+    .await;
+    assert_eq!(server_res.unwrap(), TunnelName::new("edge"));
+    assert_eq!(client_res.unwrap(), TunnelName::new("snocat"));
+    assert_eq!(server.claims(&TunnelId::new(1)), Some(claims(now)));
+    assert_eq!(server.claims(&TunnelId::new(2)), None);
+
+    // Expired tokens are reported as such to both sides
+    let mut expired = claims(now - Duration::from_secs(3600));
+    expired.expires_at = expired.issued_at + 1;
+    let client = TokenAuthenticationHandler::new(
+      None,
+      Some(TokenSigner::new(&key).unwrap().issue(&expired).unwrap()),
+    );
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    let (server_res, client_res) = futures::future::join(
+      perform_authentication(&server, &listener, &never_shutdown),
+      perform_authentication(&client, &connector, &never_shutdown),
+    )
+    .await;
+    assert!(matches!(
+      server_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::CredentialsExpired
+      ))
+    ));
+    assert!(matches!(
+      client_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::CredentialsExpired
+      ))
+    ));
+  }
+
+  #[test]
+  fn routes_are_limited_to_listed_services() {
+    let now = SystemTime::now();
+    let registry = Arc::new(TokenClaimsRegistry::new());
+    registry.record(TunnelId::new(1), claims(now));
+    let mut unrestricted = claims(now);
+    unrestricted.services.clear();
+    registry.record(TunnelId::new(2), unrestricted);
+    let authorizer = TokenServiceAuthorizer::new(Arc::clone(&registry));
+    let edge = TunnelName::new("edge");
+    let route =
+      |id, addr| authorizer.authorize(&TunnelId::new(id), &edge, &Permission::Route(addr));
+
+    assert_eq!(route(1, "/proxyme/80"), Ok(()));
+    assert_eq!(route(1, "/proxyme"), Ok(()));
+    assert_eq!(
+      route(1, "/proxymeplease/80"),
+      Err(AuthorizationDenial::NotGranted)
+    );
+    assert_eq!(
+      route(1, "/relay/edge/tcp/22"),
+      Err(AuthorizationDenial::NotGranted)
+    );
+    // Other permissions are left to other authorizers
+    assert_eq!(
+      authorizer.authorize(&TunnelId::new(1), &edge, &Permission::Listen { port: 8080 }),
+      Ok(())
+    );
+    // Tokens without services, and tunnels without tokens, are unrestricted
+    assert_eq!(route(2, "/relay/edge/tcp/22"), Ok(()));
+    assert_eq!(route(3, "/relay/edge/tcp/22"), Ok(()));
+
+    registry.forget(&TunnelId::new(1));
+    assert_eq!(route(1, "/relay/edge/tcp/22"), Ok(()));
+  }
+
+  /// Verifies that signing key files are only readable by their owner
+  #[cfg(unix)]
+  #[test]
+  fn private_key_files_are_owner_only() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("snocat-token-{}.priv.json", std::process::id()));
+    let file = super::TokenKeyFile {
+      keys: vec![TokenKeyEntry::generate_ed25519("k1".into()).unwrap()],
+    };
+    file.save_private(&path).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    let reloaded = super::TokenKeyFile::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(reloaded.unwrap().keys.len(), 1);
+  }
+
+  /// Verifies that replacing a world-readable key file leaves it readable only by its owner
+  #[cfg(unix)]
+  #[test]
+  fn private_key_files_replace_permissive_files() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!(
+      "snocat-token-replaced-{}.priv.json",
+      std::process::id()
+    ));
+    std::fs::write(&path, b"{}").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let file = super::TokenKeyFile {
+      keys: vec![TokenKeyEntry::generate_ed25519("k1".into()).unwrap()],
+    };
+    let saved = file.save_private(&path);
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    let reloaded = super::TokenKeyFile::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    saved.unwrap();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(reloaded.unwrap().keys.len(), 1);
+  }
+}
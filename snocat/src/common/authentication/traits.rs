@@ -3,7 +3,7 @@
 #[warn(unused_imports)]
 use crate::{
   common::protocol::tunnel::{
-    Tunnel, TunnelAddressInfo, TunnelError, TunnelIncomingType, TunnelName, TunnelSide,
+    Tunnel, TunnelAddressInfo, TunnelError, TunnelId, TunnelIncomingType, TunnelName, TunnelSide,
   },
   util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
 };
@@ -15,6 +15,27 @@ use thiserror::Error;
 pub struct TunnelInfo {
   pub side: TunnelSide,
   pub addr: TunnelAddressInfo,
+  /// DER certificates the remote presented to the transport, leaf first, if any
+  pub peer_certificates: Option<Vec<Vec<u8>>>,
+  /// The ID the daemon assigned the tunnel, where one has been assigned
+  pub id: Option<TunnelId>,
+}
+
+impl TunnelInfo {
+  /// Describes a tunnel which has not been assigned an ID
+  pub fn of(tunnel: &(dyn Tunnel + Send + Sync + '_)) -> Self {
+    Self {
+      side: tunnel.side(),
+      addr: tunnel.addr(),
+      peer_certificates: tunnel.peer_certificates(),
+      id: None,
+    }
+  }
+
+  pub fn with_id(mut self, id: TunnelId) -> Self {
+    self.id = Some(id);
+    self
+  }
 }
 
 /// Some errors within the authentication layer are considered fatal to the authenticator
@@ -51,6 +72,18 @@ pub enum RemoteAuthenticationError {
   // Still need to figure out where this one applies, maybe more specificity can be achieved.
   #[error("Transport error encountered authenticating remote")]
   TransportError,
+  // The remote presented credentials which have expired or are not yet valid
+  #[error("Remote credentials are expired or not yet valid")]
+  CredentialsExpired,
+  // The remote presented credentials whose signature did not verify
+  #[error("Remote credentials have an invalid signature")]
+  InvalidSignature,
+  // The remote presented credentials issued for another party
+  #[error("Remote credentials were issued for a different audience")]
+  WrongAudience,
+  // The remote presented credentials which appear on a revocation list
+  #[error("Remote credentials have been revoked")]
+  Revoked,
   // Occurs when an auth protocol is not followed by the remote
   #[error("Remote authentication protocol violation: {0}")]
   ProtocolViolation(String),
@@ -112,12 +145,32 @@ pub fn perform_authentication<'a>(
   handler: &'a (impl AuthenticationHandler + ?Sized),
   tunnel: &'a (dyn Tunnel + Send + Sync + 'a),
   shutdown_notifier: &'a CancellationListener,
+) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+  authenticate_with_info(handler, tunnel, TunnelInfo::of(tunnel), shutdown_notifier)
+}
+
+/// Performs authentication as [perform_authentication], for a tunnel the daemon knows by `id`
+pub fn perform_identified_authentication<'a>(
+  handler: &'a (impl AuthenticationHandler + ?Sized),
+  id: TunnelId,
+  tunnel: &'a (dyn Tunnel + Send + Sync + 'a),
+  shutdown_notifier: &'a CancellationListener,
+) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+  authenticate_with_info(
+    handler,
+    tunnel,
+    TunnelInfo::of(tunnel).with_id(id),
+    shutdown_notifier,
+  )
+}
+
+fn authenticate_with_info<'a>(
+  handler: &'a (impl AuthenticationHandler + ?Sized),
+  tunnel: &'a (dyn Tunnel + Send + Sync + 'a),
+  tunnel_info: TunnelInfo,
+  shutdown_notifier: &'a CancellationListener,
 ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
   use tracing::{debug, span, warn, Instrument, Level};
-  let tunnel_info = TunnelInfo {
-    side: tunnel.side(),
-    addr: tunnel.addr(),
-  };
   let tracing_span_establishment = span!(Level::DEBUG, "establishment", side=?tunnel_info.side);
   let tracing_span_authentication =
     span!(Level::DEBUG, "authentication", side=?tunnel_info.side, addr=?tunnel_info.addr);
@@ -178,3 +231,79 @@ pub fn perform_authentication<'a>(
   .instrument(tracing_span_authentication)
   .boxed()
 }
+
+/// Performs authentication as [perform_authentication], failing with
+/// [RemoteAuthenticationError::TimedOut] if it does not complete within `timeout`
+///
+/// The deadline covers establishment of the authentication channel as well as the handshake,
+/// so a remote which never answers cannot hold its tunnel open indefinitely.
+pub fn perform_authentication_with_timeout<'a>(
+  handler: &'a (impl AuthenticationHandler + ?Sized),
+  tunnel: &'a (dyn Tunnel + Send + Sync + 'a),
+  shutdown_notifier: &'a CancellationListener,
+  timeout: std::time::Duration,
+) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+  with_authentication_timeout(
+    perform_authentication(handler, tunnel, shutdown_notifier),
+    timeout,
+  )
+}
+
+/// Fails `authentication` with [RemoteAuthenticationError::TimedOut] if it does not
+/// complete within `timeout`
+pub fn with_authentication_timeout(
+  authentication: BoxFuture<'_, Result<TunnelName, AuthenticationError>>,
+  timeout: std::time::Duration,
+) -> BoxFuture<'_, Result<TunnelName, AuthenticationError>> {
+  async move {
+    match tokio::time::timeout(timeout, authentication).await {
+      Ok(result) => result,
+      Err(_elapsed) => {
+        tracing::debug!(?timeout, "Authentication timed out");
+        Err(RemoteAuthenticationError::TimedOut.into())
+      }
+    }
+  }
+  .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::{
+    perform_authentication_with_timeout, AuthenticationError, RemoteAuthenticationError,
+  };
+  use crate::{
+    common::{
+      authentication::SimpleAckAuthenticationHandler,
+      protocol::tunnel::duplex::{channel as duplex, EntangledTunnels},
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  #[tokio::test(start_paused = true)]
+  async fn unresponsive_remote_times_out() {
+    let EntangledTunnels {
+      listener,
+      connector: _silent_connector,
+    } = duplex();
+    let never_shutdown = CancellationListener::default();
+    let handler = SimpleAckAuthenticationHandler::new();
+    let started = tokio::time::Instant::now();
+    let result = perform_authentication_with_timeout(
+      &handler,
+      &listener,
+      &never_shutdown,
+      Duration::from_secs(10),
+    )
+    .await;
+    assert!(matches!(
+      result,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::TimedOut
+      ))
+    ));
+    assert_eq!(started.elapsed(), Duration::from_secs(10));
+  }
+}
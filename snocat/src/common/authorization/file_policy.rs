@@ -0,0 +1,280 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! An [Authorizer] driven by an ordered list of rules, loaded from a JSON file
+//!
+//! ```json
+//! {
+//!   "default": "deny",
+//!   "rules": [
+//!     { "name": "no-ssh", "effect": "deny", "tunnels": ["*"], "connect": ["*:22"] },
+//!     { "name": "edges", "effect": "allow", "tunnels": ["edge-*"],
+//!       "routes": ["/proxyme/", "/snocat/"], "connect": ["localhost:*"], "listen": ["8000-8100"] }
+//!   ]
+//! }
+//! ```
+//!
+//! The first rule naming both the tunnel and the permission decides; if none do, the default applies.
+#![warn(unused_imports)]
+
+use std::path::Path;
+
+use super::traits::*;
+use crate::common::protocol::{
+  is_route_beneath,
+  tunnel::{TunnelId, TunnelName},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyEffect {
+  Allow,
+  Deny,
+}
+
+impl Default for PolicyEffect {
+  fn default() -> Self {
+    PolicyEffect::Deny
+  }
+}
+
+/// A rule granting or denying permissions to a set of tunnels
+///
+/// Patterns may be `*` to match anything, or end in `*` to match by prefix.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PolicyRule {
+  /// Identifies the rule in audit logs
+  pub name: String,
+  pub effect: PolicyEffect,
+  /// Tunnel name patterns to which the rule applies
+  pub tunnels: Vec<String>,
+  /// Route-address prefixes, matching whole path segments unless they end in `*`
+  #[serde(default)]
+  pub routes: Vec<String>,
+  /// `host:port` patterns; either part may be `*`
+  ///
+  /// Deny rules naming addresses also apply to host names which resolve to them.
+  #[serde(default)]
+  pub connect: Vec<String>,
+  /// Ports, inclusive `low-high` port ranges, or `*`
+  #[serde(default)]
+  pub listen: Vec<String>,
+}
+
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+  match pattern.strip_suffix('*') {
+    Some(prefix) => value.starts_with(prefix),
+    None => pattern == value,
+  }
+}
+
+fn parse_port_range(pattern: &str) -> Option<(u16, u16)> {
+  if pattern == "*" {
+    return Some((u16::MIN, u16::MAX));
+  }
+  let (low, high) = match pattern.split_once('-') {
+    Some((low, high)) => (low.parse().ok()?, high.parse().ok()?),
+    None => {
+      let port = pattern.parse().ok()?;
+      (port, port)
+    }
+  };
+  Some((low, high)).filter(|(low, high)| low <= high)
+}
+
+fn parse_connect_pattern(pattern: &str) -> Option<(&str, Option<u16>)> {
+  let (host, port) = pattern.rsplit_once(':')?;
+  match port {
+    "*" => Some((host, None)),
+    port => Some((host, Some(port.parse().ok()?))),
+  }
+}
+
+impl PolicyRule {
+  fn validate(&self) -> anyhow::Result<()> {
+    if let Some(bad) = self.listen.iter().find(|p| parse_port_range(p).is_none()) {
+      anyhow::bail!("Rule {:?} has invalid listen pattern {:?}", self.name, bad);
+    }
+    if let Some(bad) = self
+      .connect
+      .iter()
+      .find(|p| parse_connect_pattern(p).is_none())
+    {
+      anyhow::bail!("Rule {:?} has invalid connect pattern {:?}", self.name, bad);
+    }
+    Ok(())
+  }
+
+  fn applies_to(&self, tunnel_name: &TunnelName, permission: &Permission<'_>) -> bool {
+    if !self
+      .tunnels
+      .iter()
+      .any(|pattern| matches_pattern(pattern, tunnel_name.raw()))
+    {
+      return false;
+    }
+    match permission {
+      Permission::Route(addr) => {
+        self
+          .routes
+          .iter()
+          .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => addr.starts_with(prefix),
+            None => is_route_beneath(addr, pattern),
+          })
+      }
+      Permission::Connect { host, port } => {
+        self
+          .connect
+          .iter()
+          .any(|pattern| match parse_connect_pattern(pattern) {
+            Some((host_pattern, port_pattern)) => {
+              matches_pattern(host_pattern, host) && port_pattern.map_or(true, |p| p == *port)
+            }
+            None => false,
+          })
+      }
+      Permission::Listen { port } => self.listen.iter().any(|pattern| {
+        parse_port_range(pattern).map_or(false, |(low, high)| low <= *port && *port <= high)
+      }),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct FilePolicy {
+  /// Effect when no rule applies
+  #[serde(default)]
+  pub default: PolicyEffect,
+  pub rules: Vec<PolicyRule>,
+}
+
+impl FilePolicy {
+  pub fn new(default: PolicyEffect, rules: Vec<PolicyRule>) -> anyhow::Result<Self> {
+    for rule in rules.iter() {
+      rule.validate()?;
+    }
+    Ok(Self { default, rules })
+  }
+
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    use anyhow::Context;
+    let contents = std::fs::read(path)
+      .with_context(|| format!("Failed reading authorization policy {}", path.display()))?;
+    let policy: Self = serde_json::from_slice(&contents)
+      .with_context(|| format!("Failed parsing authorization policy {}", path.display()))?;
+    Self::new(policy.default, policy.rules)
+  }
+}
+
+impl Authorizer for FilePolicy {
+  fn authorize(
+    &self,
+    _tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+    permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    match self
+      .rules
+      .iter()
+      .find(|rule| rule.applies_to(tunnel_name, permission))
+    {
+      Some(rule) if rule.effect == PolicyEffect::Allow => Ok(()),
+      Some(rule) => Err(AuthorizationDenial::DeniedByRule(rule.name.clone())),
+      None if self.default == PolicyEffect::Allow => Ok(()),
+      None => Err(AuthorizationDenial::NotGranted),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::FilePolicy;
+  use crate::common::{
+    authorization::{AuthorizationDenial, Authorizer, Permission},
+    protocol::tunnel::{TunnelId, TunnelName},
+  };
+
+  #[test]
+  fn first_applicable_rule_decides() {
+    let policy: FilePolicy = serde_json::from_str(
+      r#"{
+        "rules": [
+          { "name": "no-ssh", "effect": "deny", "tunnels": ["*"], "connect": ["*:22"] },
+          { "name": "edges", "effect": "allow", "tunnels": ["edge-*"],
+            "routes": ["/proxyme/"], "connect": ["localhost:*"], "listen": ["8000-8100"] }
+        ]
+      }"#,
+    )
+    .unwrap();
+    let policy = FilePolicy::new(policy.default, policy.rules).unwrap();
+    let id = TunnelId::new(1);
+    let edge = TunnelName::new("edge-1");
+    let other = TunnelName::new("core");
+
+    assert_eq!(
+      policy.authorize(&id, &edge, &Permission::Route("/proxyme/80")),
+      Ok(())
+    );
+    assert_eq!(
+      policy.authorize(&id, &edge, &Permission::Listen { port: 8080 }),
+      Ok(())
+    );
+    assert_eq!(
+      policy.authorize(&id, &edge, &Permission::Listen { port: 9000 }),
+      Err(AuthorizationDenial::NotGranted)
+    );
+    assert_eq!(
+      policy.authorize(
+        &id,
+        &edge,
+        &Permission::Connect {
+          host: "localhost",
+          port: 22
+        }
+      ),
+      Err(AuthorizationDenial::DeniedByRule("no-ssh".into()))
+    );
+    assert_eq!(
+      policy.authorize(
+        &id,
+        &edge,
+        &Permission::Connect {
+          host: "localhost",
+          port: 80
+        }
+      ),
+      Ok(())
+    );
+    assert_eq!(
+      policy.authorize(&id, &other, &Permission::Route("/proxyme/80")),
+      Err(AuthorizationDenial::NotGranted)
+    );
+  }
+
+  #[test]
+  fn routes_match_whole_segments() {
+    let policy: FilePolicy = serde_json::from_str(
+      r#"{ "rules": [{ "name": "web", "effect": "allow", "tunnels": ["*"],
+        "routes": ["/proxyme/80", "/tcp/80*"] }] }"#,
+    )
+    .unwrap();
+    let id = TunnelId::new(1);
+    let name = TunnelName::new("edge");
+    let route = |addr| policy.authorize(&id, &name, &Permission::Route(addr));
+
+    assert_eq!(route("/proxyme/80"), Ok(()));
+    assert_eq!(route("/proxyme/80/"), Ok(()));
+    assert_eq!(route("/proxyme/80/more"), Ok(()));
+    assert_eq!(route("/proxyme/8080"), Err(AuthorizationDenial::NotGranted));
+    assert_eq!(route("/tcp/8080"), Ok(()));
+  }
+
+  #[test]
+  fn rejects_invalid_patterns() {
+    let policy: FilePolicy = serde_json::from_str(
+      r#"{ "rules": [{ "name": "bad", "effect": "allow", "tunnels": ["*"], "listen": ["90-80"] }] }"#,
+    )
+    .unwrap();
+    assert!(FilePolicy::new(policy.default, policy.rules).is_err());
+  }
+}
@@ -0,0 +1,9 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Types deciding what authenticated tunnels are permitted to do
+#[deny(unused_imports)]
+mod traits;
+pub use traits::*;
+
+mod file_policy;
+pub use file_policy::{FilePolicy, PolicyEffect, PolicyRule};
@@ -0,0 +1,268 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+#![warn(unused_imports)]
+
+use std::sync::{Arc, RwLock, Weak};
+
+use crate::common::protocol::{
+  traits::TunnelRegistry,
+  tunnel::{TunnelId, TunnelName},
+};
+
+/// Tracing target under which authorization denials are recorded
+pub const AUDIT_LOG_TARGET: &str = "snocat::audit";
+
+/// An action an authenticated tunnel may attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission<'a> {
+  /// Requesting the service at a route address
+  Route(&'a str),
+  /// Having a connection made to a host and port on the tunnel's behalf
+  Connect { host: &'a str, port: u16 },
+  /// Having a local port listen on the tunnel's behalf
+  Listen { port: u16 },
+}
+
+impl<'a> std::fmt::Display for Permission<'a> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Permission::Route(addr) => write!(f, "route {}", addr),
+      Permission::Connect { host, port } => write!(f, "connect {}:{}", host, port),
+      Permission::Listen { port } => write!(f, "listen {}", port),
+    }
+  }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizationDenial {
+  #[error("No policy grants the permission")]
+  NotGranted,
+  #[error("Permission denied by policy rule {0:?}")]
+  DeniedByRule(String),
+  #[error("Tunnel identity could not be determined")]
+  UnknownIdentity,
+}
+
+/// Decides whether an authenticated tunnel may perform an action
+pub trait Authorizer: std::fmt::Debug + Send + Sync {
+  fn authorize(
+    &self,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+    permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial>;
+}
+
+pub type ArcAuthorizer = Arc<dyn Authorizer + Send + Sync + 'static>;
+
+/// Permits everything; the behaviour of daemons without an authorization policy
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowAllAuthorizer;
+
+impl AllowAllAuthorizer {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+impl Authorizer for AllowAllAuthorizer {
+  fn authorize(
+    &self,
+    _tunnel_id: &TunnelId,
+    _tunnel_name: &TunnelName,
+    _permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    Ok(())
+  }
+}
+
+/// Permits only what every inner [Authorizer] permits, reporting the first denial
+#[derive(Debug)]
+pub struct AllOfAuthorizer {
+  authorizers: Vec<ArcAuthorizer>,
+}
+
+impl AllOfAuthorizer {
+  pub fn new(authorizers: Vec<ArcAuthorizer>) -> Self {
+    Self { authorizers }
+  }
+}
+
+impl Authorizer for AllOfAuthorizer {
+  fn authorize(
+    &self,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+    permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    self
+      .authorizers
+      .iter()
+      .try_for_each(|authorizer| authorizer.authorize(tunnel_id, tunnel_name, permission))
+  }
+}
+
+/// Delegates to an inner [Authorizer], which may be replaced while in use
+#[derive(Debug)]
+pub struct ReloadableAuthorizer {
+  current: RwLock<ArcAuthorizer>,
+}
+
+impl ReloadableAuthorizer {
+  pub fn new(authorizer: ArcAuthorizer) -> Self {
+    Self {
+      current: RwLock::new(authorizer),
+    }
+  }
+
+  pub fn current(&self) -> ArcAuthorizer {
+    Arc::clone(
+      &self
+        .current
+        .read()
+        .expect("Authorizer lock must not be poisoned"),
+    )
+  }
+
+  /// Consults `authorizer` for all future decisions, returning the authorizer it replaced
+  pub fn replace(&self, authorizer: ArcAuthorizer) -> ArcAuthorizer {
+    std::mem::replace(
+      &mut *self
+        .current
+        .write()
+        .expect("Authorizer lock must not be poisoned"),
+      authorizer,
+    )
+  }
+}
+
+impl Authorizer for ReloadableAuthorizer {
+  fn authorize(
+    &self,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+    permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    self.current().authorize(tunnel_id, tunnel_name, permission)
+  }
+}
+
+/// Consults `authorizer`, recording any denial in the audit log
+pub fn authorize_audited(
+  authorizer: &(impl Authorizer + ?Sized),
+  tunnel_id: &TunnelId,
+  tunnel_name: &TunnelName,
+  permission: &Permission<'_>,
+) -> Result<(), AuthorizationDenial> {
+  let result = authorizer.authorize(tunnel_id, tunnel_name, permission);
+  if let Err(denial) = &result {
+    audit_denial(tunnel_id, tunnel_name, permission, denial);
+  }
+  result
+}
+
+fn audit_denial(
+  tunnel_id: &TunnelId,
+  tunnel_name: &TunnelName,
+  permission: &Permission<'_>,
+  denial: &AuthorizationDenial,
+) {
+  tracing::warn!(
+    target: AUDIT_LOG_TARGET,
+    tunnel_id = tunnel_id.inner(),
+    tunnel_name = tunnel_name.raw(),
+    permission = %permission,
+    denial = %denial,
+    "Authorization denied"
+  );
+}
+
+/// Authorization for services, which know their requesting tunnel only by ID
+///
+/// The tunnel's name is looked up in the registry; tunnels without one are denied.
+pub struct ServiceAuthorization {
+  authorizer: ArcAuthorizer,
+  tunnel_registry: Weak<dyn TunnelRegistry + Send + Sync + 'static>,
+}
+
+impl ServiceAuthorization {
+  pub fn new(
+    authorizer: ArcAuthorizer,
+    tunnel_registry: Weak<dyn TunnelRegistry + Send + Sync + 'static>,
+  ) -> Self {
+    Self {
+      authorizer,
+      tunnel_registry,
+    }
+  }
+
+  pub async fn authorize(
+    &self,
+    tunnel_id: TunnelId,
+    permission: Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    let tunnel_name = self.tunnel_name(tunnel_id, &permission).await?;
+    authorize_audited(
+      self.authorizer.as_ref(),
+      &tunnel_id,
+      &tunnel_name,
+      &permission,
+    )
+  }
+
+  /// Fails only where a rule explicitly denies `permission`; lacking a grant is not a denial
+  ///
+  /// For checks made after [Self::authorize], such as on the addresses a permitted host
+  /// name resolves to, which rules may name but which grants need not list.
+  pub async fn refuse_denied(
+    &self,
+    tunnel_id: TunnelId,
+    permission: Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    let tunnel_name = self.tunnel_name(tunnel_id, &permission).await?;
+    match self
+      .authorizer
+      .authorize(&tunnel_id, &tunnel_name, &permission)
+    {
+      Ok(()) | Err(AuthorizationDenial::NotGranted) => Ok(()),
+      Err(denial) => {
+        audit_denial(&tunnel_id, &tunnel_name, &permission, &denial);
+        Err(denial)
+      }
+    }
+  }
+
+  async fn tunnel_name(
+    &self,
+    tunnel_id: TunnelId,
+    permission: &Permission<'_>,
+  ) -> Result<TunnelName, AuthorizationDenial> {
+    let tunnel_name = match self.tunnel_registry.upgrade() {
+      Some(registry) => registry
+        .lookup_by_id(tunnel_id)
+        .await
+        .and_then(|record| record.name),
+      None => None,
+    };
+    match tunnel_name {
+      Some(tunnel_name) => Ok(tunnel_name),
+      None => {
+        tracing::warn!(
+          target: AUDIT_LOG_TARGET,
+          tunnel_id = tunnel_id.inner(),
+          permission = %permission,
+          "Authorization denied to unidentified tunnel"
+        );
+        Err(AuthorizationDenial::UnknownIdentity)
+      }
+    }
+  }
+}
+
+impl std::fmt::Debug for ServiceAuthorization {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(ServiceAuthorization))
+      .field("authorizer", &self.authorizer)
+      .finish_non_exhaustive()
+  }
+}
@@ -22,6 +22,7 @@ use std::{
 };
 
 pub mod authentication;
+pub mod authorization;
 pub mod protocol;
 pub mod tunnel_source;
 
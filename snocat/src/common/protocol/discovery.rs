@@ -0,0 +1,264 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Service discovery, allowing a peer to ask which services it may request
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::Weak;
+use tokio::io::AsyncWriteExt;
+
+use super::{
+  traits::{ServiceRegistry, TunnelRegistry},
+  tunnel::TunnelId,
+  Client, ClientError, ProtocolVersion, RouteAddress, Service, ServiceDescriptor, ServiceError,
+};
+use crate::{
+  common::authorization::{ArcAuthorizer, Permission},
+  util::{framed, tunnel_stream::TunnelStream},
+};
+
+/// The reserved address at which peers may request a listing of available services
+pub const SERVICE_DISCOVERY_ADDRESS: &str = "/snocat/services";
+
+/// Protocol ID spoken by [ServiceDiscoveryService] and [ServiceDiscoveryClient]
+pub const SERVICE_DISCOVERY_PROTOCOL_ID: &str = "snocat.service-discovery";
+
+/// Answers with a framed JSON list of [ServiceDescriptor]s available to the requesting tunnel
+pub struct ServiceDiscoveryService {
+  service_registry: Weak<dyn ServiceRegistry + Send + Sync + 'static>,
+  tunnel_registry: Weak<dyn TunnelRegistry + Send + Sync + 'static>,
+  authorizer: Option<ArcAuthorizer>,
+}
+
+impl ServiceDiscoveryService {
+  /// Registries are held weakly, as the discovery service is usually held by its own registry
+  pub fn new(
+    service_registry: Weak<dyn ServiceRegistry + Send + Sync + 'static>,
+    tunnel_registry: Weak<dyn TunnelRegistry + Send + Sync + 'static>,
+  ) -> Self {
+    Self {
+      service_registry,
+      tunnel_registry,
+      authorizer: None,
+    }
+  }
+
+  /// Lists only services whose prefix `authorizer` permits the requesting tunnel to route to
+  ///
+  /// The whole prefix must be permitted: a tunnel granted only addresses beneath a service's
+  /// prefix, such as `/proxyme/80` beneath `/proxyme`, may still use them but will not see the
+  /// service listed.
+  pub fn with_authorizer(mut self, authorizer: ArcAuthorizer) -> Self {
+    self.authorizer = Some(authorizer);
+    self
+  }
+}
+
+impl std::fmt::Debug for ServiceDiscoveryService {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(ServiceDiscoveryService))
+      .field("authorizer", &self.authorizer)
+      .finish_non_exhaustive()
+  }
+}
+
+impl Service for ServiceDiscoveryService {
+  fn accepts(&self, addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+    addr == SERVICE_DISCOVERY_ADDRESS
+  }
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(SERVICE_DISCOVERY_PROTOCOL_ID)
+  }
+
+  fn description(&self) -> Option<String> {
+    Some(String::from("Lists the services available to this tunnel"))
+  }
+
+  fn handle<'a>(
+    &'a self,
+    _addr: RouteAddress,
+    _version: ProtocolVersion,
+    mut stream: Box<dyn TunnelStream + Send + 'static>,
+    tunnel_id: TunnelId,
+  ) -> BoxFuture<'a, Result<(), ServiceError>> {
+    async move {
+      let tunnel_registry = self
+        .tunnel_registry
+        .upgrade()
+        .ok_or(ServiceError::DependencyFailure)?;
+      let service_registry = self
+        .service_registry
+        .upgrade()
+        .ok_or(ServiceError::DependencyFailure)?;
+      // Services are scoped by name, so the tunnel must have finished authenticating
+      let tunnel_name = tunnel_registry
+        .lookup_by_id(tunnel_id)
+        .await
+        .and_then(|record| record.name)
+        .ok_or(ServiceError::Refused)?;
+      let mut descriptors = service_registry.enumerate_services(&tunnel_id, &tunnel_name);
+      if let Some(authorizer) = &self.authorizer {
+        // Unaudited, as listing is not an attempt to use the services
+        // Grants on addresses beneath a prefix do not cover the prefix itself, so hide it
+        descriptors.retain(|descriptor| {
+          authorizer
+            .authorize(
+              &tunnel_id,
+              &tunnel_name,
+              &Permission::Route(&descriptor.prefix),
+            )
+            .is_ok()
+        });
+      }
+      framed::write_framed_json(&mut stream, &descriptors)
+        .await
+        .map_err(|_| ServiceError::UnexpectedEnd)?;
+      stream
+        .shutdown()
+        .await
+        .map_err(|_| ServiceError::UnexpectedEnd)?;
+      Ok(())
+    }
+    .boxed()
+  }
+}
+
+/// Requests the list of services the remote will accept from this tunnel
+///
+/// Use with [SERVICE_DISCOVERY_ADDRESS].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ServiceDiscoveryClient;
+
+impl ServiceDiscoveryClient {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+impl Client for ServiceDiscoveryClient {
+  type Response = Vec<ServiceDescriptor>;
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(SERVICE_DISCOVERY_PROTOCOL_ID)
+  }
+
+  fn handle(
+    self,
+    _addr: RouteAddress,
+    _version: ProtocolVersion,
+    mut tunnel: Box<dyn TunnelStream + Send + 'static>,
+  ) -> BoxFuture<'static, Result<Self::Response, ClientError>> {
+    async move {
+      framed::read_framed_json(&mut tunnel)
+        .await
+        .map_err(|_| ClientError::IllegalResponse(None))
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::{
+    ServiceDiscoveryClient, ServiceDiscoveryService, SERVICE_DISCOVERY_ADDRESS,
+    SERVICE_DISCOVERY_PROTOCOL_ID,
+  };
+  use crate::common::authorization::{AuthorizationDenial, Authorizer, Permission};
+  use crate::common::protocol::{
+    service_registry::{ServiceScope, TrieServiceRegistry},
+    traits::{InMemoryTunnelRegistry, TunnelRegistry},
+    tunnel::{duplex, TunnelId, TunnelName},
+    Client, Service, ServiceDescriptor, VersionRange,
+  };
+  use crate::util::tunnel_stream::WrappedStream;
+
+  /// Denies routes beneath `/private`
+  #[derive(Debug)]
+  struct DenyPrivate;
+
+  impl Authorizer for DenyPrivate {
+    fn authorize(
+      &self,
+      _tunnel_id: &TunnelId,
+      _tunnel_name: &TunnelName,
+      permission: &Permission<'_>,
+    ) -> Result<(), AuthorizationDenial> {
+      match permission {
+        Permission::Route(addr) if addr.starts_with("/private") => {
+          Err(AuthorizationDenial::NotGranted)
+        }
+        _ => Ok(()),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn lists_services_for_named_tunnel() {
+    let tunnel_registry = Arc::new(InMemoryTunnelRegistry::new());
+    let duplex::EntangledTunnels {
+      listener: _listener,
+      connector,
+    } = duplex::channel();
+    let id = TunnelId::new(1);
+    tunnel_registry
+      .register_tunnel(id, Arc::new(connector))
+      .await
+      .unwrap();
+    tunnel_registry
+      .name_tunnel(id, TunnelName::new("edge"))
+      .await
+      .unwrap();
+
+    let service_registry = Arc::new(TrieServiceRegistry::new());
+    let discovery = Arc::new(
+      ServiceDiscoveryService::new(
+        Arc::downgrade(&service_registry) as _,
+        Arc::downgrade(&tunnel_registry) as _,
+      )
+      .with_authorizer(Arc::new(DenyPrivate)),
+    );
+    service_registry.register(
+      SERVICE_DISCOVERY_ADDRESS,
+      0,
+      ServiceScope::Global,
+      discovery.clone(),
+    );
+    service_registry.register(
+      "/hidden",
+      0,
+      ServiceScope::Named(TunnelName::new("elsewhere")),
+      discovery.clone(),
+    );
+    // Unauthorized services are not listed
+    service_registry.register("/private", 0, ServiceScope::Global, discovery.clone());
+
+    let (client_stream, service_stream) = WrappedStream::duplex(1024);
+    let (response, served) = futures::future::join(
+      ServiceDiscoveryClient::new().handle(
+        SERVICE_DISCOVERY_ADDRESS.into(),
+        0,
+        Box::new(client_stream),
+      ),
+      discovery.handle(
+        SERVICE_DISCOVERY_ADDRESS.into(),
+        0,
+        Box::new(service_stream),
+        id,
+      ),
+    )
+    .await;
+    served.unwrap();
+    assert_eq!(
+      response.unwrap(),
+      vec![ServiceDescriptor {
+        prefix: SERVICE_DISCOVERY_ADDRESS.into(),
+        protocol_id: Some(SERVICE_DISCOVERY_PROTOCOL_ID.into()),
+        versions: VersionRange::default(),
+        description: discovery.description(),
+      }]
+    );
+  }
+}
@@ -0,0 +1,248 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Application-level liveness probing between tunnel peers
+//!
+//! A ping opens a link, negotiates it to [HEARTBEAT_ADDRESS], and has a nonce echoed back by the
+//! remote's [HeartbeatService]. Round-trip times therefore include negotiation, showing that the
+//! remote daemon is processing requests rather than merely keeping its connection alive.
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{
+  negotiation::{NegotiationClient, NegotiationError},
+  tunnel::{TunnelError, TunnelId, TunnelUplink},
+  ProtocolVersion, RouteAddress, Service, ServiceError, VersionRange,
+};
+use crate::util::tunnel_stream::TunnelStream;
+
+/// The reserved address at which peers answer pings
+pub const HEARTBEAT_ADDRESS: &str = "/snocat/ping";
+
+/// Protocol ID spoken by [HeartbeatService] and [ping]
+pub const HEARTBEAT_PROTOCOL_ID: &str = "snocat.heartbeat";
+
+/// Pings not answered within this long are failures, unless otherwise configured
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Consecutive failed pings after which a tunnel is closed, unless otherwise configured
+pub const DEFAULT_HEARTBEAT_FAILURE_THRESHOLD: u32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PingError {
+  #[error("Failed to open a link for the ping")]
+  LinkFailed(#[from] TunnelError),
+  #[error("Remote refused the ping: {0}")]
+  NegotiationFailed(#[from] NegotiationError),
+  #[error("Ping stream failed")]
+  StreamFailed(#[from] std::io::Error),
+  #[error("Remote echoed the wrong nonce")]
+  NonceMismatch,
+  #[error("Ping timed out")]
+  TimedOut,
+}
+
+/// Pings the remote over a newly opened link, returning the round-trip time
+pub fn ping<'a>(
+  tunnel: &'a (dyn TunnelUplink + Send + Sync + 'a),
+) -> BoxFuture<'a, Result<Duration, PingError>> {
+  async move {
+    let started = Instant::now();
+    let link = tunnel.open_background_link().await?;
+    let (mut link, _version) =
+      NegotiationClient::new(Some(HEARTBEAT_PROTOCOL_ID.into()), VersionRange::default())
+        .handle(HEARTBEAT_ADDRESS.into(), link)
+        .await?;
+    let nonce: u64 = rand::random();
+    link.write_u64(nonce).await?;
+    link.flush().await?;
+    if link.read_u64().await? != nonce {
+      return Err(PingError::NonceMismatch);
+    }
+    Ok(started.elapsed())
+  }
+  .boxed()
+}
+
+/// Answers pings by echoing their nonce
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeartbeatService;
+
+impl HeartbeatService {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+impl Service for HeartbeatService {
+  fn accepts(&self, addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+    addr == HEARTBEAT_ADDRESS
+  }
+
+  fn protocol_id(&self) -> Option<&str> {
+    Some(HEARTBEAT_PROTOCOL_ID)
+  }
+
+  fn handle<'a>(
+    &'a self,
+    _addr: RouteAddress,
+    _version: ProtocolVersion,
+    mut stream: Box<dyn TunnelStream + Send + 'static>,
+    _tunnel_id: TunnelId,
+  ) -> BoxFuture<'a, Result<(), ServiceError>> {
+    async move {
+      let nonce = stream
+        .read_u64()
+        .await
+        .map_err(|_| ServiceError::UnexpectedEnd)?;
+      stream
+        .write_u64(nonce)
+        .await
+        .map_err(|_| ServiceError::UnexpectedEnd)?;
+      stream
+        .flush()
+        .await
+        .map_err(|_| ServiceError::UnexpectedEnd)?;
+      Ok(())
+    }
+    .boxed()
+  }
+}
+
+/// How often tunnels are pinged, and how many failures they may tolerate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatPolicy {
+  interval: Duration,
+  timeout: Duration,
+  failure_threshold: u32,
+}
+
+impl HeartbeatPolicy {
+  /// Pings every `interval`, with the default timeout and failure threshold
+  pub fn new(interval: Duration) -> Self {
+    Self {
+      interval,
+      timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+      failure_threshold: DEFAULT_HEARTBEAT_FAILURE_THRESHOLD,
+    }
+  }
+
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Closes tunnels after `failure_threshold` consecutive failed pings
+  pub fn with_failure_threshold(mut self, failure_threshold: u32) -> Self {
+    self.failure_threshold = failure_threshold;
+    self
+  }
+
+  pub fn interval(&self) -> Duration {
+    self.interval
+  }
+
+  pub fn timeout(&self) -> Duration {
+    self.timeout
+  }
+
+  pub fn failure_threshold(&self) -> u32 {
+    self.failure_threshold
+  }
+}
+
+/// The outcome of recent pings to a tunnel's remote
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TunnelHealth {
+  /// Round-trip time of the most recent successful ping
+  pub rtt: Option<Duration>,
+  /// When the most recent successful ping was answered
+  pub last_answered: Option<Instant>,
+  /// Failed pings since the last success
+  pub consecutive_failures: u32,
+}
+
+impl TunnelHealth {
+  /// Records the outcome of a ping, returning the consecutive failures since the last success
+  pub fn record(&mut self, outcome: &Result<Duration, PingError>) -> u32 {
+    match outcome {
+      Ok(rtt) => {
+        self.rtt = Some(*rtt);
+        self.last_answered = Some(Instant::now());
+        self.consecutive_failures = 0;
+      }
+      // Refusals still show that the remote daemon is processing requests
+      Err(PingError::NegotiationFailed(
+        NegotiationError::Overloaded | NegotiationError::ShuttingDown,
+      )) => {
+        self.last_answered = Some(Instant::now());
+        self.consecutive_failures = 0;
+      }
+      Err(_) => self.consecutive_failures += 1,
+    }
+    self.consecutive_failures
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::Arc, time::Duration};
+  use tokio::time::timeout;
+
+  use super::{ping, HeartbeatService, PingError, HEARTBEAT_ADDRESS};
+  use crate::common::{
+    authorization::AllowAllAuthorizer,
+    protocol::{
+      negotiation::{NegotiationError, NegotiationService},
+      service_registry::{ServiceScope, TrieServiceRegistry},
+      tunnel::{duplex, Tunnel, TunnelId, TunnelIncomingType, TunnelName},
+    },
+  };
+
+  #[tokio::test]
+  async fn ping_measures_round_trip() {
+    use futures::StreamExt;
+    let service_registry = Arc::new(TrieServiceRegistry::new());
+    let negotiator = NegotiationService::new(
+      Arc::clone(&service_registry),
+      Arc::new(AllowAllAuthorizer::new()),
+    );
+    let (local, remote) = duplex::channel().into();
+    let answer = async {
+      let mut downlink = remote.downlink().await.unwrap();
+      let mut incoming = downlink.as_stream();
+      for _ in 0..2usize {
+        let TunnelIncomingType::BiStream(link) = incoming.next().await.unwrap().unwrap();
+        let negotiated = negotiator
+          .negotiate(link, TunnelId::new(1), TunnelName::new("pinger"))
+          .await;
+        if let Ok((link, addr, version, service)) = negotiated {
+          let _ = service
+            .handle(addr, version, Box::new(link), TunnelId::new(1))
+            .await;
+        }
+      }
+    };
+    let pings = async {
+      // Until the service is registered, pings are refused
+      let refused = ping(&local).await;
+      assert!(matches!(
+        refused,
+        Err(PingError::NegotiationFailed(NegotiationError::Refused))
+      ));
+      service_registry.register(
+        HEARTBEAT_ADDRESS,
+        0,
+        ServiceScope::Global,
+        Arc::new(HeartbeatService::new()),
+      );
+      ping(&local).await
+    };
+    let ((), rtt) = timeout(Duration::from_secs(5), futures::future::join(answer, pings))
+      .await
+      .expect("Pings must not time out");
+    assert!(rtt.unwrap() < Duration::from_secs(5));
+  }
+}
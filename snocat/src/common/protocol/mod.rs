@@ -23,11 +23,17 @@
 
 pub mod traits;
 pub use traits::{
-  Client, ClientError, DynamicResponseClient, Request, Response, RouteAddress, Router,
-  RoutingError, Service, ServiceError,
+  is_route_beneath, Client, ClientError, DynamicResponseClient, Request, Response, RouteAddress,
+  RoutedLink, Router, RoutingError, Service, ServiceDescriptor, ServiceError,
 };
 
+pub mod discovery;
+pub mod heartbeat;
 pub mod negotiation;
 pub mod proxy_tcp;
 pub mod request_handler;
+pub mod routing;
+pub mod service_registry;
 pub mod tunnel;
+pub mod version;
+pub use version::{EmptyVersionRange, ProtocolVersion, VersionRange};
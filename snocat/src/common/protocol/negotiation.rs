@@ -7,11 +7,19 @@ use futures::{
   Future,
 };
 use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 use tracing_futures::Instrument;
 
-use crate::util::tunnel_stream::TunnelStream;
+use crate::{
+  common::authorization::{authorize_audited, ArcAuthorizer, Permission},
+  util::tunnel_stream::TunnelStream,
+};
 
-use super::{traits::ServiceRegistry, tunnel::TunnelId, RouteAddress, Service};
+use super::{
+  traits::ServiceRegistry,
+  tunnel::{TunnelId, TunnelName},
+  ProtocolVersion, RouteAddress, Service, VersionRange,
+};
 
 /// Identifies the SNOCAT protocol over a stream
 pub const SNOCAT_NEGOTIATION_MAGIC: &[u8; 4] = &[0x4e, 0x59, 0x41, 0x4e]; // UTF-8 "NYAN"
@@ -26,6 +34,12 @@ pub enum NegotiationError {
   ProtocolViolation,
   #[error("Protocol refused")]
   Refused,
+  #[error("Request not authorized")]
+  Unauthorized,
+  #[error("Remote is shutting down")]
+  ShuttingDown,
+  #[error("Remote is overloaded")]
+  Overloaded,
   #[error("Protocol version not supported")]
   UnsupportedProtocolVersion,
   #[error("Service version not supported")]
@@ -94,63 +108,126 @@ fn protocol_magic<'a, S: TunnelStream + Send + 'a>(
   .boxed()
 }
 
-pub struct NegotiationClient;
+/// The newest revision of the negotiation protocol spoken by this implementation
+///
+/// Both sides speak the lower of their two revisions.
+/// - v0 sends only the address, and refuses with any non-zero status.
+/// - v1 follows the address with a protocol ID and version range, and on acceptance
+///   replies with the highest version both sides support.
+///
+/// Implementations of v0 refuse remotes advertising any later revision, so cannot
+/// negotiate with this one.
+const NEGOTIATION_PROTOCOL_VERSION: u8 = 1;
+
+/// Status written by the service side in reply to a request
+mod status {
+  pub const ACCEPTED: u8 = 0;
+  pub const REFUSED: u8 = 1;
+  pub const UNSUPPORTED_SERVICE_VERSION: u8 = 2;
+  pub const UNAUTHORIZED: u8 = 3;
+  pub const SHUTTING_DOWN: u8 = 4;
+  pub const OVERLOADED: u8 = 5;
+}
+
+pub struct NegotiationClient {
+  protocol_id: Option<String>,
+  versions: VersionRange,
+}
 
 impl NegotiationClient {
-  pub fn new() -> Self {
-    Self {}
+  pub fn new(protocol_id: Option<String>, versions: VersionRange) -> Self {
+    Self {
+      protocol_id,
+      versions,
+    }
   }
 
+  /// Negotiates for the given address, returning the stream and the agreed protocol version
   pub fn handle<S>(
     self,
     addr: RouteAddress,
     mut link: S,
-  ) -> BoxFuture<'static, Result<S, NegotiationError>>
+  ) -> BoxFuture<'static, Result<(S, ProtocolVersion), NegotiationError>>
   where
     S: TunnelStream + Send + 'static,
   {
-    const LOCAL_PROTOCOL_VERSION: u8 = 0;
     async move {
-      // Absolute most-basic negotiation protocol - sends the address in a frame and waits for 0u8-or-fail
-
       tracing::trace!("performing negotiation protocol handshake");
-      let remote_version = protocol_magic(&mut link, LOCAL_PROTOCOL_VERSION).await?;
+      let remote_version = protocol_magic(&mut link, NEGOTIATION_PROTOCOL_VERSION).await?;
       // TODO: Consider adding a confirmation for negotiation protocol acceptance here
+      let negotiation_version = remote_version.min(NEGOTIATION_PROTOCOL_VERSION);
+      tracing::trace!(version = negotiation_version, "negotiation protocol agreed");
 
-      // TODO: support multiple versions of negotiation mechanism
-      if remote_version > 0 {
-        // We don't support anything beyond this basic protocol yet
-        tracing::trace!(
-          version = remote_version,
-          "unsupported remote protocol version"
-        );
-        return Err(NegotiationError::UnsupportedProtocolVersion);
+      // Remotes speaking v0 can only speak the unversioned form of each protocol
+      if negotiation_version == 0 && !self.versions.contains(0) {
+        return Err(NegotiationError::UnsupportedServiceVersion);
       }
 
-      // TODO: support service/client protocol versioning (May require Service/Client cooperation)
-
       tracing::trace!("writing address");
       // Write address to the remote, and see if the requested protocol is supported
       crate::util::framed::write_frame(&mut link, &addr.into_bytes())
         .await
         .map_err(|_| NegotiationError::WriteError)?;
 
+      if negotiation_version >= 1 {
+        tracing::trace!(protocol = ?self.protocol_id, versions = %self.versions, "writing protocol versions");
+        let protocol_id = self.protocol_id.unwrap_or_default();
+        crate::util::framed::write_frame(&mut link, protocol_id.as_bytes())
+          .await
+          .map_err(|_| NegotiationError::WriteError)?;
+        link
+          .write_u32(self.versions.min())
+          .await
+          .map_err(|_| NegotiationError::WriteError)?;
+        link
+          .write_u32(self.versions.max())
+          .await
+          .map_err(|_| NegotiationError::WriteError)?;
+      }
+
       tracing::trace!("awaiting remote protocol service acceptance");
       // Await acceptance of address by a service, or refusal if none are compatible
       let accepted = link
         .read_u8()
         .await
         .map_err(|_| NegotiationError::ReadError)?;
-      if accepted > 0 {
-        // For v0, this byte doesn't carry any useful info beyond accepted or not
-        tracing::trace!(
-          code = accepted,
-          "address refused by remote protocol services"
-        );
-        Err(NegotiationError::Refused)
-      } else {
-        tracing::trace!("address accepted by remote protocol services");
-        Ok(link)
+      match accepted {
+        status::ACCEPTED if negotiation_version >= 1 => {
+          let version = link
+            .read_u32()
+            .await
+            .map_err(|_| NegotiationError::ReadError)?;
+          if !self.versions.contains(version) {
+            tracing::trace!(version, "remote agreed upon a version outside of our range");
+            return Err(NegotiationError::ProtocolViolation);
+          }
+          tracing::trace!(version, "address accepted by remote protocol services");
+          Ok((link, version))
+        }
+        status::ACCEPTED => {
+          tracing::trace!("address accepted by remote protocol services");
+          Ok((link, 0))
+        }
+        status::UNSUPPORTED_SERVICE_VERSION if negotiation_version >= 1 => {
+          tracing::trace!("no mutually supported version of the requested protocol");
+          Err(NegotiationError::UnsupportedServiceVersion)
+        }
+        status::UNAUTHORIZED if negotiation_version >= 1 => {
+          tracing::trace!("remote did not authorize the request");
+          Err(NegotiationError::Unauthorized)
+        }
+        status::SHUTTING_DOWN if negotiation_version >= 1 => {
+          tracing::trace!("remote is shutting down");
+          Err(NegotiationError::ShuttingDown)
+        }
+        status::OVERLOADED if negotiation_version >= 1 => {
+          tracing::trace!("remote is overloaded");
+          Err(NegotiationError::Overloaded)
+        }
+        code => {
+          tracing::trace!(code, "address refused by remote protocol services");
+          Err(NegotiationError::Refused)
+        }
       }
     }
     .instrument(tracing::trace_span!("Client"))
@@ -160,21 +237,88 @@ impl NegotiationClient {
 
 pub struct NegotiationService<ServiceRegistry: ?Sized> {
   service_registry: Arc<ServiceRegistry>,
+  authorizer: ArcAuthorizer,
+  draining: Option<CancellationToken>,
 }
 
 pub type ArcService = Arc<dyn Service + Send + Sync + 'static>;
 
 impl<R: ?Sized> NegotiationService<R> {
-  pub fn new(service_registry: Arc<R>) -> Self {
-    Self { service_registry }
+  /// Requests are only passed to services once `authorizer` permits their route
+  pub fn new(service_registry: Arc<R>, authorizer: ArcAuthorizer) -> Self {
+    Self {
+      service_registry,
+      authorizer,
+      draining: None,
+    }
+  }
+
+  /// Refuses requests as shutting down once `draining` is cancelled
+  pub fn with_draining(mut self, draining: CancellationToken) -> Self {
+    self.draining = Some(draining);
+    self
+  }
+}
+
+/// Writes a refusal status to the remote, then fails with the given error
+async fn refuse<S: TunnelStream + Send>(
+  link: &mut S,
+  code: u8,
+  error: NegotiationError,
+) -> NegotiationError {
+  match link.write_u8(code).await {
+    Ok(()) => error,
+    Err(_) => NegotiationError::WriteError,
   }
 }
 
+/// Performs the handshake on the service side, and reads the remote's request
+///
+/// Returns the agreed negotiation version, the address, and any protocol ID and versions requested.
+async fn read_request<S: TunnelStream + Send>(
+  link: &mut S,
+) -> Result<(u8, String, Option<String>, VersionRange), NegotiationError> {
+  tracing::trace!("performing negotiation protocol handshake");
+  let remote_version = protocol_magic(link, NEGOTIATION_PROTOCOL_VERSION).await?;
+  // TODO: Consider adding a confirmation for negotiation protocol acceptance here
+  let negotiation_version = remote_version.min(NEGOTIATION_PROTOCOL_VERSION);
+  tracing::trace!(version = negotiation_version, "negotiation protocol agreed");
+
+  let addr = crate::util::framed::read_frame_vec(link)
+    .await
+    .map_err(|_| NegotiationError::ProtocolViolation)?; // Address must be sent as a frame
+
+  let addr = String::from_utf8(addr).map_err(|_| NegotiationError::ProtocolViolation)?; // Addresses must be valid UTF-8
+
+  let (protocol_id, versions) = if negotiation_version >= 1 {
+    let protocol_id = crate::util::framed::read_frame_vec(link)
+      .await
+      .map_err(|_| NegotiationError::ProtocolViolation)?;
+    let protocol_id =
+      String::from_utf8(protocol_id).map_err(|_| NegotiationError::ProtocolViolation)?;
+    let min = link
+      .read_u32()
+      .await
+      .map_err(|_| NegotiationError::ReadError)?;
+    let max = link
+      .read_u32()
+      .await
+      .map_err(|_| NegotiationError::ReadError)?;
+    let versions =
+      VersionRange::try_new(min, max).map_err(|_| NegotiationError::ProtocolViolation)?;
+    let protocol_id = Some(protocol_id).filter(|id| !id.is_empty());
+    (protocol_id, versions)
+  } else {
+    (None, VersionRange::default())
+  };
+  Ok((negotiation_version, addr, protocol_id, versions))
+}
+
 impl<R> NegotiationService<R>
 where
   R: ServiceRegistry + Send + Sync + ?Sized + 'static,
 {
-  /// Performs negotiation, returning the stream if successful
+  /// Performs negotiation, returning the stream, address, and agreed version if successful
   ///
   /// If the negotiation task is dropped, the stream is dropped in an indeterminate state.
   /// In scenarios involving an owned stream, this will drop the stream, otherwise the
@@ -184,54 +328,103 @@ where
     &self,
     mut link: S,
     tunnel_id: TunnelId,
-  ) -> BoxFuture<'a, Result<(S, RouteAddress, ArcService), NegotiationError>> {
-    const CURRENT_PROTOCOL_VERSION: u8 = 0u8;
+    tunnel_name: TunnelName,
+  ) -> BoxFuture<'a, Result<(S, RouteAddress, ProtocolVersion, ArcService), NegotiationError>> {
     let service_registry = Arc::clone(&self.service_registry);
+    let authorizer = Arc::clone(&self.authorizer);
+    let draining = self.draining.clone();
     async move {
-      tracing::trace!("performing negotiation protocol handshake");
-      let remote_version = protocol_magic(&mut link, CURRENT_PROTOCOL_VERSION).await?;
-      // TODO: Consider adding a confirmation for negotiation protocol acceptance here
-
-      if remote_version > 0 {
-        // This should map to multiple supported versions, where possible
-        tracing::trace!(
-          version = remote_version,
-          "unsupported remote protocol version"
-        );
-        return Err(NegotiationError::UnsupportedProtocolVersion);
+      let (negotiation_version, addr, protocol_id, versions) = read_request(&mut link).await?;
+
+      if draining.map_or(false, |draining| draining.is_cancelled()) {
+        tracing::trace!("refusing request while draining");
+        let code = if negotiation_version >= 1 {
+          status::SHUTTING_DOWN
+        } else {
+          status::REFUSED
+        };
+        return Err(refuse(&mut link, code, NegotiationError::ShuttingDown).await);
       }
 
-      let addr = crate::util::framed::read_frame_vec(&mut link)
-        .await
-        .map_err(|_| NegotiationError::ProtocolViolation)?; // Address must be sent as a frame in v0
-
-      let addr = String::from_utf8(addr).map_err(|_| NegotiationError::ProtocolViolation)?; // Addresses must be valid UTF-8
+      if authorize_audited(
+        authorizer.as_ref(),
+        &tunnel_id,
+        &tunnel_name,
+        &Permission::Route(&addr),
+      )
+      .is_err()
+      {
+        let code = if negotiation_version >= 1 {
+          status::UNAUTHORIZED
+        } else {
+          status::REFUSED
+        };
+        return Err(refuse(&mut link, code, NegotiationError::Unauthorized).await);
+      }
 
       tracing::trace!("searching service registry for address handlers");
-      let found = service_registry.find_service(&addr, &tunnel_id);
-
-      match found {
+      let service = match service_registry.find_service(&addr, &tunnel_id, &tunnel_name) {
+        Some(service) => service,
         None => {
-          // Write refusal
-          // v0 calls for a non-zero u8 to be written to the stream to refuse an address
           tracing::trace!("refusing address");
-          link
-            .write_u8(1)
-            .await
-            .map_err(|_| NegotiationError::WriteError)?;
-          Err(NegotiationError::Refused)
+          return Err(refuse(&mut link, status::REFUSED, NegotiationError::Refused).await);
         }
-        Some(service) => {
-          // Write acceptance
-          // v0 calls for a 0u8 to be written to the stream to accept an address
-          tracing::trace!("accepting address");
-          link
-            .write_u8(0)
-            .await
-            .map_err(|_| NegotiationError::WriteError)?;
-          Ok((link, addr, service))
+      };
+
+      if let (Some(requested), Some(offered)) = (protocol_id.as_deref(), service.protocol_id()) {
+        if requested != offered {
+          tracing::trace!(requested, offered, "refusing mismatched protocol");
+          return Err(refuse(&mut link, status::REFUSED, NegotiationError::Refused).await);
         }
       }
+
+      let version = match service.versions().highest_common(&versions) {
+        Some(version) => version,
+        None => {
+          tracing::trace!(requested = %versions, offered = %service.versions(), "refusing unsupported versions");
+          let code = if negotiation_version >= 1 {
+            status::UNSUPPORTED_SERVICE_VERSION
+          } else {
+            status::REFUSED
+          };
+          return Err(refuse(&mut link, code, NegotiationError::UnsupportedServiceVersion).await);
+        }
+      };
+
+      tracing::trace!(version, "accepting address");
+      link
+        .write_u8(status::ACCEPTED)
+        .await
+        .map_err(|_| NegotiationError::WriteError)?;
+      if negotiation_version >= 1 {
+        link
+          .write_u32(version)
+          .await
+          .map_err(|_| NegotiationError::WriteError)?;
+      }
+      Ok((link, addr, version, service))
+    }
+    .instrument(tracing::trace_span!("Service"))
+    .boxed()
+  }
+
+  /// Reads a request only to refuse it as overloaded, without consulting any services
+  pub fn refuse_overloaded<'a, S: TunnelStream + Send + 'a>(
+    &self,
+    mut link: S,
+  ) -> BoxFuture<'a, NegotiationError> {
+    async move {
+      let negotiation_version = match read_request(&mut link).await {
+        Ok((negotiation_version, ..)) => negotiation_version,
+        Err(e) => return e,
+      };
+      tracing::trace!("refusing request while overloaded");
+      let code = if negotiation_version >= 1 {
+        status::OVERLOADED
+      } else {
+        status::REFUSED
+      };
+      refuse(&mut link, code, NegotiationError::Overloaded).await
     }
     .instrument(tracing::trace_span!("Service"))
     .boxed()
@@ -240,6 +433,7 @@ where
 
 #[cfg(test)]
 mod tests {
+  use futures::FutureExt;
   use std::{
     sync::{Arc, Weak},
     time::Duration,
@@ -247,10 +441,13 @@ mod tests {
   use tokio::time::timeout;
 
   use super::{ArcService, NegotiationClient, NegotiationError, NegotiationService};
+  use crate::common::authorization::{
+    AllowAllAuthorizer, AuthorizationDenial, Authorizer, Permission,
+  };
   use crate::common::protocol::{
     traits::ServiceRegistry,
-    tunnel::{Tunnel, TunnelId},
-    Service,
+    tunnel::{Tunnel, TunnelId, TunnelName},
+    ProtocolVersion, Service, VersionRange,
   };
   use crate::util::tunnel_stream::TunnelStream;
 
@@ -263,6 +460,7 @@ mod tests {
       self: std::sync::Arc<Self>,
       addr: &crate::common::protocol::RouteAddress,
       tunnel_id: &TunnelId,
+      _tunnel_name: &TunnelName,
     ) -> Option<std::sync::Arc<dyn crate::common::protocol::Service + Send + Sync + 'static>> {
       self
         .services
@@ -286,6 +484,7 @@ mod tests {
     fn handle(
       &'_ self,
       _addr: crate::common::protocol::RouteAddress,
+      _version: ProtocolVersion,
       _stream: Box<dyn crate::util::tunnel_stream::TunnelStream + Send + 'static>,
       _tunnel_id: TunnelId,
     ) -> futures::future::BoxFuture<'_, Result<(), crate::common::protocol::ServiceError>> {
@@ -307,8 +506,8 @@ mod tests {
     let service_registry = TestServiceRegistry {
       services: vec![Arc::new(NoOpServiceAcceptAll)],
     };
-    let service = NegotiationService::new(Arc::new(service_registry));
-    let client = NegotiationClient::new();
+    let service = NegotiationService::new(Arc::new(service_registry), Arc::new(AllowAllAuthorizer));
+    let client = NegotiationClient::new(None, VersionRange::default());
     use crate::common::util::tunnel_stream::WrappedStream;
     let (client_stream, server_stream) = WrappedStream::duplex(8192);
 
@@ -319,8 +518,8 @@ mod tests {
 
     let server_future = async move {
       // server
-      let (_stream, addr, service) = service
-        .negotiate(server_stream, TunnelId::new(1u64))
+      let (_stream, addr, _version, service) = service
+        .negotiate(server_stream, TunnelId::new(1u64), TunnelName::new("test"))
         .await?;
       Result::<_, NegotiationError>::Ok((addr, service))
     };
@@ -329,4 +528,197 @@ mod tests {
     let ((), (addr, _service)) = fut.await.expect("Must not time out").unwrap();
     assert_eq!(addr.as_str(), TEST_ADDR);
   }
+
+  /// Accepts every address, speaking a named protocol at a range of versions
+  struct VersionedService(VersionRange);
+
+  impl Service for VersionedService {
+    fn accepts(
+      &self,
+      _addr: &crate::common::protocol::RouteAddress,
+      _tunnel_id: &TunnelId,
+    ) -> bool {
+      true
+    }
+
+    fn protocol_id(&self) -> Option<&str> {
+      Some("test-protocol")
+    }
+
+    fn versions(&self) -> VersionRange {
+      self.0
+    }
+
+    fn handle(
+      &'_ self,
+      _addr: crate::common::protocol::RouteAddress,
+      _version: ProtocolVersion,
+      _stream: Box<dyn crate::util::tunnel_stream::TunnelStream + Send + 'static>,
+      _tunnel_id: TunnelId,
+    ) -> futures::future::BoxFuture<'_, Result<(), crate::common::protocol::ServiceError>> {
+      futures::future::ready(Ok(())).boxed()
+    }
+  }
+
+  /// Negotiates against a [VersionedService], returning the client and service outcomes
+  async fn negotiate_versions(
+    offered: VersionRange,
+    protocol_id: Option<&str>,
+    requested: VersionRange,
+  ) -> (
+    Result<ProtocolVersion, NegotiationError>,
+    Result<ProtocolVersion, NegotiationError>,
+  ) {
+    use crate::common::util::tunnel_stream::WrappedStream;
+    let service_registry = TestServiceRegistry {
+      services: vec![Arc::new(VersionedService(offered))],
+    };
+    let service = NegotiationService::new(Arc::new(service_registry), Arc::new(AllowAllAuthorizer));
+    let client = NegotiationClient::new(protocol_id.map(String::from), requested);
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let client_future = client
+      .handle("/test".into(), client_stream)
+      .map(|r| r.map(|(_stream, version)| version));
+    let server_future = service
+      .negotiate(server_stream, TunnelId::new(1u64), TunnelName::new("test"))
+      .map(|r| r.map(|(_stream, _addr, version, _service)| version));
+    timeout(
+      Duration::from_secs(5),
+      futures::future::join(client_future, server_future),
+    )
+    .await
+    .expect("Must not time out")
+  }
+
+  #[tokio::test]
+  async fn negotiate_highest_common_version() {
+    let (client, server) = negotiate_versions(
+      VersionRange::new(1, 3),
+      Some("test-protocol"),
+      VersionRange::new(2, 5),
+    )
+    .await;
+    assert_eq!(client.unwrap(), 3);
+    assert_eq!(server.unwrap(), 3);
+
+    let (client, server) =
+      negotiate_versions(VersionRange::new(1, 3), None, VersionRange::exactly(1)).await;
+    assert_eq!(client.unwrap(), 1);
+    assert_eq!(server.unwrap(), 1);
+  }
+
+  #[tokio::test]
+  async fn negotiate_refuses_unsupported_versions() {
+    let (client, server) =
+      negotiate_versions(VersionRange::new(1, 3), None, VersionRange::new(4, 5)).await;
+    assert!(matches!(
+      client,
+      Err(NegotiationError::UnsupportedServiceVersion)
+    ));
+    assert!(matches!(
+      server,
+      Err(NegotiationError::UnsupportedServiceVersion)
+    ));
+  }
+
+  #[tokio::test]
+  async fn negotiate_refuses_mismatched_protocol() {
+    let (client, server) = negotiate_versions(
+      VersionRange::new(1, 3),
+      Some("other-protocol"),
+      VersionRange::new(1, 3),
+    )
+    .await;
+    assert!(matches!(client, Err(NegotiationError::Refused)));
+    assert!(matches!(server, Err(NegotiationError::Refused)));
+  }
+
+  /// Denies every route beginning with `/private`
+  #[derive(Debug)]
+  struct DenyPrivate;
+
+  impl Authorizer for DenyPrivate {
+    fn authorize(
+      &self,
+      _tunnel_id: &TunnelId,
+      _tunnel_name: &TunnelName,
+      permission: &Permission<'_>,
+    ) -> Result<(), AuthorizationDenial> {
+      match permission {
+        Permission::Route(addr) if addr.starts_with("/private") => {
+          Err(AuthorizationDenial::NotGranted)
+        }
+        _ => Ok(()),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn negotiate_refuses_unauthorized_routes() {
+    use crate::common::util::tunnel_stream::WrappedStream;
+    let service_registry = Arc::new(TestServiceRegistry {
+      services: vec![Arc::new(NoOpServiceAcceptAll)],
+    });
+    let service = NegotiationService::new(service_registry, Arc::new(DenyPrivate));
+    let client = NegotiationClient::new(None, VersionRange::default());
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let (client, server) = timeout(
+      Duration::from_secs(5),
+      futures::future::join(
+        client.handle("/private/db".into(), client_stream),
+        service.negotiate(server_stream, TunnelId::new(1u64), TunnelName::new("test")),
+      ),
+    )
+    .await
+    .expect("Must not time out");
+    assert!(matches!(client, Err(NegotiationError::Unauthorized)));
+    assert!(matches!(server, Err(NegotiationError::Unauthorized)));
+  }
+
+  #[tokio::test]
+  async fn negotiate_refuses_while_draining() {
+    use crate::common::util::tunnel_stream::WrappedStream;
+    let service_registry = Arc::new(TestServiceRegistry {
+      services: vec![Arc::new(NoOpServiceAcceptAll)],
+    });
+    let draining = tokio_util::sync::CancellationToken::new();
+    let service = NegotiationService::new(service_registry, Arc::new(AllowAllAuthorizer))
+      .with_draining(draining.clone());
+    draining.cancel();
+    let client = NegotiationClient::new(None, VersionRange::default());
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let (client, server) = timeout(
+      Duration::from_secs(5),
+      futures::future::join(
+        client.handle("/test/addr".into(), client_stream),
+        service.negotiate(server_stream, TunnelId::new(1u64), TunnelName::new("test")),
+      ),
+    )
+    .await
+    .expect("Must not time out");
+    assert!(matches!(client, Err(NegotiationError::ShuttingDown)));
+    assert!(matches!(server, Err(NegotiationError::ShuttingDown)));
+  }
+
+  #[tokio::test]
+  async fn negotiate_refuses_while_overloaded() {
+    use crate::common::util::tunnel_stream::WrappedStream;
+    let service_registry = Arc::new(TestServiceRegistry {
+      services: vec![Arc::new(NoOpServiceAcceptAll)],
+    });
+    let service = NegotiationService::new(service_registry, Arc::new(AllowAllAuthorizer));
+    let client = NegotiationClient::new(None, VersionRange::default());
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let (client, server) = timeout(
+      Duration::from_secs(5),
+      futures::future::join(
+        client.handle("/test/addr".into(), client_stream),
+        service.refuse_overloaded(server_stream),
+      ),
+    )
+    .await
+    .expect("Must not time out");
+    assert!(matches!(client, Err(NegotiationError::Overloaded)));
+    assert!(matches!(server, NegotiationError::Overloaded));
+  }
 }
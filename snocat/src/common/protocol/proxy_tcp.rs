@@ -8,7 +8,11 @@ use std::{
   fmt::Display,
   net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
   str::FromStr,
-  sync::Weak,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Weak,
+  },
+  time::Duration,
 };
 use tokio::{
   io::{AsyncRead, AsyncWrite},
@@ -18,20 +22,37 @@ use tracing_futures::Instrument;
 
 use super::{
   tunnel::{Tunnel, TunnelId},
-  Client, ClientError, DynamicResponseClient, Request, Response, RouteAddress, Router,
-  RoutingError, Service, ServiceError,
+  Client, ClientError, DynamicResponseClient, ProtocolVersion, Request, Response, RouteAddress,
+  Router, RoutingError, Service, ServiceError,
 };
-use crate::util::{proxy_generic_tokio_streams, tunnel_stream::TunnelStream};
+use crate::{
+  common::authorization::{Permission, ServiceAuthorization},
+  util::{proxy_generic_tokio_streams_with_idle_timeout, tunnel_stream::TunnelStream},
+};
+
+/// Protocol ID spoken by [TcpStreamClient] and [TcpStreamService]
+pub const TCP_PROXY_PROTOCOL_ID: &str = "snocat.tcp";
 
 #[derive(Debug, Clone)]
 pub struct TcpStreamClient<Reader, Writer> {
   recv: Reader,
   send: Writer,
+  idle_timeout: Option<Duration>,
 }
 
 impl<Reader, Writer> TcpStreamClient<Reader, Writer> {
   pub fn new(recv: Reader, send: Writer) -> Self {
-    Self { recv, send }
+    Self {
+      recv,
+      send,
+      idle_timeout: None,
+    }
+  }
+
+  /// Closes the stream once no bytes move in either direction for `timeout`, if one is given
+  pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.idle_timeout = timeout;
+    self
   }
 
   pub fn build_addr(target: TcpStreamTarget) -> RouteAddress {
@@ -47,18 +68,28 @@ where
   // TODO: make Response the number of bytes forwarded by the client
   type Response = ();
 
+  fn protocol_id(&self) -> Option<&str> {
+    Some(TCP_PROXY_PROTOCOL_ID)
+  }
+
   fn handle(
     mut self,
     _addr: RouteAddress,
+    _version: ProtocolVersion,
     tunnel: Box<dyn TunnelStream + Send + 'static>,
   ) -> BoxFuture<Result<Self::Response, ClientError>> {
     let fut = async move {
-      // TODO: Read protocol version here, and ServiceError::Refused if unsupported
-      // TODO: Send protocol version here, allow other side to refuse if unsupported
-      // If a confirmation of support is received by the reading side, resume as supported version
       let (mut tunr, mut tunw) = tokio::io::split(tunnel);
-      proxy_generic_tokio_streams((&mut self.send, &mut self.recv), (&mut tunw, &mut tunr)).await;
-      tracing::info!(target = "proxy_tcp_close", "Closing stream");
+      let res = proxy_generic_tokio_streams_with_idle_timeout(
+        (&mut self.send, &mut self.recv),
+        (&mut tunw, &mut tunr),
+        self.idle_timeout,
+      )
+      .await;
+      match res {
+        Ok(_) => tracing::info!(target = "proxy_tcp_close", "Closing stream"),
+        Err(idle) => tracing::info!(target = "proxy_tcp_close", %idle, "Closing idle stream"),
+      }
       Ok(())
     };
     fut.fuse().boxed()
@@ -68,6 +99,9 @@ where
 #[derive(Debug)]
 pub struct TcpStreamService {
   pub local_only: bool,
+  authorization: Option<ServiceAuthorization>,
+  idle_timeout: Option<Duration>,
+  streams_closed_idle: AtomicU64,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -233,7 +267,88 @@ impl FromStr for TcpStreamTarget {
 
 impl TcpStreamService {
   pub fn new(local_only: bool) -> Self {
-    Self { local_only }
+    Self {
+      local_only,
+      authorization: None,
+      idle_timeout: None,
+      streams_closed_idle: AtomicU64::new(0),
+    }
+  }
+
+  /// Only connects to destinations for which the requesting tunnel holds a `Connect` permission
+  pub fn with_authorization(mut self, authorization: ServiceAuthorization) -> Self {
+    self.authorization = Some(authorization);
+    self
+  }
+
+  /// Closes streams once no bytes move in either direction for `timeout`, if one is given
+  pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.idle_timeout = timeout;
+    self
+  }
+
+  /// The number of streams closed for exceeding the idle timeout
+  pub fn streams_closed_idle(&self) -> u64 {
+    self.streams_closed_idle.load(Ordering::Relaxed)
+  }
+
+  async fn authorize(
+    &self,
+    tunnel_id: TunnelId,
+    target: &TcpStreamTarget,
+  ) -> Result<(), ServiceError> {
+    let authorization = match &self.authorization {
+      Some(authorization) => authorization,
+      None => return Ok(()),
+    };
+    let (host, port) = match target {
+      TcpStreamTarget::Port(port) => (String::from("localhost"), *port),
+      TcpStreamTarget::SocketAddr(addr) => (addr.ip().to_string(), addr.port()),
+      TcpStreamTarget::Dns(
+        DnsTarget::PreferHigher { host, port }
+        | DnsTarget::Dns6 { host, port }
+        | DnsTarget::Dns4 { host, port },
+      ) => (host.clone(), *port),
+    };
+    authorization
+      .authorize(tunnel_id, Permission::Connect { host: &host, port })
+      .await
+      .map_err(ServiceError::Unauthorized)
+  }
+
+  /// Drops resolved addresses that a rule denies, as connect rules may name addresses
+  /// which a permitted host name resolves to
+  async fn authorize_resolved(
+    &self,
+    tunnel_id: TunnelId,
+    addrs: Vec<SocketAddr>,
+  ) -> Result<Vec<SocketAddr>, ServiceError> {
+    let authorization = match &self.authorization {
+      Some(authorization) => authorization,
+      None => return Ok(addrs),
+    };
+    let mut permitted = Vec::with_capacity(addrs.len());
+    let mut denial = None;
+    for addr in addrs {
+      let host = addr.ip().to_string();
+      match authorization
+        .refuse_denied(
+          tunnel_id,
+          Permission::Connect {
+            host: &host,
+            port: addr.port(),
+          },
+        )
+        .await
+      {
+        Ok(()) => permitted.push(addr),
+        Err(e) => denial = Some(e),
+      }
+    }
+    match denial {
+      Some(denial) if permitted.is_empty() => Err(ServiceError::Unauthorized(denial)),
+      _ => Ok(permitted),
+    }
   }
 
   /// The `connect` future outlives the read reference lifetime to `self`
@@ -301,11 +416,20 @@ impl Service for TcpStreamService {
     addr.parse::<TcpStreamTarget>().is_ok()
   }
 
+  fn protocol_id(&self) -> Option<&str> {
+    Some(TCP_PROXY_PROTOCOL_ID)
+  }
+
+  fn description(&self) -> Option<String> {
+    Some(String::from("Forwards streams to TCP destinations"))
+  }
+
   fn handle<'a>(
     &'a self,
     addr: RouteAddress,
+    _version: ProtocolVersion,
     stream: Box<dyn TunnelStream + Send + 'static>,
-    _tunnel_id: TunnelId,
+    tunnel_id: TunnelId,
   ) -> BoxFuture<'a, Result<(), ServiceError>> {
     use futures::future::Either;
     tracing::debug!(
@@ -321,13 +445,16 @@ impl Service for TcpStreamService {
       Ok(target) => target,
     };
     let fut = async move {
-      // TODO: Read protocol version here, and ServiceError::Refused if unsupported
-      // TODO: Send protocol version here, allow other side to refuse if unsupported
-      // If a confirmation of support is received by the reading side, resume as supported version
+      self.authorize(tunnel_id, &target).await?;
+      let by_name = matches!(target, TcpStreamTarget::Dns(_));
       let addrs = self
         .resolve(target)
         .await
         .or(Err(ServiceError::AddressError))?;
+      let addrs = match by_name {
+        true => self.authorize_resolved(tunnel_id, addrs).await?,
+        false => addrs,
+      };
       let connector = self.connect(addrs);
       tracing::debug!(
         target = "proxy_tcp_connecting",
@@ -345,11 +472,101 @@ impl Service for TcpStreamService {
       let (mut tcpr, mut tcpw) = connection.split();
       let (mut tunr, mut tunw) = tokio::io::split(stream);
 
-      proxy_generic_tokio_streams((&mut tcpw, &mut tcpr), (&mut tunw, &mut tunr)).await;
-      tracing::info!(target = "proxy_tcp_close", "Closing stream");
+      let res = proxy_generic_tokio_streams_with_idle_timeout(
+        (&mut tcpw, &mut tcpr),
+        (&mut tunw, &mut tunr),
+        self.idle_timeout,
+      )
+      .await;
+      match res {
+        Ok(_) => tracing::info!(target = "proxy_tcp_close", "Closing stream"),
+        Err(idle) => {
+          self.streams_closed_idle.fetch_add(1, Ordering::Relaxed);
+          tracing::info!(target = "proxy_tcp_close", %idle, "Closing idle stream");
+        }
+      }
       Ok(())
     };
 
     fut.instrument(span).boxed()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::TcpStreamService;
+  use crate::common::authorization::{AuthorizationDenial, FilePolicy, ServiceAuthorization};
+  use crate::common::protocol::{
+    traits::{InMemoryTunnelRegistry, TunnelRegistry},
+    tunnel::{duplex, TunnelId, TunnelName},
+    Service, ServiceError,
+  };
+  use crate::util::tunnel_stream::WrappedStream;
+
+  async fn connect_by_name(policy: &str) -> Result<(), ServiceError> {
+    let tunnel_registry = Arc::new(InMemoryTunnelRegistry::new());
+    let duplex::EntangledTunnels {
+      listener: _listener,
+      connector,
+    } = duplex::channel();
+    let id = TunnelId::new(1);
+    tunnel_registry
+      .register_tunnel(id, Arc::new(connector))
+      .await
+      .unwrap();
+    tunnel_registry
+      .name_tunnel(id, TunnelName::new("edge"))
+      .await
+      .unwrap();
+    let policy: FilePolicy = serde_json::from_str(policy).unwrap();
+    let policy = FilePolicy::new(policy.default, policy.rules).unwrap();
+    let service = TcpStreamService::new(false).with_authorization(ServiceAuthorization::new(
+      Arc::new(policy),
+      Arc::downgrade(&tunnel_registry) as _,
+    ));
+    let (_client_stream, service_stream) = WrappedStream::duplex(1024);
+    service
+      .handle(
+        "/dns/localhost/tcp/1".into(),
+        0,
+        Box::new(service_stream),
+        id,
+      )
+      .await
+  }
+
+  #[tokio::test]
+  async fn connect_rules_apply_to_resolved_addresses() {
+    let allowed = connect_by_name(
+      r#"{ "rules": [
+        { "name": "names", "effect": "allow", "tunnels": ["*"], "connect": ["localhost:*"] }
+      ] }"#,
+    )
+    .await;
+    // Authorized, so the attempt reaches the (closed) port
+    assert!(
+      matches!(allowed, Err(ServiceError::DependencyFailure)),
+      "{:?}",
+      allowed
+    );
+
+    let denied = connect_by_name(
+      r#"{ "rules": [
+        { "name": "loopback", "effect": "deny", "tunnels": ["*"],
+          "connect": ["127.0.0.1:*", "::1:*"] },
+        { "name": "names", "effect": "allow", "tunnels": ["*"], "connect": ["localhost:*"] }
+      ] }"#,
+    )
+    .await;
+    assert!(
+      matches!(
+        &denied,
+        Err(ServiceError::Unauthorized(AuthorizationDenial::DeniedByRule(rule))) if rule == "loopback"
+      ),
+      "{:?}",
+      denied
+    );
+  }
+}
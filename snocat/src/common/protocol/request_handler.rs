@@ -6,13 +6,16 @@ use std::{backtrace::Backtrace, sync::Arc};
 
 use crate::common::protocol::{
   traits::{ServiceRegistry, TunnelRegistry},
-  Client, Request, Response, RouteAddress, Router, RoutingError,
+  Client, ProtocolVersion, Request, Response, RouteAddress, Router, RoutingError,
 };
 use crate::{
   common::protocol::ClientError,
   util::tunnel_stream::{TunnelStream, WrappedStream},
 };
 
+/// A link which has completed negotiation, and the protocol version agreed upon for it
+pub type NegotiatedLink = (Box<dyn TunnelStream + Send + 'static>, ProtocolVersion);
+
 pub struct RequestClientHandler {
   tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync + 'static>,
   service_registry: Arc<dyn ServiceRegistry + Send + Sync + 'static>,
@@ -99,13 +102,22 @@ impl RequestClientHandler {
   ) -> BoxFuture<'static, Result<Response, RequestHandlingError>> {
     async move {
       tracing::trace!("Running protocol negotiation");
-      let link = self.negotiate_link(&direct_address, link).await?;
+      let negotiation_client = NegotiationClient::new(
+        request
+          .protocol_client
+          .dynamic_protocol_id()
+          .map(String::from),
+        request.protocol_client.dynamic_versions(),
+      );
+      let (link, version) = self
+        .negotiate_link(&direct_address, negotiation_client, link)
+        .await?;
       tracing::trace!("Running protocol client");
       use tracing_futures::Instrument;
       let protocol_client_span = tracing::debug_span!("protocol_client", addr=?direct_address);
       let result = request
         .protocol_client
-        .handle_dynamic(direct_address, link)
+        .handle_dynamic(direct_address, version, link)
         .instrument(protocol_client_span)
         .await;
 
@@ -133,7 +145,7 @@ impl RequestClientHandler {
     async move {
       // Note: Type-annotated because rust-analyzer fails to resolve typings here on its own
       let (resolved_address, link): (RouteAddress, Box<dyn TunnelStream + Send + 'static>) =
-        match router.route(&request, tunnel_registry).await {
+        match router.route(&request.address, tunnel_registry).await {
           Err(RoutingError::NoMatchingTunnel) => {
             return Err(RequestHandlingError::RouteNotFound(request));
           }
@@ -153,15 +165,15 @@ impl RequestClientHandler {
   pub fn negotiate_link(
     self: Arc<Self>,
     addr: &RouteAddress,
+    negotiation_client: NegotiationClient,
     link: Box<dyn TunnelStream + Send + 'static>,
-  ) -> BoxFuture<'static, Result<Box<dyn TunnelStream + Send + 'static>, NegotiationError>> {
+  ) -> BoxFuture<'static, Result<NegotiatedLink, NegotiationError>> {
     use tracing_futures::Instrument;
     let addr = addr.clone();
-    let negotiation_client = NegotiationClient;
     let negotiation_span = tracing::debug_span!("negotiation", addr=?addr);
     async move {
-      let link = negotiation_client.handle(addr, link).await?;
-      Ok(link)
+      let (link, version) = negotiation_client.handle(addr, link).await?;
+      Ok((link, version))
     }
     .instrument(negotiation_span)
     .boxed()
@@ -0,0 +1,282 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! A [Router] that spreads requests across several candidate tunnels
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use rand::seq::SliceRandom;
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use crate::{
+  common::protocol::{
+    traits::{TunnelRecord, TunnelRegistry},
+    RouteAddress, RoutedLink, Router, RoutingError,
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+/// Produces the set of tunnels which may serve a request for the given address
+///
+/// For [BalancingStrategy::RoundRobin] to rotate fairly, candidates should be
+/// produced in a stable order, such as sorted by [TunnelId](super::super::tunnel::TunnelId).
+pub type CandidateSelector = Box<
+  dyn Fn(
+      &RouteAddress,
+      Arc<dyn TunnelRegistry + Send + Sync>,
+    ) -> BoxFuture<'static, Vec<TunnelRecord>>
+    + Send
+    + Sync
+    + 'static,
+>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BalancingStrategy {
+  /// Rotate through candidates, starting one further along for each request
+  RoundRobin,
+  /// Try candidates in a uniformly random order
+  Random,
+  /// Prefer tunnels with the fewest open streams, as reported by the tunnel
+  ///
+  /// Tunnels which do not track their streams are tried last.
+  LeastActiveStreams,
+  /// Prefer tunnels with the lowest round-trip-time estimate, as reported by the tunnel
+  ///
+  /// Tunnels which do not measure round-trip-time, such as in-memory tunnels, are tried last.
+  LowestRtt,
+}
+
+/// Routes each request to one of several candidate tunnels, chosen by a [BalancingStrategy]
+///
+/// If a chosen tunnel fails to open a link, the next candidate is tried, until
+/// either a link is opened or `max_attempts` candidates have failed.
+pub struct BalancingRouter {
+  select_candidates: CandidateSelector,
+  strategy: BalancingStrategy,
+  max_attempts: usize,
+  rotation: AtomicUsize,
+}
+
+impl BalancingRouter {
+  pub fn new<F>(select_candidates: F, strategy: BalancingStrategy, max_attempts: usize) -> Self
+  where
+    F: Fn(
+        &RouteAddress,
+        Arc<dyn TunnelRegistry + Send + Sync>,
+      ) -> BoxFuture<'static, Vec<TunnelRecord>>
+      + Send
+      + Sync
+      + 'static,
+  {
+    assert!(max_attempts > 0, "At least one routing attempt is required");
+    Self {
+      select_candidates: Box::new(select_candidates),
+      strategy,
+      max_attempts,
+      rotation: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn strategy(&self) -> BalancingStrategy {
+    self.strategy
+  }
+
+  pub fn max_attempts(&self) -> usize {
+    self.max_attempts
+  }
+
+  /// Orders candidates by preference; the first candidate is attempted first
+  fn order_candidates(&self, mut candidates: Vec<TunnelRecord>) -> Vec<TunnelRecord> {
+    if candidates.len() < 2 {
+      return candidates;
+    }
+    match self.strategy {
+      BalancingStrategy::RoundRobin => {
+        let offset = self.rotation.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        candidates.rotate_left(offset);
+      }
+      BalancingStrategy::Random => {
+        candidates.shuffle(&mut rand::thread_rng());
+      }
+      BalancingStrategy::LeastActiveStreams => {
+        candidates.sort_by_key(|record| record.tunnel.active_streams().unwrap_or(usize::MAX));
+      }
+      BalancingStrategy::LowestRtt => {
+        candidates.sort_by_key(|record| record.tunnel.rtt().unwrap_or(Duration::MAX));
+      }
+    }
+    candidates
+  }
+}
+
+impl std::fmt::Debug for BalancingRouter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(BalancingRouter))
+      .field("strategy", &self.strategy)
+      .field("max_attempts", &self.max_attempts)
+      .finish_non_exhaustive()
+  }
+}
+
+impl Router for BalancingRouter {
+  fn route(
+    &self,
+    address: &RouteAddress,
+    tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
+  ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+    let addr = address.clone();
+    let candidates = (self.select_candidates)(&addr, tunnel_registry);
+    async move {
+      let candidates = self.order_candidates(candidates.await);
+      if candidates.is_empty() {
+        return Err(RoutingError::NoMatchingTunnel);
+      }
+      let mut last_error = None;
+      for candidate in candidates.into_iter().take(self.max_attempts) {
+        match candidate.tunnel.open_link().await {
+          Ok(link) => {
+            let boxed_link: Box<dyn TunnelStream + Send + Sync + 'static> = Box::new(link);
+            return Ok((addr, boxed_link));
+          }
+          Err(e) => {
+            tracing::debug!(id = ?candidate.id, error = ?e, "Candidate tunnel failed to open a link");
+            last_error = Some(e);
+          }
+        }
+      }
+      Err(RoutingError::LinkOpenFailure(last_error.expect(
+        "At least one candidate must have been attempted",
+      )))
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::FutureExt;
+  use std::sync::Arc;
+
+  use super::{BalancingRouter, BalancingStrategy};
+  use crate::common::protocol::{
+    traits::{InMemoryTunnelRegistry, TunnelRegistry},
+    tunnel::{duplex, Tunnel, TunnelId},
+    RouteAddress, Router, RoutingError,
+  };
+
+  /// Registers `count` connector-side duplex tunnels, returning their listeners and the registry
+  async fn registry_with_tunnels(
+    count: u64,
+  ) -> (Arc<InMemoryTunnelRegistry>, Vec<duplex::DuplexTunnel>) {
+    let registry = Arc::new(InMemoryTunnelRegistry::new());
+    let mut listeners = Vec::new();
+    for id in 0..count {
+      let duplex::EntangledTunnels {
+        listener,
+        connector,
+      } = duplex::channel();
+      registry
+        .register_tunnel(TunnelId::new(id), Arc::new(connector))
+        .await
+        .unwrap();
+      listeners.push(listener);
+    }
+    (registry, listeners)
+  }
+
+  fn router_over(
+    registry: &Arc<InMemoryTunnelRegistry>,
+    strategy: BalancingStrategy,
+    max_attempts: usize,
+  ) -> BalancingRouter {
+    let registry = Arc::clone(registry);
+    BalancingRouter::new(
+      move |_addr, _tunnel_registry| {
+        let registry = Arc::clone(&registry);
+        async move { registry.records().await }.boxed()
+      },
+      strategy,
+      max_attempts,
+    )
+  }
+
+  async fn active_streams_of(registry: &InMemoryTunnelRegistry, id: u64) -> usize {
+    registry
+      .lookup_by_id(TunnelId::new(id))
+      .await
+      .unwrap()
+      .tunnel
+      .active_streams()
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn round_robin_rotates() {
+    let (registry, _listeners) = registry_with_tunnels(3).await;
+    let router = router_over(&registry, BalancingStrategy::RoundRobin, 1);
+    let registry_dyn: Arc<dyn TunnelRegistry + Send + Sync> = registry.clone();
+    let address: RouteAddress = "/test".into();
+    let mut links = Vec::new();
+    for expected_id in 0..3u64 {
+      let (_addr, link) = router
+        .route(&address, Arc::clone(&registry_dyn))
+        .await
+        .unwrap();
+      links.push(link);
+      assert_eq!(active_streams_of(&registry, expected_id).await, 1);
+    }
+  }
+
+  #[tokio::test]
+  async fn least_active_streams_prefers_idle_tunnels() {
+    let (registry, _listeners) = registry_with_tunnels(2).await;
+    let busy = registry
+      .lookup_by_id(TunnelId::new(0))
+      .await
+      .unwrap()
+      .tunnel
+      .open_link()
+      .await
+      .unwrap();
+    let router = router_over(&registry, BalancingStrategy::LeastActiveStreams, 1);
+    let address: RouteAddress = "/test".into();
+    let (_addr, _link) = router.route(&address, registry.clone()).await.unwrap();
+    assert_eq!(active_streams_of(&registry, 0).await, 1);
+    assert_eq!(active_streams_of(&registry, 1).await, 1);
+    drop(busy);
+  }
+
+  #[tokio::test]
+  async fn retries_on_link_open_failure() {
+    let (registry, mut listeners) = registry_with_tunnels(2).await;
+    // Dropping the remote side of the first tunnel causes its links to fail to open
+    drop(listeners.remove(0));
+    let address: RouteAddress = "/test".into();
+
+    let single_attempt = router_over(&registry, BalancingStrategy::RoundRobin, 1);
+    assert!(matches!(
+      single_attempt.route(&address, registry.clone()).await,
+      Err(RoutingError::LinkOpenFailure(_))
+    ));
+
+    let retrying = router_over(&registry, BalancingStrategy::RoundRobin, 2);
+    let (_addr, _link) = retrying.route(&address, registry.clone()).await.unwrap();
+    assert_eq!(active_streams_of(&registry, 1).await, 1);
+  }
+
+  #[tokio::test]
+  async fn no_candidates() {
+    let (registry, _listeners) = registry_with_tunnels(0).await;
+    let router = router_over(&registry, BalancingStrategy::Random, 3);
+    let address: RouteAddress = "/test".into();
+    assert!(matches!(
+      router.route(&address, registry.clone()).await,
+      Err(RoutingError::NoMatchingTunnel)
+    ));
+  }
+}
@@ -0,0 +1,9 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Reusable [Router](super::Router) implementations
+
+pub mod balancing;
+pub use balancing::{BalancingRouter, BalancingStrategy, CandidateSelector};
+
+pub mod prefix;
+pub use prefix::{NamedTunnelRouter, PrefixRewrite, PrefixRoute, PrefixRouter};
@@ -0,0 +1,330 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Composition of [Router]s by address prefix
+#![warn(unused_imports)]
+
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::Arc;
+
+use crate::{
+  common::protocol::{
+    traits::TunnelRegistry, tunnel::TunnelName, RouteAddress, RoutedLink, Router, RoutingError,
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+/// How a [PrefixRoute] alters an address before handing it to its inner router
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefixRewrite {
+  /// Remove the matched prefix, such that `/local/tcp/...` becomes `/tcp/...`
+  Strip,
+  /// Pass the address through unaltered
+  Keep,
+  /// Substitute the matched prefix with another, such that `/local/...` may become `/loopback/...`
+  Replace(RouteAddress),
+}
+
+/// Matches addresses beneath a prefix, sending them to an inner router after rewriting
+///
+/// Prefixes match whole path segments only; `/local` matches `/local` and `/local/tcp`,
+/// but not `/localhost`. A trailing slash on the prefix is ignored.
+pub struct PrefixRoute {
+  prefix: RouteAddress,
+  rewrite: PrefixRewrite,
+  router: Arc<dyn Router + Send + Sync + 'static>,
+}
+
+impl PrefixRoute {
+  pub fn new<P: Into<RouteAddress>>(
+    prefix: P,
+    rewrite: PrefixRewrite,
+    router: Arc<dyn Router + Send + Sync + 'static>,
+  ) -> Self {
+    let mut prefix: RouteAddress = prefix.into();
+    while prefix.ends_with('/') {
+      prefix.pop();
+    }
+    Self {
+      prefix,
+      rewrite,
+      router,
+    }
+  }
+
+  pub fn prefix(&self) -> &str {
+    &self.prefix
+  }
+
+  /// Returns the rewritten address if the route matches the given address
+  pub fn rewrite(&self, address: &str) -> Option<RouteAddress> {
+    let remainder = address.strip_prefix(self.prefix.as_str())?;
+    if !(remainder.is_empty() || remainder.starts_with('/')) {
+      return None;
+    }
+    Some(match &self.rewrite {
+      PrefixRewrite::Keep => address.to_string(),
+      PrefixRewrite::Strip if remainder.is_empty() => String::from("/"),
+      PrefixRewrite::Strip => remainder.to_string(),
+      PrefixRewrite::Replace(replacement) => {
+        let mut rewritten = replacement.trim_end_matches('/').to_string();
+        rewritten.push_str(remainder);
+        rewritten
+      }
+    })
+  }
+}
+
+impl std::fmt::Debug for PrefixRoute {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(PrefixRoute))
+      .field("prefix", &self.prefix)
+      .field("rewrite", &self.rewrite)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Dispatches each address to the first [PrefixRoute] which matches it
+///
+/// Routes are checked in the order they were provided. Addresses matching no route are
+/// given, unaltered, to the fallback router if one is present, or refused otherwise.
+pub struct PrefixRouter {
+  routes: Vec<PrefixRoute>,
+  fallback: Option<Arc<dyn Router + Send + Sync + 'static>>,
+}
+
+impl PrefixRouter {
+  pub fn new(
+    routes: Vec<PrefixRoute>,
+    fallback: Option<Arc<dyn Router + Send + Sync + 'static>>,
+  ) -> Self {
+    Self { routes, fallback }
+  }
+
+  pub fn routes(&self) -> &[PrefixRoute] {
+    &self.routes
+  }
+
+  /// Selects the router responsible for an address, and the address to give it
+  fn resolve(
+    &self,
+    address: &str,
+  ) -> Option<(&Arc<dyn Router + Send + Sync + 'static>, RouteAddress)> {
+    self
+      .routes
+      .iter()
+      .find_map(|route| Some((&route.router, route.rewrite(address)?)))
+      .or_else(|| Some((self.fallback.as_ref()?, address.to_string())))
+  }
+}
+
+impl std::fmt::Debug for PrefixRouter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(PrefixRouter))
+      .field("routes", &self.routes)
+      .field("fallback", &self.fallback.is_some())
+      .finish()
+  }
+}
+
+impl Router for PrefixRouter {
+  fn route(
+    &self,
+    address: &RouteAddress,
+    tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
+  ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+    match self.resolve(address) {
+      Some((router, rewritten)) => {
+        tracing::trace!(from = ?address, to = ?rewritten, "Rewrote address by prefix");
+        async move { router.route(&rewritten, tunnel_registry).await }.boxed()
+      }
+      None => futures::future::ready(Err(RoutingError::NoMatchingTunnel)).boxed(),
+    }
+  }
+}
+
+/// Routes `/<tunnel-name>/...` to the tunnel registered under that name, as `/...`
+///
+/// Typically mounted beneath a [PrefixRouter] with [PrefixRewrite::Strip], such that
+/// `/tunnel/<tunnel-name>/...` addresses a specific tunnel.
+#[derive(Debug, Default)]
+pub struct NamedTunnelRouter;
+
+impl NamedTunnelRouter {
+  pub fn new() -> Self {
+    Self
+  }
+
+  /// Splits an address into its leading tunnel name and the remaining address
+  fn split_name(address: &str) -> Option<(TunnelName, RouteAddress)> {
+    let path = address.strip_prefix('/')?;
+    let (name, remainder) = match path.find('/') {
+      Some(index) => path.split_at(index),
+      None => (path, "/"),
+    };
+    if name.is_empty() {
+      return None;
+    }
+    Some((TunnelName::new(name), remainder.to_string()))
+  }
+}
+
+impl Router for NamedTunnelRouter {
+  fn route(
+    &self,
+    address: &RouteAddress,
+    tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
+  ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+    let split = Self::split_name(address);
+    async move {
+      let (name, remainder) = split.ok_or(RoutingError::NoMatchingTunnel)?;
+      let record = tunnel_registry
+        .lookup_by_name(name)
+        .await
+        .ok_or(RoutingError::NoMatchingTunnel)?;
+      let link = record
+        .tunnel
+        .open_link()
+        .await
+        .map_err(RoutingError::LinkOpenFailure)?;
+      let boxed_link: Box<dyn TunnelStream + Send + Sync + 'static> = Box::new(link);
+      Ok((remainder, boxed_link))
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::{BoxFuture, FutureExt};
+  use std::sync::{Arc, Mutex};
+
+  use super::{NamedTunnelRouter, PrefixRewrite, PrefixRoute, PrefixRouter};
+  use crate::common::protocol::{
+    traits::{InMemoryTunnelRegistry, TunnelRegistry},
+    tunnel::{duplex, TunnelId, TunnelName},
+    RouteAddress, RoutedLink, Router, RoutingError,
+  };
+  use crate::util::tunnel_stream::WrappedStream;
+
+  /// Records the addresses it is given, and answers each with an unconnected stream
+  #[derive(Default)]
+  struct RecordingRouter {
+    seen: Mutex<Vec<RouteAddress>>,
+  }
+
+  impl RecordingRouter {
+    fn seen(&self) -> Vec<RouteAddress> {
+      self.seen.lock().unwrap().clone()
+    }
+  }
+
+  impl Router for RecordingRouter {
+    fn route(
+      &self,
+      address: &RouteAddress,
+      _tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
+    ) -> BoxFuture<'_, Result<RoutedLink, RoutingError>> {
+      self.seen.lock().unwrap().push(address.clone());
+      let (stream, _) = WrappedStream::duplex(16);
+      futures::future::ready(Ok((address.clone(), Box::new(stream) as Box<_>))).boxed()
+    }
+  }
+
+  fn registry() -> Arc<dyn TunnelRegistry + Send + Sync> {
+    Arc::new(InMemoryTunnelRegistry::new())
+  }
+
+  #[test]
+  fn rewrites_whole_segments() {
+    let inner = Arc::new(RecordingRouter::default());
+    let strip = PrefixRoute::new("/local/", PrefixRewrite::Strip, inner.clone());
+    assert_eq!(strip.rewrite("/local/tcp/x").as_deref(), Some("/tcp/x"));
+    assert_eq!(strip.rewrite("/local").as_deref(), Some("/"));
+    assert_eq!(strip.rewrite("/localhost/tcp"), None);
+    assert_eq!(strip.rewrite("/other/local"), None);
+
+    let keep = PrefixRoute::new("/local", PrefixRewrite::Keep, inner.clone());
+    assert_eq!(keep.rewrite("/local/tcp").as_deref(), Some("/local/tcp"));
+
+    let replace = PrefixRoute::new("/local", PrefixRewrite::Replace("/loopback/".into()), inner);
+    assert_eq!(
+      replace.rewrite("/local/tcp").as_deref(),
+      Some("/loopback/tcp")
+    );
+  }
+
+  #[tokio::test]
+  async fn first_match_wins_then_fallback() {
+    let first = Arc::new(RecordingRouter::default());
+    let second = Arc::new(RecordingRouter::default());
+    let fallback = Arc::new(RecordingRouter::default());
+    let router = PrefixRouter::new(
+      vec![
+        PrefixRoute::new("/a", PrefixRewrite::Strip, first.clone()),
+        PrefixRoute::new("/a", PrefixRewrite::Keep, second.clone()),
+      ],
+      Some(fallback.clone()),
+    );
+    let (addr, _) = router.route(&"/a/b".into(), registry()).await.unwrap();
+    assert_eq!(addr, "/b");
+    let (addr, _) = router.route(&"/c/d".into(), registry()).await.unwrap();
+    assert_eq!(addr, "/c/d");
+    assert_eq!(first.seen(), vec!["/b".to_string()]);
+    assert!(second.seen().is_empty());
+    assert_eq!(fallback.seen(), vec!["/c/d".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn no_fallback_refuses_unmatched() {
+    let router = PrefixRouter::new(
+      vec![PrefixRoute::new(
+        "/a",
+        PrefixRewrite::Strip,
+        Arc::new(RecordingRouter::default()),
+      )],
+      None,
+    );
+    assert!(matches!(
+      router.route(&"/b".into(), registry()).await,
+      Err(RoutingError::NoMatchingTunnel)
+    ));
+  }
+
+  #[tokio::test]
+  async fn named_tunnel_beneath_prefix() {
+    let tunnel_registry = Arc::new(InMemoryTunnelRegistry::new());
+    let duplex::EntangledTunnels {
+      listener: _listener,
+      connector,
+    } = duplex::channel();
+    let id = TunnelId::new(1);
+    tunnel_registry
+      .register_tunnel(id, Arc::new(connector))
+      .await
+      .unwrap();
+    tunnel_registry
+      .name_tunnel(id, TunnelName::new("edge"))
+      .await
+      .unwrap();
+    let router = PrefixRouter::new(
+      vec![PrefixRoute::new(
+        "/tunnel",
+        PrefixRewrite::Strip,
+        Arc::new(NamedTunnelRouter::new()),
+      )],
+      None,
+    );
+
+    let (addr, _link) = router
+      .route(&"/tunnel/edge/tcp/x".into(), tunnel_registry.clone())
+      .await
+      .unwrap();
+    assert_eq!(addr, "/tcp/x");
+    assert!(matches!(
+      router
+        .route(&"/tunnel/elsewhere/tcp/x".into(), tunnel_registry.clone())
+        .await,
+      Err(RoutingError::NoMatchingTunnel)
+    ));
+  }
+}
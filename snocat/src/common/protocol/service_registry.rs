@@ -0,0 +1,378 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! A [ServiceRegistry] indexing services by the route-address prefixes they serve
+#![warn(unused_imports)]
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+  },
+};
+
+use super::{
+  negotiation::ArcService,
+  traits::ServiceRegistry,
+  tunnel::{TunnelId, TunnelName},
+  RouteAddress, ServiceDescriptor,
+};
+
+/// Identifies a registration within a [TrieServiceRegistry], for later removal
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServiceHandle(u64);
+
+/// Restricts which tunnels a registered service is offered to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServiceScope {
+  /// Offered on every tunnel
+  Global,
+  /// Offered only on the tunnel with the given ID
+  Tunnel(TunnelId),
+  /// Offered only on tunnels authenticated under the given name
+  Named(TunnelName),
+}
+
+impl ServiceScope {
+  pub fn includes(&self, tunnel_id: &TunnelId, tunnel_name: &TunnelName) -> bool {
+    match self {
+      ServiceScope::Global => true,
+      ServiceScope::Tunnel(id) => id == tunnel_id,
+      ServiceScope::Named(name) => name == tunnel_name,
+    }
+  }
+}
+
+impl Default for ServiceScope {
+  fn default() -> Self {
+    ServiceScope::Global
+  }
+}
+
+struct Registration {
+  handle: ServiceHandle,
+  priority: i32,
+  scope: ServiceScope,
+  service: ArcService,
+}
+
+#[derive(Default)]
+struct TrieNode {
+  children: BTreeMap<String, TrieNode>,
+  registrations: Vec<Registration>,
+}
+
+impl TrieNode {
+  fn is_empty(&self) -> bool {
+    self.children.is_empty() && self.registrations.is_empty()
+  }
+
+  /// Removes a registration beneath the given path, pruning any nodes left empty
+  fn remove(&mut self, path: &[String], handle: ServiceHandle) -> Option<Registration> {
+    match path.split_first() {
+      None => {
+        let index = self.registrations.iter().position(|r| r.handle == handle)?;
+        Some(self.registrations.remove(index))
+      }
+      Some((segment, rest)) => {
+        let child = self.children.get_mut(segment)?;
+        let removed = child.remove(rest, handle);
+        if child.is_empty() {
+          self.children.remove(segment);
+        }
+        removed
+      }
+    }
+  }
+
+  /// Visits every registration in the trie, depth-first, alongside its path segments
+  fn visit<'a>(&'a self, path: &mut Vec<&'a str>, visitor: &mut dyn FnMut(&[&str], &Registration)) {
+    for registration in &self.registrations {
+      visitor(path, registration);
+    }
+    for (segment, child) in &self.children {
+      path.push(segment);
+      child.visit(path, visitor);
+      path.pop();
+    }
+  }
+}
+
+#[derive(Default)]
+struct Index {
+  root: TrieNode,
+  paths: HashMap<ServiceHandle, Vec<String>>,
+}
+
+/// Splits an address into its non-empty path segments
+fn segments(address: &str) -> impl Iterator<Item = &str> {
+  address.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Finds services by the longest route-address prefixes they were registered beneath
+///
+/// Every service registered at a prefix of the requested address is a candidate,
+/// and candidates are asked whether they [accept](super::Service::accepts) the address
+/// in order of descending priority, then of longest prefix, then of registration.
+/// The first to accept is chosen. Services may be added and removed while in use.
+pub struct TrieServiceRegistry {
+  index: RwLock<Index>,
+  next_handle: AtomicU64,
+}
+
+impl TrieServiceRegistry {
+  pub fn new() -> Self {
+    Self {
+      index: RwLock::new(Index::default()),
+      next_handle: AtomicU64::new(0),
+    }
+  }
+
+  /// Registers a service for addresses beneath `prefix`, such as `/proxyme` or `/`
+  ///
+  /// Prefixes are matched by whole path segments.
+  pub fn register(
+    &self,
+    prefix: &str,
+    priority: i32,
+    scope: ServiceScope,
+    service: ArcService,
+  ) -> ServiceHandle {
+    let handle = ServiceHandle(self.next_handle.fetch_add(1, Ordering::Relaxed));
+    let path: Vec<String> = segments(prefix).map(String::from).collect();
+    let mut index = self.index.write().expect("Service registry lock poisoned");
+    let node = path.iter().fold(&mut index.root, |node, segment| {
+      node.children.entry(segment.clone()).or_default()
+    });
+    node.registrations.push(Registration {
+      handle,
+      priority,
+      scope,
+      service,
+    });
+    index.paths.insert(handle, path);
+    handle
+  }
+
+  /// Removes a previously registered service, returning it if it was still present
+  ///
+  /// Negotiations already handed to the service are unaffected.
+  pub fn deregister(&self, handle: ServiceHandle) -> Option<ArcService> {
+    let mut index = self.index.write().expect("Service registry lock poisoned");
+    let path = index.paths.remove(&handle)?;
+    index
+      .root
+      .remove(&path, handle)
+      .map(|registration| registration.service)
+  }
+
+  pub fn len(&self) -> usize {
+    self
+      .index
+      .read()
+      .expect("Service registry lock poisoned")
+      .paths
+      .len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl Default for TrieServiceRegistry {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl std::fmt::Debug for TrieServiceRegistry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(TrieServiceRegistry))
+      .field("services", &self.len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl ServiceRegistry for TrieServiceRegistry {
+  fn find_service(
+    self: Arc<Self>,
+    addr: &RouteAddress,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+  ) -> Option<ArcService> {
+    let index = self.index.read().expect("Service registry lock poisoned");
+    // Gather candidates along the address path, tagged by the depth of their prefix
+    let mut candidates: Vec<(usize, &Registration)> = Vec::new();
+    let mut node = Some(&index.root);
+    let mut path = segments(addr);
+    let mut depth = 0;
+    while let Some(current) = node {
+      candidates.extend(current.registrations.iter().map(|r| (depth, r)));
+      node = path
+        .next()
+        .and_then(|segment| current.children.get(segment));
+      depth += 1;
+    }
+    candidates.sort_by(|(a_depth, a), (b_depth, b)| {
+      b.priority
+        .cmp(&a.priority)
+        .then(b_depth.cmp(a_depth))
+        .then(a.handle.cmp(&b.handle))
+    });
+    candidates
+      .into_iter()
+      .map(|(_, registration)| registration)
+      .filter(|registration| registration.scope.includes(tunnel_id, tunnel_name))
+      .find(|registration| registration.service.accepts(addr, tunnel_id))
+      .map(|registration| Arc::clone(&registration.service))
+  }
+
+  fn enumerate_services(
+    self: Arc<Self>,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+  ) -> Vec<ServiceDescriptor> {
+    let index = self.index.read().expect("Service registry lock poisoned");
+    let mut descriptors = Vec::new();
+    index
+      .root
+      .visit(&mut Vec::new(), &mut |path, registration| {
+        if registration.scope.includes(tunnel_id, tunnel_name) {
+          descriptors.push(ServiceDescriptor {
+            prefix: format!("/{}", path.join("/")),
+            protocol_id: registration.service.protocol_id().map(String::from),
+            versions: registration.service.versions(),
+            description: registration.service.description(),
+          });
+        }
+      });
+    descriptors
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::{BoxFuture, FutureExt};
+  use std::sync::Arc;
+
+  use super::{ServiceScope, TrieServiceRegistry};
+  use crate::common::protocol::{
+    negotiation::ArcService,
+    traits::ServiceRegistry,
+    tunnel::{TunnelId, TunnelName},
+    ProtocolVersion, RouteAddress, Service, ServiceError,
+  };
+  use crate::util::tunnel_stream::TunnelStream;
+
+  struct AcceptAllService;
+
+  impl Service for AcceptAllService {
+    fn accepts(&self, _addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+      true
+    }
+
+    fn handle(
+      &'_ self,
+      _addr: RouteAddress,
+      _version: ProtocolVersion,
+      _stream: Box<dyn TunnelStream + Send + 'static>,
+      _tunnel_id: TunnelId,
+    ) -> BoxFuture<'_, Result<(), ServiceError>> {
+      futures::future::ready(Ok(())).boxed()
+    }
+  }
+
+  fn service() -> ArcService {
+    Arc::new(AcceptAllService)
+  }
+
+  /// Asserts that the registry resolves the address to exactly the expected service instance
+  fn assert_finds(
+    registry: &Arc<TrieServiceRegistry>,
+    (addr, id, name): (&str, u64, &str),
+    expected: Option<&ArcService>,
+  ) {
+    let found =
+      Arc::clone(registry).find_service(&addr.into(), &TunnelId::new(id), &TunnelName::new(name));
+    match (found, expected) {
+      (Some(found), Some(expected)) => assert_eq!(
+        Arc::as_ptr(&found) as *const (),
+        Arc::as_ptr(expected) as *const (),
+        "{}",
+        addr
+      ),
+      (None, None) => (),
+      (found, _) => panic!("Unexpected resolution for {}: {}", addr, found.is_some()),
+    }
+  }
+
+  #[test]
+  fn longest_prefix_then_priority() {
+    let registry = Arc::new(TrieServiceRegistry::new());
+    let (root, a, ab, urgent) = (service(), service(), service(), service());
+    registry.register("/", 0, ServiceScope::Global, root.clone());
+    registry.register("/a", 0, ServiceScope::Global, a.clone());
+    registry.register("/a/b", 0, ServiceScope::Global, ab.clone());
+    assert_finds(&registry, ("/a/b/c", 1, "t"), Some(&ab));
+    assert_finds(&registry, ("/a/bc", 1, "t"), Some(&a));
+    assert_finds(&registry, ("/z", 1, "t"), Some(&root));
+
+    registry.register("/", 10, ServiceScope::Global, urgent.clone());
+    assert_finds(&registry, ("/a/b/c", 1, "t"), Some(&urgent));
+  }
+
+  #[test]
+  fn scoped_services() {
+    let registry = Arc::new(TrieServiceRegistry::new());
+    let (global, by_id, by_name) = (service(), service(), service());
+    registry.register("/x", 0, ServiceScope::Global, global.clone());
+    registry.register(
+      "/x",
+      1,
+      ServiceScope::Tunnel(TunnelId::new(7)),
+      by_id.clone(),
+    );
+    registry.register(
+      "/x",
+      2,
+      ServiceScope::Named(TunnelName::new("edge")),
+      by_name.clone(),
+    );
+    assert_finds(&registry, ("/x", 1, "other"), Some(&global));
+    assert_finds(&registry, ("/x", 7, "other"), Some(&by_id));
+    assert_finds(&registry, ("/x", 7, "edge"), Some(&by_name));
+  }
+
+  #[test]
+  fn deregister_by_handle() {
+    let registry = Arc::new(TrieServiceRegistry::new());
+    let (a, ab) = (service(), service());
+    let outer = registry.register("/a", 0, ServiceScope::Global, a.clone());
+    let inner = registry.register("/a/b", 0, ServiceScope::Global, ab);
+    assert_eq!(registry.len(), 2);
+    assert!(registry.deregister(inner).is_some());
+    assert!(registry.deregister(inner).is_none());
+    assert_finds(&registry, ("/a/b", 1, "t"), Some(&a));
+    assert!(registry.deregister(outer).is_some());
+    assert_finds(&registry, ("/a/b", 1, "t"), None);
+    assert!(registry.is_empty());
+  }
+
+  #[test]
+  fn enumerates_services_in_scope() {
+    let registry = Arc::new(TrieServiceRegistry::new());
+    registry.register("/", 0, ServiceScope::Global, service());
+    registry.register("/a/b", 0, ServiceScope::Global, service());
+    registry.register("/c", 0, ServiceScope::Tunnel(TunnelId::new(7)), service());
+    let prefixes = |id| {
+      Arc::clone(&registry)
+        .enumerate_services(&TunnelId::new(id), &TunnelName::new("t"))
+        .into_iter()
+        .map(|descriptor| descriptor.prefix)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(prefixes(1), vec!["/", "/a/b"]);
+    assert_eq!(prefixes(7), vec!["/", "/a/b", "/c"]);
+  }
+}
@@ -11,11 +11,25 @@ use std::{
   sync::{Arc, Weak},
 };
 
-use super::tunnel::{Tunnel, TunnelId, TunnelName};
+use super::{
+  tunnel::{Tunnel, TunnelId, TunnelName},
+  version::{ProtocolVersion, VersionRange},
+};
 use crate::common::protocol::tunnel::TunnelError;
 
 pub type RouteAddress = String;
 
+/// Whether `address` is `prefix` or lies beneath it, matching whole path segments
+///
+/// `/local` matches `/local` and `/local/tcp`, but not `/localhost`. A trailing slash on
+/// the prefix is ignored.
+pub fn is_route_beneath(address: &str, prefix: &str) -> bool {
+  match address.strip_prefix(prefix.trim_end_matches('/')) {
+    Some(remainder) => remainder.is_empty() || remainder.starts_with('/'),
+    None => false,
+  }
+}
+
 pub struct Request {
   pub address: RouteAddress,
   pub protocol_client: Box<dyn DynamicResponseClient + Send + Sync + 'static>,
@@ -176,6 +190,11 @@ impl InMemoryTunnelRegistry {
     let lock = self.tunnels.lock().await;
     lock.keys().max().cloned()
   }
+
+  pub async fn records(&self) -> Vec<TunnelRecord> {
+    let lock = self.tunnels.lock().await;
+    lock.values().cloned().collect()
+  }
 }
 
 impl TunnelRegistry for InMemoryTunnelRegistry {
@@ -369,6 +388,9 @@ pub enum RoutingError {
   LinkOpenFailure(TunnelError),
 }
 
+/// The address to hand to the protocol client, and the link it should be handed over
+pub type RoutedLink = (RouteAddress, Box<dyn TunnelStream + Send + Sync + 'static>);
+
 /// Routers are responsible for taking an address and forwarding it to
 /// the appropriate tunnel. When forwarding, the router can alter the
 /// address to remove any routing-specific information before it is
@@ -376,10 +398,9 @@ pub enum RoutingError {
 pub trait Router: Downcast + DowncastSync {
   fn route(
     &self,
-    //TODO: Consider taking only a [RouteAddress] here, except if other request metadata is desired
-    request: &Request,
+    address: &RouteAddress,
     tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync>,
-  ) -> BoxFuture<Result<(RouteAddress, Box<dyn TunnelStream + Send + Sync + 'static>), RoutingError>>;
+  ) -> BoxFuture<Result<RoutedLink, RoutingError>>;
 }
 impl_downcast!(sync Router);
 
@@ -398,17 +419,35 @@ pub enum ClientError {
 pub trait Client {
   type Response: Send + 'static;
 
+  /// Identifies the protocol spoken by the client, which must match that of the remote service
+  ///
+  /// Unidentified protocols are matched against any service which accepts the address.
+  fn protocol_id(&self) -> Option<&str> {
+    None
+  }
+
+  /// The protocol versions the client can speak; negotiation agrees on the highest in common
+  fn versions(&self) -> VersionRange {
+    VersionRange::default()
+  }
+
   fn handle(
     self,
     addr: RouteAddress,
+    version: ProtocolVersion,
     tunnel: Box<dyn TunnelStream + Send + 'static>,
   ) -> BoxFuture<Result<Self::Response, ClientError>>;
 }
 
 pub trait DynamicResponseClient: Send {
+  fn dynamic_protocol_id(&self) -> Option<&str>;
+
+  fn dynamic_versions(&self) -> VersionRange;
+
   fn handle_dynamic(
     self: Box<Self>,
     addr: RouteAddress,
+    version: ProtocolVersion,
     tunnel: Box<dyn TunnelStream + Send + 'static>,
   ) -> BoxFuture<Result<Response, ClientError>>;
 }
@@ -419,12 +458,21 @@ where
   TResponse: Any + Send + 'static,
   Self: Sized,
 {
+  fn dynamic_protocol_id(&self) -> Option<&str> {
+    Client::protocol_id(self)
+  }
+
+  fn dynamic_versions(&self) -> VersionRange {
+    Client::versions(self)
+  }
+
   fn handle_dynamic(
     self: Box<Self>,
     addr: RouteAddress,
+    version: ProtocolVersion,
     tunnel: Box<dyn TunnelStream + Send + 'static>,
   ) -> BoxFuture<Result<Response, ClientError>> {
-    Client::handle(*self, addr, tunnel)
+    Client::handle(*self, addr, version, tunnel)
       .map(|result| result.map(|inner| Response::new(Box::new(inner))))
       .boxed()
   }
@@ -440,6 +488,10 @@ pub enum ServiceError {
   IllegalResponse,
   #[error("Invalid address provided by remote client")]
   AddressError,
+  #[error("Request not authorized: {0}")]
+  Unauthorized(#[from] crate::common::authorization::AuthorizationDenial),
+  #[error("Service panicked: {0}")]
+  Panicked(String),
   #[error("An internal dependency failed")]
   DependencyFailure,
   #[error("An internal dependency failed with a backtrace")]
@@ -450,20 +502,57 @@ pub enum ServiceError {
 
 pub trait Service {
   fn accepts(&self, addr: &RouteAddress, tunnel_id: &TunnelId) -> bool;
-  // fn protocol_id() -> String where Self: Sized;
+
+  /// Identifies the protocol spoken by the service; clients naming another protocol are refused
+  fn protocol_id(&self) -> Option<&str> {
+    None
+  }
+
+  /// The protocol versions the service can speak; negotiation agrees on the highest in common
+  fn versions(&self) -> VersionRange {
+    VersionRange::default()
+  }
+
+  /// A human-readable summary of the service, offered to remotes through service discovery
+  fn description(&self) -> Option<String> {
+    None
+  }
 
   fn handle<'a>(
     &'a self,
     addr: RouteAddress,
+    version: ProtocolVersion,
     stream: Box<dyn TunnelStream + Send + 'static>,
     tunnel_id: TunnelId,
   ) -> BoxFuture<'a, Result<(), ServiceError>>;
 }
 
+/// Describes a service to remote peers, as reported by service discovery
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ServiceDescriptor {
+  /// The route-address prefix beneath which the service is registered
+  pub prefix: RouteAddress,
+  pub protocol_id: Option<String>,
+  pub versions: VersionRange,
+  pub description: Option<String>,
+}
+
 pub trait ServiceRegistry {
   fn find_service(
     self: Arc<Self>,
     addr: &RouteAddress,
     tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
   ) -> Option<Arc<dyn Service + Send + Sync + 'static>>;
+
+  /// Lists the services available to the given tunnel
+  ///
+  /// Registries which cannot enumerate their services report none.
+  fn enumerate_services(
+    self: Arc<Self>,
+    _tunnel_id: &TunnelId,
+    _tunnel_name: &TunnelName,
+  ) -> Vec<ServiceDescriptor> {
+    Vec::new()
+  }
 }
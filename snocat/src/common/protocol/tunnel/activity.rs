@@ -0,0 +1,213 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+#![forbid(unused_imports, dead_code)]
+use std::{
+  pin::Pin,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+};
+
+use tokio::{
+  io::{AsyncRead, ReadBuf},
+  time::Instant,
+};
+
+use crate::util::tunnel_stream::WrappedStream;
+
+/// Counts the streams currently held open on a tunnel, in either direction
+///
+/// Streams are counted from the moment they are passed through [ActiveStreamCounter::track]
+/// until the read-side of the returned stream is dropped.
+///
+/// The counter also tracks how long the tunnel has been idle, without streams or other activity
+/// in progress. Background streams, such as incoming streams not yet known to be requests, are
+/// counted without ending idleness; their handlers may mark them with [ActiveStreamCounter::hold].
+#[derive(Debug, Clone)]
+pub struct ActiveStreamCounter {
+  active: Arc<AtomicUsize>,
+  activity: Arc<Mutex<Activity>>,
+}
+
+#[derive(Debug)]
+struct Activity {
+  holds: usize,
+  idle_since: Option<Instant>,
+}
+
+impl Default for ActiveStreamCounter {
+  fn default() -> Self {
+    Self {
+      active: Default::default(),
+      activity: Arc::new(Mutex::new(Activity {
+        holds: 0,
+        idle_since: Some(Instant::now()),
+      })),
+    }
+  }
+}
+
+impl ActiveStreamCounter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn count(&self) -> usize {
+    self.active.load(Ordering::Acquire)
+  }
+
+  /// When the tunnel last became idle, or `None` while activity is in progress
+  pub fn idle_since(&self) -> Option<Instant> {
+    self
+      .activity
+      .lock()
+      .expect("Activity lock must not be poisoned")
+      .idle_since
+  }
+
+  /// Marks the tunnel as active until the returned guard is dropped
+  pub fn hold(&self) -> ActivityGuard {
+    let mut activity = self
+      .activity
+      .lock()
+      .expect("Activity lock must not be poisoned");
+    activity.holds += 1;
+    activity.idle_since = None;
+    ActivityGuard {
+      activity: Arc::clone(&self.activity),
+    }
+  }
+
+  /// Wraps a stream such that it is counted as active until it is dropped
+  pub fn track(&self, stream: WrappedStream) -> WrappedStream {
+    self.wrap(stream, Some(self.hold()))
+  }
+
+  /// Wraps a stream such that it is counted until it is dropped, without ending idleness
+  pub fn track_background(&self, stream: WrappedStream) -> WrappedStream {
+    self.wrap(stream, None)
+  }
+
+  fn wrap(&self, stream: WrappedStream, hold: Option<ActivityGuard>) -> WrappedStream {
+    let guard = ActiveStreamGuard::new(Arc::clone(&self.active), hold);
+    match stream {
+      WrappedStream::Boxed(recv, send) => WrappedStream::Boxed(
+        Box::new(TrackedRead {
+          inner: recv,
+          _guard: guard,
+        }),
+        send,
+      ),
+      other => {
+        let (recv, send) = tokio::io::split(other);
+        WrappedStream::Boxed(
+          Box::new(TrackedRead {
+            inner: recv,
+            _guard: guard,
+          }),
+          Box::new(send),
+        )
+      }
+    }
+  }
+}
+
+/// Holds a tunnel active, resuming its idle time when dropped
+#[derive(Debug)]
+pub struct ActivityGuard {
+  activity: Arc<Mutex<Activity>>,
+}
+
+impl Drop for ActivityGuard {
+  fn drop(&mut self) {
+    let mut activity = self
+      .activity
+      .lock()
+      .expect("Activity lock must not be poisoned");
+    activity.holds -= 1;
+    if activity.holds == 0 {
+      activity.idle_since = Some(Instant::now());
+    }
+  }
+}
+
+#[derive(Debug)]
+struct ActiveStreamGuard {
+  active: Arc<AtomicUsize>,
+  _hold: Option<ActivityGuard>,
+}
+
+impl ActiveStreamGuard {
+  fn new(active: Arc<AtomicUsize>, hold: Option<ActivityGuard>) -> Self {
+    active.fetch_add(1, Ordering::AcqRel);
+    Self {
+      active,
+      _hold: hold,
+    }
+  }
+}
+
+impl Drop for ActiveStreamGuard {
+  fn drop(&mut self) {
+    self.active.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+struct TrackedRead<R> {
+  inner: R,
+  _guard: ActiveStreamGuard,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TrackedRead<R> {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<std::io::Result<()>> {
+    AsyncRead::poll_read(Pin::new(&mut self.get_mut().inner), cx, buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::ActiveStreamCounter;
+  use crate::util::tunnel_stream::WrappedStream;
+
+  #[test]
+  fn counts_until_dropped() {
+    let counter = ActiveStreamCounter::new();
+    let (a, b) = WrappedStream::duplex(64);
+    let a = counter.track(a);
+    assert_eq!(counter.count(), 1);
+    let b = counter.track(b);
+    assert_eq!(counter.count(), 2);
+    drop(a);
+    assert_eq!(counter.count(), 1);
+    drop(b);
+    assert_eq!(counter.count(), 0);
+  }
+
+  #[test]
+  fn background_streams_leave_tunnels_idle() {
+    let counter = ActiveStreamCounter::new();
+    let idle = counter.idle_since().expect("New tunnels must be idle");
+    let (a, b) = WrappedStream::duplex(64);
+    let a = counter.track_background(a);
+    assert_eq!(counter.count(), 1);
+    assert_eq!(counter.idle_since(), Some(idle));
+    let hold = counter.hold();
+    let b = counter.track(b);
+    assert_eq!(counter.idle_since(), None);
+    drop(hold);
+    assert_eq!(
+      counter.idle_since(),
+      None,
+      "Tracked streams must remain active"
+    );
+    drop(b);
+    assert!(counter.idle_since().expect("The tunnel must become idle") >= idle);
+    drop(a);
+  }
+}
@@ -4,12 +4,17 @@
 use std::sync::Arc;
 
 use futures::{future::BoxFuture, FutureExt, StreamExt};
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::{
+  sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+  time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
   common::protocol::tunnel::{
-    Sided, Tunnel, TunnelDownlink, TunnelError, TunnelIncoming, TunnelIncomingType, TunnelSide,
-    TunnelUplink,
+    activity::{ActiveStreamCounter, ActivityGuard},
+    Sided, Tunnel, TunnelAddressInfo, TunnelControl, TunnelDownlink, TunnelError, TunnelIncoming,
+    TunnelIncomingType, TunnelSide, TunnelUplink,
   },
   util::tunnel_stream::WrappedStream,
 };
@@ -17,7 +22,11 @@ use crate::{
 pub struct DuplexTunnel {
   channel_to_remote: UnboundedSender<WrappedStream>,
   side: TunnelSide,
+  addr: TunnelAddressInfo,
+  peer_certificates: Option<Vec<Vec<u8>>>,
   incoming: Arc<tokio::sync::Mutex<TunnelIncoming>>,
+  active_streams: ActiveStreamCounter,
+  closed: CancellationToken,
 }
 
 impl Sided for DuplexTunnel {
@@ -27,14 +36,46 @@ impl Sided for DuplexTunnel {
 }
 
 impl TunnelUplink for DuplexTunnel {
+  fn addr(&self) -> TunnelAddressInfo {
+    self.addr.clone()
+  }
+
   fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    self.open_tracked(ActiveStreamCounter::track)
+  }
+
+  fn open_background_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    self.open_tracked(ActiveStreamCounter::track_background)
+  }
+
+  fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+    self.peer_certificates.clone()
+  }
+}
+
+impl DuplexTunnel {
+  /// Reports `chain` as the certificates presented by the remote, as a TLS transport would
+  pub fn with_peer_certificates(self, chain: Vec<Vec<u8>>) -> Self {
+    Self {
+      peer_certificates: Some(chain),
+      ..self
+    }
+  }
+
+  fn open_tracked(
+    &self,
+    track: fn(&ActiveStreamCounter, WrappedStream) -> WrappedStream,
+  ) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    if self.closed.is_cancelled() {
+      return futures::future::ready(Err(TunnelError::LocallyClosed)).boxed();
+    }
     let (local, remote) = tokio::io::duplex(8192);
     futures::future::ready(
       self
         .channel_to_remote
         .send(WrappedStream::DuplexStream(remote))
         .map_err(|_| TunnelError::ConnectionClosed)
-        .map(|_| WrappedStream::DuplexStream(local)),
+        .map(|_| track(&self.active_streams, WrappedStream::DuplexStream(local))),
     )
     .boxed()
   }
@@ -49,6 +90,26 @@ impl Tunnel for DuplexTunnel {
       .map(|x| Some(Box::new(x) as Box<_>))
       .boxed()
   }
+
+  fn active_streams(&self) -> Option<usize> {
+    Some(self.active_streams.count())
+  }
+
+  fn idle_since(&self) -> Option<Instant> {
+    self.active_streams.idle_since()
+  }
+
+  fn hold_active(&self) -> Option<ActivityGuard> {
+    Some(self.active_streams.hold())
+  }
+}
+
+impl TunnelControl for DuplexTunnel {
+  /// Stops opening and accepting links; links already open are unaffected
+  fn close(&self) -> BoxFuture<'_, Result<(), TunnelError>> {
+    self.closed.cancel();
+    futures::future::ready(Ok(())).boxed()
+  }
 }
 
 /// Two entangled ([Tunnel], [TunnelIncoming]) pairs
@@ -67,14 +128,39 @@ impl Into<(DuplexTunnel, DuplexTunnel)> for EntangledTunnels {
 /// Produces two entangled ([Tunnel], [TunnelIncoming]) pairs
 /// Each pair maps its [Tunnel] to the opposite member's entangled [TunnelIncoming]
 pub fn channel() -> EntangledTunnels {
+  channel_with_addresses(
+    TunnelAddressInfo::Unidentified,
+    TunnelAddressInfo::Unidentified,
+  )
+}
+
+/// As [channel], but each tunnel reports the given address for its remote
+pub fn channel_with_addresses(
+  listener_remote: TunnelAddressInfo,
+  connector_remote: TunnelAddressInfo,
+) -> EntangledTunnels {
   fn duplex_for(
     up: UnboundedSender<WrappedStream>,
     down: UnboundedReceiver<WrappedStream>,
     side: TunnelSide,
+    addr: TunnelAddressInfo,
   ) -> DuplexTunnel {
     use tokio_stream::wrappers::UnboundedReceiverStream;
+    let active_streams = ActiveStreamCounter::new();
+    let closed = CancellationToken::new();
     let down = UnboundedReceiverStream::new(down);
-    let incoming_inner = down.map(TunnelIncomingType::BiStream).map(Ok).boxed();
+    let incoming_inner = down
+      .map({
+        let active_streams = active_streams.clone();
+        // Incoming streams are active once their handler holds them so
+        move |stream| TunnelIncomingType::BiStream(active_streams.track_background(stream))
+      })
+      .map(Ok)
+      .take_until({
+        let closed = closed.clone();
+        async move { closed.cancelled().await }
+      })
+      .boxed();
     let incoming = TunnelIncoming {
       inner: incoming_inner,
       side,
@@ -82,14 +168,18 @@ pub fn channel() -> EntangledTunnels {
     DuplexTunnel {
       channel_to_remote: up,
       side,
+      addr,
+      peer_certificates: None,
       incoming: Arc::new(tokio::sync::Mutex::new(incoming)),
+      active_streams,
+      closed,
     }
   }
   let (left_up, right_down) = mpsc::unbounded_channel::<WrappedStream>();
   let (right_up, left_down) = mpsc::unbounded_channel::<WrappedStream>();
   let (listener, connector) = (
-    duplex_for(left_up, left_down, TunnelSide::Listen),
-    duplex_for(right_up, right_down, TunnelSide::Connect),
+    duplex_for(left_up, left_down, TunnelSide::Listen, listener_remote),
+    duplex_for(right_up, right_down, TunnelSide::Connect, connector_remote),
   );
   EntangledTunnels {
     listener,
@@ -6,6 +6,7 @@ use std::{
   ops::Deref,
   pin::Pin,
   sync::Arc,
+  time::Duration,
 };
 
 use crate::util::tunnel_stream::WrappedStream;
@@ -23,12 +24,15 @@ use tokio::{
     mpsc::{self, UnboundedReceiver, UnboundedSender},
     oneshot, OwnedMutexGuard,
   },
+  time::Instant,
 };
 
+pub mod activity;
 pub mod duplex;
 pub mod id;
 pub mod quinn_tunnel;
 
+use self::activity::ActivityGuard;
 pub use self::id::TunnelId;
 pub use self::quinn_tunnel::{from_quinn_endpoint, QuinnTunnel};
 pub type BoxedTunnel<'a> = Box<dyn Tunnel + Send + Sync + Unpin + 'a>;
@@ -265,6 +269,20 @@ pub trait TunnelUplink: Sided {
   }
 
   fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>>;
+
+  /// Opens a link which does not count as tunnel activity, such as for a heartbeat
+  ///
+  /// Implementations not tracking activity open ordinary links.
+  fn open_background_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    self.open_link()
+  }
+
+  /// DER-encoded certificates the remote presented when the tunnel was established, leaf first
+  ///
+  /// Returns `None` if the transport has no certificates or the remote presented none.
+  fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+    None
+  }
 }
 
 impl<T> TunnelUplink for T
@@ -272,9 +290,21 @@ where
   T: Deref + Send + Sync + Unpin,
   <T as Deref>::Target: TunnelUplink + Sided,
 {
+  fn addr(&self) -> TunnelAddressInfo {
+    self.deref().addr()
+  }
+
   fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
     self.deref().open_link()
   }
+
+  fn open_background_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    self.deref().open_background_link()
+  }
+
+  fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+    self.deref().peer_certificates()
+  }
 }
 
 pub trait TunnelDownlink: Sided {
@@ -292,6 +322,30 @@ where
 
 pub trait Tunnel: TunnelUplink + Send + Sync + Unpin {
   fn downlink<'a>(&'a self) -> BoxFuture<'a, Option<Box<dyn TunnelDownlink + Send + Unpin>>>;
+
+  /// The number of streams currently open on the tunnel, in either direction
+  ///
+  /// Returns `None` if the implementation does not track its streams.
+  fn active_streams(&self) -> Option<usize> {
+    None
+  }
+
+  /// The most recent round-trip-time estimate, if the transport measures one
+  fn rtt(&self) -> Option<Duration> {
+    None
+  }
+
+  /// When the tunnel last became idle, with no active streams or held activity
+  ///
+  /// Returns `None` while the tunnel is active, or if the implementation does not track activity.
+  fn idle_since(&self) -> Option<Instant> {
+    None
+  }
+
+  /// Marks the tunnel as active until the guard is dropped, such as while handling a request
+  fn hold_active(&self) -> Option<ActivityGuard> {
+    None
+  }
 }
 
 impl<T> Tunnel for T
@@ -302,6 +356,22 @@ where
   fn downlink<'a>(&'a self) -> BoxFuture<'a, Option<Box<dyn TunnelDownlink + Send + Unpin>>> {
     self.deref().downlink()
   }
+
+  fn active_streams(&self) -> Option<usize> {
+    self.deref().active_streams()
+  }
+
+  fn rtt(&self) -> Option<Duration> {
+    self.deref().rtt()
+  }
+
+  fn idle_since(&self) -> Option<Instant> {
+    self.deref().idle_since()
+  }
+
+  fn hold_active(&self) -> Option<ActivityGuard> {
+    self.deref().hold_active()
+  }
 }
 
 pub enum TunnelIncomingType {
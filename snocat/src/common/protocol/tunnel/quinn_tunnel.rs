@@ -1,12 +1,13 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 #![forbid(unused_imports, dead_code)]
-use std::{ops::Deref, sync::Arc};
+use std::{any::Any, ops::Deref, sync::Arc, time::Duration};
 
 use futures::{
   future::{self, BoxFuture},
   FutureExt, StreamExt, TryFutureExt, TryStreamExt,
 };
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
@@ -17,7 +18,10 @@ use crate::{
   util::{dropkick::Dropkick, tunnel_stream::WrappedStream},
 };
 
-use super::{TunnelControl, TunnelControlPerChannel, TunnelMonitoring, TunnelMonitoringPerChannel};
+use super::{
+  activity::{ActiveStreamCounter, ActivityGuard},
+  TunnelControl, TunnelControlPerChannel, TunnelMonitoring, TunnelMonitoringPerChannel,
+};
 
 pub struct QuinnTunnel<S: quinn::crypto::Session> {
   connection: quinn::generic::Connection<S>,
@@ -26,6 +30,7 @@ pub struct QuinnTunnel<S: quinn::crypto::Session> {
 
   incoming_closed: Arc<Dropkick<CancellationToken>>,
   outgoing_closed: Arc<Dropkick<CancellationToken>>,
+  active_streams: ActiveStreamCounter,
 }
 
 impl<S: quinn::crypto::Session> QuinnTunnel<S> {
@@ -47,6 +52,8 @@ where
   fn close<'a>(&'a self) -> BoxFuture<'a, Result<(), TunnelError>> {
     self.incoming_closed.cancel();
     self.outgoing_closed.cancel();
+    // Closing the connection cuts any streams still open on it
+    self.connection.close(0u32.into(), b"closed");
     future::ready(Ok(())).boxed()
   }
 }
@@ -121,17 +128,49 @@ where
 impl<S> TunnelUplink for QuinnTunnel<S>
 where
   S: quinn::crypto::Session + 'static,
+  S::Identity: 'static,
 {
   fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    self.open_tracked(ActiveStreamCounter::track)
+  }
+
+  fn open_background_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+    self.open_tracked(ActiveStreamCounter::track_background)
+  }
+
+  fn addr(&self) -> TunnelAddressInfo {
+    TunnelAddressInfo::Socket(self.connection.remote_address())
+  }
+
+  fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+    // Only TLS sessions identify their peers by certificate chain
+    let identity: Box<dyn Any> = Box::new(self.connection.peer_identity()?);
+    let chain = identity.downcast::<quinn::CertificateChain>().ok()?;
+    Some(chain.iter().map(|cert| cert.0.clone()).collect())
+  }
+}
+
+impl<S> QuinnTunnel<S>
+where
+  S: quinn::crypto::Session + 'static,
+{
+  fn open_tracked(
+    &self,
+    track: fn(&ActiveStreamCounter, WrappedStream) -> WrappedStream,
+  ) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
     if self.is_closed_uplink() {
       return future::ready(Err(TunnelError::ConnectionClosed)).boxed();
     }
     // TODO: make streams exit when close() is called
+    let active_streams = self.active_streams.clone();
     self
       .connection
       .open_bi()
-      .map(|result| match result {
-        Ok((send, recv)) => Ok(WrappedStream::Boxed(Box::new(recv), Box::new(send))),
+      .map(move |result| match result {
+        Ok((send, recv)) => Ok(track(
+          &active_streams,
+          WrappedStream::Boxed(Box::new(recv), Box::new(send)),
+        )),
         Err(e) => Err(e.into()),
       })
       .inspect_err({
@@ -145,15 +184,12 @@ where
       })
       .boxed()
   }
-
-  fn addr(&self) -> TunnelAddressInfo {
-    TunnelAddressInfo::Socket(self.connection.remote_address())
-  }
 }
 
 impl<S> Tunnel for QuinnTunnel<S>
 where
   S: quinn::crypto::Session + 'static,
+  S::Identity: 'static,
 {
   fn downlink<'a>(&'a self) -> BoxFuture<'a, Option<Box<dyn TunnelDownlink + Send + Unpin>>> {
     if self.is_closed_downlink() {
@@ -168,6 +204,22 @@ where
       .map(|x| Some(Box::new(x) as Box<_>))
       .boxed()
   }
+
+  fn active_streams(&self) -> Option<usize> {
+    Some(self.active_streams.count())
+  }
+
+  fn rtt(&self) -> Option<Duration> {
+    Some(self.connection.rtt())
+  }
+
+  fn idle_since(&self) -> Option<Instant> {
+    self.active_streams.idle_since()
+  }
+
+  fn hold_active(&self) -> Option<ActivityGuard> {
+    Some(self.active_streams.hold())
+  }
 }
 
 impl From<quinn::ConnectionError> for TunnelError {
@@ -200,10 +252,17 @@ where
   // We need to use it earlier to prep the incoming stream.
   let incoming_cancellation: Arc<Dropkick<CancellationToken>> =
     Arc::new(CancellationToken::new().into());
+  let active_streams = ActiveStreamCounter::new();
   let stream_tunnels = bi_streams
-    .map_ok(|(send, recv)| {
-      // TODO: make incoming streams exit when close() is called
-      TunnelIncomingType::BiStream(WrappedStream::Boxed(Box::new(recv), Box::new(send)))
+    .map_ok({
+      let active_streams = active_streams.clone();
+      move |(send, recv)| {
+        // TODO: make incoming streams exit when close() is called
+        // Incoming streams are active once their handler holds them so
+        TunnelIncomingType::BiStream(
+          active_streams.track_background(WrappedStream::Boxed(Box::new(recv), Box::new(send))),
+        )
+      }
     })
     .map_err(Into::into)
     // Only take new streams until incoming is cancelled
@@ -240,5 +299,6 @@ where
     })),
     incoming_closed: incoming_cancellation,
     outgoing_closed: Arc::new(CancellationToken::new().into()),
+    active_streams,
   }
 }
@@ -0,0 +1,117 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Versioning of the protocols spoken between [Client](super::Client)s and [Service](super::Service)s
+#![forbid(unused_imports, dead_code)]
+
+pub type ProtocolVersion = u32;
+
+/// An inclusive range of protocol versions supported by one side of a request
+///
+/// The default range supports only version 0, as spoken by unversioned protocols.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "UncheckedVersionRange")]
+pub struct VersionRange {
+  min: ProtocolVersion,
+  max: ProtocolVersion,
+}
+
+/// A version range whose minimum exceeds its maximum, supporting no versions
+#[derive(thiserror::Error, Copy, Clone, Debug, PartialEq, Eq)]
+#[error("Version range {min}..={max} is empty")]
+pub struct EmptyVersionRange {
+  pub min: ProtocolVersion,
+  pub max: ProtocolVersion,
+}
+
+/// A [VersionRange] as deserialized, before its bounds are checked
+#[derive(serde::Deserialize)]
+struct UncheckedVersionRange {
+  min: ProtocolVersion,
+  max: ProtocolVersion,
+}
+
+impl std::convert::TryFrom<UncheckedVersionRange> for VersionRange {
+  type Error = EmptyVersionRange;
+
+  fn try_from(range: UncheckedVersionRange) -> Result<Self, Self::Error> {
+    Self::try_new(range.min, range.max)
+  }
+}
+
+impl VersionRange {
+  /// Panics if `min` exceeds `max`; see [Self::try_new] for ranges from untrusted sources
+  pub fn new(min: ProtocolVersion, max: ProtocolVersion) -> Self {
+    Self::try_new(min, max).expect("Version ranges must not be empty")
+  }
+
+  pub fn try_new(min: ProtocolVersion, max: ProtocolVersion) -> Result<Self, EmptyVersionRange> {
+    if min <= max {
+      Ok(Self { min, max })
+    } else {
+      Err(EmptyVersionRange { min, max })
+    }
+  }
+
+  pub fn exactly(version: ProtocolVersion) -> Self {
+    Self::new(version, version)
+  }
+
+  pub fn min(&self) -> ProtocolVersion {
+    self.min
+  }
+
+  pub fn max(&self) -> ProtocolVersion {
+    self.max
+  }
+
+  pub fn contains(&self, version: ProtocolVersion) -> bool {
+    self.min <= version && version <= self.max
+  }
+
+  /// The highest version supported by both ranges, if they overlap at all
+  pub fn highest_common(&self, other: &VersionRange) -> Option<ProtocolVersion> {
+    let highest = self.max.min(other.max);
+    if highest >= self.min.max(other.min) {
+      Some(highest)
+    } else {
+      None
+    }
+  }
+}
+
+impl std::fmt::Display for VersionRange {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.min == self.max {
+      write!(f, "{}", self.min)
+    } else {
+      write!(f, "{}..={}", self.min, self.max)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{EmptyVersionRange, VersionRange};
+
+  #[test]
+  fn highest_common_version() {
+    let a = VersionRange::new(1, 4);
+    assert_eq!(a.highest_common(&VersionRange::new(2, 9)), Some(4));
+    assert_eq!(a.highest_common(&VersionRange::new(0, 2)), Some(2));
+    assert_eq!(a.highest_common(&VersionRange::exactly(1)), Some(1));
+    assert_eq!(a.highest_common(&VersionRange::new(5, 6)), None);
+    assert_eq!(a.highest_common(&VersionRange::default()), None);
+    assert!(VersionRange::default().contains(0));
+  }
+
+  #[test]
+  fn empty_ranges_are_rejected() {
+    assert_eq!(
+      VersionRange::try_new(3, 1),
+      Err(EmptyVersionRange { min: 3, max: 1 })
+    );
+    let range: VersionRange = serde_json::from_str(r#"{"min": 1, "max": 3}"#).unwrap();
+    assert_eq!(range, VersionRange::new(1, 3));
+    assert!(serde_json::from_str::<VersionRange>(r#"{"min": 3, "max": 1}"#).is_err());
+  }
+}
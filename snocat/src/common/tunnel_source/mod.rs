@@ -37,6 +37,11 @@ impl<Session: quinn::crypto::Session + 'static> QuinnListenEndpoint<Session> {
       incoming,
     })
   }
+
+  /// The address actually bound, which differs from the requested one when binding port 0
+  pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+    self.endpoint.local_addr()
+  }
 }
 
 impl<Session> Stream for QuinnListenEndpoint<Session>
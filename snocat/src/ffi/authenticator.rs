@@ -0,0 +1,554 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Tunnel authentication performed by the host
+//!
+//! Each authentication begins a session, whose channel the host reads and writes through
+//! `snocat_authenticator_session_*` calls until it completes the session with an
+//! `AuthenticatorSessionResult`.
+#![warn(unused_imports)]
+
+use ffi_support::{ConcurrentHandleMap, ExternError};
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use prost::Message;
+use std::{
+  ffi::CString,
+  os::raw::{c_char, c_void},
+  sync::Arc,
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+  sync::{oneshot, Mutex},
+};
+
+use super::{required_callback, FfiError, HostContext, SnocatMessageCallback};
+use crate::{
+  common::{
+    authentication::{
+      AuthenticationError, AuthenticationHandler, AuthenticationHandlingError,
+      RemoteAuthenticationError, TunnelInfo,
+    },
+    protocol::tunnel::{TunnelAddressInfo, TunnelName, TunnelSide},
+  },
+  proto::ffi::{
+    authenticator_session_read_result, authenticator_session_result,
+    authenticator_session_write_result, AuthenticatorSessionIoError,
+    AuthenticatorSessionReadResult, AuthenticatorSessionResult, AuthenticatorSessionWriteResult,
+  },
+  util::{cancellation::CancellationListener, dropkick::Dropkick, tunnel_stream::TunnelStream},
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnocatTunnelSide {
+  Connect,
+  Listen,
+}
+
+/// Begins authenticating a tunnel; `remote_address` is null if unknown, and valid only for the call
+pub type SnocatBeginSessionCallback = Option<
+  extern "C" fn(
+    context: *mut c_void,
+    session: u64,
+    side: SnocatTunnelSide,
+    remote_address: *const c_char,
+  ),
+>;
+
+/// Host callbacks authenticating the tunnels of a daemon
+#[repr(C)]
+pub struct SnocatAuthenticator {
+  /// Passed to each callback
+  pub context: *mut c_void,
+  /// Must not be null
+  pub begin_session: SnocatBeginSessionCallback,
+  /// Called once the daemon no longer needs `context`; may be null
+  pub release: Option<extern "C" fn(context: *mut c_void)>,
+}
+
+type SessionChannel = Box<dyn TunnelStream + Send + Unpin>;
+
+struct AuthenticatorSession {
+  reader: Arc<Mutex<ReadHalf<SessionChannel>>>,
+  writer: Arc<Mutex<WriteHalf<SessionChannel>>>,
+  runtime: tokio::runtime::Handle,
+  completion: oneshot::Sender<AuthenticatorSessionResult>,
+}
+
+lazy_static::lazy_static! {
+  static ref SESSIONS: ConcurrentHandleMap<AuthenticatorSession> = ConcurrentHandleMap::new();
+}
+
+/// An [AuthenticationHandler] deferring each authentication to an [SnocatAuthenticator]
+pub struct FfiAuthenticationHandler {
+  authenticator: SnocatAuthenticator,
+  begin_session: extern "C" fn(*mut c_void, u64, SnocatTunnelSide, *const c_char),
+}
+
+// The host guarantees that its callbacks and context are usable from any thread
+unsafe impl Send for FfiAuthenticationHandler {}
+unsafe impl Sync for FfiAuthenticationHandler {}
+
+impl FfiAuthenticationHandler {
+  /// Fails if `begin_session` is null, releasing the authenticator immediately
+  ///
+  /// # Safety
+  /// The authenticator's callbacks must remain callable from any thread until it is released.
+  pub unsafe fn new(authenticator: SnocatAuthenticator) -> Result<Self, FfiError> {
+    match required_callback(authenticator.begin_session, "begin_session") {
+      Ok(begin_session) => Ok(Self {
+        authenticator,
+        begin_session,
+      }),
+      Err(e) => {
+        if let Some(release) = authenticator.release {
+          release(authenticator.context);
+        }
+        Err(e)
+      }
+    }
+  }
+}
+
+impl Drop for FfiAuthenticationHandler {
+  fn drop(&mut self) {
+    if let Some(release) = self.authenticator.release {
+      release(self.authenticator.context);
+    }
+  }
+}
+
+impl std::fmt::Debug for FfiAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(stringify!(FfiAuthenticationHandler))
+      .field("context", &self.authenticator.context)
+      .finish_non_exhaustive()
+  }
+}
+
+impl AuthenticationHandler for FfiAuthenticationHandler {
+  fn authenticate<'a>(
+    &'a self,
+    channel: Box<dyn TunnelStream + Send + Unpin>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<TunnelName, AuthenticationError>> {
+    async move {
+      let (completion, completed) = oneshot::channel();
+      let (reader, writer) = tokio::io::split(channel);
+      let session = SESSIONS
+        .insert(AuthenticatorSession {
+          reader: Arc::new(Mutex::new(reader)),
+          writer: Arc::new(Mutex::new(writer)),
+          runtime: tokio::runtime::Handle::current(),
+          completion,
+        })
+        .into_u64();
+      // Invalidates abandoned sessions, so that late calls from the host fail cleanly
+      let _session = Dropkick::callback(move || {
+        let _ = SESSIONS.delete_u64(session);
+      });
+
+      let side = match tunnel_info.side {
+        TunnelSide::Connect => SnocatTunnelSide::Connect,
+        TunnelSide::Listen => SnocatTunnelSide::Listen,
+      };
+      let remote_address = match tunnel_info.addr {
+        TunnelAddressInfo::Unidentified => None,
+        addr => Some(CString::new(addr.to_string()).expect("Addresses never contain NUL")),
+      };
+      (self.begin_session)(
+        self.authenticator.context,
+        session,
+        side,
+        remote_address
+          .as_ref()
+          .map_or(std::ptr::null(), |addr| addr.as_ptr()),
+      );
+
+      let result = match future::select(completed, shutdown_notifier.cancelled().boxed()).await {
+        Either::Left((Ok(result), _)) => result,
+        Either::Left((Err(_), _)) => {
+          return Err(
+            AuthenticationHandlingError::DependencyFailure(
+              "host authenticator".into(),
+              anyhow::Error::msg("Session was dropped without a result"),
+            )
+            .into(),
+          )
+        }
+        Either::Right(_) => return Err(RemoteAuthenticationError::LinkClosedLocally.into()),
+      };
+      match result.result {
+        Some(authenticator_session_result::Result::Acceptance(acceptance))
+          if !acceptance.connection_id.is_empty() =>
+        {
+          Ok(TunnelName::new(acceptance.connection_id))
+        }
+        Some(authenticator_session_result::Result::Denial(denial)) => {
+          tracing::debug!(
+            reason_code = denial.reason_code,
+            reason = %denial.reason,
+            "Host authenticator denied tunnel"
+          );
+          Err(RemoteAuthenticationError::Refused.into())
+        }
+        _ => Err(
+          AuthenticationHandlingError::ApplicationError(anyhow::Error::msg(
+            "Host authenticator completed without a connection ID or denial",
+          ))
+          .into(),
+        ),
+      }
+    }
+    .boxed()
+  }
+}
+
+/// Reads up to `max_length` bytes from an authenticator session
+///
+/// `callback` receives an `AuthenticatorSessionReadResult`, whose buffer is empty once the
+/// remote has finished writing.
+#[no_mangle]
+pub extern "C" fn snocat_authenticator_session_read(
+  session: u64,
+  max_length: u32,
+  callback: SnocatMessageCallback,
+  context: *mut c_void,
+  error: &mut ExternError,
+) {
+  use authenticator_session_read_result::{Result as ReadResult, Success};
+  let context = HostContext(context);
+  ffi_support::call_with_result(error, || -> Result<(), FfiError> {
+    let callback = required_callback(callback, "callback")?;
+    SESSIONS.get_u64(session, |session| -> Result<(), FfiError> {
+      if max_length == 0 {
+        return Err(FfiError::InvalidArgument(
+          "max_length must be nonzero".into(),
+        ));
+      }
+      let reader = Arc::clone(&session.reader);
+      session.runtime.spawn(async move {
+        let mut buffer = vec![0u8; max_length as usize];
+        let result = match reader.lock().await.read(&mut buffer).await {
+          Ok(read) => {
+            buffer.truncate(read);
+            ReadResult::Success(Success { buffer })
+          }
+          Err(e) => {
+            tracing::debug!(error = %e, "Authenticator session read failed");
+            ReadResult::Error(AuthenticatorSessionIoError {})
+          }
+        };
+        context.deliver(
+          callback,
+          &AuthenticatorSessionReadResult {
+            result: Some(result),
+          },
+        );
+      });
+      Ok(())
+    })
+  })
+}
+
+/// Writes `length` bytes from `data` to an authenticator session
+///
+/// The bytes are copied before returning. `callback` receives an
+/// `AuthenticatorSessionWriteResult` once they have been flushed.
+///
+/// # Safety
+/// `data` must point to `length` readable bytes, or may be null if `length` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn snocat_authenticator_session_write(
+  session: u64,
+  data: *const u8,
+  length: usize,
+  callback: SnocatMessageCallback,
+  context: *mut c_void,
+  error: &mut ExternError,
+) {
+  use authenticator_session_write_result::{Result as WriteResult, Success};
+  let data = host_bytes(data, length);
+  let context = HostContext(context);
+  ffi_support::call_with_result(error, || -> Result<(), FfiError> {
+    let callback = required_callback(callback, "callback")?;
+    SESSIONS.get_u64(session, |session| -> Result<(), FfiError> {
+      let writer = Arc::clone(&session.writer);
+      session.runtime.spawn(async move {
+        let mut writer = writer.lock().await;
+        let result = match writer.write_all(&data).await {
+          Ok(()) => writer.flush().await,
+          Err(e) => Err(e),
+        };
+        let result = match result {
+          Ok(()) => WriteResult::Success(Success {}),
+          Err(e) => {
+            tracing::debug!(error = %e, "Authenticator session write failed");
+            WriteResult::Error(AuthenticatorSessionIoError {})
+          }
+        };
+        context.deliver(
+          callback,
+          &AuthenticatorSessionWriteResult {
+            result: Some(result),
+          },
+        );
+      });
+      Ok(())
+    })
+  })
+}
+
+/// Completes an authenticator session with an encoded `AuthenticatorSessionResult`
+///
+/// An acceptance names the tunnel by its connection ID. The session handle is invalidated,
+/// but reads and writes already in progress still report their results.
+///
+/// # Safety
+/// `result` must point to `length` readable bytes, or may be null if `length` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn snocat_authenticator_session_complete(
+  session: u64,
+  result: *const u8,
+  length: usize,
+  error: &mut ExternError,
+) {
+  let encoded = host_bytes(result, length);
+  ffi_support::call_with_result(error, || -> Result<(), FfiError> {
+    // Decoded before the session is claimed, so that malformed results may be retried
+    let result = AuthenticatorSessionResult::decode(encoded.as_slice()).map_err(|e| {
+      FfiError::InvalidArgument(format!("Malformed AuthenticatorSessionResult: {}", e))
+    })?;
+    let session = SESSIONS
+      .remove_u64(session)?
+      .ok_or(FfiError::InvalidState("Session was already completed"))?;
+    // The authentication may have been abandoned since the host last checked
+    let _ = session.completion.send(result);
+    Ok(())
+  })
+}
+
+unsafe fn host_bytes(data: *const u8, length: usize) -> Vec<u8> {
+  if length == 0 {
+    Vec::new()
+  } else {
+    std::slice::from_raw_parts(data, length).to_vec()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use ffi_support::{ByteBuffer, ExternError};
+  use prost::Message;
+  use std::{
+    os::raw::{c_char, c_void},
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      mpsc, Arc,
+    },
+  };
+
+  use super::{
+    snocat_authenticator_session_complete, snocat_authenticator_session_read,
+    snocat_authenticator_session_write, FfiAuthenticationHandler, SnocatAuthenticator,
+    SnocatTunnelSide,
+  };
+  use crate::{
+    common::{
+      authentication::{
+        perform_authentication, AuthenticationError, RemoteAuthenticationError,
+        SimpleAckAuthenticationHandler,
+      },
+      protocol::tunnel::{
+        duplex::{channel as duplex, EntangledTunnels},
+        TunnelName,
+      },
+    },
+    proto::ffi::{
+      authenticator_session_read_result, authenticator_session_result, AuthenticatorAcceptance,
+      AuthenticatorDenial, AuthenticatorSessionReadResult, AuthenticatorSessionResult,
+    },
+    util::cancellation::CancellationListener,
+  };
+
+  /// A host speaking the listening side of [SimpleAckAuthenticationHandler]'s protocol
+  struct TestHost {
+    accept: bool,
+    released: AtomicBool,
+  }
+
+  /// A context owning its own sender, as the host may move on before the callback returns
+  fn forwarding_context(sender: &mpsc::Sender<Vec<u8>>) -> *mut c_void {
+    Box::into_raw(Box::new(sender.clone())) as *mut c_void
+  }
+
+  extern "C" fn forward(context: *mut c_void, message: ByteBuffer) {
+    let sender = unsafe { Box::from_raw(context as *mut mpsc::Sender<Vec<u8>>) };
+    sender.send(message.destroy_into_vec()).unwrap();
+  }
+
+  extern "C" fn begin_session(
+    context: *mut c_void,
+    session: u64,
+    side: SnocatTunnelSide,
+    _remote_address: *const c_char,
+  ) {
+    assert_eq!(side, SnocatTunnelSide::Listen);
+    let host = unsafe {
+      Arc::increment_strong_count(context as *const TestHost);
+      Arc::from_raw(context as *const TestHost)
+    };
+    std::thread::spawn(move || {
+      let (sender, messages) = mpsc::channel::<Vec<u8>>();
+      let mut error = ExternError::default();
+      let mut hello = [0u8; 64];
+      hello[..4].copy_from_slice(b"HELO");
+      unsafe {
+        snocat_authenticator_session_write(
+          session,
+          hello.as_ptr(),
+          hello.len(),
+          Some(forward),
+          forwarding_context(&sender),
+          &mut error,
+        );
+      }
+      assert!(error.get_code().is_success());
+      messages.recv().unwrap();
+
+      let mut received = Vec::new();
+      while received.len() < 64 {
+        snocat_authenticator_session_read(
+          session,
+          64,
+          Some(forward),
+          forwarding_context(&sender),
+          &mut error,
+        );
+        assert!(error.get_code().is_success());
+        let read = AuthenticatorSessionReadResult::decode(messages.recv().unwrap().as_slice());
+        match read.unwrap().result {
+          Some(authenticator_session_read_result::Result::Success(success)) => {
+            assert!(
+              !success.buffer.is_empty(),
+              "Remote closed the session early"
+            );
+            received.extend(success.buffer);
+          }
+          _ => panic!("Session read failed"),
+        }
+      }
+      assert!(received.starts_with(b"HELO/HELO\0"));
+
+      let result = AuthenticatorSessionResult {
+        result: Some(match host.accept {
+          true => authenticator_session_result::Result::Acceptance(AuthenticatorAcceptance {
+            connection_id: "edge".into(),
+          }),
+          false => authenticator_session_result::Result::Denial(AuthenticatorDenial {
+            reason_code: 1,
+            reason: "Not today".into(),
+          }),
+        }),
+      };
+      let mut encoded = Vec::new();
+      result.encode(&mut encoded).unwrap();
+      unsafe {
+        snocat_authenticator_session_complete(session, encoded.as_ptr(), encoded.len(), &mut error);
+      }
+      assert!(error.get_code().is_success());
+    });
+  }
+
+  extern "C" fn release(context: *mut c_void) {
+    let host = unsafe { Arc::from_raw(context as *const TestHost) };
+    host.released.store(true, Ordering::SeqCst);
+  }
+
+  async fn authenticate(accept: bool) -> (Result<TunnelName, AuthenticationError>, Arc<TestHost>) {
+    let host = Arc::new(TestHost {
+      accept,
+      released: AtomicBool::new(false),
+    });
+    let server = unsafe {
+      FfiAuthenticationHandler::new(SnocatAuthenticator {
+        context: Arc::into_raw(Arc::clone(&host)) as *mut c_void,
+        begin_session: Some(begin_session),
+        release: Some(release),
+      })
+      .unwrap()
+    };
+    let client = SimpleAckAuthenticationHandler::new();
+    let EntangledTunnels {
+      listener,
+      connector,
+    } = duplex();
+    let never_shutdown = CancellationListener::default();
+    let (server_res, _) = futures::future::join(
+      perform_authentication(&server, &listener, &never_shutdown),
+      perform_authentication(&client, &connector, &never_shutdown),
+    )
+    .await;
+    drop(server);
+    (server_res, host)
+  }
+
+  #[tokio::test]
+  async fn host_accepts_through_session_messages() {
+    let (result, host) = authenticate(true).await;
+    assert_eq!(result.unwrap(), TunnelName::new("edge"));
+    assert!(host.released.load(Ordering::SeqCst));
+  }
+
+  #[tokio::test]
+  async fn host_denial_refuses_tunnel() {
+    let (result, _) = authenticate(false).await;
+    assert!(matches!(
+      result,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    ));
+  }
+
+  #[test]
+  fn null_callbacks_are_rejected() {
+    let host = Arc::new(TestHost {
+      accept: true,
+      released: AtomicBool::new(false),
+    });
+    let handler = unsafe {
+      FfiAuthenticationHandler::new(SnocatAuthenticator {
+        context: Arc::into_raw(Arc::clone(&host)) as *mut c_void,
+        begin_session: None,
+        release: Some(release),
+      })
+    };
+    assert!(handler.is_err());
+    assert!(host.released.load(Ordering::SeqCst));
+
+    let mut error = ExternError::default();
+    snocat_authenticator_session_read(0, 64, None, std::ptr::null_mut(), &mut error);
+    assert_eq!(
+      error.get_code().code(),
+      crate::ffi::SNOCAT_ERROR_INVALID_ARGUMENT
+    );
+    unsafe { error.manually_release() };
+    let mut error = ExternError::default();
+    unsafe {
+      snocat_authenticator_session_write(
+        0,
+        std::ptr::null(),
+        0,
+        None,
+        std::ptr::null_mut(),
+        &mut error,
+      );
+    }
+    assert_eq!(
+      error.get_code().code(),
+      crate::ffi::SNOCAT_ERROR_INVALID_ARGUMENT
+    );
+    unsafe { error.manually_release() };
+  }
+}
@@ -0,0 +1,232 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Daemons owned by the host, each running on a runtime of its own
+#![warn(unused_imports)]
+
+use ffi_support::{ConcurrentHandleMap, ExternError, FfiStr};
+use futures::{future, StreamExt};
+use quinn::TransportConfig;
+use std::{net::SocketAddr, os::raw::c_void, path::Path, sync::Arc, time::Duration};
+use tokio::{runtime::Runtime, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+  required_callback, FfiAuthenticationHandler, FfiError, HostContext, SnocatAuthenticator,
+  SnocatMessageCallback,
+};
+use crate::{
+  common::{
+    protocol::{
+      routing::NamedTunnelRouter,
+      service_registry::TrieServiceRegistry,
+      traits::InMemoryTunnelRegistry,
+      tunnel::{id::MonotonicAtomicGenerator, QuinnTunnel},
+    },
+    tunnel_source::QuinnListenEndpoint,
+  },
+  server::{
+    events::tunnel_events,
+    modular::{ModularDaemon, ShutdownReport},
+  },
+};
+
+type FfiTunnel = QuinnTunnel<quinn::crypto::rustls::TlsSession>;
+
+/// Time that draining tunnels may finish their requests before being closed
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+/// Time after which shutdown closes all remaining tunnels, so that it never blocks the host for long
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(45);
+
+struct FfiDaemon {
+  daemon: Arc<ModularDaemon<FfiTunnel>>,
+  shutdown: CancellationToken,
+  running: Option<JoinHandle<ShutdownReport>>,
+  runtime: Runtime,
+}
+
+lazy_static::lazy_static! {
+  static ref DAEMONS: ConcurrentHandleMap<FfiDaemon> = ConcurrentHandleMap::new();
+}
+
+impl FfiDaemon {
+  fn new(authentication_handler: FfiAuthenticationHandler) -> Result<Self, FfiError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+      .enable_all()
+      .thread_name("snocat")
+      .build()
+      .map_err(|e| FfiError::Io(e.into()))?;
+    let daemon = ModularDaemon::new(
+      Arc::new(TrieServiceRegistry::new()),
+      Arc::new(InMemoryTunnelRegistry::new()),
+      Arc::new(NamedTunnelRouter::new()),
+      Arc::new(authentication_handler),
+      Arc::new(MonotonicAtomicGenerator::new(0)),
+    )
+    .with_drain_grace_period(Some(DRAIN_GRACE_PERIOD))
+    .with_shutdown_deadline(Some(SHUTDOWN_DEADLINE));
+    Ok(Self {
+      daemon: Arc::new(daemon),
+      shutdown: CancellationToken::new(),
+      running: None,
+      runtime,
+    })
+  }
+
+  fn start(
+    &mut self,
+    listen_address: SocketAddr,
+    certificate: &Path,
+    private_key: &Path,
+  ) -> Result<u16, FfiError> {
+    if self.running.is_some() || self.shutdown.is_cancelled() {
+      return Err(FfiError::InvalidState("Daemon was already started"));
+    }
+    let server_config = build_server_config(certificate, private_key).map_err(FfiError::Io)?;
+    let _runtime = self.runtime.enter();
+    let endpoint = QuinnListenEndpoint::bind(listen_address, server_config)
+      .map_err(|e| FfiError::Io(e.into()))?;
+    let port = endpoint
+      .local_addr()
+      .map_err(|e| FfiError::Io(e.into()))?
+      .port();
+    self.running = Some(Arc::clone(&self.daemon).run(endpoint, self.shutdown.clone()));
+    Ok(port)
+  }
+
+  fn subscribe(
+    &self,
+    callback: SnocatMessageCallback,
+    context: HostContext,
+  ) -> Result<(), FfiError> {
+    let callback = required_callback(callback, "callback")?;
+    self
+      .runtime
+      .spawn(tunnel_events(&self.daemon).for_each(move |event| {
+        context.deliver(callback, &event);
+        future::ready(())
+      }));
+    Ok(())
+  }
+
+  /// Stops accepting tunnels, and waits for those established to close
+  ///
+  /// Tunnels are forcibly closed after [SHUTDOWN_DEADLINE].
+  fn shutdown(&mut self) {
+    self.shutdown.cancel();
+    if let Some(running) = self.running.take() {
+      match self.runtime.block_on(running) {
+        Ok(report) => tracing::debug!(?report, "Daemon shut down"),
+        Err(e) => tracing::warn!(error = %e, "Daemon failed while shutting down"),
+      }
+    }
+  }
+}
+
+impl Drop for FfiDaemon {
+  fn drop(&mut self) {
+    self.shutdown();
+  }
+}
+
+fn build_server_config(
+  certificate: &Path,
+  private_key: &Path,
+) -> anyhow::Result<quinn::ServerConfig> {
+  use anyhow::Context;
+  let certificate_pem = std::fs::read(certificate)
+    .with_context(|| format!("Failed reading certificate {}", certificate.display()))?;
+  let private_key_pem = std::fs::read(private_key)
+    .with_context(|| format!("Failed reading private key {}", private_key.display()))?;
+  let certificate_chain = quinn::CertificateChain::from_pem(&certificate_pem)
+    .context("Failed parsing certificate chain")?;
+  let private_key =
+    quinn::PrivateKey::from_pem(&private_key_pem).context("Failed parsing private key")?;
+  let mut transport_config = TransportConfig::default();
+  transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
+  transport_config.max_idle_timeout(Some(Duration::from_secs(30)))?;
+  let mut server_config = quinn::ServerConfig::default();
+  server_config.transport = Arc::new(transport_config);
+  let mut builder = quinn::ServerConfigBuilder::new(server_config);
+  builder.protocols(crate::util::ALPN_QUIC_HTTP);
+  builder.certificate(certificate_chain, private_key)?;
+  Ok(builder.build())
+}
+
+fn required_str<'a>(value: &FfiStr<'a>, name: &str) -> Result<&'a str, FfiError> {
+  value
+    .as_opt_str()
+    .ok_or_else(|| FfiError::InvalidArgument(format!("{} must be a non-null UTF-8 string", name)))
+}
+
+/// Creates a daemon whose tunnels are authenticated by `authenticator`
+///
+/// The authenticator is released when the daemon is destroyed, or immediately if creation fails,
+/// as it does if `begin_session` is null.
+///
+/// # Safety
+/// The authenticator's callbacks must remain callable from any thread until it is released.
+#[no_mangle]
+pub unsafe extern "C" fn snocat_daemon_create(
+  authenticator: SnocatAuthenticator,
+  error: &mut ExternError,
+) -> u64 {
+  DAEMONS.insert_with_result(error, move || {
+    FfiDaemon::new(FfiAuthenticationHandler::new(authenticator)?)
+  })
+}
+
+/// Starts accepting tunnels on `listen_address`, returning the port bound
+///
+/// The certificate chain and private key are read from PEM files. A daemon may only be started once.
+#[no_mangle]
+pub extern "C" fn snocat_daemon_start(
+  daemon: u64,
+  listen_address: FfiStr<'_>,
+  certificate_path: FfiStr<'_>,
+  private_key_path: FfiStr<'_>,
+  error: &mut ExternError,
+) -> u16 {
+  DAEMONS.call_with_result_mut(error, daemon, |daemon| -> Result<u16, FfiError> {
+    let listen_address = required_str(&listen_address, "listen_address")?;
+    let listen_address = listen_address.parse().map_err(|_| {
+      FfiError::InvalidArgument(format!("Invalid listen address {:?}", listen_address))
+    })?;
+    daemon.start(
+      listen_address,
+      Path::new(required_str(&certificate_path, "certificate_path")?),
+      Path::new(required_str(&private_key_path, "private_key_path")?),
+    )
+  })
+}
+
+/// Delivers an encoded `TunnelEvent` to `callback` as each tunnel connects, authenticates,
+/// and disconnects, until the daemon is destroyed
+#[no_mangle]
+pub extern "C" fn snocat_daemon_subscribe(
+  daemon: u64,
+  callback: SnocatMessageCallback,
+  context: *mut c_void,
+  error: &mut ExternError,
+) {
+  let context = HostContext(context);
+  DAEMONS.call_with_result(error, daemon, |daemon| daemon.subscribe(callback, context))
+}
+
+/// Stops accepting tunnels, and blocks until those established have closed
+///
+/// Tunnels with requests still in progress are closed after 30 seconds, and any tunnels
+/// remaining are closed after 45 seconds.
+///
+/// Must not be called from a callback.
+#[no_mangle]
+pub extern "C" fn snocat_daemon_shutdown(daemon: u64, error: &mut ExternError) {
+  DAEMONS.call_with_output_mut(error, daemon, FfiDaemon::shutdown)
+}
+
+/// Shuts down and releases a daemon, invalidating its handle
+///
+/// Must not be called from a callback.
+#[no_mangle]
+pub extern "C" fn snocat_daemon_destroy(daemon: u64, error: &mut ExternError) {
+  ffi_support::call_with_result(error, || DAEMONS.delete_u64(daemon))
+}
@@ -0,0 +1,95 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! C ABI for hosting snocat daemons from other languages
+//!
+//! Daemons and authenticator sessions are referred to by opaque `u64` handles. Fallible calls
+//! report failure through a trailing `ExternError`, whose message the host releases with
+//! [snocat_string_free]. Structured data crosses the boundary as `ffi.proto` messages in
+//! `ByteBuffer`s, which the host releases with [snocat_bytebuffer_free].
+//!
+//! Callbacks may be invoked from any of a daemon's runtime threads, and must not block.
+#![warn(unused_imports)]
+
+use ffi_support::{ByteBuffer, ErrorCode, ExternError, HandleError};
+use std::os::raw::{c_char, c_void};
+
+mod authenticator;
+pub use authenticator::*;
+
+mod daemon;
+pub use daemon::*;
+
+/// An argument was null, malformed, or otherwise unusable
+pub const SNOCAT_ERROR_INVALID_ARGUMENT: i32 = 1;
+/// The call is not valid in the current state of its target
+pub const SNOCAT_ERROR_INVALID_STATE: i32 = 2;
+/// Files could not be loaded, or sockets bound
+pub const SNOCAT_ERROR_IO: i32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum FfiError {
+  #[error("Invalid argument: {0}")]
+  InvalidArgument(String),
+  #[error("Invalid state: {0}")]
+  InvalidState(&'static str),
+  #[error(transparent)]
+  InvalidHandle(#[from] HandleError),
+  #[error("{0:#}")]
+  Io(anyhow::Error),
+}
+
+impl From<FfiError> for ExternError {
+  fn from(e: FfiError) -> Self {
+    let code = match &e {
+      FfiError::InvalidArgument(_) => ErrorCode::new(SNOCAT_ERROR_INVALID_ARGUMENT),
+      FfiError::InvalidState(_) => ErrorCode::new(SNOCAT_ERROR_INVALID_STATE),
+      FfiError::InvalidHandle(_) => ErrorCode::INVALID_HANDLE,
+      FfiError::Io(_) => ErrorCode::new(SNOCAT_ERROR_IO),
+    };
+    ExternError::new_error(code, e.to_string())
+  }
+}
+
+/// Receives an encoded `ffi.proto` message, to be released with [snocat_bytebuffer_free]
+///
+/// Calls given a null callback fail with [SNOCAT_ERROR_INVALID_ARGUMENT].
+pub type SnocatMessageCallback = Option<extern "C" fn(context: *mut c_void, message: ByteBuffer)>;
+
+type MessageCallback = extern "C" fn(context: *mut c_void, message: ByteBuffer);
+
+/// Rejects a null callback, which the host may pass for any nullable function pointer
+fn required_callback<T>(callback: Option<T>, name: &str) -> Result<T, FfiError> {
+  callback.ok_or_else(|| FfiError::InvalidArgument(format!("{} must not be null", name)))
+}
+
+/// A pointer provided by the host, which it guarantees is usable from any thread
+#[derive(Clone, Copy)]
+struct HostContext(*mut c_void);
+
+unsafe impl Send for HostContext {}
+unsafe impl Sync for HostContext {}
+
+impl HostContext {
+  fn deliver(self, callback: MessageCallback, message: &impl prost::Message) {
+    let mut encoded = Vec::with_capacity(message.encoded_len());
+    message
+      .encode(&mut encoded)
+      .expect("Vectors grow to fit encoded messages");
+    callback(self.0, ByteBuffer::from_vec(encoded));
+  }
+}
+
+/// Releases an error message returned by this library
+///
+/// # Safety
+/// `message` must have been returned by this library and not yet released, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn snocat_string_free(message: *mut c_char) {
+  ffi_support::destroy_c_string(message)
+}
+
+/// Releases a message passed to a callback by this library
+#[no_mangle]
+pub extern "C" fn snocat_bytebuffer_free(message: ByteBuffer) {
+  ffi_support::abort_on_panic::with_abort_on_panic(|| message.destroy())
+}
@@ -12,6 +12,8 @@
 #![allow(unused_imports)]
 
 pub mod common;
+pub mod ffi;
+pub mod proto;
 pub mod util;
 
 pub mod client;
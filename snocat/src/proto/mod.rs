@@ -0,0 +1,19 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Protocol buffer messages, compiled from the `.proto` files alongside this module
+
+/// Outcomes of tasks delegated to another process, resolving a
+/// [DelegationPool](crate::util::delegation::DelegationPool)
+pub mod delegation {
+  include!(concat!(env!("OUT_DIR"), "/snocat.delegation.rs"));
+}
+
+/// Outcomes of events dispatched to another process
+pub mod eventing {
+  include!(concat!(env!("OUT_DIR"), "/snocat.eventing.rs"));
+}
+
+/// Messages exchanged with hosts of the [C ABI](crate::ffi)
+pub mod ffi {
+  include!(concat!(env!("OUT_DIR"), "/snocat.ffi.rs"));
+}
@@ -0,0 +1,310 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Local administration of a running [ModularDaemon]
+//!
+//! [AdminService] answers [AdminRequest]s framed as JSON over any stream, such as a Unix socket
+//! connection, which only local operators should be able to reach. [send_request] is its client.
+#![warn(unused_imports)]
+
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use std::{sync::Arc, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+  modular::{ModularDaemon, TunnelStatus},
+  PortAllocation, PortRangeAllocator,
+};
+use crate::{
+  common::{
+    authorization::AUDIT_LOG_TARGET,
+    protocol::tunnel::{TunnelAddressInfo, TunnelId, TunnelName},
+  },
+  util::framed::{read_framed_json, write_framed_json},
+};
+
+/// Identifies a tunnel by its ID or its name
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelSelector {
+  Id(TunnelId),
+  Name(TunnelName),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+  ListTunnels,
+  /// Closes a tunnel immediately, abandoning its requests in progress
+  CloseTunnel {
+    tunnel: TunnelSelector,
+  },
+  /// Shuts the daemon down, draining each of its tunnels
+  Drain,
+  ListPorts,
+}
+
+/// An established tunnel, as listed by [AdminRequest::ListTunnels]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TunnelSummary {
+  pub id: TunnelId,
+  pub name: Option<TunnelName>,
+  pub remote_addr: Option<String>,
+  pub connected_since: SystemTime,
+  pub active_streams: Option<usize>,
+  /// Ports allocated on the tunnel's behalf
+  pub ports: Vec<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AdminResponse {
+  Tunnels { tunnels: Vec<TunnelSummary> },
+  Closed { id: TunnelId },
+  NoSuchTunnel,
+  Draining,
+  Ports { allocations: Vec<PortAllocation> },
+}
+
+/// Answers [AdminRequest]s on behalf of a daemon
+pub struct AdminService<TTunnel> {
+  daemon: Arc<ModularDaemon<TTunnel>>,
+  shutdown: CancellationToken,
+  ports: Option<PortRangeAllocator>,
+}
+
+impl<TTunnel> AdminService<TTunnel>
+where
+  TTunnel: Send + Sync + 'static,
+{
+  /// Administers `daemon`, which is drained by cancelling the `shutdown` token it was run with
+  pub fn new(daemon: Arc<ModularDaemon<TTunnel>>, shutdown: CancellationToken) -> Self {
+    Self {
+      daemon,
+      shutdown,
+      ports: None,
+    }
+  }
+
+  /// Reports the ports allocated by `ports`, alongside the tunnels they were allocated to
+  pub fn with_port_allocator(mut self, ports: PortRangeAllocator) -> Self {
+    self.ports = Some(ports);
+    self
+  }
+
+  pub async fn respond(&self, request: AdminRequest) -> AdminResponse {
+    tracing::info!(target: AUDIT_LOG_TARGET, ?request, "Admin request");
+    match request {
+      AdminRequest::ListTunnels => {
+        let allocations = self.allocations().await;
+        let tunnels = self
+          .daemon
+          .tunnels()
+          .await
+          .into_iter()
+          .map(|status| Self::summarize(status, &allocations))
+          .collect();
+        AdminResponse::Tunnels { tunnels }
+      }
+      AdminRequest::CloseTunnel { tunnel } => {
+        let id = match tunnel {
+          TunnelSelector::Id(id) => Some(id),
+          TunnelSelector::Name(name) => self
+            .daemon
+            .tunnels()
+            .await
+            .into_iter()
+            .find(|status| status.name.as_ref() == Some(&name))
+            .map(|status| status.id),
+        };
+        match id {
+          Some(id) if self.daemon.close_tunnel(id) => AdminResponse::Closed { id },
+          _ => AdminResponse::NoSuchTunnel,
+        }
+      }
+      AdminRequest::Drain => {
+        self.shutdown.cancel();
+        AdminResponse::Draining
+      }
+      AdminRequest::ListPorts => AdminResponse::Ports {
+        allocations: self.allocations().await,
+      },
+    }
+  }
+
+  async fn allocations(&self) -> Vec<PortAllocation> {
+    match &self.ports {
+      Some(ports) => ports.allocations().await,
+      None => Vec::new(),
+    }
+  }
+
+  fn summarize(status: TunnelStatus, allocations: &[PortAllocation]) -> TunnelSummary {
+    let id = status.id;
+    TunnelSummary {
+      id,
+      name: status.name,
+      remote_addr: match status.addr {
+        TunnelAddressInfo::Unidentified => None,
+        addr => Some(addr.to_string()),
+      },
+      connected_since: status.connected_at,
+      active_streams: status.active_streams,
+      ports: allocations
+        .iter()
+        .filter(|allocation| allocation.tunnel == Some(id))
+        .map(|allocation| allocation.port)
+        .collect(),
+    }
+  }
+
+  /// Answers requests from a connection until it closes
+  pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, mut connection: S) -> Result<()> {
+    loop {
+      // Reads fail once the connection closes, as well as for malformed requests
+      let request: AdminRequest = match read_framed_json(&mut connection).await {
+        Ok(request) => request,
+        Err(e) => {
+          tracing::debug!(error = ?e, "Admin connection closed");
+          return Ok(());
+        }
+      };
+      let response = self.respond(request).await;
+      write_framed_json(&mut connection, &response).await?;
+    }
+  }
+
+  /// Serves each connection in its own task, until `connections` ends
+  pub async fn serve_connections<S, TConnections>(self: Arc<Self>, connections: TConnections)
+  where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    TConnections: Stream<Item = std::io::Result<S>>,
+  {
+    futures::pin_mut!(connections);
+    while let Some(connection) = connections.next().await {
+      match connection {
+        Ok(connection) => {
+          let this = Arc::clone(&self);
+          tokio::spawn(async move {
+            if let Err(e) = this.serve(connection).await {
+              tracing::debug!(error = ?e, "Admin connection failed");
+            }
+          });
+        }
+        Err(e) => tracing::warn!(error = ?e, "Failed to accept admin connection"),
+      }
+    }
+  }
+}
+
+/// Sends a request to an [AdminService], returning its response
+pub async fn send_request<S: AsyncRead + AsyncWrite + Unpin>(
+  connection: &mut S,
+  request: &AdminRequest,
+) -> Result<AdminResponse> {
+  write_framed_json(connection, request).await?;
+  read_framed_json(connection).await
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::stream;
+  use std::{sync::Arc, time::Duration};
+  use tokio::time::timeout;
+  use tokio_util::sync::CancellationToken;
+
+  use super::{send_request, AdminRequest, AdminResponse, AdminService, TunnelSelector};
+  use crate::{
+    common::{
+      authentication::NoOpAuthenticationHandler,
+      protocol::{
+        routing::NamedTunnelRouter,
+        service_registry::TrieServiceRegistry,
+        traits::InMemoryTunnelRegistry,
+        tunnel::{
+          duplex::{channel as duplex, DuplexTunnel},
+          id::MonotonicAtomicGenerator,
+          TunnelName,
+        },
+      },
+    },
+    server::{modular::DisconnectReason, modular::ModularDaemon, PortRangeAllocator},
+  };
+
+  #[tokio::test]
+  async fn admin_lists_and_closes_tunnels() {
+    use futures::StreamExt;
+    let daemon = Arc::new(ModularDaemon::<DuplexTunnel>::new(
+      Arc::new(TrieServiceRegistry::new()),
+      Arc::new(InMemoryTunnelRegistry::new()),
+      Arc::new(NamedTunnelRouter::new()),
+      Arc::new(NoOpAuthenticationHandler::new()),
+      Arc::new(MonotonicAtomicGenerator::new(0)),
+    ));
+    let shutdown = CancellationToken::new();
+    let mut authenticated = daemon.tunnel_authenticated.subscribe();
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    let tunnels = duplex();
+    let _remote = tunnels.connector;
+    let running = Arc::clone(&daemon).run(
+      stream::iter(vec![tunnels.listener]).chain(stream::pending()),
+      shutdown.clone(),
+    );
+    let (id, name, _) = timeout(Duration::from_secs(5), authenticated.recv())
+      .await
+      .expect("Tunnel must authenticate")
+      .unwrap();
+
+    let ports = PortRangeAllocator::new(4000u16..=4001);
+    let port = ports.allocate_to(id).await.unwrap();
+    let admin = AdminService::new(Arc::clone(&daemon), shutdown.clone()).with_port_allocator(ports);
+    let (mut client, server) = tokio::io::duplex(4096);
+    let serving = tokio::spawn(async move { admin.serve(server).await });
+
+    let listed = send_request(&mut client, &AdminRequest::ListTunnels)
+      .await
+      .unwrap();
+    let tunnels = match listed {
+      AdminResponse::Tunnels { tunnels } => tunnels,
+      other => panic!("Unexpected response {:?}", other),
+    };
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].id, id);
+    assert_eq!(tunnels[0].name, Some(name.clone()));
+    assert_eq!(tunnels[0].ports, vec![port.port()]);
+
+    let missing = AdminRequest::CloseTunnel {
+      tunnel: TunnelSelector::Name(TunnelName::new("missing")),
+    };
+    assert_eq!(
+      send_request(&mut client, &missing).await.unwrap(),
+      AdminResponse::NoSuchTunnel
+    );
+    let close = AdminRequest::CloseTunnel {
+      tunnel: TunnelSelector::Name(name),
+    };
+    assert_eq!(
+      send_request(&mut client, &close).await.unwrap(),
+      AdminResponse::Closed { id }
+    );
+    let (_, _, reason) = timeout(Duration::from_secs(5), disconnected.recv())
+      .await
+      .expect("Closed tunnels must disconnect")
+      .unwrap();
+    assert_eq!(reason, DisconnectReason::Terminated);
+
+    assert_eq!(
+      send_request(&mut client, &AdminRequest::Drain)
+        .await
+        .unwrap(),
+      AdminResponse::Draining
+    );
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Draining must stop the daemon")
+      .unwrap();
+    drop(client);
+    serving.await.unwrap().unwrap();
+  }
+}
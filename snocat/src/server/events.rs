@@ -0,0 +1,232 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Encoding of [ModularDaemon] tunnel lifecycle events for sinks in other processes
+//!
+//! Events are [TunnelEvent]s, which are carried as the value of a completed [EventResult].
+//! A subscriber which lags behind the daemon receives a `dispatch_failed` result in place
+//! of the events it missed.
+#![warn(unused_imports)]
+
+use futures::{
+  future,
+  stream::{BoxStream, StreamExt},
+};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+
+use super::modular::{DisconnectReason, ModularDaemon, TunnelLifecycleEvent};
+use crate::{
+  common::protocol::tunnel::TunnelUplink,
+  proto::{
+    eventing::{event_result, EventResult},
+    ffi::{tunnel_event, TunnelDisconnectReason, TunnelEvent},
+  },
+};
+
+/// Type URL under which [TunnelEvent]s are packed into [EventResult]s
+pub const TUNNEL_EVENT_TYPE_URL: &str = "type.googleapis.com/snocat.ffi.TunnelEvent";
+
+impl From<DisconnectReason> for TunnelDisconnectReason {
+  fn from(reason: DisconnectReason) -> Self {
+    match reason {
+      DisconnectReason::Closed => TunnelDisconnectReason::Closed,
+      DisconnectReason::Shutdown => TunnelDisconnectReason::Shutdown,
+      DisconnectReason::AuthenticationFailed => TunnelDisconnectReason::AuthenticationFailed,
+      DisconnectReason::ReauthenticationFailed => TunnelDisconnectReason::ReauthenticationFailed,
+      DisconnectReason::Revoked => TunnelDisconnectReason::Revoked,
+      DisconnectReason::HeartbeatFailed => TunnelDisconnectReason::HeartbeatFailed,
+      DisconnectReason::Idle => TunnelDisconnectReason::Idle,
+      DisconnectReason::Terminated => TunnelDisconnectReason::Terminated,
+      DisconnectReason::Error => TunnelDisconnectReason::Error,
+    }
+  }
+}
+
+impl From<&TunnelEvent> for EventResult {
+  fn from(event: &TunnelEvent) -> Self {
+    use prost::Message;
+    let mut value = Vec::with_capacity(event.encoded_len());
+    event
+      .encode(&mut value)
+      .expect("Vectors grow to fit encoded messages");
+    EventResult {
+      result: Some(event_result::Result::Completed(event_result::Completion {
+        value: Some(prost_types::Any {
+          type_url: TUNNEL_EVENT_TYPE_URL.into(),
+          value,
+        }),
+      })),
+    }
+  }
+}
+
+/// Each of a daemon's lifecycle events in the order they occurred, or an error where a
+/// lagging subscriber missed some
+fn lifecycle<TTunnel>(
+  daemon: &ModularDaemon<TTunnel>,
+) -> BoxStream<'static, Result<TunnelEvent, BroadcastStreamRecvError>>
+where
+  TTunnel: TunnelUplink + Send + Sync + 'static,
+{
+  use tunnel_event::{Authenticated, Connected, Disconnected, Event};
+  BroadcastStream::new(daemon.tunnel_lifecycle.subscribe())
+    .map(|received| {
+      received.map(|(id, lifecycle_event, occurred_at)| {
+        let event = match lifecycle_event {
+          TunnelLifecycleEvent::Connected { addr } => Event::Connected(Connected {
+            remote_address: addr.to_string(),
+          }),
+          TunnelLifecycleEvent::Authenticated { name } => Event::Authenticated(Authenticated {
+            tunnel_name: name.raw().to_string(),
+          }),
+          TunnelLifecycleEvent::Disconnected { name, reason } => {
+            Event::Disconnected(Disconnected {
+              tunnel_name: name.map(|name| name.raw().to_string()).unwrap_or_default(),
+              reason: TunnelDisconnectReason::from(reason) as i32,
+            })
+          }
+        };
+        TunnelEvent {
+          tunnel_id: id.inner(),
+          timestamp: Some(occurred_at.into()),
+          event: Some(event),
+        }
+      })
+    })
+    .boxed()
+}
+
+/// Subscribes to a daemon's lifecycle events, skipping any missed by lagging behind
+pub fn tunnel_events<TTunnel>(daemon: &ModularDaemon<TTunnel>) -> BoxStream<'static, TunnelEvent>
+where
+  TTunnel: TunnelUplink + Send + Sync + 'static,
+{
+  lifecycle(daemon)
+    .filter_map(|event| {
+      if let Err(BroadcastStreamRecvError::Lagged(missed)) = &event {
+        tracing::warn!(
+          missed,
+          "Tunnel event subscriber lagged; events were dropped"
+        );
+      }
+      future::ready(event.ok())
+    })
+    .boxed()
+}
+
+/// Subscribes to a daemon's lifecycle events as [EventResult]s
+pub fn tunnel_event_results<TTunnel>(
+  daemon: &ModularDaemon<TTunnel>,
+) -> BoxStream<'static, EventResult>
+where
+  TTunnel: TunnelUplink + Send + Sync + 'static,
+{
+  lifecycle(daemon)
+    .map(|event| match event {
+      Ok(event) => EventResult::from(&event),
+      Err(BroadcastStreamRecvError::Lagged(missed)) => {
+        tracing::warn!(
+          missed,
+          "Tunnel event subscriber lagged; events were dropped"
+        );
+        EventResult {
+          result: Some(event_result::Result::DispatchFailed(
+            event_result::DispatchFailure {},
+          )),
+        }
+      }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::StreamExt;
+  use prost::Message;
+  use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+  };
+
+  use super::{tunnel_event_results, TUNNEL_EVENT_TYPE_URL};
+  use crate::{
+    common::{
+      authentication::NoOpAuthenticationHandler,
+      protocol::{
+        routing::NamedTunnelRouter,
+        service_registry::TrieServiceRegistry,
+        traits::InMemoryTunnelRegistry,
+        tunnel::{
+          duplex::DuplexTunnel, id::MonotonicAtomicGenerator, TunnelAddressInfo, TunnelId,
+          TunnelName,
+        },
+      },
+    },
+    proto::{
+      eventing::event_result,
+      ffi::{tunnel_event::Event, TunnelDisconnectReason, TunnelEvent},
+    },
+    server::modular::{DisconnectReason, ModularDaemon, TunnelLifecycleEvent},
+  };
+
+  #[tokio::test]
+  async fn lifecycle_events_encode_as_event_results() {
+    let daemon = ModularDaemon::<DuplexTunnel>::new(
+      Arc::new(TrieServiceRegistry::new()),
+      Arc::new(InMemoryTunnelRegistry::new()),
+      Arc::new(NamedTunnelRouter::new()),
+      Arc::new(NoOpAuthenticationHandler::new()),
+      Arc::new(MonotonicAtomicGenerator::new(0)),
+    );
+    let mut results = tunnel_event_results(&daemon);
+    let lifecycle = vec![
+      TunnelLifecycleEvent::Connected {
+        addr: TunnelAddressInfo::Port(9090),
+      },
+      TunnelLifecycleEvent::Authenticated {
+        name: TunnelName::new("edge"),
+      },
+      TunnelLifecycleEvent::Disconnected {
+        name: Some(TunnelName::new("edge")),
+        reason: DisconnectReason::Revoked,
+      },
+    ];
+    // Timestamps are those of the events, rather than of their encoding
+    let occurred_at = |seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+    for (seconds, event) in (1000..).zip(lifecycle) {
+      assert!(daemon
+        .tunnel_lifecycle
+        .send((TunnelId::new(4), event, occurred_at(seconds)))
+        .is_ok());
+    }
+
+    let mut events = Vec::new();
+    for _ in 0..3usize {
+      let value = match results.next().await.unwrap().result {
+        Some(event_result::Result::Completed(completion)) => completion.value.unwrap(),
+        other => panic!("Expected a completed event, got {:?}", other),
+      };
+      assert_eq!(value.type_url, TUNNEL_EVENT_TYPE_URL);
+      events.push(TunnelEvent::decode(value.value.as_slice()).unwrap());
+    }
+    // Events arrive in the order they occurred
+    assert!(events.iter().all(|event| event.tunnel_id == 4));
+    let timestamps: Vec<_> = events
+      .iter()
+      .map(|event| event.timestamp.clone().unwrap().seconds)
+      .collect();
+    assert_eq!(timestamps, vec![1000, 1001, 1002]);
+    assert!(matches!(
+      &events[0].event,
+      Some(Event::Connected(connected)) if connected.remote_address == "9090"
+    ));
+    assert!(matches!(
+      &events[1].event,
+      Some(Event::Authenticated(authenticated)) if authenticated.tunnel_name == "edge"
+    ));
+    assert!(matches!(
+      &events[2].event,
+      Some(Event::Disconnected(disconnected))
+        if disconnected.reason == TunnelDisconnectReason::Revoked as i32
+    ));
+  }
+}
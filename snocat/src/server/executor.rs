@@ -0,0 +1,32 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Spawning of the tasks which handle each request a [ModularDaemon](super::modular::ModularDaemon) accepts
+#![warn(unused_imports)]
+
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+/// Runs each request's task to completion, or until it is dropped
+///
+/// Tasks handle their own cancellation and panics; executors may wrap them for instrumentation.
+pub trait RequestExecutor {
+  fn spawn(&self, task: BoxFuture<'static, ()>);
+}
+
+pub type ArcRequestExecutor = Arc<dyn RequestExecutor + Send + Sync + 'static>;
+
+/// Spawns request tasks onto the current Tokio runtime
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioRequestExecutor;
+
+impl TokioRequestExecutor {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+impl RequestExecutor for TokioRequestExecutor {
+  fn spawn(&self, task: BoxFuture<'static, ()>) {
+    tokio::task::spawn(task);
+  }
+}
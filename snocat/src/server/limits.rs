@@ -0,0 +1,421 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Limits on the requests a [ModularDaemon](super::modular::ModularDaemon) handles at once
+//!
+//! Each stream a tunnel opens is admitted before negotiation, claiming a negotiation slot until
+//! negotiation completes and a handler slot until its service finishes with it. Slots are
+//! limited per tunnel and across all tunnels; streams arriving while no slot is free are
+//! either refused as overloaded, or left unread on the tunnel until one frees up.
+//!
+//! Refusals are themselves negotiated, so each tunnel refuses only a bounded number of
+//! streams at once, and drops those beyond it unanswered.
+#![warn(unused_imports)]
+
+use std::{
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Streams each tunnel may be refusing as overloaded at once
+pub const MAXIMUM_TUNNEL_REFUSALS: usize = 16;
+
+/// What a tunnel does with streams arriving while it is at its limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadBehavior {
+  /// Negotiate only far enough to refuse the stream as overloaded
+  Refuse,
+  /// Stop reading streams from the tunnel until a slot frees up
+  Backpressure,
+}
+
+impl Default for OverloadBehavior {
+  fn default() -> Self {
+    OverloadBehavior::Refuse
+  }
+}
+
+/// Limits on concurrent requests, which are unlimited unless otherwise configured
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestLimits {
+  tunnel_negotiations: Option<usize>,
+  tunnel_handlers: Option<usize>,
+  negotiations: Option<usize>,
+  handlers: Option<usize>,
+  negotiation_timeout: Option<Duration>,
+  overload: OverloadBehavior,
+}
+
+impl RequestLimits {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Limits the negotiations each tunnel may have in progress at once
+  pub fn with_tunnel_negotiations(mut self, limit: Option<usize>) -> Self {
+    self.tunnel_negotiations = limit;
+    self
+  }
+
+  /// Limits the requests each tunnel may have admitted at once, whether negotiating or handled
+  pub fn with_tunnel_handlers(mut self, limit: Option<usize>) -> Self {
+    self.tunnel_handlers = limit;
+    self
+  }
+
+  /// Limits the negotiations in progress at once across all tunnels
+  pub fn with_negotiations(mut self, limit: Option<usize>) -> Self {
+    self.negotiations = limit;
+    self
+  }
+
+  /// Limits the requests admitted at once across all tunnels, whether negotiating or handled
+  pub fn with_handlers(mut self, limit: Option<usize>) -> Self {
+    self.handlers = limit;
+    self
+  }
+
+  /// Abandons streams which have not completed negotiation within `timeout`, if one is given
+  pub fn with_negotiation_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.negotiation_timeout = timeout;
+    self
+  }
+
+  pub fn with_overload_behavior(mut self, overload: OverloadBehavior) -> Self {
+    self.overload = overload;
+    self
+  }
+
+  pub fn negotiation_timeout(&self) -> Option<Duration> {
+    self.negotiation_timeout
+  }
+
+  pub fn overload_behavior(&self) -> OverloadBehavior {
+    self.overload
+  }
+}
+
+/// Request counts across all of a daemon's tunnels, as of when the snapshot was taken
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RequestMetrics {
+  /// Streams currently negotiating
+  pub negotiating: usize,
+  /// Requests currently being handled by services
+  pub handling: usize,
+  /// Streams refused as overloaded, counted once the refusal has been sent
+  pub refused_overloaded: u64,
+  /// Streams dropped unanswered while overloaded, as their tunnel was refusing too many others
+  pub dropped_overloaded: u64,
+  /// Times a tunnel stopped reading streams to wait for a slot, when applying backpressure
+  pub delayed_overloaded: u64,
+  /// Streams abandoned for exceeding the negotiation timeout
+  pub negotiations_timed_out: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+  negotiating: AtomicUsize,
+  handling: AtomicUsize,
+  refused_overloaded: AtomicU64,
+  dropped_overloaded: AtomicU64,
+  delayed_overloaded: AtomicU64,
+  negotiations_timed_out: AtomicU64,
+}
+
+/// Increments a gauge for as long as it is held
+struct Gauge {
+  counters: Arc<Counters>,
+  select: fn(&Counters) -> &AtomicUsize,
+}
+
+impl Gauge {
+  fn new(counters: &Arc<Counters>, select: fn(&Counters) -> &AtomicUsize) -> Self {
+    select(counters).fetch_add(1, Ordering::Relaxed);
+    Self {
+      counters: Arc::clone(counters),
+      select,
+    }
+  }
+}
+
+impl Drop for Gauge {
+  fn drop(&mut self) {
+    (self.select)(&self.counters).fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Semaphores enforcing a pair of limits, the first of which is always acquired first
+#[derive(Clone, Default)]
+struct Slots(Option<Arc<Semaphore>>, Option<Arc<Semaphore>>);
+
+impl Slots {
+  fn limited(limit: Option<usize>) -> Option<Arc<Semaphore>> {
+    limit.map(|limit| Arc::new(Semaphore::new(limit)))
+  }
+
+  fn try_acquire(&self) -> Option<Vec<OwnedSemaphorePermit>> {
+    let mut permits = Vec::with_capacity(2);
+    for slot in self.0.iter().chain(self.1.iter()) {
+      permits.push(Arc::clone(slot).try_acquire_owned().ok()?);
+    }
+    Some(permits)
+  }
+
+  async fn acquire(&self) -> Vec<OwnedSemaphorePermit> {
+    let mut permits = Vec::with_capacity(2);
+    for slot in self.0.iter().chain(self.1.iter()) {
+      permits.push(
+        Arc::clone(slot)
+          .acquire_owned()
+          .await
+          .expect("Request limit semaphores are never closed"),
+      );
+    }
+    permits
+  }
+}
+
+/// Enforces a daemon's [RequestLimits] across all of its tunnels
+pub(crate) struct RequestLimiter {
+  limits: RequestLimits,
+  negotiations: Option<Arc<Semaphore>>,
+  handlers: Option<Arc<Semaphore>>,
+  counters: Arc<Counters>,
+}
+
+impl RequestLimiter {
+  pub fn new(limits: RequestLimits) -> Self {
+    Self {
+      negotiations: Slots::limited(limits.negotiations),
+      handlers: Slots::limited(limits.handlers),
+      limits,
+      counters: Default::default(),
+    }
+  }
+
+  pub fn limits(&self) -> &RequestLimits {
+    &self.limits
+  }
+
+  pub fn metrics(&self) -> RequestMetrics {
+    let counters = &self.counters;
+    RequestMetrics {
+      negotiating: counters.negotiating.load(Ordering::Relaxed),
+      handling: counters.handling.load(Ordering::Relaxed),
+      refused_overloaded: counters.refused_overloaded.load(Ordering::Relaxed),
+      dropped_overloaded: counters.dropped_overloaded.load(Ordering::Relaxed),
+      delayed_overloaded: counters.delayed_overloaded.load(Ordering::Relaxed),
+      negotiations_timed_out: counters.negotiations_timed_out.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Limits for a newly-established tunnel, sharing this limiter's daemon-wide slots
+  pub fn for_tunnel(&self) -> TunnelLimiter {
+    TunnelLimiter {
+      negotiations: Slots(
+        Slots::limited(self.limits.tunnel_negotiations),
+        self.negotiations.clone(),
+      ),
+      handlers: Slots(
+        Slots::limited(self.limits.tunnel_handlers),
+        self.handlers.clone(),
+      ),
+      refusals: Arc::new(Semaphore::new(MAXIMUM_TUNNEL_REFUSALS)),
+      negotiation_timeout: self.limits.negotiation_timeout,
+      overload: self.limits.overload,
+      counters: Arc::clone(&self.counters),
+    }
+  }
+}
+
+/// Enforces [RequestLimits] for the streams of a single tunnel
+pub(crate) struct TunnelLimiter {
+  negotiations: Slots,
+  handlers: Slots,
+  refusals: Arc<Semaphore>,
+  negotiation_timeout: Option<Duration>,
+  overload: OverloadBehavior,
+  counters: Arc<Counters>,
+}
+
+impl TunnelLimiter {
+  pub fn overload_behavior(&self) -> OverloadBehavior {
+    self.overload
+  }
+
+  pub fn negotiation_timeout(&self) -> Option<Duration> {
+    self.negotiation_timeout
+  }
+
+  /// Admits a stream if slots are free
+  ///
+  /// Streams which are not admitted should be refused via [Self::try_refuse], or dropped.
+  pub fn try_admit(&self) -> Option<Admission> {
+    let handler = self.handlers.try_acquire()?;
+    let negotiation = self.negotiations.try_acquire()?;
+    Some(self.admission(handler, negotiation))
+  }
+
+  /// Waits until slots are free to admit a stream, recording a delay if none are yet
+  ///
+  /// Slots are claimed after the stream is read, so that idle tunnels hold none, and the
+  /// tunnel reads no further streams while waiting.
+  pub async fn admit(&self) -> Admission {
+    if let Some(admission) = self.try_admit() {
+      return admission;
+    }
+    self
+      .counters
+      .delayed_overloaded
+      .fetch_add(1, Ordering::Relaxed);
+    let handler = self.handlers.acquire().await;
+    let negotiation = self.negotiations.acquire().await;
+    self.admission(handler, negotiation)
+  }
+
+  /// Claims a slot in which to refuse a stream as overloaded, unless too many are being refused
+  pub fn try_refuse(&self) -> Option<Refusal> {
+    let slot = Arc::clone(&self.refusals).try_acquire_owned().ok()?;
+    Some(Refusal {
+      _slot: slot,
+      counters: Arc::clone(&self.counters),
+    })
+  }
+
+  /// Records a stream dropped unanswered, as no slot was free in which to refuse it
+  pub fn dropped_overloaded(&self) {
+    self
+      .counters
+      .dropped_overloaded
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Records a stream abandoned for exceeding the negotiation timeout
+  pub fn negotiation_timed_out(&self) {
+    self
+      .counters
+      .negotiations_timed_out
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn admission(
+    &self,
+    handler: Vec<OwnedSemaphorePermit>,
+    negotiation: Vec<OwnedSemaphorePermit>,
+  ) -> Admission {
+    Admission {
+      _handler: handler,
+      negotiation: Some((
+        negotiation,
+        Gauge::new(&self.counters, |counters| &counters.negotiating),
+      )),
+      handling: None,
+      counters: Arc::clone(&self.counters),
+    }
+  }
+}
+
+/// A slot held by a stream being refused as overloaded, released when dropped
+pub(crate) struct Refusal {
+  _slot: OwnedSemaphorePermit,
+  counters: Arc<Counters>,
+}
+
+impl Refusal {
+  /// Records that the refusal reached the stream, releasing its slot
+  pub fn sent(self) {
+    self
+      .counters
+      .refused_overloaded
+      .fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+/// Slots held by an admitted stream, released when dropped
+pub(crate) struct Admission {
+  _handler: Vec<OwnedSemaphorePermit>,
+  negotiation: Option<(Vec<OwnedSemaphorePermit>, Gauge)>,
+  handling: Option<Gauge>,
+  counters: Arc<Counters>,
+}
+
+impl Admission {
+  /// Releases the negotiation slot once the stream has been handed to a service
+  pub fn negotiated(&mut self) {
+    self.negotiation = None;
+    self.handling = Some(Gauge::new(&self.counters, |counters| &counters.handling));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{RequestLimiter, RequestLimits, RequestMetrics, MAXIMUM_TUNNEL_REFUSALS};
+
+  #[tokio::test]
+  async fn limits_apply_per_tunnel_and_globally() {
+    let limiter = RequestLimiter::new(
+      RequestLimits::new()
+        .with_tunnel_handlers(Some(2))
+        .with_handlers(Some(3)),
+    );
+    let (a, b) = (limiter.for_tunnel(), limiter.for_tunnel());
+    let mut first = a.try_admit().expect("Tunnel A must admit its first stream");
+    let _second = a
+      .try_admit()
+      .expect("Tunnel A must admit its second stream");
+    assert!(a.try_admit().is_none(), "Tunnel A must be at its limit");
+    let _third = b.try_admit().expect("Tunnel B must admit its first stream");
+    assert!(b.try_admit().is_none(), "The daemon must be at its limit");
+    first.negotiated();
+    assert_eq!(
+      limiter.metrics(),
+      RequestMetrics {
+        negotiating: 2,
+        handling: 1,
+        ..Default::default()
+      }
+    );
+
+    // Backpressure resumes once a slot is released
+    drop(first);
+    let fourth = tokio::time::timeout(std::time::Duration::from_secs(5), b.admit())
+      .await
+      .expect("A released slot must admit waiting streams");
+    drop(fourth);
+    assert_eq!(limiter.metrics().negotiating, 2);
+  }
+
+  #[test]
+  fn refusals_are_bounded_per_tunnel() {
+    let limiter = RequestLimiter::new(RequestLimits::new());
+    let (a, b) = (limiter.for_tunnel(), limiter.for_tunnel());
+    let refusals: Vec<_> = std::iter::from_fn(|| a.try_refuse())
+      .take(MAXIMUM_TUNNEL_REFUSALS + 1)
+      .collect();
+    assert_eq!(refusals.len(), MAXIMUM_TUNNEL_REFUSALS);
+    assert!(
+      b.try_refuse().is_some(),
+      "Tunnel B must refuse independently"
+    );
+    drop(refusals);
+    assert!(
+      a.try_refuse().is_some(),
+      "Released refusals must be reusable"
+    );
+    // Only refusals which were sent are counted
+    assert_eq!(limiter.metrics().refused_overloaded, 0);
+    a.try_refuse().unwrap().sent();
+    a.dropped_overloaded();
+    assert_eq!(
+      limiter.metrics(),
+      RequestMetrics {
+        refused_overloaded: 1,
+        dropped_overloaded: 1,
+        ..Default::default()
+      }
+    );
+  }
+}
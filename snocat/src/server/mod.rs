@@ -3,21 +3,38 @@
 //! Types for building an Snocat server and accepting, authenticating, and routing connections
 #![warn(unused_imports)]
 use futures::future::FutureExt;
-use std::collections::HashSet;
-use std::{ops::RangeInclusive, sync::Arc};
+use std::collections::HashMap;
+use std::{
+  ops::RangeInclusive,
+  sync::{Arc, RwLock},
+};
 use tokio::sync::Mutex;
 
+use crate::common::protocol::tunnel::TunnelId;
+
+pub mod admin;
+pub mod events;
+pub mod executor;
+pub mod limits;
 pub mod modular;
 
 #[derive(Debug, Clone)]
 pub struct PortRangeAllocator {
-  range: std::ops::RangeInclusive<u16>,
-  allocated: Arc<Mutex<std::collections::HashSet<u16>>>,
+  range: Arc<RwLock<std::ops::RangeInclusive<u16>>>,
+  /// Allocated ports, and the tunnels they were allocated to, if any
+  allocated: Arc<Mutex<HashMap<u16, Option<TunnelId>>>>,
   mark_queue: tokio::sync::mpsc::UnboundedSender<u16>,
   // UnboundedReceiver does not implement clone, so we need an ArcMut of it
   mark_receiver: Arc<Mutex<tokio::sync::mpsc::UnboundedReceiver<u16>>>,
 }
 
+/// A port currently allocated, as listed by [PortRangeAllocator::allocations]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PortAllocation {
+  pub port: u16,
+  pub tunnel: Option<TunnelId>,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum PortRangeAllocationError {
   #[error("No ports were available to be allocated in range {0:?}")]
@@ -32,7 +49,7 @@ impl PortRangeAllocator {
     };
     let (mark_sender, mark_receiver) = tokio::sync::mpsc::unbounded_channel();
     PortRangeAllocator {
-      range: std::ops::RangeInclusive::new(start, end),
+      range: Arc::new(RwLock::new(std::ops::RangeInclusive::new(start, end))),
       allocated: Default::default(),
       mark_queue: mark_sender,
       mark_receiver: Arc::new(Mutex::new(mark_receiver)),
@@ -40,9 +57,24 @@ impl PortRangeAllocator {
   }
 
   pub async fn allocate(&self) -> Result<PortRangeAllocationHandle, PortRangeAllocationError> {
+    self.allocate_owned(None).await
+  }
+
+  /// Allocates a port on behalf of a tunnel, which is reported alongside it by [Self::allocations]
+  pub async fn allocate_to(
+    &self,
+    tunnel: TunnelId,
+  ) -> Result<PortRangeAllocationHandle, PortRangeAllocationError> {
+    self.allocate_owned(Some(tunnel)).await
+  }
+
+  async fn allocate_owned(
+    &self,
+    owner: Option<TunnelId>,
+  ) -> Result<PortRangeAllocationHandle, PortRangeAllocationError> {
     // Used for cleaning up in the deallocator
     let cloned_self = self.clone();
-    let range = self.range.clone();
+    let range = self.range();
     let mark_receiver = Arc::clone(&self.mark_receiver);
     let mut lock = self.allocated.lock().await;
 
@@ -55,19 +87,34 @@ impl PortRangeAllocator {
     let port = range
       .clone()
       .into_iter()
-      .filter(|test_port| !lock.contains(test_port))
+      .filter(|test_port| !lock.contains_key(test_port))
       .min()
       .ok_or_else(|| PortRangeAllocationError::NoFreePorts(range.clone()))?;
 
     let allocation = PortRangeAllocationHandle::new(port, cloned_self);
-    lock.insert(allocation.port);
+    lock.insert(allocation.port, owner);
     Ok(allocation)
   }
 
+  /// Lists the ports currently allocated, in ascending order
+  pub async fn allocations(&self) -> Vec<PortAllocation> {
+    let mut lock = self.allocated.lock().await;
+    {
+      let mut mark_receiver = self.mark_receiver.lock().await;
+      Self::cleanup_freed_ports(&mut *lock, &mut *mark_receiver);
+    }
+    let mut allocations: Vec<_> = lock
+      .iter()
+      .map(|(&port, &tunnel)| PortAllocation { port, tunnel })
+      .collect();
+    allocations.sort_by_key(|allocation| allocation.port);
+    allocations
+  }
+
   pub async fn free(&self, port: u16) -> Result<bool, anyhow::Error> {
     let mark_receiver = Arc::clone(&self.mark_receiver);
     let mut lock = self.allocated.lock().await;
-    let removed = lock.remove(&port);
+    let removed = lock.remove(&port).is_some();
     if removed {
       tracing::trace!(port = port, "unbound port");
     }
@@ -77,14 +124,14 @@ impl PortRangeAllocator {
   }
 
   fn cleanup_freed_ports(
-    allocations: &mut HashSet<u16>,
+    allocations: &mut HashMap<u16, Option<TunnelId>>,
     mark_receiver: &mut tokio::sync::mpsc::UnboundedReceiver<u16>,
   ) {
     // recv waits forever if a sender can still produce values
     // skip that by only receiving those immediately available
     // HACK: Relies on unbounded receivers being immediately available without intermediate polling
     while let Some(Some(marked)) = mark_receiver.recv().now_or_never() {
-      let removed = allocations.remove(&marked);
+      let removed = allocations.remove(&marked).is_some();
       if removed {
         tracing::trace!(port = marked, "unbound marked port");
       }
@@ -111,8 +158,22 @@ impl PortRangeAllocator {
     }
   }
 
-  pub fn range(&self) -> &RangeInclusive<u16> {
-    &self.range
+  pub fn range(&self) -> RangeInclusive<u16> {
+    self
+      .range
+      .read()
+      .expect("Port range lock must not be poisoned")
+      .clone()
+  }
+
+  /// Allocates future ports from `range`, shared by all clones of this allocator
+  ///
+  /// Ports already allocated outside of the new range stay allocated until they are freed.
+  pub fn set_range(&self, range: RangeInclusive<u16>) {
+    *self
+      .range
+      .write()
+      .expect("Port range lock must not be poisoned") = range;
   }
 }
 
@@ -143,3 +204,42 @@ impl Drop for PortRangeAllocationHandle {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{PortAllocation, PortRangeAllocator};
+
+  #[tokio::test]
+  async fn set_range_applies_to_future_allocations_of_every_clone() {
+    let allocator = PortRangeAllocator::new(100u16..=101u16);
+    let kept = allocator.allocate().await.unwrap();
+    assert_eq!(kept.port(), 100);
+
+    allocator.clone().set_range(200..=201);
+    assert_eq!(allocator.range(), 200..=201);
+    let moved = allocator.allocate().await.unwrap();
+    assert_eq!(moved.port(), 200);
+    // Ports outside of the new range stay allocated until freed
+    assert_eq!(
+      allocator.allocations().await,
+      vec![
+        PortAllocation {
+          port: 100,
+          tunnel: None
+        },
+        PortAllocation {
+          port: 200,
+          tunnel: None
+        },
+      ]
+    );
+    drop(kept);
+    assert_eq!(
+      allocator.allocations().await,
+      vec![PortAllocation {
+        port: 200,
+        tunnel: None
+      }]
+    );
+  }
+}
@@ -1,22 +1,50 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 
-use authentication::perform_authentication;
+use authentication::{perform_identified_authentication, with_authentication_timeout};
 use futures::{
-  future::{self, TryFutureExt},
-  Future, Stream, StreamExt, TryStreamExt,
+  future::{self, BoxFuture, FutureExt, TryFutureExt},
+  stream::FuturesUnordered,
+  Future, Stream, StreamExt,
+};
+use std::{
+  any::Any,
+  collections::BTreeMap,
+  panic::AssertUnwindSafe,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, SystemTime},
+};
+use tokio::sync::{
+  broadcast::{channel as event_channel, Sender as Broadcaster},
+  oneshot,
 };
-use std::sync::Arc;
-use tokio::sync::broadcast::{channel as event_channel, Sender as Broadcaster};
 use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
 
+use super::{
+  executor::{ArcRequestExecutor, TokioRequestExecutor},
+  limits::{
+    Admission, OverloadBehavior, Refusal, RequestLimiter, RequestLimits, RequestMetrics,
+    TunnelLimiter,
+  },
+};
 use crate::{
   common::{
     authentication::{
-      self, AuthenticationError, AuthenticationHandler, AuthenticationHandlingError,
+      self, reauthenticate, AuthenticationError, AuthenticationHandler,
+      AuthenticationHandlingError, ReauthenticationService, RemoteAuthenticationError, Revocations,
+      TunnelInfo, REAUTHENTICATION_ADDRESS,
+    },
+    authorization::{
+      AllowAllAuthorizer, ArcAuthorizer, AuthorizationDenial, Authorizer, Permission,
     },
     protocol::{
+      heartbeat::{
+        ping, HeartbeatPolicy, HeartbeatService, PingError, TunnelHealth, HEARTBEAT_ADDRESS,
+      },
       negotiation::{self, NegotiationError, NegotiationService},
       request_handler::RequestClientHandler,
       traits::{
@@ -24,15 +52,97 @@ use crate::{
         TunnelRegistry,
       },
       tunnel::{
-        self, id::TunnelIDGenerator, Tunnel, TunnelDownlink, TunnelError, TunnelId,
-        TunnelIncomingType, TunnelName,
+        self, id::TunnelIDGenerator, Tunnel, TunnelAddressInfo, TunnelControl, TunnelDownlink,
+        TunnelError, TunnelId, TunnelIncomingType, TunnelName, TunnelSide,
       },
-      RouteAddress, Router,
+      ProtocolVersion, RouteAddress, Router, Service, ServiceError, VersionRange,
     },
   },
-  util::tunnel_stream::WrappedStream,
+  util::{
+    dropkick::Dropkick,
+    tunnel_stream::{TunnelStream, WrappedStream},
+  },
 };
 
+/// Authentication attempts taking longer than this are abandoned unless otherwise configured
+pub const DEFAULT_AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a tunnel was disconnected, as reported through [ModularDaemon::tunnel_disconnected]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+  /// The tunnel stopped producing requests, having been closed by either side
+  Closed,
+  /// The daemon was asked to shut down, or to drain the tunnel
+  Shutdown,
+  /// The tunnel failed authentication when it was established
+  AuthenticationFailed,
+  /// The tunnel failed a later re-authentication
+  ReauthenticationFailed,
+  /// The tunnel's name is on the revocation list
+  Revoked,
+  /// The remote stopped answering heartbeat pings
+  HeartbeatFailed,
+  /// The tunnel had no active streams or requests for its idle timeout
+  Idle,
+  /// The tunnel was closed on request, through [ModularDaemon::close_tunnel]
+  Terminated,
+  /// The tunnel could not be registered, or failed while handling requests
+  Error,
+}
+
+/// A stage of a tunnel's lifecycle, as published in order through [ModularDaemon::tunnel_lifecycle]
+#[derive(Clone, Debug)]
+pub enum TunnelLifecycleEvent {
+  Connected {
+    addr: TunnelAddressInfo,
+  },
+  Authenticated {
+    name: TunnelName,
+  },
+  Disconnected {
+    name: Option<TunnelName>,
+    reason: DisconnectReason,
+  },
+}
+
+/// Tunnels forcibly closed by a daemon, as reported by the handle returned from [ModularDaemon::run]
+///
+/// Draining tunnels are forcibly closed once their grace period or the daemon's shutdown deadline
+/// passes with requests still in progress.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+  pub tunnels_closed: usize,
+  /// Streams open on those tunnels when they were closed
+  pub streams_cut: usize,
+}
+
+/// An established tunnel, as listed by [ModularDaemon::tunnels]
+#[derive(Clone, Debug)]
+pub struct TunnelStatus {
+  pub id: TunnelId,
+  /// The tunnel's name, once authenticated
+  pub name: Option<TunnelName>,
+  pub addr: TunnelAddressInfo,
+  pub connected_at: SystemTime,
+  pub active_streams: Option<usize>,
+}
+
+/// Drains or closes an established tunnel on request
+struct TunnelControls {
+  draining: CancellationToken,
+  closing: CancellationToken,
+  connected_at: SystemTime,
+}
+
+/// Shared by each tunnel run from a single tunnel source
+#[derive(Clone)]
+struct Teardown {
+  shutdown: CancellationToken,
+  /// Cancelled once the shutdown deadline passes
+  deadline_lapsed: CancellationToken,
+  report: Arc<Mutex<ShutdownReport>>,
+}
+
 pub struct ModularDaemon<TTunnel> {
   service_registry: Arc<dyn ServiceRegistry + Send + Sync + 'static>,
   tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync + 'static>,
@@ -40,12 +150,27 @@ pub struct ModularDaemon<TTunnel> {
   request_handler: Arc<RequestClientHandler>,
   authentication_handler: Arc<dyn AuthenticationHandler + Send + Sync + 'static>,
   tunnel_id_generator: Arc<dyn TunnelIDGenerator + Send + Sync + 'static>,
+  authentication_timeout: Option<Duration>,
+  authorizer: ArcAuthorizer,
+  reauthentication_interval: Option<Duration>,
+  revocations: Option<Arc<Revocations>>,
+  reauthentication_requests: Mutex<BTreeMap<TunnelId, Arc<tokio::sync::Notify>>>,
+  drain_grace_period: Option<Duration>,
+  shutdown_deadline: Option<Duration>,
+  tunnel_controls: Mutex<BTreeMap<TunnelId, TunnelControls>>,
+  request_limiter: RequestLimiter,
+  request_executor: ArcRequestExecutor,
+  heartbeat: Option<HeartbeatPolicy>,
+  tunnel_health: Mutex<BTreeMap<TunnelId, TunnelHealth>>,
+  idle_timeout: Option<Duration>,
+  tunnels_closed_idle: AtomicU64,
 
   // event hooks
   pub tunnel_connected: Broadcaster<(TunnelId, Arc<TTunnel>)>,
   pub tunnel_authenticated: Broadcaster<(TunnelId, TunnelName, Arc<TTunnel>)>,
-  pub tunnel_disconnected:
-    Broadcaster<(TunnelId, Option<TunnelName> /*, DisconnectReason? */)>,
+  pub tunnel_disconnected: Broadcaster<(TunnelId, Option<TunnelName>, DisconnectReason)>,
+  /// Each of the above, in the order they occurred, across all tunnels, with when they occurred
+  pub tunnel_lifecycle: Broadcaster<(TunnelId, TunnelLifecycleEvent, SystemTime)>,
 }
 
 impl<TTunnel> ModularDaemon<TTunnel> {
@@ -53,22 +178,140 @@ impl<TTunnel> ModularDaemon<TTunnel> {
     &self.request_handler
   }
 
+  /// Asks the daemon to re-authenticate a tunnel it is listening for
+  ///
+  /// Returns false if no such tunnel is established; tunnels failing re-authentication
+  /// are closed with [DisconnectReason::ReauthenticationFailed].
+  pub fn request_reauthentication(&self, id: TunnelId) -> bool {
+    let requests = self
+      .reauthentication_requests
+      .lock()
+      .expect("Re-authentication request lock must not be poisoned");
+    match requests.get(&id) {
+      Some(request) => {
+        request.notify_one();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Asks the daemon to drain a tunnel, as it would on shutdown
+  ///
+  /// The tunnel refuses new requests, and closes once those in progress complete or its
+  /// grace period passes. Returns false if no such tunnel is established.
+  pub fn drain_tunnel(&self, id: TunnelId) -> bool {
+    let controls = self
+      .tunnel_controls
+      .lock()
+      .expect("Tunnel control lock must not be poisoned");
+    match controls.get(&id) {
+      Some(controls) => {
+        controls.draining.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Asks the daemon to close a tunnel immediately, abandoning requests in progress
+  ///
+  /// The tunnel is closed with [DisconnectReason::Terminated]. Returns false if no such
+  /// tunnel is established.
+  pub fn close_tunnel(&self, id: TunnelId) -> bool {
+    let controls = self
+      .tunnel_controls
+      .lock()
+      .expect("Tunnel control lock must not be poisoned");
+    match controls.get(&id) {
+      Some(controls) => {
+        controls.closing.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Lists the tunnels currently established, in order of their IDs
+  pub async fn tunnels(&self) -> Vec<TunnelStatus> {
+    let connected: Vec<(TunnelId, SystemTime)> = self
+      .tunnel_controls
+      .lock()
+      .expect("Tunnel control lock must not be poisoned")
+      .iter()
+      .map(|(id, controls)| (*id, controls.connected_at))
+      .collect();
+    let mut tunnels = Vec::with_capacity(connected.len());
+    for (id, connected_at) in connected {
+      // Tunnels may be deregistered while we look them up
+      if let Some(record) = self.tunnel_registry.lookup_by_id(id).await {
+        tunnels.push(TunnelStatus {
+          id,
+          name: record.name,
+          addr: record.tunnel.addr(),
+          connected_at,
+          active_streams: record.tunnel.active_streams(),
+        });
+      }
+    }
+    tunnels
+  }
+
+  /// The outcome of recent heartbeat pings to an established tunnel's remote
+  ///
+  /// Returns None if the tunnel is not established, or heartbeats are not enabled.
+  pub fn tunnel_health(&self, id: TunnelId) -> Option<TunnelHealth> {
+    self
+      .tunnel_health
+      .lock()
+      .expect("Tunnel health lock must not be poisoned")
+      .get(&id)
+      .copied()
+  }
+
+  pub fn request_limits(&self) -> &RequestLimits {
+    self.request_limiter.limits()
+  }
+
+  /// Counts of requests in progress and refused, across all tunnels
+  pub fn request_metrics(&self) -> RequestMetrics {
+    self.request_limiter.metrics()
+  }
+
+  /// The number of tunnels closed with [DisconnectReason::Idle]
+  pub fn tunnels_closed_idle(&self) -> u64 {
+    self.tunnels_closed_idle.load(Ordering::Relaxed)
+  }
+
+  fn is_revoked(&self, tunnel_name: &TunnelName) -> bool {
+    self.revocations.as_ref().map_or(false, |revocations| {
+      revocations.is_name_revoked(tunnel_name)
+    })
+  }
+
   fn authenticate_tunnel<'a>(
     self: &Arc<Self>,
+    id: TunnelId,
     tunnel: tunnel::ArcTunnel<'a>,
     shutdown: &CancellationToken,
   ) -> impl Future<Output = Result<Option<(tunnel::TunnelName, tunnel::ArcTunnel<'a>)>, anyhow::Error>>
        + 'a {
     let shutdown = shutdown.clone();
     let authentication_handler = Arc::clone(&self.authentication_handler);
+    let authentication_timeout = self.authentication_timeout;
 
     async move {
-      let result = perform_authentication(
+      let shutdown = shutdown.into();
+      let authentication = perform_identified_authentication(
         authentication_handler.as_ref(),
+        id,
         tunnel.as_ref(),
-        &shutdown.into(),
-      )
-      .await;
+        &shutdown,
+      );
+      let result = match authentication_timeout {
+        Some(timeout) => with_authentication_timeout(authentication, timeout).await,
+        None => authentication.await,
+      };
       match result {
         Err(AuthenticationError::Handling(AuthenticationHandlingError::FatalApplicationError(
           fatal_error,
@@ -122,72 +365,193 @@ where
       router,
       authentication_handler,
       tunnel_id_generator,
+      authentication_timeout: Some(DEFAULT_AUTHENTICATION_TIMEOUT),
+      authorizer: Arc::new(AllowAllAuthorizer::new()),
+      reauthentication_interval: None,
+      revocations: None,
+      reauthentication_requests: Mutex::new(BTreeMap::new()),
+      drain_grace_period: None,
+      shutdown_deadline: None,
+      tunnel_controls: Mutex::new(BTreeMap::new()),
+      request_limiter: RequestLimiter::new(RequestLimits::new()),
+      request_executor: Arc::new(TokioRequestExecutor::new()),
+      heartbeat: None,
+      tunnel_health: Mutex::new(BTreeMap::new()),
+      idle_timeout: None,
+      tunnels_closed_idle: AtomicU64::new(0),
 
       // For event handlers, we simply drop the receive sides,
       // as new ones can be made with Sender::subscribe(&self)
       tunnel_connected: event_channel(32).0,
       tunnel_authenticated: event_channel(32).0,
       tunnel_disconnected: event_channel(32).0,
+      tunnel_lifecycle: event_channel(96).0,
     }
   }
 
+  /// Abandons authentication of tunnels which take longer than `timeout`, if one is given
+  ///
+  /// Defaults to [DEFAULT_AUTHENTICATION_TIMEOUT].
+  pub fn with_authentication_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.authentication_timeout = timeout;
+    self
+  }
+
+  /// Consults `authorizer` before routing each incoming request to a service
+  ///
+  /// Defaults to permitting every request from authenticated tunnels.
+  pub fn with_authorizer(mut self, authorizer: ArcAuthorizer) -> Self {
+    self.authorizer = authorizer;
+    self
+  }
+
+  pub fn authorizer(&self) -> &ArcAuthorizer {
+    &self.authorizer
+  }
+
+  /// Periodically re-authenticates tunnels this daemon listens for, if an interval is given
+  ///
+  /// Re-authentication may also be requested on demand via [Self::request_reauthentication].
+  pub fn with_reauthentication_interval(mut self, interval: Option<Duration>) -> Self {
+    self.reauthentication_interval = interval;
+    self
+  }
+
+  /// Refuses tunnels whose names are revoked, and closes them if revoked later
+  ///
+  /// Whenever the list changes, listened tunnels are also re-authenticated, so that
+  /// handlers may check any revoked keys or certificates.
+  pub fn with_revocations(mut self, revocations: Arc<Revocations>) -> Self {
+    self.revocations = Some(revocations);
+    self
+  }
+
+  /// Forcibly closes draining tunnels whose requests are still in progress after `grace_period`
+  ///
+  /// Tunnels drain when the daemon is asked to shut down, or via [Self::drain_tunnel].
+  /// Without a grace period, draining tunnels remain open until their requests complete.
+  pub fn with_drain_grace_period(mut self, grace_period: Option<Duration>) -> Self {
+    self.drain_grace_period = grace_period;
+    self
+  }
+
+  /// Forcibly closes all remaining tunnels once `deadline` has passed since shutdown was requested
+  pub fn with_shutdown_deadline(mut self, deadline: Option<Duration>) -> Self {
+    self.shutdown_deadline = deadline;
+    self
+  }
+
+  /// Limits the requests handled at once, per tunnel and across all tunnels
+  ///
+  /// Requests are unlimited by default.
+  pub fn with_request_limits(mut self, limits: RequestLimits) -> Self {
+    self.request_limiter = RequestLimiter::new(limits);
+    self
+  }
+
+  /// Spawns the task handling each incoming request via `executor`
+  ///
+  /// Defaults to spawning onto the current Tokio runtime.
+  pub fn with_request_executor(mut self, executor: ArcRequestExecutor) -> Self {
+    self.request_executor = executor;
+    self
+  }
+
+  /// Pings the remote of each established tunnel according to `policy`, if one is given
+  ///
+  /// Tunnels failing too many consecutive pings are closed with [DisconnectReason::HeartbeatFailed].
+  /// Heartbeat pings are always answered, whether or not this daemon sends its own.
+  pub fn with_heartbeat(mut self, policy: Option<HeartbeatPolicy>) -> Self {
+    self.heartbeat = policy;
+    self
+  }
+
+  /// Closes tunnels which have been idle for `timeout`, if one is given
+  ///
+  /// Tunnels are idle while they have no streams open other than heartbeats, and no requests
+  /// to services other than reserved ones. Idle tunnels are closed with [DisconnectReason::Idle].
+  pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.idle_timeout = timeout;
+    self
+  }
+
   /// Run the server against a tunnel_source.
   ///
   /// This can be performed concurrently against multiple sources, with a shared server instance.
   /// The implementation assumes that shutdown_request_listener will also halt the tunnel_source.
+  ///
+  /// Shutdown drains each tunnel, and the returned handle resolves once all have closed.
   pub fn run<TunnelSource, TIntoTunnel>(
     self: Arc<Self>,
     tunnel_source: TunnelSource,
     shutdown_request_listener: CancellationToken,
-  ) -> tokio::task::JoinHandle<()>
+  ) -> tokio::task::JoinHandle<ShutdownReport>
   where
     TunnelSource: Stream<Item = TIntoTunnel> + Send + 'static,
     TIntoTunnel: Into<TTunnel>,
-    TTunnel: Tunnel + 'static,
+    TTunnel: Tunnel + TunnelControl + 'static,
   {
+    let teardown = Teardown {
+      shutdown: shutdown_request_listener.clone(),
+      deadline_lapsed: CancellationToken::new(),
+      report: Arc::new(Mutex::new(ShutdownReport::default())),
+    };
+    let deadline = {
+      let teardown = teardown.clone();
+      let shutdown_deadline = self.shutdown_deadline;
+      async move {
+        if let Some(deadline) = shutdown_deadline {
+          teardown.shutdown.cancelled().await;
+          tokio::time::sleep(deadline).await;
+          tracing::info!(
+            ?deadline,
+            "Shutdown deadline passed; closing remaining tunnels"
+          );
+          teardown.deadline_lapsed.cancel();
+        }
+        future::pending::<()>().await
+      }
+    };
     let this = Arc::clone(&self);
     // Pipeline phases:
     // Attach baggage - Arcs need cloned once per incoming tunnel, if they need to access it
     // The baggage attachment phase takes the initial Arc items clones them per-stream
     // This also generates a u64 as an ID for this tunnel, using a naive interlocked/atomic counter
     let pipeline = tunnel_source
-      .take_until({
-        let shutdown_request_listener = shutdown_request_listener.clone();
-        async move { shutdown_request_listener.cancelled().await }
-      })
-      .scan(
-        (this, shutdown_request_listener),
-        |(this, shutdown_request_listener), tunnel| {
-          let id = this.tunnel_id_generator.next();
-          let tunnel: TTunnel = tunnel.into();
-          future::ready(Some((
-            tunnel,
-            id,
-            this.clone(),
-            shutdown_request_listener.clone(),
-          )))
-        },
-      );
+      .take_until(async move { shutdown_request_listener.cancelled().await })
+      .scan((this, teardown.clone()), |(this, teardown), tunnel| {
+        let id = this.tunnel_id_generator.next();
+        let tunnel: TTunnel = tunnel.into();
+        future::ready(Some((tunnel, id, this.clone(), teardown.clone())))
+      });
 
     // Tunnel Lifecycle - Sub-pipeline performed by futures on a per-tunnel basis
     // This could be done at the stream level, but Rust-Analyzer's typesystem struggles
     // to understand stream associated types at this level.
-    let pipeline = pipeline.for_each_concurrent(
-      None,
-      |(tunnel, id, this, shutdown_request_listener)| async move {
-        let tunnel = Arc::new(tunnel);
-        if let Err(e) = this
-          .tunnel_lifecycle(id, tunnel, shutdown_request_listener)
-          .await
-        {
-          tracing::debug!(error=?e, "tunnel lifetime exited with error");
-        }
-      },
-    );
+    let pipeline = pipeline.for_each_concurrent(None, |(tunnel, id, this, teardown)| async move {
+      let tunnel = Arc::new(tunnel);
+      if let Err(e) = this.tunnel_lifecycle(id, tunnel, teardown).await {
+        tracing::debug!(error=?e, "tunnel lifetime exited with error");
+      }
+    });
+
+    // The deadline is only of consequence while tunnels remain
+    let server = async move {
+      futures::pin_mut!(pipeline, deadline);
+      future::select(pipeline, deadline).await;
+      let report = *teardown
+        .report
+        .lock()
+        .expect("Shutdown report lock must not be poisoned");
+      if report.tunnels_closed > 0 {
+        tracing::info!(?report, "Forcibly closed tunnels while shutting down");
+      }
+      report
+    };
 
     // Spawn an instrumented task for the server which will return
     // when all connections shut down and the tunnel source closes
-    tokio::task::spawn(pipeline.instrument(tracing::span!(tracing::Level::INFO, "modular_server")))
+    tokio::task::spawn(server.instrument(tracing::span!(tracing::Level::INFO, "modular_server")))
   }
 }
 
@@ -226,15 +590,118 @@ impl From<RequestProcessingError> for TunnelLifecycleError {
   }
 }
 
+/// Answers requests to reserved services, such as re-authentication and heartbeats,
+/// before consulting the daemon's own service registry
+///
+/// Requests to services other than reserved ones hold their tunnel active while handled.
+struct ReservedServiceRegistry {
+  inner: Arc<dyn ServiceRegistry + Send + Sync + 'static>,
+  reserved: Vec<negotiation::ArcService>,
+  tunnel: tunnel::ArcTunnel<'static>,
+}
+
+impl ServiceRegistry for ReservedServiceRegistry {
+  fn find_service(
+    self: Arc<Self>,
+    addr: &RouteAddress,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+  ) -> Option<negotiation::ArcService> {
+    if let Some(service) = self
+      .reserved
+      .iter()
+      .find(|service| service.accepts(addr, tunnel_id))
+    {
+      return Some(Arc::clone(service));
+    }
+    Arc::clone(&self.inner)
+      .find_service(addr, tunnel_id, tunnel_name)
+      .map(|inner| {
+        Arc::new(ActiveService {
+          inner,
+          tunnel: Arc::clone(&self.tunnel),
+        }) as negotiation::ArcService
+      })
+  }
+
+  fn enumerate_services(
+    self: Arc<Self>,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+  ) -> Vec<crate::common::protocol::ServiceDescriptor> {
+    Arc::clone(&self.inner).enumerate_services(tunnel_id, tunnel_name)
+  }
+}
+
+/// Holds a tunnel active while its inner service handles a request
+struct ActiveService {
+  inner: negotiation::ArcService,
+  tunnel: tunnel::ArcTunnel<'static>,
+}
+
+impl Service for ActiveService {
+  fn accepts(&self, addr: &RouteAddress, tunnel_id: &TunnelId) -> bool {
+    self.inner.accepts(addr, tunnel_id)
+  }
+
+  fn protocol_id(&self) -> Option<&str> {
+    self.inner.protocol_id()
+  }
+
+  fn versions(&self) -> VersionRange {
+    self.inner.versions()
+  }
+
+  fn description(&self) -> Option<String> {
+    self.inner.description()
+  }
+
+  fn handle<'a>(
+    &'a self,
+    addr: RouteAddress,
+    version: ProtocolVersion,
+    stream: Box<dyn TunnelStream + Send + 'static>,
+    tunnel_id: TunnelId,
+  ) -> BoxFuture<'a, Result<(), ServiceError>> {
+    let active = self.tunnel.hold_active();
+    self
+      .inner
+      .handle(addr, version, stream, tunnel_id)
+      .inspect(move |_| drop(active))
+      .boxed()
+  }
+}
+
+/// Permits requests to reserved routes regardless of the inner authorizer
+#[derive(Debug)]
+struct ReservedRouteAuthorizer {
+  inner: ArcAuthorizer,
+  routes: Vec<&'static str>,
+}
+
+impl Authorizer for ReservedRouteAuthorizer {
+  fn authorize(
+    &self,
+    tunnel_id: &TunnelId,
+    tunnel_name: &TunnelName,
+    permission: &Permission<'_>,
+  ) -> Result<(), AuthorizationDenial> {
+    match permission {
+      Permission::Route(route) if self.routes.contains(route) => Ok(()),
+      permission => self.inner.authorize(tunnel_id, tunnel_name, permission),
+    }
+  }
+}
+
 impl<TTunnel> ModularDaemon<TTunnel>
 where
-  TTunnel: Tunnel + 'static,
+  TTunnel: Tunnel + TunnelControl + 'static,
 {
   fn tunnel_lifecycle(
     self: Arc<Self>,
     id: TunnelId,
     tunnel: Arc<TTunnel>,
-    shutdown: CancellationToken,
+    teardown: Teardown,
   ) -> impl Future<Output = Result<(), TunnelLifecycleError>> + 'static {
     async move {
       // A registry mutex that prevents us from racing when calling the registry for
@@ -251,50 +718,141 @@ where
       // Send tunnel_connected event once the tunnel is successfully registered to its ID
       // Ignore error as it occurs only when no receivers exist to read the event
       let _ = self.tunnel_connected.send((id, tunnel.clone()));
+      let _ = self.tunnel_lifecycle.send((id, TunnelLifecycleEvent::Connected { addr: tunnel.addr() }, SystemTime::now()));
 
-      // From here on, any failure must trigger attempted deregistration of the tunnel,
-      // So further phases return their result to check for failures, which then result
-      // in a deregistration call.
+      // From here on, the tunnel must be deregistered when its lifecycle ends, so further
+      // phases return their result, which decides the reason given for disconnection.
       // Phases resume in registered_tunnel_lifecycle.
       let tunnel_registry = Arc::clone(&serialized_registry);
-      match self.registered_tunnel_lifecycle(id, tunnel, shutdown, tunnel_registry).await {
-        Ok(lifecycle_result) => Ok(lifecycle_result),
-        Err(e) => {
-          let deregistered = serialized_registry.deregister_tunnel(id).await.ok();
-          match &e {
-            &TunnelLifecycleError::AuthenticationRefused => tracing::debug!(err=?e, record=?deregistered, "Deregistered due to authentication refusal"),
-            e => tracing::info!(err=?e, record=?deregistered, "Deregistered due to lifecycle error")
+      let draining = teardown.shutdown.child_token();
+      let closing = CancellationToken::new();
+      let _control_registration = self.register_controls(id, draining.clone(), closing.clone());
+      let lifecycle = Arc::clone(&self)
+        .registered_tunnel_lifecycle(id, Arc::clone(&tunnel), draining.clone(), tunnel_registry);
+      let lifecycle = async move {
+        tokio::select! {
+          result = lifecycle => result,
+          () = closing.cancelled() => {
+            tracing::info!("Closing tunnel on request");
+            Ok(DisconnectReason::Terminated)
           }
-          Err(e)
         }
       }
+      .boxed();
+      let drain_deadline = Self::drain_deadline(draining, teardown.deadline_lapsed.clone(), self.drain_grace_period).boxed();
+      let result = match future::select(lifecycle, drain_deadline).await {
+        future::Either::Left((result, _drain_deadline)) => result,
+        future::Either::Right(((), lifecycle)) => {
+          // Abandon requests still in progress, then cut their streams along with the tunnel
+          let streams_cut = tunnel.active_streams().unwrap_or(0);
+          drop(lifecycle);
+          tracing::info!(streams_cut, "Forcibly closing tunnel after drain grace period");
+          let mut report = teardown.report.lock().expect("Shutdown report lock must not be poisoned");
+          report.tunnels_closed += 1;
+          report.streams_cut += streams_cut;
+          Ok(DisconnectReason::Shutdown)
+        }
+      };
+      // Broadcast events may hold the tunnel open, so it is closed rather than dropped,
+      // whatever ended its lifecycle; closing a tunnel already closed by its remote is harmless
+      if let Err(e) = tunnel.close().await {
+        tracing::debug!(error=?e, "Disconnected tunnel failed to close");
+      }
+      let deregistered = serialized_registry.deregister_tunnel(id).await.ok();
+      let reason = match &result {
+        Ok(reason) => reason.clone(),
+        Err(TunnelLifecycleError::AuthenticationRefused) => DisconnectReason::AuthenticationFailed,
+        Err(_) => DisconnectReason::Error,
+      };
+      match &result {
+        Ok(_) => tracing::debug!(?reason, record=?deregistered, "Deregistered disconnected tunnel"),
+        Err(e @ TunnelLifecycleError::AuthenticationRefused) => tracing::debug!(err=?e, record=?deregistered, "Deregistered due to authentication refusal"),
+        Err(e) => tracing::info!(err=?e, record=?deregistered, "Deregistered due to lifecycle error"),
+      }
+      // Ignore error as it occurs only when no receivers exist to read the event
+      let name = deregistered.and_then(|record| record.name);
+      let _ = self.tunnel_disconnected.send((id, name.clone(), reason.clone()));
+      let _ = self.tunnel_lifecycle.send((id, TunnelLifecycleEvent::Disconnected { name, reason }, SystemTime::now()));
+      result.map(|_reason| ())
     }.instrument(tracing::span!(tracing::Level::DEBUG, "tunnel", ?id))
   }
 
+  /// Tracks a tunnel's tokens for [Self::drain_tunnel] and [Self::close_tunnel],
+  /// until the returned guard is dropped
+  fn register_controls(
+    self: &Arc<Self>,
+    id: TunnelId,
+    draining: CancellationToken,
+    closing: CancellationToken,
+  ) -> Dropkick<impl FnOnce() + Send> {
+    self
+      .tunnel_controls
+      .lock()
+      .expect("Tunnel control lock must not be poisoned")
+      .insert(
+        id,
+        TunnelControls {
+          draining,
+          closing,
+          connected_at: SystemTime::now(),
+        },
+      );
+    let this = Arc::clone(self);
+    Dropkick::callback(move || {
+      this
+        .tunnel_controls
+        .lock()
+        .expect("Tunnel control lock must not be poisoned")
+        .remove(&id);
+    })
+  }
+
+  /// Resolves once a draining tunnel must be closed, having outlasted its
+  /// grace period or the daemon's shutdown deadline
+  async fn drain_deadline(
+    draining: CancellationToken,
+    deadline_lapsed: CancellationToken,
+    grace_period: Option<Duration>,
+  ) {
+    let grace = async {
+      draining.cancelled().await;
+      match grace_period {
+        Some(grace_period) => tokio::time::sleep(grace_period).await,
+        None => future::pending().await,
+      }
+    };
+    tokio::select! {
+      () = grace => (),
+      () = deadline_lapsed.cancelled() => (),
+    }
+  }
+
   async fn registered_tunnel_lifecycle(
     self: Arc<Self>,
     id: TunnelId,
     tunnel: Arc<TTunnel>,
     shutdown: CancellationToken,
     serialized_tunnel_registry: Arc<dyn TunnelRegistry + Send + Sync + 'static>,
-  ) -> Result<(), TunnelLifecycleError> {
+  ) -> Result<DisconnectReason, TunnelLifecycleError> {
     // Authenticate connections - Each connection will be piped into the authenticator,
     // which has the option of declining the connection, and may save additional metadata.
     let tunnel_authentication = {
       self
-        .authenticate_tunnel(tunnel.clone(), &shutdown)
+        .authenticate_tunnel(id, tunnel.clone(), &shutdown)
         .instrument(tracing::span!(tracing::Level::DEBUG, "authentication", ?id))
         .map_err(TunnelLifecycleError::FatalError)
     };
 
     let tunnel_name = match tunnel_authentication.await? {
       Some((tunnel_name, _tunnel_dyn)) => tunnel_name,
-      None => {
-        let _ = serialized_tunnel_registry.deregister_tunnel(id).await;
-        return Ok(());
-      }
+      None => return Ok(DisconnectReason::AuthenticationFailed),
     };
 
+    if self.is_revoked(&tunnel_name) {
+      tracing::info!(?tunnel_name, "Refusing tunnel with revoked name");
+      return Ok(DisconnectReason::Revoked);
+    }
+
     // Tunnel naming - The tunnel registry is notified of the authenticator-provided tunnel name
     {
       let tunnel_registry = Arc::clone(&serialized_tunnel_registry);
@@ -311,37 +869,267 @@ where
     let _ = self
       .tunnel_authenticated
       .send((id, tunnel_name.clone(), tunnel.clone()));
+    let _ = self.tunnel_lifecycle.send((
+      id,
+      TunnelLifecycleEvent::Authenticated {
+        name: tunnel_name.clone(),
+      },
+      SystemTime::now(),
+    ));
+
+    // Both sides answer heartbeats, and connecting sides answer re-authentication from their
+    // remote, outside of the usual services and authorization, as the latter is itself authenticated.
+    let mut reserved: Vec<negotiation::ArcService> = vec![Arc::new(HeartbeatService::new())];
+    let mut reserved_routes = vec![HEARTBEAT_ADDRESS];
+    if matches!(tunnel.side(), TunnelSide::Connect) {
+      reserved.push(Arc::new(ReauthenticationService::new(
+        Arc::clone(&self.authentication_handler),
+        TunnelInfo::of(tunnel.as_ref()).with_id(id),
+        tunnel_name.clone(),
+        shutdown.clone().into(),
+      )));
+      reserved_routes.push(REAUTHENTICATION_ADDRESS);
+    }
+    let service_registry: Arc<dyn ServiceRegistry + Send + Sync + 'static> =
+      Arc::new(ReservedServiceRegistry {
+        inner: Arc::clone(&self.service_registry),
+        reserved,
+        tunnel: Arc::clone(&tunnel) as tunnel::ArcTunnel<'static>,
+      });
+    let authorizer: ArcAuthorizer = Arc::new(ReservedRouteAuthorizer {
+      inner: Arc::clone(&self.authorizer),
+      routes: reserved_routes,
+    });
 
     // Process incoming requests until the incoming channel is closed.
-    {
-      let service_registry = Arc::clone(&self.service_registry);
-      Self::handle_incoming_requests(
-        id,
-        tunnel
-          .downlink()
-          .await
-          .ok_or(TunnelLifecycleError::RequestProcessingError(
-            RequestProcessingError::TunnelError(TunnelError::ConnectionClosed),
-          ))?,
-        service_registry,
-        shutdown,
-      )
-      .instrument(tracing::span!(
-        tracing::Level::DEBUG,
-        "request_handling",
-        ?id
-      ))
+    let requests = {
+      self
+        .handle_incoming_requests(
+          id,
+          tunnel_name.clone(),
+          tunnel
+            .downlink()
+            .await
+            .ok_or(TunnelLifecycleError::RequestProcessingError(
+              RequestProcessingError::TunnelError(TunnelError::ConnectionClosed),
+            ))?,
+          service_registry,
+          authorizer,
+          shutdown.clone(),
+        )
+        .instrument(tracing::span!(
+          tracing::Level::DEBUG,
+          "request_handling",
+          ?id
+        ))
+    };
+
+    // Supervision closes the tunnel, abandoning requests in progress, if its credentials lapse,
+    // its remote stops answering heartbeats, or it sits idle
+    let idle = Arc::clone(&self)
+      .monitor_idleness(Arc::clone(&tunnel))
+      .instrument(tracing::span!(tracing::Level::DEBUG, "idle", ?id));
+    let heartbeat = Arc::clone(&self)
+      .monitor_heartbeat(id, Arc::clone(&tunnel))
+      .instrument(tracing::span!(tracing::Level::DEBUG, "heartbeat", ?id));
+    let credentials = Arc::clone(&self)
+      .supervise_tunnel(id, tunnel_name, tunnel, shutdown.clone())
+      .instrument(tracing::span!(tracing::Level::DEBUG, "supervision", ?id));
+    let supervision = async move {
+      tokio::select! {
+        reason = credentials => reason,
+        reason = heartbeat => reason,
+        reason = idle => reason,
+      }
+    };
+
+    futures::pin_mut!(requests, supervision);
+    match future::select(requests, supervision).await {
+      future::Either::Left((requests_result, _supervision)) => {
+        requests_result?;
+        Ok(if shutdown.is_cancelled() {
+          DisconnectReason::Shutdown
+        } else {
+          DisconnectReason::Closed
+        })
+      }
+      future::Either::Right((reason, _requests)) => {
+        tracing::info!(?reason, "Closing tunnel");
+        Ok(reason)
+      }
     }
-    .await?;
+  }
+
+  /// Resolves once the tunnel must be closed, having failed too many heartbeats in a row
+  ///
+  /// Never resolves if heartbeats are not enabled.
+  async fn monitor_heartbeat(
+    self: Arc<Self>,
+    id: TunnelId,
+    tunnel: Arc<TTunnel>,
+  ) -> DisconnectReason {
+    let policy = match self.heartbeat {
+      Some(policy) => policy,
+      None => return future::pending().await,
+    };
+    self
+      .tunnel_health
+      .lock()
+      .expect("Tunnel health lock must not be poisoned")
+      .insert(id, TunnelHealth::default());
+    let _health_registration = {
+      let this = Arc::clone(&self);
+      Dropkick::callback(move || {
+        this
+          .tunnel_health
+          .lock()
+          .expect("Tunnel health lock must not be poisoned")
+          .remove(&id);
+      })
+    };
+
+    loop {
+      tokio::time::sleep(policy.interval()).await;
+      let outcome = tokio::time::timeout(policy.timeout(), ping(tunnel.as_ref()))
+        .await
+        .unwrap_or(Err(PingError::TimedOut));
+      let failures = self
+        .tunnel_health
+        .lock()
+        .expect("Tunnel health lock must not be poisoned")
+        .entry(id)
+        .or_default()
+        .record(&outcome);
+      match &outcome {
+        Ok(rtt) => tracing::trace!(?rtt, "Heartbeat answered"),
+        Err(e) => tracing::debug!(error = %e, failures, "Heartbeat failed"),
+      }
+      if failures > 0 && failures >= policy.failure_threshold() {
+        tracing::info!(failures, "Remote stopped answering heartbeats");
+        return DisconnectReason::HeartbeatFailed;
+      }
+    }
+  }
 
-    // Deregister closed tunnels after graceful exit
-    let _record = serialized_tunnel_registry.deregister_tunnel(id).await;
+  /// Resolves once the tunnel must be closed, having been idle for the idle timeout
+  ///
+  /// Never resolves if no idle timeout is set, or the tunnel does not track its activity.
+  async fn monitor_idleness(self: Arc<Self>, tunnel: Arc<TTunnel>) -> DisconnectReason {
+    let idle_timeout = match self.idle_timeout {
+      Some(idle_timeout) => idle_timeout,
+      None => return future::pending().await,
+    };
+    loop {
+      match tunnel.idle_since() {
+        Some(since) if since.elapsed() >= idle_timeout => {
+          tracing::info!(idle = ?since.elapsed(), "Tunnel is idle");
+          self.tunnels_closed_idle.fetch_add(1, Ordering::Relaxed);
+          return DisconnectReason::Idle;
+        }
+        Some(since) => tokio::time::sleep_until(since + idle_timeout).await,
+        // Activity is not announced, so active tunnels are checked again after the timeout
+        None => tokio::time::sleep(idle_timeout).await,
+      }
+    }
+  }
 
-    // TODO: Find a way to call self.tunnel_disconnected automatically, and simplify deregistration code path
-    //       Otherwise, these deregister calls are an absurd amount of complexity.
-    //       Maybe use drop semantics paired with a cancellation token and a task?
+  /// Resolves once the tunnel must be closed, having failed re-authentication or been revoked
+  ///
+  /// Never resolves if neither re-authentication nor revocation apply to the tunnel.
+  async fn supervise_tunnel(
+    self: Arc<Self>,
+    id: TunnelId,
+    tunnel_name: TunnelName,
+    tunnel: Arc<TTunnel>,
+    shutdown: CancellationToken,
+  ) -> DisconnectReason {
+    // Only the listening side opens the link over which re-authentication is performed
+    let reauthenticates = matches!(tunnel.side(), TunnelSide::Listen);
+    let requested = Arc::new(tokio::sync::Notify::new());
+    let _request_registration = if reauthenticates {
+      self
+        .reauthentication_requests
+        .lock()
+        .expect("Re-authentication request lock must not be poisoned")
+        .insert(id, Arc::clone(&requested));
+      let this = Arc::clone(&self);
+      Some(Dropkick::callback(move || {
+        this
+          .reauthentication_requests
+          .lock()
+          .expect("Re-authentication request lock must not be poisoned")
+          .remove(&id);
+      }))
+    } else {
+      None
+    };
+    let mut revocation_updates = self
+      .revocations
+      .as_ref()
+      .map(|revocations| revocations.subscribe());
+    let interval = self.reauthentication_interval.filter(|_| reauthenticates);
 
-    Ok(())
+    loop {
+      let periodic = async {
+        match interval {
+          Some(interval) => tokio::time::sleep(interval).await,
+          None => future::pending().await,
+        }
+      };
+      let on_demand = async {
+        match reauthenticates {
+          true => requested.notified().await,
+          false => future::pending().await,
+        }
+      };
+      let revocation_update = async {
+        match &mut revocation_updates {
+          // A closed channel can never produce an update
+          Some(updates) => match updates.changed().await {
+            Ok(()) => (),
+            Err(_closed) => future::pending().await,
+          },
+          None => future::pending().await,
+        }
+      };
+      tokio::select! {
+        () = periodic => tracing::debug!("Periodic re-authentication due"),
+        () = on_demand => tracing::debug!("Re-authentication requested"),
+        () = revocation_update => {
+          if self.is_revoked(&tunnel_name) {
+            return DisconnectReason::Revoked;
+          }
+          if !reauthenticates {
+            continue;
+          }
+          tracing::debug!("Re-authenticating against updated revocations");
+        }
+      }
+
+      let shutdown = shutdown.clone().into();
+      let reauthentication = reauthenticate(
+        self.authentication_handler.as_ref(),
+        tunnel.as_ref(),
+        id,
+        &tunnel_name,
+        &shutdown,
+      );
+      let result = match self.authentication_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, reauthentication)
+          .await
+          .unwrap_or_else(|_elapsed| {
+            Err(AuthenticationError::Remote(RemoteAuthenticationError::TimedOut).into())
+          }),
+        None => reauthentication.await,
+      };
+      match result {
+        Ok(()) => tracing::debug!("Tunnel re-authenticated"),
+        Err(e) => {
+          tracing::info!(error = %e, "Tunnel failed re-authentication");
+          return DisconnectReason::ReauthenticationFailed;
+        }
+      }
+    }
   }
 
   // Process incoming requests until the incoming channel is closed.
@@ -349,38 +1137,173 @@ where
   // A tunnel has "closed on its own" if incoming closes *or* outgoing requests fail with
   // a notification that the outgoing channel has been closed.
   //
+  // Once draining, requests are refused as shutting down, and the tunnel closes when those
+  // already accepted have completed.
+  //
+  // Requests beyond the tunnel's limits are refused as overloaded or, under backpressure,
+  // held until a request in progress completes, leaving any others unread on the tunnel.
+  //
+  // Each request is handled in its own task, which is abandoned if the tunnel closes first.
+  //
   // The request handler for this side should be configured to send a close request for
   // the tunnel with the given ID when it sees a request fail due to tunnel closure.
   // TODO: configure request handler (?) to do that using a std::sync::Weak<ModularDaemon>.
   async fn handle_incoming_requests<TDownlink: TunnelDownlink>(
+    &self,
     id: TunnelId,
+    tunnel_name: TunnelName,
     mut incoming: TDownlink,
     service_registry: Arc<dyn ServiceRegistry + Send + Sync + 'static>,
-    shutdown: CancellationToken,
+    authorizer: ArcAuthorizer,
+    draining: CancellationToken,
   ) -> Result<(), RequestProcessingError> {
-    let negotiator = Arc::new(NegotiationService::new(service_registry));
-
-    incoming
-      .as_stream()
-      // Stop accepting new requests after a graceful shutdown is requested
-      .take_until(shutdown.clone().cancelled())
-      .map_err(|e: TunnelError| RequestProcessingError::TunnelError(e))
-      .scan((negotiator, shutdown), |(negotiator, shutdown), link| {
-        let res = link.map(|content| (Arc::clone(&*negotiator), shutdown.clone(), content));
-        future::ready(Some(res))
-      })
-      .try_for_each_concurrent(None, |(negotiator, shutdown, link)| {
-        Self::handle_incoming_request(id, link, negotiator, shutdown)
-      })
-      .await?;
+    let negotiator = Arc::new(
+      NegotiationService::new(service_registry, authorizer).with_draining(draining.clone()),
+    );
+    let limiter = Arc::new(self.request_limiter.for_tunnel());
+    let tunnel_closed = CancellationToken::new();
+    let _abandon_requests = Dropkick::callback({
+      let tunnel_closed = tunnel_closed.clone();
+      move || tunnel_closed.cancel()
+    });
+    let mut links = incoming.as_stream().fuse();
+    let mut in_flight = FuturesUnordered::new();
+    let mut incoming_closed = false;
+    // Under backpressure, a stream read while at the limit waits here for a slot,
+    // and no further streams are read from the tunnel until it is admitted
+    let mut waiting = None;
 
+    loop {
+      if (incoming_closed || draining.is_cancelled()) && in_flight.is_empty() {
+        return Ok(());
+      }
+      let admitted = tokio::select! {
+        link = links.next(), if !incoming_closed && waiting.is_none() => match link {
+          Some(link) => {
+            let link = link.map_err(RequestProcessingError::TunnelError)?;
+            match limiter.overload_behavior() {
+              OverloadBehavior::Backpressure => {
+                waiting = Some(link);
+                None
+              }
+              OverloadBehavior::Refuse => match limiter.try_admit() {
+                Some(admission) => Some((link, admission)),
+                None => {
+                  match limiter.try_refuse() {
+                    Some(refusal) => in_flight.push(self.spawn_request(&tunnel_closed, Self::refuse_overloaded_request(
+                      link,
+                      Arc::clone(&negotiator),
+                      limiter.negotiation_timeout(),
+                      refusal,
+                    ))),
+                    None => {
+                      limiter.dropped_overloaded();
+                      tracing::debug!("Dropped request while overloaded, as too many are being refused");
+                    }
+                  }
+                  None
+                }
+              },
+            }
+          }
+          None => {
+            incoming_closed = true;
+            None
+          }
+        },
+        admission = limiter.admit(), if waiting.is_some() => waiting.take().map(|link| (link, admission)),
+        Some(result) = in_flight.next() => {
+          result?;
+          None
+        }
+        // Wakes the loop to check whether draining has completed
+        () = draining.cancelled(), if !draining.is_cancelled() => None,
+      };
+      if let Some((link, admission)) = admitted {
+        in_flight.push(self.spawn_request(
+          &tunnel_closed,
+          Self::handle_incoming_request(
+            id,
+            tunnel_name.clone(),
+            link,
+            Arc::clone(&negotiator),
+            admission,
+            Arc::clone(&limiter),
+            draining.clone(),
+          ),
+        ));
+      }
+    }
+  }
+
+  /// Runs a request as its own task, until it completes or `tunnel_closed` is cancelled
+  ///
+  /// Panics are caught rather than unwinding into the tunnel's lifecycle.
+  fn spawn_request(
+    &self,
+    tunnel_closed: &CancellationToken,
+    request: impl Future<Output = Result<(), RequestProcessingError>> + Send + 'static,
+  ) -> future::BoxFuture<'static, Result<(), RequestProcessingError>> {
+    let (result_sender, result) = oneshot::channel();
+    let tunnel_closed = tunnel_closed.clone();
+    let task = async move {
+      tokio::select! {
+        outcome = AssertUnwindSafe(request).catch_unwind() => {
+          // The tunnel no longer awaits results once it has closed
+          let _ = result_sender.send(outcome);
+        }
+        () = tunnel_closed.cancelled() => tracing::trace!("Request abandoned with its tunnel"),
+      }
+    };
+    self.request_executor.spawn(task.in_current_span().boxed());
+    async move {
+      match result.await {
+        Ok(Ok(result)) => result,
+        Ok(Err(panic)) => {
+          tracing::error!(panic = %panic_message(panic.as_ref()), "Request handling panicked");
+          Ok(())
+        }
+        // Tasks dropped by the executor are treated as abandoned
+        Err(_dropped) => Ok(()),
+      }
+    }
+    .boxed()
+  }
+
+  async fn refuse_overloaded_request<Services>(
+    link: TunnelIncomingType,
+    negotiator: Arc<NegotiationService<Services>>,
+    negotiation_timeout: Option<Duration>,
+    refusal: Refusal,
+  ) -> Result<(), RequestProcessingError>
+  where
+    Services: ServiceRegistry + Send + Sync + ?Sized + 'static,
+  {
+    let refusing = match link {
+      tunnel::TunnelIncomingType::BiStream(link) => negotiator.refuse_overloaded(link),
+    };
+    let refused = match negotiation_timeout {
+      Some(timeout) => tokio::time::timeout(timeout, refusing).await.ok(),
+      None => Some(refusing.await),
+    };
+    match refused {
+      // Refusal succeeded in writing its status to the stream
+      Some(NegotiationError::Overloaded) => {
+        refusal.sent();
+        tracing::debug!("Refused request while overloaded");
+      }
+      other => tracing::debug!(error = ?other, "Failed to refuse request while overloaded"),
+    }
     Ok(())
   }
 
   async fn handle_incoming_request<Services>(
     id: TunnelId,
+    tunnel_name: TunnelName,
     link: TunnelIncomingType,
     negotiator: Arc<NegotiationService<Services>>,
+    admission: Admission,
+    limiter: Arc<TunnelLimiter>,
     shutdown: CancellationToken,
   ) -> Result<(), RequestProcessingError>
   where
@@ -388,21 +1311,45 @@ where
   {
     match link {
       tunnel::TunnelIncomingType::BiStream(link) => {
-        Self::handle_incoming_request_bistream(id, link, negotiator, shutdown).await
+        Self::handle_incoming_request_bistream(
+          id,
+          tunnel_name,
+          link,
+          negotiator,
+          admission,
+          limiter,
+          shutdown,
+        )
+        .await
       }
     }
   }
 
   async fn handle_incoming_request_bistream<Services>(
     tunnel_id: TunnelId,
+    tunnel_name: TunnelName,
     link: WrappedStream,
     negotiator: Arc<NegotiationService<Services>>,
-    shutdown: CancellationToken, // TODO: Respond to shutdown listener requests
+    mut admission: Admission,
+    limiter: Arc<TunnelLimiter>,
+    shutdown: CancellationToken,
   ) -> Result<(), RequestProcessingError>
   where
     Services: ServiceRegistry + Send + Sync + ?Sized + 'static,
   {
-    match negotiator.negotiate(link, tunnel_id).await {
+    let negotiation = negotiator.negotiate(link, tunnel_id, tunnel_name);
+    let negotiated = match limiter.negotiation_timeout() {
+      Some(timeout) => match tokio::time::timeout(timeout, negotiation).await {
+        Ok(negotiated) => negotiated,
+        Err(_elapsed) => {
+          limiter.negotiation_timed_out();
+          tracing::debug!("Abandoned request which did not complete negotiation in time");
+          return Ok(());
+        }
+      },
+      None => negotiation.await,
+    };
+    match negotiated {
       // Tunnels established on an invalid negotiation protocol are useless; consider this fatal
       Err(NegotiationError::UnsupportedProtocolVersion) => {
         Err(RequestProcessingError::UnsupportedProtocolVersion)
@@ -417,6 +1364,19 @@ where
         tracing::debug!("Refused remote protocol request");
         Ok(())
       }
+      Err(NegotiationError::ShuttingDown) => {
+        tracing::debug!("Refused request while draining");
+        Ok(())
+      }
+      Err(NegotiationError::Overloaded) => {
+        tracing::debug!("Refused request while overloaded");
+        Ok(())
+      }
+      // Denials are recorded in the audit log by the authorizer
+      Err(NegotiationError::Unauthorized) => {
+        tracing::debug!("Refused unauthorized request");
+        Ok(())
+      }
       // Lack of support for a service is just a more specific refusal
       Err(NegotiationError::UnsupportedServiceVersion) => {
         tracing::debug!("Refused request due to unsupported service version");
@@ -432,18 +1392,29 @@ where
           NegotiationError::FatalError(e).into(),
         ))
       }
-      Ok((link, route_addr, service)) => {
+      Ok((link, route_addr, version, service)) => {
         if shutdown.is_cancelled() {
           // Drop services post-negotiation if the connection is awaiting
           // shutdown, instead of handing them to the service to be performed.
           return Ok(());
         }
+        admission.negotiated();
         let route_addr: RouteAddress = route_addr;
         let service: negotiation::ArcService = service;
-        match service
-          .handle(route_addr.clone(), Box::new(link), tunnel_id)
+        let handling = service.handle(route_addr.clone(), version, Box::new(link), tunnel_id);
+        let handled = AssertUnwindSafe(handling)
+          .catch_unwind()
           .await
-        {
+          .unwrap_or_else(|panic| Err(ServiceError::Panicked(panic_message(panic.as_ref()))));
+        match handled {
+          Err(e @ ServiceError::Panicked(_)) => {
+            tracing::error!(
+              address = route_addr.as_str(),
+              error = %e,
+              "Protocol Service panicked"
+            );
+            Ok(())
+          }
           // TODO: Figure out which of these should be considered fatal to the tunnel, if any
           Err(e) => {
             tracing::debug!(
@@ -550,3 +1521,489 @@ where
     })?
   }
 }
+
+/// Describes a panic by its message, if it carried one
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+  match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+    (Some(message), _) => message.to_string(),
+    (None, Some(message)) => message.clone(),
+    (None, None) => "Panicked with a non-string payload".into(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::{
+    future::{self, BoxFuture, FutureExt},
+    stream, StreamExt,
+  };
+  use std::{sync::Arc, time::Duration};
+  use tokio::{io::AsyncReadExt, time::timeout};
+  use tokio_util::sync::CancellationToken;
+
+  use super::{DisconnectReason, ModularDaemon, ShutdownReport};
+  use crate::{
+    common::protocol::heartbeat::{ping, HeartbeatPolicy},
+    common::{
+      authentication::{NoOpAuthenticationHandler, RevocationList, Revocations},
+      protocol::{
+        negotiation::{NegotiationClient, NegotiationError},
+        routing::NamedTunnelRouter,
+        service_registry::{ServiceScope, TrieServiceRegistry},
+        traits::InMemoryTunnelRegistry,
+        tunnel::{
+          duplex::{channel as duplex, DuplexTunnel},
+          id::MonotonicAtomicGenerator,
+          TunnelError, TunnelId, TunnelUplink,
+        },
+        ProtocolVersion, RouteAddress, Service, ServiceError, VersionRange,
+      },
+    },
+    server::limits::{OverloadBehavior, RequestLimits, RequestMetrics},
+    util::tunnel_stream::TunnelStream,
+  };
+
+  /// Holds each request open until its client closes it, or indefinitely
+  struct HoldService {
+    until_closed: bool,
+  }
+
+  impl Service for HoldService {
+    fn accepts(&self, _addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+      true
+    }
+
+    fn handle(
+      &'_ self,
+      _addr: RouteAddress,
+      _version: ProtocolVersion,
+      mut stream: Box<dyn TunnelStream + Send + 'static>,
+      _tunnel_id: TunnelId,
+    ) -> BoxFuture<'_, Result<(), ServiceError>> {
+      let until_closed = self.until_closed;
+      async move {
+        match until_closed {
+          true => {
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+          }
+          false => future::pending().await,
+        }
+        Ok(())
+      }
+      .boxed()
+    }
+  }
+
+  /// Panics upon handling any request
+  struct PanicService;
+
+  impl Service for PanicService {
+    fn accepts(&self, _addr: &RouteAddress, _tunnel_id: &TunnelId) -> bool {
+      true
+    }
+
+    fn handle(
+      &'_ self,
+      _addr: RouteAddress,
+      _version: ProtocolVersion,
+      _stream: Box<dyn TunnelStream + Send + 'static>,
+      _tunnel_id: TunnelId,
+    ) -> BoxFuture<'_, Result<(), ServiceError>> {
+      async move { panic!("PanicService handled a request") }.boxed()
+    }
+  }
+
+  /// Runs a daemon over one tunnel, returning the daemon, the tunnel's remote, and its ID
+  async fn serve(
+    daemon: ModularDaemon<DuplexTunnel>,
+    shutdown: &CancellationToken,
+  ) -> (
+    Arc<ModularDaemon<DuplexTunnel>>,
+    DuplexTunnel,
+    TunnelId,
+    tokio::task::JoinHandle<ShutdownReport>,
+  ) {
+    let daemon = Arc::new(daemon);
+    let tunnels = duplex();
+    let mut authenticated = daemon.tunnel_authenticated.subscribe();
+    let source = stream::iter(vec![tunnels.listener]).chain(stream::pending());
+    let running = Arc::clone(&daemon).run(source, shutdown.clone());
+    let (id, _, _) = timeout(Duration::from_secs(5), authenticated.recv())
+      .await
+      .expect("Tunnel must authenticate")
+      .unwrap();
+    (daemon, tunnels.connector, id, running)
+  }
+
+  fn daemon(until_closed: bool) -> ModularDaemon<DuplexTunnel> {
+    let service_registry = Arc::new(TrieServiceRegistry::new());
+    service_registry.register(
+      "/hold",
+      0,
+      ServiceScope::Global,
+      Arc::new(HoldService { until_closed }),
+    );
+    service_registry.register("/panic", 0, ServiceScope::Global, Arc::new(PanicService));
+    ModularDaemon::new(
+      service_registry,
+      Arc::new(InMemoryTunnelRegistry::new()),
+      Arc::new(NamedTunnelRouter::new()),
+      Arc::new(NoOpAuthenticationHandler::new()),
+      Arc::new(MonotonicAtomicGenerator::new(0)),
+    )
+  }
+
+  async fn request(
+    remote: &DuplexTunnel,
+  ) -> Result<impl TunnelStream + Send + 'static, NegotiationError> {
+    request_to(remote, "/hold").await
+  }
+
+  async fn request_to(
+    remote: &DuplexTunnel,
+    addr: &str,
+  ) -> Result<impl TunnelStream + Send + 'static, NegotiationError> {
+    let link = remote.open_link().await.unwrap();
+    NegotiationClient::new(None, VersionRange::default())
+      .handle(addr.into(), link)
+      .await
+      .map(|(link, _version)| link)
+  }
+
+  /// Verifies that shutdown refuses new requests, and cuts those outlasting the grace period
+  #[tokio::test]
+  async fn drain_cuts_requests_after_grace_period() {
+    let shutdown = CancellationToken::new();
+    let daemon = daemon(false).with_drain_grace_period(Some(Duration::from_millis(200)));
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    let (_daemon, remote, _id, running) = serve(daemon, &shutdown).await;
+
+    let _held = request(&remote).await.expect("Request must be accepted");
+    shutdown.cancel();
+    assert!(matches!(
+      request(&remote).await,
+      Err(NegotiationError::ShuttingDown)
+    ));
+
+    let report = timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must close tunnels after the grace period")
+      .unwrap();
+    assert_eq!(
+      report,
+      ShutdownReport {
+        tunnels_closed: 1,
+        streams_cut: 1,
+      }
+    );
+    let (_, _, reason) = disconnected.recv().await.unwrap();
+    assert_eq!(reason, DisconnectReason::Shutdown);
+  }
+
+  /// Verifies that a drained tunnel closes once its requests complete, without being cut
+  #[tokio::test]
+  async fn drain_tunnel_awaits_requests() {
+    let shutdown = CancellationToken::new();
+    let daemon = daemon(true);
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    let (daemon, remote, id, running) = serve(daemon, &shutdown).await;
+
+    let held = request(&remote).await.expect("Request must be accepted");
+    assert!(daemon.drain_tunnel(id));
+    assert!(matches!(
+      request(&remote).await,
+      Err(NegotiationError::ShuttingDown)
+    ));
+    drop(held);
+
+    let (disconnected_id, _, reason) = timeout(Duration::from_secs(5), disconnected.recv())
+      .await
+      .expect("Drained tunnel must close once its requests complete")
+      .unwrap();
+    assert_eq!(disconnected_id, id);
+    assert_eq!(reason, DisconnectReason::Shutdown);
+    assert!(!daemon.drain_tunnel(id), "Closed tunnels cannot be drained");
+
+    shutdown.cancel();
+    let report = timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+    assert_eq!(report, ShutdownReport::default());
+  }
+
+  /// Verifies that requests beyond a tunnel's limit are refused until one completes
+  #[tokio::test]
+  async fn requests_beyond_limits_are_refused() {
+    let shutdown = CancellationToken::new();
+    let limits = RequestLimits::new().with_tunnel_handlers(Some(1));
+    let daemon = daemon(true).with_request_limits(limits);
+    let (daemon, remote, _id, running) = serve(daemon, &shutdown).await;
+
+    let held = request(&remote).await.expect("Request must be accepted");
+    assert!(matches!(
+      request(&remote).await,
+      Err(NegotiationError::Overloaded)
+    ));
+    // Refusals are counted by their task once sent, which may trail the peer reading them
+    timeout(Duration::from_secs(5), async {
+      while daemon.request_metrics().refused_overloaded == 0 {
+        tokio::task::yield_now().await;
+      }
+    })
+    .await
+    .expect("Sent refusals must be counted");
+    assert_eq!(
+      daemon.request_metrics(),
+      RequestMetrics {
+        handling: 1,
+        refused_overloaded: 1,
+        ..Default::default()
+      }
+    );
+    drop(held);
+
+    let accepted = timeout(Duration::from_secs(5), async {
+      loop {
+        match request(&remote).await {
+          Err(NegotiationError::Overloaded) => tokio::task::yield_now().await,
+          result => break result,
+        }
+      }
+    })
+    .await
+    .expect("Requests must be admitted once the limit frees up");
+    assert!(accepted.is_ok());
+
+    shutdown.cancel();
+    drop(accepted);
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+  }
+
+  /// Verifies that idle tunnels hold no slots under backpressure, and waiting requests are admitted
+  #[tokio::test]
+  async fn backpressure_admits_requests_once_read() {
+    let shutdown = CancellationToken::new();
+    let limits = RequestLimits::new()
+      .with_handlers(Some(1))
+      .with_overload_behavior(OverloadBehavior::Backpressure);
+    let daemon = daemon(true).with_request_limits(limits);
+    let (daemon, remote, _id, running) = serve(daemon, &shutdown).await;
+    assert_eq!(daemon.request_metrics(), RequestMetrics::default());
+
+    let held = request(&remote).await.expect("Request must be accepted");
+    let link = remote.open_link().await.unwrap();
+    let waiting = tokio::spawn(async move {
+      NegotiationClient::new(None, VersionRange::default())
+        .handle("/hold".into(), link)
+        .await
+        .map(|(link, _version)| link)
+    });
+    timeout(Duration::from_secs(5), async {
+      while daemon.request_metrics().delayed_overloaded == 0 {
+        tokio::task::yield_now().await;
+      }
+    })
+    .await
+    .expect("Requests beyond the limit must wait for a slot");
+    drop(held);
+
+    let accepted = timeout(Duration::from_secs(5), waiting)
+      .await
+      .expect("Waiting requests must be admitted once the limit frees up")
+      .unwrap();
+    assert!(accepted.is_ok());
+    assert_eq!(daemon.request_metrics().refused_overloaded, 0);
+
+    shutdown.cancel();
+    drop(accepted);
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+  }
+
+  /// Verifies that a panicking service ends only its own request, leaving the tunnel serving
+  #[tokio::test]
+  async fn service_panics_are_isolated() {
+    let shutdown = CancellationToken::new();
+    let daemon = daemon(true);
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    let (_daemon, remote, _id, running) = serve(daemon, &shutdown).await;
+
+    let mut panicked = request_to(&remote, "/panic")
+      .await
+      .expect("Request must be accepted");
+    let mut remainder = Vec::new();
+    timeout(Duration::from_secs(5), panicked.read_to_end(&mut remainder))
+      .await
+      .expect("Panicking requests must be closed")
+      .unwrap();
+    assert!(remainder.is_empty());
+
+    let held = request(&remote)
+      .await
+      .expect("Tunnel must keep serving after a service panics");
+    assert!(disconnected.try_recv().is_err());
+
+    drop(held);
+    shutdown.cancel();
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+  }
+
+  /// Verifies that answered heartbeats record their round-trip times
+  #[tokio::test]
+  async fn heartbeats_record_round_trip_times() {
+    let policy = HeartbeatPolicy::new(Duration::from_millis(20));
+    let shutdown = CancellationToken::new();
+    let daemon = daemon(true).with_heartbeat(Some(policy));
+    let (daemon, remote, id, running) = serve(daemon, &shutdown).await;
+
+    // The remote answers pings while a daemon serves its side of the tunnel
+    let responder = Arc::new(ModularDaemon::<DuplexTunnel>::new(
+      Arc::new(TrieServiceRegistry::new()),
+      Arc::new(InMemoryTunnelRegistry::new()),
+      Arc::new(NamedTunnelRouter::new()),
+      Arc::new(NoOpAuthenticationHandler::new()),
+      Arc::new(MonotonicAtomicGenerator::new(0)),
+    ));
+    let responding = responder.run(
+      stream::iter(vec![remote]).chain(stream::pending()),
+      shutdown.clone(),
+    );
+    let health = timeout(Duration::from_secs(5), async {
+      loop {
+        match daemon.tunnel_health(id) {
+          Some(health) if health.rtt.is_some() => break health,
+          _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+      }
+    })
+    .await
+    .expect("Answered heartbeats must record their round-trip time");
+    assert_eq!(health.consecutive_failures, 0);
+
+    shutdown.cancel();
+    let (running, responding) = timeout(Duration::from_secs(5), future::join(running, responding))
+      .await
+      .expect("Daemons must stop once their tunnels close");
+    running.unwrap();
+    responding.unwrap();
+  }
+
+  /// Verifies that tunnels whose remote stops answering heartbeats are closed
+  #[tokio::test]
+  async fn heartbeats_close_unresponsive_tunnels() {
+    let policy = HeartbeatPolicy::new(Duration::from_millis(20))
+      .with_timeout(Duration::from_millis(50))
+      .with_failure_threshold(2);
+    let shutdown = CancellationToken::new();
+    let daemon = daemon(true).with_heartbeat(Some(policy));
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    // Nothing serves the remote side of the tunnel, so pings are never answered
+    let (daemon, _remote, id, running) = serve(daemon, &shutdown).await;
+
+    let (disconnected_id, _, reason) = timeout(Duration::from_secs(5), disconnected.recv())
+      .await
+      .expect("Unanswered heartbeats must close the tunnel")
+      .unwrap();
+    assert_eq!(disconnected_id, id);
+    assert_eq!(reason, DisconnectReason::HeartbeatFailed);
+    assert!(daemon.tunnel_health(id).is_none());
+
+    shutdown.cancel();
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+  }
+
+  /// Verifies that idle tunnels are closed, and that heartbeats do not count as activity
+  #[tokio::test]
+  async fn idle_tunnels_are_closed() {
+    let idle_timeout = Duration::from_millis(200);
+    let shutdown = CancellationToken::new();
+    let daemon = daemon(true).with_idle_timeout(Some(idle_timeout));
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    let (daemon, remote, id, running) = serve(daemon, &shutdown).await;
+
+    // Requests in progress hold the tunnel open beyond its idle timeout
+    let held = request(&remote).await.expect("Requests must be accepted");
+    tokio::time::sleep(idle_timeout * 2).await;
+    assert!(disconnected.try_recv().is_err());
+    drop(held);
+
+    // Answering pings does not hold the tunnel open
+    let pinging = async {
+      loop {
+        let _ = ping(&remote).await;
+        tokio::time::sleep(idle_timeout / 4).await;
+      }
+    };
+    let closed = async {
+      tokio::select! {
+        () = pinging => unreachable!("Pings continue until the tunnel closes"),
+        closed = disconnected.recv() => closed,
+      }
+    };
+    let (disconnected_id, _, reason) = timeout(Duration::from_secs(5), closed)
+      .await
+      .expect("Idle tunnels must be closed")
+      .unwrap();
+    assert_eq!(disconnected_id, id);
+    assert_eq!(reason, DisconnectReason::Idle);
+    assert_eq!(daemon.tunnels_closed_idle(), 1);
+
+    shutdown.cancel();
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+  }
+
+  /// Verifies that revoked tunnels are closed, even while events hold them open
+  #[tokio::test]
+  async fn revoked_tunnels_are_closed() {
+    let shutdown = CancellationToken::new();
+    let revocations = Arc::new(Revocations::new(RevocationList::default()));
+    let daemon = daemon(true).with_revocations(Arc::clone(&revocations));
+    let mut connected = daemon.tunnel_connected.subscribe();
+    let mut disconnected = daemon.tunnel_disconnected.subscribe();
+    let (daemon, _remote, id, running) = serve(daemon, &shutdown).await;
+    let (_, tunnel) = connected.recv().await.unwrap();
+
+    let name = daemon
+      .tunnels()
+      .await
+      .into_iter()
+      .find(|status| status.id == id)
+      .and_then(|status| status.name)
+      .expect("Authenticated tunnels must be named");
+    let mut list = RevocationList::default();
+    list.tunnel_names.insert(name.raw().into());
+    revocations.replace(list);
+
+    let (disconnected_id, _, reason) = timeout(Duration::from_secs(5), disconnected.recv())
+      .await
+      .expect("Revoked tunnels must be closed")
+      .unwrap();
+    assert_eq!(disconnected_id, id);
+    assert_eq!(reason, DisconnectReason::Revoked);
+    assert!(matches!(
+      tunnel.open_link().await,
+      Err(TunnelError::LocallyClosed)
+    ));
+
+    shutdown.cancel();
+    timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Daemon must stop once its tunnels close")
+      .unwrap();
+  }
+}
@@ -2,19 +2,55 @@
 // Licensed under the MIT license OR Apache 2.0
 //! Utilities for handling transfer of ownership of a future to an external resolver, and
 //! tracking the ouutstanding tasks without blocking.
+//!
+//! Tasks delegated with [DelegationPool::delegate_serialized] are resolved by a serialized
+//! [DelegateResult], such as one produced by another process.
+//!
+//! A task remains outstanding until its receiver yields a result or is dropped. Receivers may be
+//! [cancelled](DelegatedReceiver::cancel) or given a [deadline](DelegatedReceiver::with_deadline),
+//! either of which releases the task and notifies its dispatcher.
 
 use futures::future::BoxFuture;
 use futures::future::{Future, FutureExt};
-use futures::TryFutureExt;
 use std::fmt;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::Mutex;
 use std::task::{Context, Poll};
-use tokio::sync::oneshot::error::RecvError;
-use tokio::sync::{oneshot, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::proto::delegation::{delegate_result, DelegateResult};
+
+#[derive(Debug)]
+pub struct DelegatedTask {
+  /// Present until a serialized result is received for the task
+  resolver: Option<oneshot::Sender<DelegateResult>>,
+  cancellation: CancellationToken,
+  created: Instant,
+  deadline: Option<Instant>,
+}
 
-#[derive(Default, Debug)]
-pub struct DelegatedTask {}
+impl DelegatedTask {
+  fn new(resolver: Option<oneshot::Sender<DelegateResult>>) -> Self {
+    Self {
+      resolver,
+      cancellation: CancellationToken::new(),
+      created: Instant::now(),
+      deadline: None,
+    }
+  }
+}
+
+/// A delegated task which has yet to yield a result
+#[derive(Clone, Debug)]
+pub struct OutstandingTask {
+  pub task_id: u64,
+  /// Time elapsed since the task was delegated
+  pub age: Duration,
+  pub deadline: Option<Instant>,
+}
 
 #[derive(Default, Debug)]
 struct DelegationSet {
@@ -45,11 +81,32 @@ impl DelegationSet {
   pub fn get_outstanding_ids(&self) -> Vec<u64> {
     self.items.keys().cloned().collect()
   }
+
+  fn take_resolver(&mut self, task_id: u64) -> Option<oneshot::Sender<DelegateResult>> {
+    self
+      .items
+      .get_mut(&task_id)
+      .and_then(|task| task.resolver.take())
+  }
+}
+
+/// The exception with which a serialized delegate failed
+#[derive(Clone, PartialEq, Debug)]
+pub enum DelegatedException {
+  Any(prost_types::Any),
+  Text(String),
+  Json(String),
+  Unspecified,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum DelegationError {
   DispatcherDropped,
+  Cancelled,
+  DeadlineExceeded,
+  Exception(DelegatedException),
+  /// A serialized result named no outcome
+  MalformedResult,
 }
 
 impl fmt::Display for DelegationError {
@@ -60,20 +117,73 @@ impl fmt::Display for DelegationError {
 
 impl std::error::Error for DelegationError {}
 
+impl DelegationError {
+  /// Interprets a serialized result, yielding the value of a completion
+  pub fn from_delegate_result(result: DelegateResult) -> Result<Option<prost_types::Any>, Self> {
+    use delegate_result::{exception::Exception, Result as Outcome};
+    match result.result {
+      Some(Outcome::Completed(completion)) => Ok(completion.value),
+      Some(Outcome::Cancelled(_)) => Err(DelegationError::Cancelled),
+      Some(Outcome::Exception(exception)) => {
+        Err(DelegationError::Exception(match exception.exception {
+          Some(Exception::Any(any)) => DelegatedException::Any(any),
+          Some(Exception::Text(text)) => DelegatedException::Text(text),
+          Some(Exception::Json(json)) => DelegatedException::Json(json),
+          None => DelegatedException::Unspecified,
+        }))
+      }
+      None => Err(DelegationError::MalformedResult),
+    }
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ResolutionError {
+  #[error("No outstanding delegated task has ID {0}")]
+  UnknownTask(u64),
+  #[error("Delegated task {0} was already resolved, or does not accept serialized results")]
+  Unresolvable(u64),
+  #[error("Serialized delegate result could not be decoded")]
+  Malformed(#[from] prost::DecodeError),
+}
+
 #[derive(Default, Debug)]
 pub struct DelegationPool {
+  // we use a std::sync::Mutex instead of an async one so that tasks may be released on drop
   delegations: Mutex<DelegationSet>,
 }
 
 pub struct DelegatedReceiver<'a, T: Send> {
   task_id: u64,
+  pool: &'a DelegationPool,
+  cancellation: CancellationToken,
   receiver: BoxFuture<'a, Result<T, DelegationError>>,
 }
 
-impl<'a, T: Send> DelegatedReceiver<'a, T> {
+impl<'a, T: Send + 'a> DelegatedReceiver<'a, T> {
   pub fn get_task_id(&self) -> u64 {
     self.task_id
   }
+
+  /// Abandons the task, yielding [DelegationError::Cancelled] and notifying its dispatcher
+  pub fn cancel(&self) {
+    self.cancellation.cancel();
+  }
+
+  /// Abandons the task with [DelegationError::DeadlineExceeded] if it is unresolved by `deadline`
+  pub fn with_deadline(mut self, deadline: Instant) -> Self {
+    if let Some(task) = self.pool.lock().items.get_mut(&self.task_id) {
+      task.deadline = Some(deadline);
+    }
+    self.receiver = tokio::time::timeout_at(deadline, self.receiver)
+      .map(|result| result.unwrap_or(Err(DelegationError::DeadlineExceeded)))
+      .boxed();
+    self
+  }
+
+  pub fn with_timeout(self, timeout: Duration) -> Self {
+    self.with_deadline(Instant::now() + timeout)
+  }
 }
 
 impl<'a, 'b: 'a, T: Send + 'b> std::fmt::Debug for DelegatedReceiver<'a, T> {
@@ -90,6 +200,25 @@ impl<'a, 'b: 'a, T: Send + 'b> Future for DelegatedReceiver<'a, T> {
   }
 }
 
+/// Releases a task from its pool when its receiver completes or is dropped
+///
+/// Tasks released without a result have their cancellation token cancelled.
+struct Outstanding<'a> {
+  pool: &'a DelegationPool,
+  task_id: u64,
+  cancellation: CancellationToken,
+  settled: bool,
+}
+
+impl<'a> Drop for Outstanding<'a> {
+  fn drop(&mut self) {
+    if !self.settled {
+      self.cancellation.cancel();
+    }
+    self.pool.detach(self.task_id);
+  }
+}
+
 impl DelegationPool {
   pub fn new() -> DelegationPool {
     DelegationPool {
@@ -97,6 +226,56 @@ impl DelegationPool {
     }
   }
 
+  fn lock(&self) -> std::sync::MutexGuard<'_, DelegationSet> {
+    self
+      .delegations
+      .lock()
+      .expect("Delegation set lock must not be poisoned")
+  }
+
+  /// Registers a task, returning a guard which releases it and the task's cancellation token
+  fn attach(&self, resolver: Option<oneshot::Sender<DelegateResult>>) -> Outstanding<'_> {
+    let task = DelegatedTask::new(resolver);
+    let cancellation = task.cancellation.clone();
+    let task_id = self.lock().attach_new(task);
+    Outstanding {
+      pool: self,
+      task_id,
+      cancellation,
+      settled: false,
+    }
+  }
+
+  /// Builds the receiver for a task, which yields `result` unless cancelled first
+  fn receive<'a, T: Send + 'a>(
+    &'a self,
+    mut outstanding: Outstanding<'a>,
+    result: impl Future<Output = Result<T, DelegationError>> + Send + 'a,
+  ) -> DelegatedReceiver<'a, T> {
+    let task_id = outstanding.task_id;
+    let cancellation = outstanding.cancellation.clone();
+    DelegatedReceiver {
+      task_id,
+      pool: self,
+      cancellation: cancellation.clone(),
+      receiver: async move {
+        tokio::select! {
+          result = result => {
+            // Any result delivered settles the task, even an exception or cancellation
+            outstanding.settled = !matches!(result, Err(DelegationError::DispatcherDropped));
+            result
+          },
+          _ = cancellation.cancelled() => Err(DelegationError::Cancelled),
+        }
+      }
+      .boxed(),
+    }
+  }
+
+  /// Delegates a task whose result is sent through the `dispatcher` given to `dispatch`
+  ///
+  /// If the receiver is cancelled, times out, or is dropped, the dispatcher is closed,
+  /// which it may observe through [oneshot::Sender::closed].
   pub fn delegate<
     'a,
     'b: 'a,
@@ -110,69 +289,122 @@ impl DelegationPool {
     let (dispatcher, promise) = oneshot::channel::<T>();
 
     async move {
-      let task_id = self.delegations.lock().await.attach_new(DelegatedTask {});
+      let outstanding = self.attach(None);
 
       // Fire the `dispatch` closure that must eventually result a value being sent via `dispatcher`
       dispatch(dispatcher).await;
 
-      DelegatedReceiver {
-        task_id,
-        receiver: async move {
-          self.detach(task_id).await;
-          promise
-            .map_err(|_| DelegationError::DispatcherDropped)
-            .await
-        }
-        .boxed(),
+      self.receive(
+        outstanding,
+        promise.map(|result| result.map_err(|_| DelegationError::DispatcherDropped)),
+      )
+    }
+  }
+
+  /// Delegates a task to be resolved by a serialized [DelegateResult], as from another process
+  ///
+  /// `dispatch` receives the ID of the task, which [DelegationPool::resolve] later resolves,
+  /// and a token which is cancelled if the task is abandoned before then.
+  /// Completions yield their value, and cancellations and exceptions yield [DelegationError]s.
+  pub async fn delegate_serialized<FutDispatch: Future<Output = ()> + Send>(
+    &self,
+    dispatch: impl (FnOnce(u64, CancellationToken) -> FutDispatch) + Send,
+  ) -> DelegatedReceiver<'_, Option<prost_types::Any>> {
+    let (resolver, promise) = oneshot::channel::<DelegateResult>();
+    let outstanding = self.attach(Some(resolver));
+
+    dispatch(outstanding.task_id, outstanding.cancellation.clone()).await;
+
+    self.receive(
+      outstanding,
+      promise.map(|result| {
+        result
+          .map_err(|_| DelegationError::DispatcherDropped)
+          .and_then(DelegationError::from_delegate_result)
+      }),
+    )
+  }
+
+  /// Resolves a task delegated by [DelegationPool::delegate_serialized]
+  pub async fn resolve(&self, task_id: u64, result: DelegateResult) -> Result<(), ResolutionError> {
+    let mut delegations = self.lock();
+    if !delegations.items.contains_key(&task_id) {
+      return Err(ResolutionError::UnknownTask(task_id));
+    }
+    let resolver = delegations
+      .take_resolver(task_id)
+      .ok_or(ResolutionError::Unresolvable(task_id))?;
+    // A dropped receiver has abandoned the task, which is not the resolver's concern
+    let _ = resolver.send(result);
+    Ok(())
+  }
+
+  /// Resolves a task from an encoded [DelegateResult]
+  pub async fn resolve_encoded(&self, task_id: u64, encoded: &[u8]) -> Result<(), ResolutionError> {
+    use prost::Message;
+    self
+      .resolve(task_id, DelegateResult::decode(encoded)?)
+      .await
+  }
+
+  /// Cancels an outstanding task by ID, returning whether it was found
+  pub fn cancel(&self, task_id: u64) -> bool {
+    match self.lock().items.get(&task_id) {
+      Some(task) => {
+        task.cancellation.cancel();
+        true
       }
+      None => false,
     }
   }
 
-  pub(super) async fn detach(&self, task_id: u64) -> () {
-    let mut lock = self.delegations.lock().await;
-    lock
+  /// Lists tasks yet to yield a result, in the order they were delegated
+  pub fn outstanding(&self) -> Vec<OutstandingTask> {
+    let now = Instant::now();
+    self
+      .lock()
+      .items
+      .iter()
+      .map(|(task_id, task)| OutstandingTask {
+        task_id: *task_id,
+        age: now.saturating_duration_since(task.created),
+        deadline: task.deadline,
+      })
+      .collect()
+  }
+
+  pub(super) fn detach(&self, task_id: u64) {
+    self
+      .lock()
       .detach(&task_id)
       .expect("Value must not be detached by another source");
   }
 }
 
 pub mod blocking {
-  use futures::future::BoxFuture;
-  use futures::future::{Future, FutureExt};
-  use futures::TryFutureExt;
-  use std::pin::Pin;
-  use std::sync::Arc;
-  use std::task::{Context, Poll};
-  use tokio::sync::oneshot::error::RecvError;
-  use tokio::sync::{oneshot, Mutex};
-
-  use crate::util::delegation::{DelegatedReceiver, DelegationError, DelegationPool};
-
-  // pub fn delegate_threaded<
-  //   'a,
-  //   'b: 'a,
-  //   'c: 'b,
-  //   T: Sized + Send + 'c,
-  // >(
-  //   pool: &'b DelegationPool,
-  //   dispatch_blocking: impl (FnOnce(oneshot::Sender<T>) -> ()) + Send + 'b,
-  // ) -> impl Future<Output = BoxFuture<'b, Result<T, DelegationError>>> + 'a {
-  //   async move {
-  //     let recvr = pool.delegate::<'a, 'b, 'c>(|dispatcher| async move {
-  //       // If the future returned by this is dropped, the thread will not be cancelled
-  //       let blocking = tokio::task::spawn_blocking(|| {
-  //         dispatch_blocking(dispatcher)
-  //       });
-  //       match blocking.await {
-  //         Ok(()) => (),
-  //         Err(_join_error) => panic!("Dispatcher thread panicked")
-  //       }
-  //     }).await;
-  //
-  //     recvr.boxed()
-  //   }
-  //     .boxed()
-  // }
+  use tokio::sync::oneshot;
+
+  use crate::util::delegation::{DelegatedReceiver, DelegationPool};
+
+  /// Delegates a task to a blocking thread, which sends its result through the given dispatcher
+  ///
+  /// Threads are not interrupted when their receiver is abandoned; long-running work should
+  /// check [oneshot::Sender::is_closed] and give up once it reports the task abandoned.
+  /// A thread which panics drops its dispatcher, yielding
+  /// [DelegationError::DispatcherDropped](super::DelegationError::DispatcherDropped).
+  pub async fn delegate_threaded<T: Send + 'static>(
+    pool: &DelegationPool,
+    dispatch_blocking: impl (FnOnce(oneshot::Sender<T>)) + Send + 'static,
+  ) -> DelegatedReceiver<'_, T> {
+    pool
+      .delegate(|dispatcher| async move {
+        // The thread is detached, as its result arrives through `dispatcher` rather than its handle
+        drop(tokio::task::spawn_blocking(move || {
+          dispatch_blocking(dispatcher)
+        }));
+      })
+      .await
+  }
 }
 
 #[cfg(test)]
@@ -218,7 +450,7 @@ mod tests {
         dispatcher = Some(dispatch);
       });
       assert_eq!(
-        pool.delegations.lock().await.get_outstanding_ids().len(),
+        pool.lock().get_outstanding_ids().len(),
         0,
         "Must not register until dispatched"
       );
@@ -232,7 +464,7 @@ mod tests {
     }
 
     assert_eq!(
-      pool.delegations.lock().await.get_outstanding_ids().len(),
+      pool.lock().get_outstanding_ids().len(),
       1,
       "Must register active tasks"
     );
@@ -241,9 +473,180 @@ mod tests {
     assert_eq!(res, 42);
 
     assert_eq!(
-      pool.delegations.lock().await.get_outstanding_ids().len(),
+      pool.lock().get_outstanding_ids().len(),
       0,
       "Must not leak tasks"
     );
   }
+
+  /// Verifies that blocking threads resolve their delegated tasks
+  #[tokio::test]
+  async fn delegation_threaded() {
+    let pool = super::DelegationPool::new();
+    let receiver = super::blocking::delegate_threaded(&pool, |dispatch| {
+      std::thread::sleep(std::time::Duration::from_millis(10));
+      dispatch.send(42).unwrap();
+    })
+    .await;
+    assert_eq!(pool.outstanding().len(), 1, "Must register threaded tasks");
+    assert_eq!(receiver.await, Ok(42));
+    assert!(pool.outstanding().is_empty(), "Must not leak tasks");
+  }
+
+  /// Verifies that cancellation, by receiver or by ID, releases the task and closes its dispatcher
+  #[tokio::test]
+  async fn delegation_cancellation() {
+    let pool = super::DelegationPool::new();
+    let mut dispatchers = Vec::new();
+    for _ in 0..2 {
+      let receiver = pool
+        .delegate(|dispatch: tokio::sync::oneshot::Sender<u32>| {
+          dispatchers.push(dispatch);
+          async {}
+        })
+        .await;
+      if dispatchers.len() == 1 {
+        receiver.cancel();
+      } else {
+        assert!(pool.cancel(receiver.get_task_id()));
+      }
+      assert_eq!(receiver.await, Err(super::DelegationError::Cancelled));
+    }
+    for mut dispatcher in dispatchers {
+      dispatcher.closed().await;
+    }
+    assert!(pool.outstanding().is_empty(), "Must not leak tasks");
+    assert!(!pool.cancel(0), "Released tasks must not be cancellable");
+
+    let mut token = None;
+    let receiver = pool
+      .delegate_serialized(|_, cancellation| {
+        token = Some(cancellation);
+        async {}
+      })
+      .await;
+    drop(receiver);
+    assert!(
+      token.unwrap().is_cancelled(),
+      "Dropping a receiver must notify its dispatcher"
+    );
+    assert!(pool.outstanding().is_empty(), "Must not leak tasks");
+  }
+
+  /// Verifies that outstanding tasks report their age and deadline, and expire at the latter
+  #[tokio::test]
+  async fn delegation_deadline() {
+    use std::time::Duration;
+    tokio::time::pause();
+    let pool = super::DelegationPool::new();
+    let mut dispatcher = None;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    let receiver = pool
+      .delegate(|dispatch: tokio::sync::oneshot::Sender<u32>| {
+        dispatcher = Some(dispatch);
+        async {}
+      })
+      .await
+      .with_deadline(deadline);
+    tokio::time::advance(Duration::from_secs(4)).await;
+
+    let outstanding = pool.outstanding();
+    assert_eq!(outstanding.len(), 1);
+    assert_eq!(outstanding[0].task_id, receiver.get_task_id());
+    assert_eq!(outstanding[0].age, Duration::from_secs(4));
+    assert_eq!(outstanding[0].deadline, Some(deadline));
+
+    assert_eq!(
+      receiver.await,
+      Err(super::DelegationError::DeadlineExceeded)
+    );
+    assert!(dispatcher.unwrap().is_closed());
+    assert!(pool.outstanding().is_empty(), "Must not leak tasks");
+  }
+
+  /// Resolves a serialized delegate from its encoded result, returning the receiver's output
+  async fn resolve_serialized(
+    result: crate::proto::delegation::delegate_result::Result,
+  ) -> Result<Option<prost_types::Any>, super::DelegationError> {
+    use prost::Message;
+    let pool = super::DelegationPool::new();
+    let mut dispatched = None;
+    let receiver = pool
+      .delegate_serialized(|task_id, cancellation| {
+        dispatched = Some((task_id, cancellation));
+        async {}
+      })
+      .await;
+    let (dispatched_id, cancellation) = dispatched.unwrap();
+    assert_eq!(dispatched_id, receiver.get_task_id());
+
+    let mut encoded = Vec::new();
+    crate::proto::delegation::DelegateResult {
+      result: Some(result),
+    }
+    .encode(&mut encoded)
+    .unwrap();
+    pool
+      .resolve_encoded(receiver.get_task_id(), &encoded)
+      .await
+      .unwrap();
+    assert!(matches!(
+      pool.resolve_encoded(receiver.get_task_id(), &encoded).await,
+      Err(super::ResolutionError::Unresolvable(_))
+    ));
+    let result = receiver.await;
+    assert!(
+      !cancellation.is_cancelled(),
+      "Delivered results must settle their task, whatever their outcome"
+    );
+    result
+  }
+
+  /// Verifies that each serialized outcome resolves its delegated task
+  #[tokio::test]
+  async fn delegation_serialized_outcomes() {
+    use super::{DelegatedException, DelegationError};
+    use crate::proto::delegation::delegate_result::{
+      exception::Exception as ExceptionKind, Cancellation, Completion, Exception, Result,
+    };
+    let value = prost_types::Any {
+      type_url: "type.googleapis.com/google.protobuf.StringValue".into(),
+      value: vec![0x0a, 0x02, b'o', b'k'],
+    };
+    assert_eq!(
+      resolve_serialized(Result::Completed(Completion {
+        value: Some(value.clone())
+      }))
+      .await,
+      Ok(Some(value))
+    );
+    assert_eq!(
+      resolve_serialized(Result::Cancelled(Cancellation {})).await,
+      Err(DelegationError::Cancelled)
+    );
+    assert_eq!(
+      resolve_serialized(Result::Exception(Exception {
+        exception: Some(ExceptionKind::Text("Host failed".into())),
+      }))
+      .await,
+      Err(DelegationError::Exception(DelegatedException::Text(
+        "Host failed".into()
+      )))
+    );
+  }
+
+  /// Verifies that results for unknown tasks, or which fail to decode, are rejected
+  #[tokio::test]
+  async fn delegation_serialized_rejections() {
+    let pool = super::DelegationPool::new();
+    assert!(matches!(
+      pool.resolve_encoded(7, &[]).await,
+      Err(super::ResolutionError::UnknownTask(7))
+    ));
+    let receiver = pool.delegate_serialized(|_, _| async {}).await;
+    assert!(matches!(
+      pool.resolve_encoded(receiver.get_task_id(), &[0xff]).await,
+      Err(super::ResolutionError::Malformed(_))
+    ));
+  }
 }
@@ -0,0 +1,132 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Detection of streams which have stopped moving bytes
+#![warn(unused_imports)]
+
+use std::{
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::Duration,
+};
+use tokio::{
+  io::{AsyncRead, ReadBuf},
+  time::Instant,
+};
+
+/// Records when bytes were last read from any of the streams sharing it
+#[derive(Clone, Debug)]
+pub struct IdleClock {
+  last_active: Arc<Mutex<Instant>>,
+}
+
+impl Default for IdleClock {
+  fn default() -> Self {
+    Self {
+      last_active: Arc::new(Mutex::new(Instant::now())),
+    }
+  }
+}
+
+impl IdleClock {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn touch(&self) {
+    *self
+      .last_active
+      .lock()
+      .expect("Idle clock lock must not be poisoned") = Instant::now();
+  }
+
+  pub fn last_active(&self) -> Instant {
+    *self
+      .last_active
+      .lock()
+      .expect("Idle clock lock must not be poisoned")
+  }
+
+  /// Resolves once no bytes have been read for `timeout`
+  pub async fn expired(&self, timeout: Duration) {
+    loop {
+      let deadline = self.last_active() + timeout;
+      if Instant::now() >= deadline {
+        return;
+      }
+      tokio::time::sleep_until(deadline).await;
+    }
+  }
+
+  /// Wraps a reader such that each read of one or more bytes touches this clock
+  pub fn track<R>(&self, inner: R) -> IdleRead<R> {
+    IdleRead {
+      inner,
+      clock: self.clone(),
+    }
+  }
+}
+
+/// A reader touching an [IdleClock] whenever bytes are read from it
+pub struct IdleRead<R> {
+  inner: R,
+  clock: IdleClock,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleRead<R> {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<std::io::Result<()>> {
+    let this = self.get_mut();
+    let filled = buf.filled().len();
+    let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+    if buf.filled().len() > filled {
+      this.clock.touch();
+    }
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  use crate::util::{proxy_generic_tokio_streams_with_idle_timeout, IdleTimeout};
+
+  #[tokio::test(start_paused = true)]
+  async fn idle_proxies_time_out() {
+    let timeout = Duration::from_secs(10);
+    let (mut source, proxied) = tokio::io::duplex(64);
+    let (target, mut far) = tokio::io::duplex(64);
+    let proxy = tokio::spawn(async move {
+      let (mut pr, mut pw) = tokio::io::split(proxied);
+      let (mut tr, mut tw) = tokio::io::split(target);
+      proxy_generic_tokio_streams_with_idle_timeout(
+        (&mut pw, &mut pr),
+        (&mut tw, &mut tr),
+        Some(timeout),
+      )
+      .await
+    });
+
+    // Writing more often than the timeout keeps the proxy open
+    let mut received = [0u8; 4];
+    for _ in 0..5usize {
+      tokio::time::sleep(timeout / 2).await;
+      source.write_all(b"ping").await.unwrap();
+      far.read_exact(&mut received).await.unwrap();
+      assert_eq!(&received, b"ping");
+    }
+
+    let idle_since = tokio::time::Instant::now();
+    let res = tokio::time::timeout(timeout * 2, proxy)
+      .await
+      .expect("Idle proxies must close")
+      .unwrap();
+    assert!(matches!(res, Err(IdleTimeout(idle)) if idle == timeout));
+    assert!(idle_since.elapsed() >= timeout);
+  }
+}
@@ -13,6 +13,7 @@ use quinn::{
 use std::boxed::Box;
 use std::path::Path;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -21,6 +22,7 @@ pub mod cancellation;
 pub mod delegation;
 pub mod dropkick;
 pub mod framed;
+pub mod idle;
 mod mapped_owned_async_mutex;
 pub(crate) mod merge_streams;
 pub mod tunnel_stream;
@@ -92,6 +94,40 @@ pub async fn proxy_generic_tokio_streams<
   res
 }
 
+/// Proxied streams were closed after moving no bytes in either direction for the given duration
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Proxied streams were idle for {0:?}")]
+pub struct IdleTimeout(pub Duration);
+
+/// As [proxy_generic_tokio_streams], but gives up once no bytes move for `idle_timeout`, if given
+pub async fn proxy_generic_tokio_streams_with_idle_timeout<
+  SenderA: tokio::io::AsyncWrite + Unpin,
+  ReaderA: tokio::io::AsyncRead + Unpin,
+  SenderB: tokio::io::AsyncWrite + Unpin,
+  ReaderB: tokio::io::AsyncRead + Unpin,
+>(
+  a: (&mut SenderA, &mut ReaderA),
+  b: (&mut SenderB, &mut ReaderB),
+  idle_timeout: Option<Duration>,
+) -> Result<Either<(), ()>, IdleTimeout> {
+  let idle_timeout = match idle_timeout {
+    Some(idle_timeout) => idle_timeout,
+    None => return Ok(proxy_generic_tokio_streams(a, b).await),
+  };
+  let clock = idle::IdleClock::new();
+  let (sender_a, reader_a) = a;
+  let (sender_b, reader_b) = b;
+  let (mut reader_a, mut reader_b) = (clock.track(reader_a), clock.track(reader_b));
+  let proxy = proxy_generic_tokio_streams((sender_a, &mut reader_a), (sender_b, &mut reader_b));
+  tokio::select! {
+    res = proxy => Ok(res),
+    () = clock.expired(idle_timeout) => {
+      tracing::debug!(?idle_timeout, "Proxied streams idle, shutting down proxy");
+      Err(IdleTimeout(idle_timeout))
+    }
+  }
+}
+
 pub async fn proxy_tcp_streams(mut source: TcpStream, mut proxy: TcpStream) -> Result<()> {
   let res: Either<_, _> = {
     let (mut reader, mut writer) = (&mut source).split();
@@ -147,6 +183,40 @@ pub async fn proxy_from_tcp_stream<Sender: AsyncWrite + Unpin, Reader: AsyncRead
   Ok(())
 }
 
+/// Writes `contents` to `path`, readable only by its owner where supported
+///
+/// The contents are staged in a freshly created file beside `path` and renamed over it, so an
+/// existing file's permissions are never inherited and readers never observe a partial write.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+  use std::io::Write;
+  let file_name = path.file_name().ok_or_else(|| {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path must name a file")
+  })?;
+  let mut staged_name = std::ffi::OsString::from(".");
+  staged_name.push(file_name);
+  staged_name.push(format!(
+    ".{}.{:016x}.tmp",
+    std::process::id(),
+    rand::random::<u64>()
+  ));
+  let staged = path.with_file_name(staged_name);
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  let written = options.open(&staged).and_then(|mut file| {
+    file.write_all(contents)?;
+    file.sync_all()
+  });
+  match written.and_then(|()| std::fs::rename(&staged, path)) {
+    Ok(()) => Ok(()),
+    Err(e) => {
+      let _ = std::fs::remove_file(&staged);
+      Err(e)
+    }
+  }
+}
+
 /// Run a block, then, regardless of success/failure, run another block, with access to the results.
 /// Exceptions from the first block are preferred, then from the finally block, then successes
 pub async fn finally_async<
@@ -48,7 +48,11 @@ pub fn parse_ipaddr(v: &str) -> Result<std::net::IpAddr> {
 pub fn parse_port_range(v: &str) -> Result<std::ops::RangeInclusive<u16>> {
   use std::convert::TryFrom;
   match v.split_once(':') {
-    None => Err(AnyErr::msg("Could not match ':' in port range string")),
+    // A single port is a range of one
+    None => v
+      .parse::<u16>()
+      .map(|port| std::ops::RangeInclusive::new(port, port))
+      .map_err(|_| AnyErr::msg("Port range was neither a u16 nor a '<start>:<end>' range")),
     Some((start, end)) => {
       let (start, end) = (start.parse::<u16>(), end.parse::<u16>());
       match (start, end) {
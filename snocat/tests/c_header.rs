@@ -0,0 +1,16 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+#![cfg(feature = "c-header")]
+
+/// Verifies that the checked-in header matches the one generated from `src/ffi`
+///
+/// Rebuild with `SNOCAT_UPDATE_C_HEADER=1` set to update it.
+#[test]
+fn c_header_is_up_to_date() {
+  let generated = include_str!(concat!(env!("OUT_DIR"), "/snocat.h"));
+  let checked_in = include_str!("../include/snocat.h");
+  assert!(
+    generated == checked_in,
+    "include/snocat.h is out of date; rebuild with SNOCAT_UPDATE_C_HEADER=1 set to regenerate it"
+  );
+}
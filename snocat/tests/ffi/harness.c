@@ -0,0 +1,299 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//
+// Hosts a daemon through the C ABI, authenticating tunnels with the listening side of
+// snocat's simple-ack protocol and watching their lifecycle events.
+//
+// Usage: harness <certificate.pem> <private-key.pem> [snocat-cli]
+// When snocat-cli is given, it is run as a client against the daemon.
+
+#include <pthread.h>
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "snocat.h"
+
+#define HOST_NAME "c-host"
+#define ACK_LENGTH 64
+
+static pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+static pthread_cond_t changed = PTHREAD_COND_INITIALIZER;
+static char authenticated_name[64];
+static int authenticated = 0;
+static int disconnected = 0;
+static int released = 0;
+static int failures = 0;
+
+#define CHECK(condition, ...)                                                                      \
+  do {                                                                                             \
+    if (!(condition)) {                                                                            \
+      fprintf(stderr, "FAILED %s:%d: ", __FILE__, __LINE__);                                       \
+      fprintf(stderr, __VA_ARGS__);                                                                \
+      fprintf(stderr, "\n");                                                                       \
+      failures++;                                                                                  \
+    }                                                                                              \
+  } while (0)
+
+static void expect_code(ExternError *error, int32_t code, const char *call) {
+  CHECK(error->code == code, "%s returned code %d (%s), expected %d", call, error->code,
+        error->message ? error->message : "no message", code);
+  snocat_string_free(error->message);
+  error->message = NULL;
+}
+
+// Minimal protobuf decoding, sufficient for the messages this harness inspects
+
+static int read_varint(const uint8_t **cursor, const uint8_t *end, uint64_t *value) {
+  *value = 0;
+  for (int shift = 0; *cursor < end && shift < 64; shift += 7) {
+    uint8_t byte = *(*cursor)++;
+    *value |= (uint64_t)(byte & 0x7f) << shift;
+    if (!(byte & 0x80)) {
+      return 1;
+    }
+  }
+  return 0;
+}
+
+// Finds a field by number, yielding its bytes if length-delimited; returns 0 if absent
+static int find_field(const uint8_t *data, size_t length, uint32_t field, const uint8_t **found,
+                      size_t *found_length) {
+  const uint8_t *cursor = data, *end = data + length;
+  uint64_t key, value;
+  while (cursor < end && read_varint(&cursor, end, &key)) {
+    switch (key & 7) {
+    case 0:
+      if (!read_varint(&cursor, end, &value)) {
+        return 0;
+      }
+      if ((key >> 3) == field) {
+        *found = NULL;
+        *found_length = value;
+        return 1;
+      }
+      break;
+    case 2:
+      if (!read_varint(&cursor, end, &value) || value > (uint64_t)(end - cursor)) {
+        return 0;
+      }
+      if ((key >> 3) == field) {
+        *found = cursor;
+        *found_length = value;
+        return 1;
+      }
+      cursor += value;
+      break;
+    default:
+      return 0;
+    }
+  }
+  return 0;
+}
+
+// Authentication: write a HELO header, then expect it echoed back as HELO/HELO
+
+typedef struct {
+  uint64_t session;
+  uint8_t received[ACK_LENGTH];
+  size_t received_length;
+} Session;
+
+static void complete(Session *session, int accept) {
+  uint8_t result[2 + 2 + sizeof(HOST_NAME)];
+  size_t length;
+  if (accept) {
+    // AuthenticatorSessionResult { acceptance: { connection_id: HOST_NAME } }
+    size_t name_length = strlen(HOST_NAME);
+    result[0] = 0x0a;
+    result[1] = (uint8_t)(name_length + 2);
+    result[2] = 0x0a;
+    result[3] = (uint8_t)name_length;
+    memcpy(result + 4, HOST_NAME, name_length);
+    length = name_length + 4;
+  } else {
+    // AuthenticatorSessionResult { denial: {} }
+    result[0] = 0x12;
+    result[1] = 0;
+    length = 2;
+  }
+  ExternError error = {0};
+  snocat_authenticator_session_complete(session->session, result, length, &error);
+  expect_code(&error, 0, "snocat_authenticator_session_complete");
+  free(session);
+}
+
+static void on_read(void *context, ByteBuffer message) {
+  Session *session = context;
+  const uint8_t *success, *buffer;
+  size_t success_length, buffer_length = 0;
+  int ok = find_field(message.data, (size_t)message.len, 1, &success, &success_length);
+  if (ok && success_length > 0) {
+    ok = find_field(success, success_length, 1, &buffer, &buffer_length);
+  }
+  if (!ok || buffer_length == 0 || session->received_length + buffer_length > ACK_LENGTH) {
+    snocat_bytebuffer_free(message);
+    complete(session, 0);
+    return;
+  }
+  memcpy(session->received + session->received_length, buffer, buffer_length);
+  session->received_length += buffer_length;
+  snocat_bytebuffer_free(message);
+
+  if (session->received_length < ACK_LENGTH) {
+    ExternError error = {0};
+    snocat_authenticator_session_read(session->session, ACK_LENGTH - session->received_length,
+                                      on_read, session, &error);
+    expect_code(&error, 0, "snocat_authenticator_session_read");
+    return;
+  }
+  complete(session, memcmp(session->received, "HELO/HELO", 10) == 0);
+}
+
+static void on_written(void *context, ByteBuffer message) {
+  Session *session = context;
+  const uint8_t *success;
+  size_t success_length;
+  int ok = find_field(message.data, (size_t)message.len, 1, &success, &success_length);
+  snocat_bytebuffer_free(message);
+  if (!ok) {
+    complete(session, 0);
+    return;
+  }
+  ExternError error = {0};
+  snocat_authenticator_session_read(session->session, ACK_LENGTH, on_read, session, &error);
+  expect_code(&error, 0, "snocat_authenticator_session_read");
+}
+
+static void begin_session(void *context, uint64_t session_handle, SnocatTunnelSide side,
+                          const char *remote_address) {
+  (void)context;
+  CHECK(side == SnocatTunnelSide_Listen, "Daemon sessions should be on the listening side");
+  printf("Authenticating %s\n", remote_address ? remote_address : "an unknown address");
+  Session *session = calloc(1, sizeof(Session));
+  session->session = session_handle;
+  uint8_t hello[ACK_LENGTH] = "HELO";
+  ExternError error = {0};
+  snocat_authenticator_session_write(session_handle, hello, sizeof(hello), on_written, session,
+                                     &error);
+  expect_code(&error, 0, "snocat_authenticator_session_write");
+}
+
+static void release(void *context) {
+  (void)context;
+  pthread_mutex_lock(&lock);
+  released = 1;
+  pthread_cond_broadcast(&changed);
+  pthread_mutex_unlock(&lock);
+}
+
+static void on_event(void *context, ByteBuffer message) {
+  (void)context;
+  const uint8_t *event, *name;
+  size_t event_length, name_length;
+  pthread_mutex_lock(&lock);
+  if (find_field(message.data, (size_t)message.len, 3, &event, &event_length)) {
+    printf("Event: connected\n");
+  } else if (find_field(message.data, (size_t)message.len, 4, &event, &event_length)) {
+    if (find_field(event, event_length, 1, &name, &name_length) &&
+        name_length < sizeof(authenticated_name)) {
+      memcpy(authenticated_name, name, name_length);
+      authenticated_name[name_length] = '\0';
+    }
+    authenticated = 1;
+    printf("Event: authenticated as %s\n", authenticated_name);
+  } else if (find_field(message.data, (size_t)message.len, 5, &event, &event_length)) {
+    disconnected++;
+    printf("Event: disconnected\n");
+  }
+  pthread_cond_broadcast(&changed);
+  pthread_mutex_unlock(&lock);
+  snocat_bytebuffer_free(message);
+}
+
+// Waits up to `seconds` for `*flag` to become nonzero
+static int wait_for(int *flag, int seconds) {
+  struct timespec deadline;
+  clock_gettime(CLOCK_REALTIME, &deadline);
+  deadline.tv_sec += seconds;
+  pthread_mutex_lock(&lock);
+  while (!*flag && pthread_cond_timedwait(&changed, &lock, &deadline) == 0) {
+  }
+  int result = *flag;
+  pthread_mutex_unlock(&lock);
+  return result;
+}
+
+int main(int argc, char **argv) {
+  if (argc < 3) {
+    fprintf(stderr, "Usage: %s <certificate.pem> <private-key.pem> [snocat-cli]\n", argv[0]);
+    return 2;
+  }
+  const char *certificate = argv[1], *private_key = argv[2], *cli = argc > 3 ? argv[3] : NULL;
+  ExternError error = {0};
+
+  SnocatAuthenticator incomplete = {NULL, NULL, NULL};
+  snocat_daemon_create(incomplete, &error);
+  expect_code(&error, SNOCAT_ERROR_INVALID_ARGUMENT, "snocat_daemon_create without begin_session");
+
+  SnocatAuthenticator authenticator = {NULL, begin_session, release};
+  uint64_t daemon = snocat_daemon_create(authenticator, &error);
+  expect_code(&error, 0, "snocat_daemon_create");
+
+  snocat_daemon_subscribe(daemon + 1, on_event, NULL, &error);
+  expect_code(&error, -1000, "snocat_daemon_subscribe with an invalid handle");
+  snocat_daemon_subscribe(daemon, NULL, NULL, &error);
+  expect_code(&error, SNOCAT_ERROR_INVALID_ARGUMENT, "snocat_daemon_subscribe without a callback");
+  snocat_daemon_start(daemon, "not an address", certificate, private_key, &error);
+  expect_code(&error, SNOCAT_ERROR_INVALID_ARGUMENT, "snocat_daemon_start with a bad address");
+  snocat_daemon_start(daemon, "127.0.0.1:0", "/nonexistent.pem", private_key, &error);
+  expect_code(&error, SNOCAT_ERROR_IO, "snocat_daemon_start with a missing certificate");
+
+  snocat_daemon_subscribe(daemon, on_event, NULL, &error);
+  expect_code(&error, 0, "snocat_daemon_subscribe");
+  uint16_t port = snocat_daemon_start(daemon, "127.0.0.1:0", certificate, private_key, &error);
+  expect_code(&error, 0, "snocat_daemon_start");
+  CHECK(port != 0, "Daemon should report the port it bound");
+  printf("Daemon listening on port %u\n", port);
+  snocat_daemon_start(daemon, "127.0.0.1:0", certificate, private_key, &error);
+  expect_code(&error, SNOCAT_ERROR_INVALID_STATE, "snocat_daemon_start when already started");
+
+  if (cli) {
+    char driver[32];
+    snprintf(driver, sizeof(driver), "127.0.0.1:%u", port);
+    pid_t client = fork();
+    if (client == 0) {
+      execl(cli, cli, "client", "--authority", certificate, "--driver", driver, "--driver-san",
+            "localhost", "--target", "127.0.0.1:1", (char *)NULL);
+      perror("execl");
+      _exit(127);
+    }
+    wait_for(&authenticated, 20);
+    CHECK(strcmp(authenticated_name, HOST_NAME) == 0, "Client should authenticate as %s, got '%s'",
+          HOST_NAME, authenticated_name);
+    kill(client, SIGKILL);
+    waitpid(client, NULL, 0);
+  }
+
+  snocat_daemon_shutdown(daemon, &error);
+  expect_code(&error, 0, "snocat_daemon_shutdown");
+  if (cli) {
+    CHECK(wait_for(&disconnected, 10), "Tunnel should report its disconnection");
+  }
+  snocat_daemon_destroy(daemon, &error);
+  expect_code(&error, 0, "snocat_daemon_destroy");
+  CHECK(wait_for(&released, 10), "Authenticator should be released with its daemon");
+  snocat_daemon_destroy(daemon, &error);
+  expect_code(&error, -1000, "snocat_daemon_destroy when already destroyed");
+
+  if (failures) {
+    fprintf(stderr, "%d check(s) failed\n", failures);
+    return 1;
+  }
+  printf("All checks passed\n");
+  return 0;
+}
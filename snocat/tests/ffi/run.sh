@@ -0,0 +1,18 @@
+#!/bin/sh
+# Copyright (c) Microsoft Corporation.
+# Licensed under the MIT license OR Apache 2.0
+#
+# Builds the cdylib and snocat-cli, then runs the C harness against them
+set -eu
+
+here="$(cd "$(dirname "$0")" && pwd)"
+workspace="$(cd "$here/../../.." && pwd)"
+target="${CARGO_TARGET_DIR:-$workspace/target}/debug"
+scratch="$(mktemp -d)"
+trap 'rm -rf "$scratch"' EXIT
+
+cargo build --manifest-path "$workspace/Cargo.toml" -p snocat -p snocat-cli
+"$target/snocat-cli" cert "$scratch/server" --san localhost
+${CC:-cc} -Wall -Wextra -o "$scratch/harness" "$here/harness.c" \
+  -I "$here/../../include" -L "$target" -lsnocat -lpthread -Wl,-rpath,"$target"
+"$scratch/harness" "$scratch/server.pub.pem" "$scratch/server.priv.pem" "$target/snocat-cli"